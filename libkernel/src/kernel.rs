@@ -58,6 +58,7 @@ impl<'a> Kernel<'a> {
             .call_once(|| Barrier::new(num_cores as usize));
         let cpu_features = cpuid.get_feature_info().expect("CPU features");
         let local_apic = cpu_features.initial_local_apic_id() as u32;
+        crate::platform::enable_sse();
         let mut port = serial::Serial::new(&serial::COM1);
         if local_apic == self.bootstrap_processor_id {
             port.init();
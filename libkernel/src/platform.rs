@@ -1,4 +1,5 @@
 use spin::{Barrier, Once};
+use x86::controlregs::{self, Cr0, Cr4};
 use x86::cpuid;
 
 pub(crate) fn num_cores() -> u16 {
@@ -18,3 +19,16 @@ pub(crate) fn pick_leader_core() -> bool {
     let barrier = once.call_once(|| Barrier::new(num_cores() as usize));
     barrier.wait().is_leader()
 }
+
+/// Enables SSE on the current core for compiled WebAssembly modules
+///
+/// The kernel itself is built without SSE, so XMM registers and MXCSR are
+/// only used by module code. MXCSR keeps its reset value (round to nearest
+/// even, all exceptions masked), which compiled floating-point code relies on.
+pub(crate) fn enable_sse() {
+    unsafe {
+        let cr0 = controlregs::cr0();
+        controlregs::cr0_write((cr0 - Cr0::CR0_EMULATE_COPROCESSOR) | Cr0::CR0_MONITOR_COPROCESSOR);
+        controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE);
+    }
+}
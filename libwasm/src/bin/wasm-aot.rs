@@ -0,0 +1,62 @@
+//! Compiles WebAssembly modules ahead of time into artifacts the kernel
+//! loads without compiling, for example from the BOOTBOOT initrd
+//!
+//! Usage: `wasm-aot [--optimize] [--sse4.1] [--popcnt] <input.wasm> <output>`
+//!
+//! Code only uses SSE2 unless `--sse4.1` or `--popcnt` is given, in which
+//! case it only loads on processors with these extensions.
+
+use paraos_libwasm::x86_64::{AssembledModule, Features, X86_64Compiler};
+use paraos_libwasm::Compiler;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: wasm-aot [--optimize] [--sse4.1] [--popcnt] <input.wasm> <output>";
+
+fn main() -> ExitCode {
+    let mut optimize = false;
+    let mut features = Features::default();
+    let mut paths = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--optimize" => optimize = true,
+            "--sse4.1" => features.sse4_1 = true,
+            "--popcnt" => features.popcnt = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {arg}\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [input, output] = &paths[..] else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let wasm = match std::fs::read(input) {
+        Ok(wasm) => wasm,
+        Err(e) => {
+            eprintln!("cannot read {input}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let module: AssembledModule = match X86_64Compiler::new(features)
+        .optimize(optimize)
+        .compile(&wasm)
+    {
+        Ok(module) => module,
+        Err(e) => {
+            eprintln!("cannot compile {input}: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = std::fs::write(output, module.to_artifact()) {
+        eprintln!("cannot write {output}: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,75 @@
+use crate::ir::{Block, Function, Op, Value};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+enum Visit {
+    Enter(Block),
+    /// Leaving a block, facts learnt in it are undone down to this length
+    Leave(usize),
+}
+
+/// Clears the bounds checks of memory accesses known to be in bounds
+///
+/// Memories never shrink, so once bytes up to some end past an address have
+/// been accessed, bytes up to that end stay accessible. Accesses dominated by
+/// one to the same memory at the same address, with an end at most as far,
+/// are thus in bounds, as are accesses at constant addresses that end
+/// within `minimum_sizes`, by memory index.
+pub(crate) fn eliminate_bounds_checks(function: &mut Function, minimum_sizes: &[u64]) {
+    let dominators = function.dominators();
+    let mut children = vec![vec![]; function.blocks.len()];
+    for (b, dominator) in dominators.iter().enumerate() {
+        match dominator {
+            Some(d) if d.index() != b => children[d.index()].push(Block(b as u32)),
+            _ => (),
+        }
+    }
+    let mut constants = vec![None; function.values.len()];
+    for block in &function.blocks {
+        for inst in &block.insts {
+            if let (Op::Const(c), Some(result)) = (&inst.op, inst.result) {
+                constants[result.index()] = Some(*c);
+            }
+        }
+    }
+    // Furthest end accessed past each address, in each memory
+    let mut facts: BTreeMap<(u32, Value), u64> = BTreeMap::new();
+    let mut undo: Vec<((u32, Value), Option<u64>)> = vec![];
+    let mut visits = vec![Visit::Enter(Function::ENTRY)];
+    while let Some(visit) = visits.pop() {
+        let block = match visit {
+            Visit::Enter(block) => block,
+            Visit::Leave(length) => {
+                for (key, previous) in undo.drain(length..).rev() {
+                    match previous {
+                        Some(end) => facts.insert(key, end),
+                        None => facts.remove(&key),
+                    };
+                }
+                continue;
+            }
+        };
+        visits.push(Visit::Leave(undo.len()));
+        visits.extend(children[block.index()].iter().map(|c| Visit::Enter(*c)));
+        for inst in &mut function.block_mut(block).insts {
+            let (access, address) = match &mut inst.op {
+                Op::Load(access, address) | Op::Store(access, address, _) => (access, *address),
+                _ => continue,
+            };
+            let Some(end) = access.end() else {
+                continue;
+            };
+            let key = (access.memory, address);
+            let covered = facts.get(&key).is_some_and(|e| *e >= end);
+            let constant = constants[address.index()]
+                .and_then(|c| c.checked_add(end))
+                .is_some_and(|e| e <= minimum_sizes[access.memory as usize]);
+            if covered || constant {
+                access.checked = false;
+            } else {
+                undo.push((key, facts.insert(key, end)));
+            }
+        }
+    }
+}
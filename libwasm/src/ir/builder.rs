@@ -0,0 +1,532 @@
+use crate::ir::{
+    Access, BinaryOp, Block, CompareOp, Function, Inst, Module, Op, Target, Terminator, UnaryOp,
+    Value,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use wasmparser_nostd::{
+    BinaryReaderError, FunctionBody, MemoryImmediate, Operator, Type, TypeOrFuncType,
+};
+
+enum FrameKind {
+    Function,
+    Block,
+    Loop,
+    If {
+        else_block: Block,
+        /// Parameters of the `if`, which the `else` branch starts with too
+        params: Vec<Value>,
+        has_else: bool,
+    },
+}
+
+struct Frame {
+    kind: FrameKind,
+    /// Where branches to the frame go: the loop header for loops, `end`
+    /// otherwise
+    target: Block,
+    /// Block following the frame, whose parameters are its results
+    end: Block,
+    /// Number of values branches to the frame take
+    arity: usize,
+    results: usize,
+    /// Operand stack height below the frame's parameters
+    height: usize,
+}
+
+/// Turns structured control flow into blocks, and the operand stack into
+/// values, operator by operator
+struct Builder<'a> {
+    module: &'a Module,
+    function: Function,
+    frames: Vec<Frame>,
+    stack: Vec<Value>,
+    /// Block being filled, unless the code is unreachable
+    current: Option<Block>,
+    /// Blocks opened in unreachable code, which are skipped
+    skipped: u32,
+}
+
+fn is_integer(ty: &Type) -> bool {
+    matches!(ty, Type::I32 | Type::I64)
+}
+
+/// Builds the IR of a function, with locals read and written by
+/// instructions, unless it uses values or operators the IR doesn't cover
+pub(crate) fn build(
+    module: &Module,
+    index: u32,
+    body: &FunctionBody,
+) -> Result<Option<Function>, BinaryReaderError> {
+    let ty = module.function_type(index).clone();
+    let mut locals = ty.params.to_vec();
+    for local in body.get_locals_reader()? {
+        let (count, ty) = local?;
+        if !is_integer(&ty) {
+            return Ok(None);
+        }
+        locals.extend((0..count).map(|_| ty));
+    }
+    if !ty.params.iter().chain(ty.returns.iter()).all(is_integer) {
+        return Ok(None);
+    }
+    let mut function = Function {
+        ty: ty.clone(),
+        locals,
+        values: vec![],
+        blocks: vec![],
+    };
+    let entry = function.new_block();
+    for param in ty.params.iter() {
+        let value = function.new_value(*param);
+        function.block_mut(entry).params.push(value);
+    }
+    let exit = function.new_block();
+    for ret in ty.returns.iter() {
+        let value = function.new_value(*ret);
+        function.block_mut(exit).params.push(value);
+    }
+    function.block_mut(exit).terminator = Terminator::Return(function.block(exit).params.clone());
+    let mut builder = Builder {
+        module,
+        function,
+        frames: vec![Frame {
+            kind: FrameKind::Function,
+            target: exit,
+            end: exit,
+            arity: ty.returns.len(),
+            results: ty.returns.len(),
+            height: 0,
+        }],
+        stack: vec![],
+        current: Some(entry),
+        skipped: 0,
+    };
+    for op in body.get_operators_reader()? {
+        if !builder.operator(op?) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(builder.function))
+}
+
+impl<'a> Builder<'a> {
+    fn block_type(&self, ty: TypeOrFuncType) -> Option<(Vec<Type>, Vec<Type>)> {
+        let (params, results) = match ty {
+            TypeOrFuncType::Type(Type::EmptyBlockType) => (vec![], vec![]),
+            TypeOrFuncType::Type(ty) => (vec![], vec![ty]),
+            TypeOrFuncType::FuncType(index) => {
+                let ty = &self.module.types[index as usize];
+                (ty.params.to_vec(), ty.returns.to_vec())
+            }
+        };
+        params
+            .iter()
+            .chain(results.iter())
+            .all(is_integer)
+            .then_some((params, results))
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    /// Values on top of the operand stack, which stay there
+    fn top(&self, count: usize) -> Vec<Value> {
+        self.stack[self.stack.len() - count..].to_vec()
+    }
+
+    fn inst(&mut self, op: Op, ty: Option<Type>) {
+        let result = ty.map(|ty| self.function.new_value(ty));
+        let block = self.current.unwrap();
+        self.function
+            .block_mut(block)
+            .insts
+            .push(Inst { op, result });
+        self.stack.extend(result);
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        let block = self.current.take().unwrap();
+        self.function.block_mut(block).terminator = terminator;
+    }
+
+    /// New block with parameters of the given types
+    fn block_with_params(&mut self, types: &[Type]) -> Block {
+        let block = self.function.new_block();
+        for ty in types {
+            let value = self.function.new_value(*ty);
+            self.function.block_mut(block).params.push(value);
+        }
+        block
+    }
+
+    fn enter(&mut self, kind: FrameKind, target: Block, end: Block, arity: usize, params: usize) {
+        self.frames.push(Frame {
+            kind,
+            target,
+            end,
+            arity,
+            results: self.function.block(end).params.len(),
+            height: self.stack.len() - params,
+        });
+    }
+
+    fn branch_target(&self, relative_depth: u32) -> Target {
+        let frame = &self.frames[self.frames.len() - 1 - relative_depth as usize];
+        Target::new(frame.target, self.top(frame.arity))
+    }
+
+    fn index_type(&self, memory: u32) -> Type {
+        if self.module.memories[memory as usize].memory64 {
+            Type::I64
+        } else {
+            Type::I32
+        }
+    }
+
+    /// Adds the operator to the function, returning `false` if the IR
+    /// doesn't cover it
+    fn operator(&mut self, op: Operator) -> bool {
+        if self.current.is_none() {
+            match op {
+                Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => {
+                    self.skipped += 1;
+                    return true;
+                }
+                Operator::End | Operator::Else if self.skipped > 0 => {
+                    self.skipped -= matches!(op, Operator::End) as u32;
+                    return true;
+                }
+                Operator::End | Operator::Else => (),
+                // Would open a block that isn't counted
+                Operator::Try { .. } => return false,
+                _ => return true,
+            }
+        }
+        if let Some((op, ty)) = binary_op(&op) {
+            let b = self.pop();
+            let a = self.pop();
+            self.inst(Op::Binary(op, a, b), Some(ty));
+            return true;
+        }
+        if let Some(op) = compare_op(&op) {
+            let b = self.pop();
+            let a = self.pop();
+            self.inst(Op::Compare(op, a, b), Some(Type::I32));
+            return true;
+        }
+        if let Some((op, ty)) = unary_op(&op) {
+            let a = self.pop();
+            self.inst(Op::Unary(op, a), Some(ty));
+            return true;
+        }
+        if let Some((memarg, ty, size, signed)) = load_op(&op) {
+            let address = self.pop();
+            let access = access(memarg, size, signed);
+            self.inst(Op::Load(access, address), Some(ty));
+            return true;
+        }
+        if let Some((memarg, size)) = store_op(&op) {
+            let value = self.pop();
+            let address = self.pop();
+            self.inst(Op::Store(access(memarg, size, false), address, value), None);
+            return true;
+        }
+        match op {
+            Operator::I32Const { value } => {
+                self.inst(Op::Const(value as u32 as u64), Some(Type::I32))
+            }
+            Operator::I64Const { value } => self.inst(Op::Const(value as u64), Some(Type::I64)),
+            Operator::LocalGet { local_index } => {
+                let ty = self.function.locals[local_index as usize];
+                self.inst(Op::LocalGet(local_index), Some(ty));
+            }
+            Operator::LocalSet { local_index } => {
+                let value = self.pop();
+                self.inst(Op::LocalSet(local_index, value), None);
+            }
+            Operator::LocalTee { local_index } => {
+                let value = *self.stack.last().unwrap();
+                self.inst(Op::LocalSet(local_index, value), None);
+            }
+            Operator::GlobalGet { global_index } => {
+                let ty = self.module.globals[global_index as usize];
+                if !is_integer(&ty) {
+                    return false;
+                }
+                self.inst(Op::GlobalGet(global_index), Some(ty));
+            }
+            Operator::GlobalSet { global_index } => {
+                let value = self.pop();
+                self.inst(Op::GlobalSet(global_index, value), None);
+            }
+            Operator::Drop => {
+                self.pop();
+            }
+            Operator::Nop => (),
+            Operator::Select | Operator::TypedSelect { .. } => {
+                let condition = self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                let ty = self.function.value_type(a);
+                self.inst(Op::Select(condition, a, b), Some(ty));
+            }
+            Operator::MemorySize { mem, .. } => {
+                self.inst(Op::MemorySize(mem), Some(self.index_type(mem)));
+            }
+            Operator::MemoryGrow { mem, .. } => {
+                let delta = self.pop();
+                self.inst(Op::MemoryGrow(mem, delta), Some(self.index_type(mem)));
+            }
+            Operator::Call { function_index } => {
+                let ty = self.module.function_type(function_index);
+                if ty.returns.len() > 1
+                    || !ty.params.iter().chain(ty.returns.iter()).all(is_integer)
+                {
+                    return false;
+                }
+                let result = ty.returns.first().cloned();
+                let args = self.stack.split_off(self.stack.len() - ty.params.len());
+                self.inst(Op::Call(function_index, args), result);
+            }
+            Operator::Block { ty } => {
+                let Some((params, results)) = self.block_type(ty) else {
+                    return false;
+                };
+                let end = self.block_with_params(&results);
+                self.enter(FrameKind::Block, end, end, results.len(), params.len());
+            }
+            Operator::Loop { ty } => {
+                let Some((params, results)) = self.block_type(ty) else {
+                    return false;
+                };
+                let header = self.block_with_params(&params);
+                let end = self.block_with_params(&results);
+                let args = self.stack.split_off(self.stack.len() - params.len());
+                self.terminate(Terminator::Jump(Target::new(header, args)));
+                self.current = Some(header);
+                self.stack
+                    .extend(self.function.block(header).params.iter().cloned());
+                self.enter(FrameKind::Loop, header, end, params.len(), params.len());
+            }
+            Operator::If { ty } => {
+                let Some((params, results)) = self.block_type(ty) else {
+                    return false;
+                };
+                let condition = self.pop();
+                let then = self.function.new_block();
+                let else_block = self.function.new_block();
+                let end = self.block_with_params(&results);
+                self.terminate(Terminator::Branch(
+                    condition,
+                    Target::new(then, vec![]),
+                    Target::new(else_block, vec![]),
+                ));
+                self.current = Some(then);
+                let kind = FrameKind::If {
+                    else_block,
+                    params: self.top(params.len()),
+                    has_else: false,
+                };
+                self.enter(kind, end, end, results.len(), params.len());
+            }
+            Operator::Else => {
+                let frame = self.frames.last_mut().unwrap();
+                let (end, results, height) = (frame.end, frame.results, frame.height);
+                let FrameKind::If {
+                    else_block,
+                    params,
+                    has_else,
+                } = &mut frame.kind
+                else {
+                    unreachable!("validated else");
+                };
+                *has_else = true;
+                let (else_block, params) = (*else_block, params.clone());
+                if self.current.is_some() {
+                    let args = self.top(results);
+                    self.terminate(Terminator::Jump(Target::new(end, args)));
+                }
+                self.stack.truncate(height);
+                self.stack.extend(params);
+                self.current = Some(else_block);
+            }
+            Operator::End => {
+                let frame = self.frames.pop().unwrap();
+                let function_end = matches!(frame.kind, FrameKind::Function);
+                if self.current.is_some() {
+                    let args = self.top(frame.results);
+                    self.terminate(Terminator::Jump(Target::new(frame.end, args)));
+                }
+                if let FrameKind::If {
+                    else_block,
+                    params,
+                    has_else: false,
+                } = frame.kind
+                {
+                    // Parameters are passed through as results
+                    self.function.block_mut(else_block).terminator =
+                        Terminator::Jump(Target::new(frame.end, params));
+                }
+                if !function_end {
+                    self.stack.truncate(frame.height);
+                    self.stack
+                        .extend(self.function.block(frame.end).params.iter().cloned());
+                    self.current = Some(frame.end);
+                }
+            }
+            Operator::Br { relative_depth } => {
+                let target = self.branch_target(relative_depth);
+                self.terminate(Terminator::Jump(target));
+            }
+            Operator::BrIf { relative_depth } => {
+                let condition = self.pop();
+                let target = self.branch_target(relative_depth);
+                let next = self.function.new_block();
+                self.terminate(Terminator::Branch(
+                    condition,
+                    target,
+                    Target::new(next, vec![]),
+                ));
+                self.current = Some(next);
+            }
+            Operator::BrTable { table } => {
+                let Ok(depths) = table.targets().collect::<Result<Vec<_>, _>>() else {
+                    return false;
+                };
+                let index = self.pop();
+                let targets = depths
+                    .into_iter()
+                    .map(|depth| self.branch_target(depth))
+                    .collect();
+                let default = self.branch_target(table.default());
+                self.terminate(Terminator::Table(index, targets, default));
+            }
+            Operator::Return => {
+                let values = self.top(self.function.ty.returns.len());
+                self.terminate(Terminator::Return(values));
+            }
+            Operator::Unreachable => self.terminate(Terminator::Trap),
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn access(memarg: MemoryImmediate, size: u32, signed: bool) -> Access {
+    Access {
+        memory: memarg.memory,
+        offset: memarg.offset,
+        size,
+        signed,
+        checked: true,
+    }
+}
+
+fn binary_op(op: &Operator) -> Option<(BinaryOp, Type)> {
+    Some(match op {
+        Operator::I32Add => (BinaryOp::Add, Type::I32),
+        Operator::I32Sub => (BinaryOp::Sub, Type::I32),
+        Operator::I32Mul => (BinaryOp::Mul, Type::I32),
+        Operator::I32DivS => (BinaryOp::DivS, Type::I32),
+        Operator::I32DivU => (BinaryOp::DivU, Type::I32),
+        Operator::I32RemS => (BinaryOp::RemS, Type::I32),
+        Operator::I32RemU => (BinaryOp::RemU, Type::I32),
+        Operator::I32And => (BinaryOp::And, Type::I32),
+        Operator::I32Or => (BinaryOp::Or, Type::I32),
+        Operator::I32Xor => (BinaryOp::Xor, Type::I32),
+        Operator::I32Shl => (BinaryOp::Shl, Type::I32),
+        Operator::I32ShrS => (BinaryOp::ShrS, Type::I32),
+        Operator::I32ShrU => (BinaryOp::ShrU, Type::I32),
+        Operator::I32Rotl => (BinaryOp::Rotl, Type::I32),
+        Operator::I32Rotr => (BinaryOp::Rotr, Type::I32),
+        Operator::I64Add => (BinaryOp::Add, Type::I64),
+        Operator::I64Sub => (BinaryOp::Sub, Type::I64),
+        Operator::I64Mul => (BinaryOp::Mul, Type::I64),
+        Operator::I64DivS => (BinaryOp::DivS, Type::I64),
+        Operator::I64DivU => (BinaryOp::DivU, Type::I64),
+        Operator::I64RemS => (BinaryOp::RemS, Type::I64),
+        Operator::I64RemU => (BinaryOp::RemU, Type::I64),
+        Operator::I64And => (BinaryOp::And, Type::I64),
+        Operator::I64Or => (BinaryOp::Or, Type::I64),
+        Operator::I64Xor => (BinaryOp::Xor, Type::I64),
+        Operator::I64Shl => (BinaryOp::Shl, Type::I64),
+        Operator::I64ShrS => (BinaryOp::ShrS, Type::I64),
+        Operator::I64ShrU => (BinaryOp::ShrU, Type::I64),
+        Operator::I64Rotl => (BinaryOp::Rotl, Type::I64),
+        Operator::I64Rotr => (BinaryOp::Rotr, Type::I64),
+        _ => return None,
+    })
+}
+
+fn compare_op(op: &Operator) -> Option<CompareOp> {
+    Some(match op {
+        Operator::I32Eq | Operator::I64Eq => CompareOp::Eq,
+        Operator::I32Ne | Operator::I64Ne => CompareOp::Ne,
+        Operator::I32LtS | Operator::I64LtS => CompareOp::LtS,
+        Operator::I32LtU | Operator::I64LtU => CompareOp::LtU,
+        Operator::I32GtS | Operator::I64GtS => CompareOp::GtS,
+        Operator::I32GtU | Operator::I64GtU => CompareOp::GtU,
+        Operator::I32LeS | Operator::I64LeS => CompareOp::LeS,
+        Operator::I32LeU | Operator::I64LeU => CompareOp::LeU,
+        Operator::I32GeS | Operator::I64GeS => CompareOp::GeS,
+        Operator::I32GeU | Operator::I64GeU => CompareOp::GeU,
+        _ => return None,
+    })
+}
+
+/// Unary operator along with the type of its result
+fn unary_op(op: &Operator) -> Option<(UnaryOp, Type)> {
+    Some(match op {
+        Operator::I32Eqz | Operator::I64Eqz => (UnaryOp::Eqz, Type::I32),
+        Operator::I32Clz => (UnaryOp::Clz, Type::I32),
+        Operator::I32Ctz => (UnaryOp::Ctz, Type::I32),
+        Operator::I32Popcnt => (UnaryOp::Popcnt, Type::I32),
+        Operator::I32Extend8S => (UnaryOp::Extend8S, Type::I32),
+        Operator::I32Extend16S => (UnaryOp::Extend16S, Type::I32),
+        Operator::I32WrapI64 => (UnaryOp::Wrap, Type::I32),
+        Operator::I64Clz => (UnaryOp::Clz, Type::I64),
+        Operator::I64Ctz => (UnaryOp::Ctz, Type::I64),
+        Operator::I64Popcnt => (UnaryOp::Popcnt, Type::I64),
+        Operator::I64Extend8S => (UnaryOp::Extend8S, Type::I64),
+        Operator::I64Extend16S => (UnaryOp::Extend16S, Type::I64),
+        Operator::I64Extend32S => (UnaryOp::Extend32S, Type::I64),
+        Operator::I64ExtendI32S => (UnaryOp::ExtendI32S, Type::I64),
+        Operator::I64ExtendI32U => (UnaryOp::ExtendI32U, Type::I64),
+        _ => return None,
+    })
+}
+
+/// Memory argument, result type, size and signedness of a load
+fn load_op(op: &Operator) -> Option<(MemoryImmediate, Type, u32, bool)> {
+    Some(match *op {
+        Operator::I32Load { memarg } => (memarg, Type::I32, 4, false),
+        Operator::I32Load8S { memarg } => (memarg, Type::I32, 1, true),
+        Operator::I32Load8U { memarg } => (memarg, Type::I32, 1, false),
+        Operator::I32Load16S { memarg } => (memarg, Type::I32, 2, true),
+        Operator::I32Load16U { memarg } => (memarg, Type::I32, 2, false),
+        Operator::I64Load { memarg } => (memarg, Type::I64, 8, false),
+        Operator::I64Load8S { memarg } => (memarg, Type::I64, 1, true),
+        Operator::I64Load8U { memarg } => (memarg, Type::I64, 1, false),
+        Operator::I64Load16S { memarg } => (memarg, Type::I64, 2, true),
+        Operator::I64Load16U { memarg } => (memarg, Type::I64, 2, false),
+        Operator::I64Load32S { memarg } => (memarg, Type::I64, 4, true),
+        Operator::I64Load32U { memarg } => (memarg, Type::I64, 4, false),
+        _ => return None,
+    })
+}
+
+/// Memory argument and size of a store
+fn store_op(op: &Operator) -> Option<(MemoryImmediate, u32)> {
+    Some(match *op {
+        Operator::I32Store { memarg } => (memarg, 4),
+        Operator::I32Store8 { memarg } => (memarg, 1),
+        Operator::I32Store16 { memarg } => (memarg, 2),
+        Operator::I64Store { memarg } => (memarg, 8),
+        Operator::I64Store8 { memarg } => (memarg, 1),
+        Operator::I64Store16 { memarg } => (memarg, 2),
+        Operator::I64Store32 { memarg } => (memarg, 4),
+        _ => return None,
+    })
+}
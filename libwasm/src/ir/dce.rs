@@ -0,0 +1,82 @@
+use crate::ir::{Block, Function};
+use alloc::vec;
+
+#[derive(Clone, Copy)]
+enum Definition {
+    /// Result of the instruction at this index of the block
+    Inst(Block, usize),
+    /// Parameter at this index of the block
+    Param(Block, usize),
+}
+
+/// Removes unreachable blocks, along with instructions without effects
+/// and block parameters whose values end up unused
+///
+/// Values are live if something with an effect, or a terminator, uses them,
+/// or if they are used to compute a live value, which for arguments means
+/// being passed to a live parameter. Everything else goes.
+pub(crate) fn eliminate_dead_code(function: &mut Function) {
+    function.remove_unreachable_blocks();
+    let mut definitions = vec![None; function.values.len()];
+    let mut worklist = vec![];
+    for (b, block) in function.blocks.iter().enumerate() {
+        let b = Block(b as u32);
+        for (i, param) in block.params.iter().enumerate() {
+            definitions[param.index()] = Some(Definition::Param(b, i));
+        }
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Some(result) = inst.result {
+                definitions[result.index()] = Some(Definition::Inst(b, i));
+            }
+            if !inst.op.is_removable() {
+                worklist.extend(inst.op.operands());
+            }
+        }
+        worklist.extend(block.terminator.operands());
+    }
+    let predecessors = function.predecessors();
+    let mut live = vec![false; function.values.len()];
+    while let Some(value) = worklist.pop() {
+        if live[value.index()] {
+            continue;
+        }
+        live[value.index()] = true;
+        match definitions[value.index()] {
+            Some(Definition::Inst(b, i)) => {
+                worklist.extend(function.block(b).insts[i].op.operands());
+            }
+            Some(Definition::Param(b, i)) => {
+                for pred in &predecessors[b.index()] {
+                    for target in function.block(*pred).terminator.targets() {
+                        if target.block == b {
+                            worklist.push(target.args[i]);
+                        }
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+    for block in &mut function.blocks {
+        block
+            .insts
+            .retain(|inst| !inst.op.is_removable() || inst.result.is_some_and(|r| live[r.index()]));
+    }
+    // Parameters of the entry block are the function's
+    for (b, preds) in predecessors.iter().enumerate().skip(1) {
+        let block = Block(b as u32);
+        for i in (0..function.block(block).params.len()).rev() {
+            if live[function.block(block).params[i].index()] {
+                continue;
+            }
+            function.block_mut(block).params.remove(i);
+            for pred in preds {
+                for target in function.block_mut(*pred).terminator.targets_mut() {
+                    if target.block == block {
+                        target.args.remove(i);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::ir::{BinaryOp, CompareOp, Function, Op, Terminator, UnaryOp, Value};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use wasmparser_nostd::Type;
+
+/// What an instruction comes down to
+enum Folded {
+    Constant(u64),
+    /// One of its operands
+    Operand(Value),
+}
+
+/// Evaluates instructions whose operands are constants, and those that
+/// constant operands make trivial, such as adding zero. Branches on
+/// constants become jumps.
+///
+/// Uses of instructions that come down to an operand are replaced, leaving
+/// the instructions to dead code elimination.
+pub(crate) fn fold_constants(function: &mut Function) {
+    let mut constants: Vec<Option<u64>> = vec![None; function.values.len()];
+    let mut replacements: BTreeMap<Value, Value> = BTreeMap::new();
+    let resolve = |replacements: &BTreeMap<Value, Value>, value: &mut Value| {
+        while let Some(replacement) = replacements.get(value) {
+            *value = *replacement;
+        }
+    };
+    // Values are defined before they are used in reverse postorder, but
+    // for block parameters
+    for block in function.reverse_postorder() {
+        let mut insts = core::mem::take(&mut function.block_mut(block).insts);
+        for inst in &mut insts {
+            for operand in inst.op.operands_mut() {
+                resolve(&replacements, operand);
+            }
+            let Some(result) = inst.result else {
+                continue;
+            };
+            if let Op::Const(c) = inst.op {
+                constants[result.index()] = Some(c);
+                continue;
+            }
+            match fold(function, &inst.op, result, &constants) {
+                Some(Folded::Constant(c)) => {
+                    inst.op = Op::Const(c);
+                    constants[result.index()] = Some(c);
+                }
+                Some(Folded::Operand(value)) => {
+                    replacements.insert(result, value);
+                }
+                None => (),
+            }
+        }
+        let block = function.block_mut(block);
+        block.insts = insts;
+        for operand in block.terminator.operands_mut() {
+            resolve(&replacements, operand);
+        }
+        let jump =
+            match &block.terminator {
+                Terminator::Branch(condition, then, else_) => constants[condition.index()]
+                    .map(|c| if c != 0 { then.clone() } else { else_.clone() }),
+                Terminator::Table(index, targets, default) => constants[index.index()]
+                    .map(|c| targets.get(c as usize).unwrap_or(default).clone()),
+                _ => None,
+            };
+        if let Some(target) = jump {
+            block.terminator = Terminator::Jump(target);
+        }
+    }
+    function.replace_values(&replacements);
+}
+
+fn fold(function: &Function, op: &Op, result: Value, constants: &[Option<u64>]) -> Option<Folded> {
+    let constant = |value: &Value| constants[value.index()];
+    let ty = function.value_type(result);
+    match op {
+        Op::Binary(op, a, b) => match (constant(a), constant(b)) {
+            (Some(x), Some(y)) => binary(*op, ty, x, y).map(Folded::Constant),
+            (_, Some(y)) => simplify(*op, ty, *a, y),
+            (Some(x), _) if op.is_commutative() => simplify(*op, ty, *b, x),
+            _ => None,
+        },
+        Op::Compare(op, a, b) => {
+            let ty = function.value_type(*a);
+            let same = a == b;
+            match (constant(a), constant(b)) {
+                (Some(x), Some(y)) => Some(Folded::Constant(compare(*op, ty, x, y) as u64)),
+                _ if same => Some(Folded::Constant(compare(*op, ty, 0, 0) as u64)),
+                _ => None,
+            }
+        }
+        Op::Unary(op, a) => {
+            let ty = function.value_type(*a);
+            constant(a).map(|x| Folded::Constant(unary(*op, ty, x)))
+        }
+        Op::Select(condition, a, b) => match constant(condition) {
+            Some(c) => Some(Folded::Operand(if c != 0 { *a } else { *b })),
+            None if a == b => Some(Folded::Operand(*a)),
+            None => None,
+        },
+        _ => None,
+    }
+}
+
+/// Binary operator with a constant right operand, or left one if it is
+/// commutative
+fn simplify(op: BinaryOp, ty: Type, value: Value, constant: u64) -> Option<Folded> {
+    let ones = if ty == Type::I32 {
+        u32::MAX as u64
+    } else {
+        u64::MAX
+    };
+    let bits = if ty == Type::I32 { 32 } else { 64 };
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Or | BinaryOp::Xor if constant == 0 => {
+            Some(Folded::Operand(value))
+        }
+        BinaryOp::Shl | BinaryOp::ShrS | BinaryOp::ShrU | BinaryOp::Rotl | BinaryOp::Rotr
+            if constant.is_multiple_of(bits) =>
+        {
+            Some(Folded::Operand(value))
+        }
+        BinaryOp::Mul if constant == 1 => Some(Folded::Operand(value)),
+        BinaryOp::And if constant == ones => Some(Folded::Operand(value)),
+        BinaryOp::Mul | BinaryOp::And if constant == 0 => Some(Folded::Constant(0)),
+        BinaryOp::Or if constant == ones => Some(Folded::Constant(ones)),
+        _ => None,
+    }
+}
+
+macro_rules! evaluate {
+    ($unsigned:ty, $signed:ty, $op:expr, $a:expr, $b:expr) => {{
+        let (a, b) = ($a as $unsigned, $b as $unsigned);
+        let (sa, sb) = (a as $signed, b as $signed);
+        let result = match $op {
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+            BinaryOp::Mul => a.wrapping_mul(b),
+            // Division overflow traps, unlike the remainder, which is zero
+            BinaryOp::DivS => sa.checked_div(sb)? as $unsigned,
+            BinaryOp::DivU => a.checked_div(b)?,
+            BinaryOp::RemS if b == 0 => return None,
+            BinaryOp::RemS => sa.wrapping_rem(sb) as $unsigned,
+            BinaryOp::RemU => a.checked_rem(b)?,
+            BinaryOp::And => a & b,
+            BinaryOp::Or => a | b,
+            BinaryOp::Xor => a ^ b,
+            BinaryOp::Shl => a.wrapping_shl(b as u32),
+            BinaryOp::ShrS => sa.wrapping_shr(b as u32) as $unsigned,
+            BinaryOp::ShrU => a.wrapping_shr(b as u32),
+            BinaryOp::Rotl => a.rotate_left((b % <$unsigned>::BITS as $unsigned) as u32),
+            BinaryOp::Rotr => a.rotate_right((b % <$unsigned>::BITS as $unsigned) as u32),
+        };
+        Some(result as u64)
+    }};
+}
+
+/// Result of a binary operator, unless it traps
+fn binary(op: BinaryOp, ty: Type, a: u64, b: u64) -> Option<u64> {
+    if ty == Type::I32 {
+        evaluate!(u32, i32, op, a, b)
+    } else {
+        evaluate!(u64, i64, op, a, b)
+    }
+}
+
+fn compare(op: CompareOp, ty: Type, a: u64, b: u64) -> bool {
+    let (sa, sb) = if ty == Type::I32 {
+        (a as u32 as i32 as i64, b as u32 as i32 as i64)
+    } else {
+        (a as i64, b as i64)
+    };
+    match op {
+        CompareOp::Eq => a == b,
+        CompareOp::Ne => a != b,
+        CompareOp::LtS => sa < sb,
+        CompareOp::LtU => a < b,
+        CompareOp::GtS => sa > sb,
+        CompareOp::GtU => a > b,
+        CompareOp::LeS => sa <= sb,
+        CompareOp::LeU => a <= b,
+        CompareOp::GeS => sa >= sb,
+        CompareOp::GeU => a >= b,
+    }
+}
+
+/// Result of a unary operator on an operand of type `ty`
+fn unary(op: UnaryOp, ty: Type, a: u64) -> u64 {
+    let wide = ty == Type::I64;
+    match op {
+        UnaryOp::Eqz => (a == 0) as u64,
+        UnaryOp::Clz if wide => a.leading_zeros() as u64,
+        UnaryOp::Clz => (a as u32).leading_zeros() as u64,
+        UnaryOp::Ctz if wide => a.trailing_zeros() as u64,
+        UnaryOp::Ctz => (a as u32).trailing_zeros() as u64,
+        UnaryOp::Popcnt => a.count_ones() as u64,
+        UnaryOp::Extend8S if wide => a as i8 as u64,
+        UnaryOp::Extend8S => a as i8 as u32 as u64,
+        UnaryOp::Extend16S if wide => a as i16 as u64,
+        UnaryOp::Extend16S => a as i16 as u32 as u64,
+        UnaryOp::Extend32S | UnaryOp::ExtendI32S => a as i32 as u64,
+        UnaryOp::ExtendI32U | UnaryOp::Wrap => a as u32 as u64,
+    }
+}
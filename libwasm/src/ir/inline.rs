@@ -0,0 +1,123 @@
+use crate::ir::{Block, Function, Inst, Op, Target, Terminator};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Size, in instructions, of the largest functions that get inlined
+const INLINE_LIMIT: usize = 24;
+
+/// Functions that calls get replaced with: small ones that call nothing
+/// themselves, so inlining them can't recurse
+pub(crate) fn candidates(functions: &BTreeMap<u32, Function>) -> BTreeMap<u32, Function> {
+    functions
+        .iter()
+        .filter(|(_, function)| {
+            function.instruction_count() <= INLINE_LIMIT
+                && function.blocks.iter().all(|block| {
+                    block
+                        .insts
+                        .iter()
+                        .all(|inst| !matches!(inst.op, Op::Call(..)))
+                })
+        })
+        .map(|(index, function)| (*index, function.clone()))
+        .collect()
+}
+
+/// Replaces calls to the `inlined` functions, other than the function
+/// itself, with copies of their blocks, returning whether there were any
+pub(crate) fn inline_calls(
+    function: &mut Function,
+    index: u32,
+    inlined: &BTreeMap<u32, Function>,
+) -> bool {
+    let mut changed = false;
+    // Blocks split at a call continue in new blocks, which get visited too
+    let mut b = 0;
+    while b < function.blocks.len() {
+        let block = Block(b as u32);
+        let call = function.block(block).insts.iter().position(|inst| {
+            matches!(&inst.op, Op::Call(callee, _) if *callee != index && inlined.contains_key(callee))
+        });
+        if let Some(position) = call {
+            inline(function, block, position, inlined);
+            changed = true;
+        }
+        b += 1;
+    }
+    changed
+}
+
+/// Splits the block at the call, which is replaced with a jump to a copy of
+/// the called function, whose returns jump to the rest of the block
+fn inline(
+    function: &mut Function,
+    block: Block,
+    position: usize,
+    inlined: &BTreeMap<u32, Function>,
+) {
+    let rest = function.block_mut(block).insts.split_off(position + 1);
+    let call = function.block_mut(block).insts.pop().unwrap();
+    let Op::Call(callee, args) = call.op else {
+        unreachable!("inlining a call");
+    };
+    let callee = &inlined[&callee];
+    let continuation = function.new_block();
+    let terminator =
+        core::mem::replace(&mut function.block_mut(block).terminator, Terminator::Trap);
+    let rest_block = function.block_mut(continuation);
+    rest_block.params.extend(call.result);
+    rest_block.insts = rest;
+    rest_block.terminator = terminator;
+
+    let values: Vec<_> = callee
+        .values
+        .iter()
+        .map(|ty| function.new_value(*ty))
+        .collect();
+    let first = function.blocks.len() as u32;
+    let copy = |target: &Target| Target {
+        block: Block(first + target.block.0),
+        args: target.args.iter().map(|v| values[v.index()]).collect(),
+    };
+    for callee_block in &callee.blocks {
+        let copied = function.new_block();
+        let terminator = match &callee_block.terminator {
+            Terminator::Jump(target) => Terminator::Jump(copy(target)),
+            Terminator::Branch(condition, then, else_) => {
+                Terminator::Branch(values[condition.index()], copy(then), copy(else_))
+            }
+            Terminator::Table(index, targets, default) => Terminator::Table(
+                values[index.index()],
+                targets.iter().map(copy).collect(),
+                copy(default),
+            ),
+            Terminator::Return(results) => Terminator::Jump(Target::new(
+                continuation,
+                results.iter().map(|v| values[v.index()]).collect(),
+            )),
+            Terminator::Trap => Terminator::Trap,
+        };
+        let copied = function.block_mut(copied);
+        copied.params = callee_block
+            .params
+            .iter()
+            .map(|v| values[v.index()])
+            .collect();
+        copied.insts = callee_block
+            .insts
+            .iter()
+            .map(|inst| {
+                let mut op = inst.op.clone();
+                for operand in op.operands_mut() {
+                    *operand = values[operand.index()];
+                }
+                Inst {
+                    op,
+                    result: inst.result.map(|v| values[v.index()]),
+                }
+            })
+            .collect();
+        copied.terminator = terminator;
+    }
+    function.block_mut(block).terminator = Terminator::Jump(Target::new(Block(first), args));
+}
@@ -0,0 +1,672 @@
+//! SSA-form intermediate representation of function bodies, between the
+//! operators of a module and the code generated for them
+//!
+//! A function is a graph of basic blocks, each defining values with its
+//! instructions and ending with a terminator. Blocks take parameters in
+//! place of phi nodes, and every branch passes arguments for the parameters
+//! of its target. Functions are [built](builder) with locals still read and
+//! written by instructions, then [optimized](Module::optimize), which turns
+//! locals into values among other passes.
+//!
+//! Only integer code is covered: functions with float, vector or reference
+//! values, or operators without an [`Op`], are left out, for backends to
+//! compile straight from their operators.
+
+mod bounds;
+mod builder;
+mod dce;
+mod fold;
+mod inline;
+mod ssa;
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use wasmparser_nostd::{
+    BinaryReaderError, FuncType, ImportSectionEntryType, MemoryType, Parser, Payload, Type, TypeDef,
+};
+
+/// Size of a page of linear memory
+const PAGE_SIZE: u64 = 1 << 16;
+
+/// SSA value, defined by an instruction or as a block parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Value(pub(crate) u32);
+
+impl Value {
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Basic block of a function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Block(pub(crate) u32);
+
+impl Block {
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    DivU,
+    RemS,
+    RemU,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrS,
+    ShrU,
+    Rotl,
+    Rotr,
+}
+
+impl BinaryOp {
+    pub(crate) fn is_commutative(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Mul | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor
+        )
+    }
+
+    /// Whether the operator traps on some operands
+    pub(crate) fn can_trap(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::DivS | BinaryOp::DivU | BinaryOp::RemS | BinaryOp::RemU
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CompareOp {
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    GtS,
+    GtU,
+    LeS,
+    LeU,
+    GeS,
+    GeU,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UnaryOp {
+    Eqz,
+    Clz,
+    Ctz,
+    Popcnt,
+    Extend8S,
+    Extend16S,
+    Extend32S,
+    ExtendI32S,
+    ExtendI32U,
+    Wrap,
+}
+
+/// Load or store of `size` bytes at an address plus `offset`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Access {
+    pub(crate) memory: u32,
+    pub(crate) offset: u64,
+    pub(crate) size: u32,
+    /// Whether a load narrower than its result is sign-extended
+    pub(crate) signed: bool,
+    /// Cleared once the access is known to be within bounds
+    pub(crate) checked: bool,
+}
+
+impl Access {
+    /// End of the accessed bytes relative to the address, unless it
+    /// overflows
+    pub(crate) fn end(&self) -> Option<u64> {
+        self.offset.checked_add(self.size as u64)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// Constant, zero-extended for `i32`
+    Const(u64),
+    LocalGet(u32),
+    LocalSet(u32, Value),
+    Binary(BinaryOp, Value, Value),
+    Compare(CompareOp, Value, Value),
+    Unary(UnaryOp, Value),
+    /// Second operand if the first one is non-zero, third one otherwise
+    Select(Value, Value, Value),
+    /// Load from the address
+    Load(Access, Value),
+    /// Store of the second operand at the address
+    Store(Access, Value, Value),
+    MemorySize(u32),
+    MemoryGrow(u32, Value),
+    GlobalGet(u32),
+    GlobalSet(u32, Value),
+    /// Call of a function with at most one result
+    Call(u32, Vec<Value>),
+}
+
+impl Op {
+    pub(crate) fn operands(&self) -> Vec<Value> {
+        match self {
+            Op::Const(_) | Op::LocalGet(_) | Op::MemorySize(_) | Op::GlobalGet(_) => vec![],
+            Op::LocalSet(_, v)
+            | Op::Unary(_, v)
+            | Op::Load(_, v)
+            | Op::MemoryGrow(_, v)
+            | Op::GlobalSet(_, v) => vec![*v],
+            Op::Binary(_, a, b) | Op::Compare(_, a, b) | Op::Store(_, a, b) => vec![*a, *b],
+            Op::Select(c, a, b) => vec![*c, *a, *b],
+            Op::Call(_, args) => args.clone(),
+        }
+    }
+
+    pub(crate) fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Op::Const(_) | Op::LocalGet(_) | Op::MemorySize(_) | Op::GlobalGet(_) => vec![],
+            Op::LocalSet(_, v)
+            | Op::Unary(_, v)
+            | Op::Load(_, v)
+            | Op::MemoryGrow(_, v)
+            | Op::GlobalSet(_, v) => vec![v],
+            Op::Binary(_, a, b) | Op::Compare(_, a, b) | Op::Store(_, a, b) => vec![a, b],
+            Op::Select(c, a, b) => vec![c, a, b],
+            Op::Call(_, args) => args.iter_mut().collect(),
+        }
+    }
+
+    /// Whether the instruction can go if its result is unused: it has no
+    /// effect and can't trap
+    pub(crate) fn is_removable(&self) -> bool {
+        match self {
+            Op::Const(_)
+            | Op::LocalGet(_)
+            | Op::Compare(..)
+            | Op::Unary(..)
+            | Op::Select(..)
+            | Op::MemorySize(_)
+            | Op::GlobalGet(_) => true,
+            Op::Binary(op, _, _) => !op.can_trap(),
+            Op::Load(access, _) => !access.checked,
+            Op::LocalSet(..)
+            | Op::Store(..)
+            | Op::MemoryGrow(..)
+            | Op::GlobalSet(..)
+            | Op::Call(..) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Inst {
+    pub(crate) op: Op,
+    pub(crate) result: Option<Value>,
+}
+
+/// Edge to a block, with arguments for its parameters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub(crate) block: Block,
+    pub(crate) args: Vec<Value>,
+}
+
+impl Target {
+    pub(crate) fn new(block: Block, args: Vec<Value>) -> Self {
+        Self { block, args }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Terminator {
+    Jump(Target),
+    /// Goes to the first target if the condition is non-zero
+    Branch(Value, Target, Target),
+    /// Goes to the target at the index, or to the default one past them
+    Table(Value, Vec<Target>, Target),
+    Return(Vec<Value>),
+    /// `unreachable`
+    Trap,
+}
+
+impl Terminator {
+    pub(crate) fn targets(&self) -> Vec<&Target> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch(_, then, else_) => vec![then, else_],
+            Terminator::Table(_, targets, default) => targets.iter().chain([default]).collect(),
+            Terminator::Return(_) | Terminator::Trap => vec![],
+        }
+    }
+
+    pub(crate) fn targets_mut(&mut self) -> Vec<&mut Target> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch(_, then, else_) => vec![then, else_],
+            Terminator::Table(_, targets, default) => targets.iter_mut().chain([default]).collect(),
+            Terminator::Return(_) | Terminator::Trap => vec![],
+        }
+    }
+
+    /// Operands, other than the arguments of targets
+    pub(crate) fn operands(&self) -> Vec<Value> {
+        match self {
+            Terminator::Branch(v, _, _) | Terminator::Table(v, _, _) => vec![*v],
+            Terminator::Return(values) => values.clone(),
+            Terminator::Jump(_) | Terminator::Trap => vec![],
+        }
+    }
+
+    pub(crate) fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Terminator::Branch(v, _, _) | Terminator::Table(v, _, _) => vec![v],
+            Terminator::Return(values) => values.iter_mut().collect(),
+            Terminator::Jump(_) | Terminator::Trap => vec![],
+        }
+    }
+
+    /// Every operand, arguments of targets included
+    pub(crate) fn uses(&self) -> Vec<Value> {
+        let mut uses = self.operands();
+        for target in self.targets() {
+            uses.extend(&target.args);
+        }
+        uses
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BasicBlock {
+    pub(crate) params: Vec<Value>,
+    pub(crate) insts: Vec<Inst>,
+    pub(crate) terminator: Terminator,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Function {
+    pub(crate) ty: FuncType,
+    /// Types of the parameters and locals
+    pub(crate) locals: Vec<Type>,
+    /// Types of the values, by index
+    pub(crate) values: Vec<Type>,
+    /// Blocks, starting with the entry block, whose parameters are the
+    /// function's
+    pub(crate) blocks: Vec<BasicBlock>,
+}
+
+impl Function {
+    pub(crate) const ENTRY: Block = Block(0);
+
+    pub(crate) fn new_value(&mut self, ty: Type) -> Value {
+        self.values.push(ty);
+        Value(self.values.len() as u32 - 1)
+    }
+
+    pub(crate) fn new_block(&mut self) -> Block {
+        self.blocks.push(BasicBlock {
+            params: vec![],
+            insts: vec![],
+            terminator: Terminator::Trap,
+        });
+        Block(self.blocks.len() as u32 - 1)
+    }
+
+    pub(crate) fn block(&self, block: Block) -> &BasicBlock {
+        &self.blocks[block.index()]
+    }
+
+    pub(crate) fn block_mut(&mut self, block: Block) -> &mut BasicBlock {
+        &mut self.blocks[block.index()]
+    }
+
+    pub(crate) fn value_type(&self, value: Value) -> Type {
+        self.values[value.index()]
+    }
+
+    pub(crate) fn instruction_count(&self) -> usize {
+        self.blocks.iter().map(|b| b.insts.len() + 1).sum()
+    }
+
+    pub(crate) fn successors(&self, block: Block) -> Vec<Block> {
+        let mut successors: Vec<Block> = vec![];
+        for target in self.block(block).terminator.targets() {
+            if !successors.contains(&target.block) {
+                successors.push(target.block);
+            }
+        }
+        successors
+    }
+
+    /// Distinct predecessors of every block
+    pub(crate) fn predecessors(&self) -> Vec<Vec<Block>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for b in 0..self.blocks.len() {
+            for successor in self.successors(Block(b as u32)) {
+                predecessors[successor.index()].push(Block(b as u32));
+            }
+        }
+        predecessors
+    }
+
+    /// Blocks reachable from the entry, each after its predecessors but for
+    /// loop back edges
+    pub(crate) fn reverse_postorder(&self) -> Vec<Block> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = vec![];
+        // Blocks along with how many of their successors were visited
+        let mut stack = vec![(Self::ENTRY, 0)];
+        visited[Self::ENTRY.index()] = true;
+        while let Some((block, next)) = stack.pop() {
+            let successors = self.successors(block);
+            match successors.get(next) {
+                Some(successor) => {
+                    stack.push((block, next + 1));
+                    if !visited[successor.index()] {
+                        visited[successor.index()] = true;
+                        stack.push((*successor, 0));
+                    }
+                }
+                None => postorder.push(block),
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Immediate dominator of every reachable block, with the entry being its
+    /// own
+    ///
+    /// Computed as described in "A Simple, Fast Dominance Algorithm" by
+    /// Cooper, Harvey and Kennedy.
+    pub(crate) fn dominators(&self) -> Vec<Option<Block>> {
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            rank[block.index()] = i;
+        }
+        let predecessors = self.predecessors();
+        let mut idom = vec![None; self.blocks.len()];
+        idom[Self::ENTRY.index()] = Some(Self::ENTRY);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new_idom: Option<Block> = None;
+                for pred in &predecessors[block.index()] {
+                    if idom[pred.index()].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(mut other) => {
+                            let mut pred = *pred;
+                            while pred != other {
+                                while rank[pred.index()] > rank[other.index()] {
+                                    pred = idom[pred.index()].unwrap();
+                                }
+                                while rank[other.index()] > rank[pred.index()] {
+                                    other = idom[other.index()].unwrap();
+                                }
+                            }
+                            pred
+                        }
+                    });
+                }
+                if idom[block.index()] != new_idom {
+                    idom[block.index()] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    /// Replaces uses of values with others, following chains of replacements
+    pub(crate) fn replace_values(&mut self, replacements: &BTreeMap<Value, Value>) {
+        if replacements.is_empty() {
+            return;
+        }
+        let resolve = |value: &mut Value| {
+            while let Some(replacement) = replacements.get(value) {
+                *value = *replacement;
+            }
+        };
+        for block in &mut self.blocks {
+            for inst in &mut block.insts {
+                inst.op.operands_mut().into_iter().for_each(resolve);
+            }
+            block
+                .terminator
+                .operands_mut()
+                .into_iter()
+                .for_each(resolve);
+            for target in block.terminator.targets_mut() {
+                target.args.iter_mut().for_each(resolve);
+            }
+        }
+    }
+
+    /// Drops blocks that can't be reached from the entry, renumbering the
+    /// others
+    pub(crate) fn remove_unreachable_blocks(&mut self) {
+        let order = self.reverse_postorder();
+        if order.len() == self.blocks.len() {
+            return;
+        }
+        let mut numbers = vec![None; self.blocks.len()];
+        let mut reachable = order.clone();
+        reachable.sort();
+        for (i, block) in reachable.iter().enumerate() {
+            numbers[block.index()] = Some(Block(i as u32));
+        }
+        let mut blocks = core::mem::take(&mut self.blocks);
+        for block in reachable {
+            let mut block = core::mem::replace(
+                &mut blocks[block.index()],
+                BasicBlock {
+                    params: vec![],
+                    insts: vec![],
+                    terminator: Terminator::Trap,
+                },
+            );
+            for target in block.terminator.targets_mut() {
+                target.block = numbers[target.block.index()].unwrap();
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// Appends blocks to their only predecessor when it jumps straight to
+    /// them
+    pub(crate) fn merge_blocks(&mut self) {
+        let predecessors = self.predecessors();
+        let mut replacements = BTreeMap::new();
+        for b in 0..self.blocks.len() {
+            while let Terminator::Jump(target) = &self.blocks[b].terminator {
+                let merged = target.block;
+                if merged == Self::ENTRY
+                    || merged.index() == b
+                    || predecessors[merged.index()].len() != 1
+                {
+                    break;
+                }
+                let args = target.args.clone();
+                // Left unreachable, to be removed
+                let merged = core::mem::replace(
+                    &mut self.blocks[merged.index()],
+                    BasicBlock {
+                        params: vec![],
+                        insts: vec![],
+                        terminator: Terminator::Trap,
+                    },
+                );
+                replacements.extend(merged.params.into_iter().zip(args));
+                self.blocks[b].insts.extend(merged.insts);
+                self.blocks[b].terminator = merged.terminator;
+            }
+        }
+        self.replace_values(&replacements);
+        self.remove_unreachable_blocks();
+    }
+
+    /// Number of uses of every value, counting each operand
+    pub(crate) fn use_counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.values.len()];
+        for block in &self.blocks {
+            for inst in &block.insts {
+                for operand in inst.op.operands() {
+                    counts[operand.index()] += 1;
+                }
+            }
+            for operand in block.terminator.uses() {
+                counts[operand.index()] += 1;
+            }
+        }
+        counts
+    }
+
+    /// Promotes locals, then folds constants, merges blocks and removes dead
+    /// code until that changes nothing
+    fn simplify(&mut self) {
+        ssa::promote_locals(self);
+        loop {
+            let before = (self.instruction_count(), self.blocks.len());
+            fold::fold_constants(self);
+            ssa::remove_trivial_params(self);
+            self.merge_blocks();
+            dce::eliminate_dead_code(self);
+            if (self.instruction_count(), self.blocks.len()) == before {
+                break;
+            }
+        }
+    }
+}
+
+/// Functions of a module that could be turned into IR, along with what
+/// passes need to know of the rest of the module
+pub(crate) struct Module {
+    types: Vec<FuncType>,
+    /// Type index of every function, imported ones first
+    function_types: Vec<u32>,
+    globals: Vec<Type>,
+    memories: Vec<MemoryType>,
+    imported_memories: u32,
+    pub(crate) functions: BTreeMap<u32, Function>,
+}
+
+impl Module {
+    /// Builds the functions of a valid module
+    pub(crate) fn build(module: &[u8]) -> Result<Self, BinaryReaderError> {
+        let mut ir = Self {
+            types: vec![],
+            function_types: vec![],
+            globals: vec![],
+            memories: vec![],
+            imported_memories: 0,
+            functions: BTreeMap::new(),
+        };
+        let mut function_index = 0;
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::TypeSection(ts) => {
+                    for t in ts {
+                        let TypeDef::Func(func_type) = t? else {
+                            continue;
+                        };
+                        ir.types.push(func_type);
+                    }
+                }
+                Payload::ImportSection(is) => {
+                    for i in is {
+                        match i?.ty {
+                            ImportSectionEntryType::Function(ty) => {
+                                ir.function_types.push(ty);
+                                function_index += 1;
+                            }
+                            ImportSectionEntryType::Global(ty) => ir.globals.push(ty.content_type),
+                            ImportSectionEntryType::Memory(ty) => {
+                                ir.memories.push(ty);
+                                ir.imported_memories += 1;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                Payload::FunctionSection(fs) => {
+                    for ty in fs {
+                        ir.function_types.push(ty?);
+                    }
+                }
+                Payload::GlobalSection(gs) => {
+                    for g in gs {
+                        ir.globals.push(g?.ty.content_type);
+                    }
+                }
+                Payload::MemorySection(ms) => {
+                    for m in ms {
+                        ir.memories.push(m?);
+                    }
+                }
+                Payload::CodeSectionEntry(mut body) => {
+                    body.allow_memarg64(ir.memories.iter().any(|m| m.memory64));
+                    if let Some(function) = builder::build(&ir, function_index, &body)? {
+                        ir.functions.insert(function_index, function);
+                    }
+                    function_index += 1;
+                }
+                _ => (),
+            }
+        }
+        Ok(ir)
+    }
+
+    pub(crate) fn function_type(&self, function_index: u32) -> &FuncType {
+        &self.types[self.function_types[function_index as usize] as usize]
+    }
+
+    /// Size in bytes that a memory is known to have at least
+    ///
+    /// Memories only grow, from their initial size for those the module
+    /// defines. Nothing is assumed of imported ones.
+    fn minimum_size(&self, memory: u32) -> u64 {
+        if memory < self.imported_memories {
+            0
+        } else {
+            let pages = self.memories[memory as usize].initial;
+            pages.saturating_mul(PAGE_SIZE)
+        }
+    }
+
+    /// Runs every pass over the functions, which code generation expects
+    ///
+    /// Functions are simplified on their own first, so that small ones can be
+    /// inlined as they end up, and again after inlining. Bounds checks are
+    /// eliminated last, as it may allow more loads to be removed.
+    pub(crate) fn optimize(&mut self) {
+        for function in self.functions.values_mut() {
+            function.simplify();
+        }
+        let inlined = inline::candidates(&self.functions);
+        for (index, function) in self.functions.iter_mut() {
+            if inline::inline_calls(function, *index, &inlined) {
+                function.simplify();
+            }
+        }
+        let minimum_sizes: Vec<u64> = (0..self.memories.len() as u32)
+            .map(|memory| self.minimum_size(memory))
+            .collect();
+        for function in self.functions.values_mut() {
+            bounds::eliminate_bounds_checks(function, &minimum_sizes);
+            dce::eliminate_dead_code(function);
+        }
+    }
+}
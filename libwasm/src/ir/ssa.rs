@@ -0,0 +1,213 @@
+use crate::ir::{Block, Function, Inst, Op, Value};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// State of the promotion of locals, following "Simple and Efficient
+/// Construction of Static Single Assignment Form" by Braun et al.
+///
+/// Blocks are filled in reverse postorder, tracking the value every local
+/// has in them. Reading a local that a block doesn't set looks for it in the
+/// predecessors, adding a parameter where several of them meet. A block is
+/// sealed once all of its predecessors are filled: until then, which loop
+/// headers are before their back edges, its parameters wait for arguments.
+struct Promotion<'a> {
+    function: &'a mut Function,
+    predecessors: Vec<Vec<Block>>,
+    /// Value of every local set or read so far in each block
+    definitions: Vec<BTreeMap<u32, Value>>,
+    filled: Vec<bool>,
+    sealed: Vec<bool>,
+    /// Locals that unsealed blocks have parameters for
+    incomplete: Vec<Vec<u32>>,
+    /// Zeroes that declared locals start with, to define in the entry block
+    zeroes: Vec<(u32, Value)>,
+    /// Results of `local.get`, replaced with the value read
+    replacements: BTreeMap<Value, Value>,
+}
+
+/// Replaces `local.get` and `local.set` with values, passed between blocks
+/// as parameters
+pub(crate) fn promote_locals(function: &mut Function) {
+    function.remove_unreachable_blocks();
+    let blocks = function.blocks.len();
+    let mut promotion = Promotion {
+        predecessors: function.predecessors(),
+        function,
+        definitions: vec![BTreeMap::new(); blocks],
+        filled: vec![false; blocks],
+        sealed: vec![false; blocks],
+        incomplete: vec![vec![]; blocks],
+        zeroes: vec![],
+        replacements: BTreeMap::new(),
+    };
+    for block in promotion.function.reverse_postorder() {
+        promotion.fill(block);
+    }
+    let Promotion {
+        function,
+        zeroes,
+        replacements,
+        ..
+    } = promotion;
+    let entry = function.block_mut(Function::ENTRY);
+    entry.insts.splice(
+        0..0,
+        zeroes.into_iter().map(|(_, value)| Inst {
+            op: Op::Const(0),
+            result: Some(value),
+        }),
+    );
+    function.replace_values(&replacements);
+    remove_trivial_params(function);
+}
+
+impl<'a> Promotion<'a> {
+    fn fill(&mut self, block: Block) {
+        if !self.sealed[block.index()] {
+            self.seal_if_ready(block);
+        }
+        let insts = core::mem::take(&mut self.function.block_mut(block).insts);
+        let mut kept = Vec::with_capacity(insts.len());
+        for inst in insts {
+            match inst.op {
+                Op::LocalGet(local) => {
+                    let value = self.read(local, block);
+                    self.replacements.insert(inst.result.unwrap(), value);
+                }
+                Op::LocalSet(local, mut value) => {
+                    while let Some(replacement) = self.replacements.get(&value) {
+                        value = *replacement;
+                    }
+                    self.definitions[block.index()].insert(local, value);
+                }
+                _ => kept.push(inst),
+            }
+        }
+        self.function.block_mut(block).insts = kept;
+        self.filled[block.index()] = true;
+        for successor in self.function.successors(block) {
+            if !self.sealed[successor.index()] {
+                self.seal_if_ready(successor);
+            }
+        }
+    }
+
+    fn seal_if_ready(&mut self, block: Block) {
+        if !self.predecessors[block.index()]
+            .iter()
+            .all(|pred| self.filled[pred.index()])
+        {
+            return;
+        }
+        for local in core::mem::take(&mut self.incomplete[block.index()]) {
+            self.add_arguments(local, block);
+        }
+        self.sealed[block.index()] = true;
+    }
+
+    /// Value of a local at the current point of a block being filled, or at
+    /// the end of a filled block
+    fn read(&mut self, local: u32, block: Block) -> Value {
+        if let Some(value) = self.definitions[block.index()].get(&local) {
+            return *value;
+        }
+        let ty = self.function.locals[local as usize];
+        let value = if !self.sealed[block.index()] {
+            let param = self.add_param(block, local);
+            self.incomplete[block.index()].push(local);
+            param
+        } else if block == Function::ENTRY {
+            if (local as usize) < self.function.ty.params.len() {
+                self.function.block(block).params[local as usize]
+            } else {
+                let zero = self.function.new_value(ty);
+                self.zeroes.push((local, zero));
+                zero
+            }
+        } else if let [pred] = self.predecessors[block.index()][..] {
+            self.read(local, pred)
+        } else {
+            let param = self.add_param(block, local);
+            self.add_arguments(local, block);
+            param
+        };
+        self.definitions[block.index()].insert(local, value);
+        value
+    }
+
+    /// Adds a parameter for a local, which it then stands for in the block
+    fn add_param(&mut self, block: Block, local: u32) -> Value {
+        let param = self
+            .function
+            .new_value(self.function.locals[local as usize]);
+        self.function.block_mut(block).params.push(param);
+        self.definitions[block.index()].insert(local, param);
+        param
+    }
+
+    /// Passes the value of a local from every predecessor to the block's
+    /// parameter for it
+    fn add_arguments(&mut self, local: u32, block: Block) {
+        for pred in self.predecessors[block.index()].clone() {
+            let value = self.read(local, pred);
+            for target in self.function.block_mut(pred).terminator.targets_mut() {
+                if target.block == block {
+                    target.args.push(value);
+                }
+            }
+        }
+    }
+}
+
+/// Removes block parameters that always get the same value, or themselves,
+/// replacing them with that value
+pub(crate) fn remove_trivial_params(function: &mut Function) {
+    loop {
+        let mut replacements: BTreeMap<Value, Value> = BTreeMap::new();
+        let resolve = |replacements: &BTreeMap<Value, Value>, mut value: Value| {
+            while let Some(replacement) = replacements.get(&value) {
+                value = *replacement;
+            }
+            value
+        };
+        let predecessors = function.predecessors();
+        for (b, preds) in predecessors.iter().enumerate().skip(1) {
+            let block = Block(b as u32);
+            for i in (0..function.block(block).params.len()).rev() {
+                let param = function.block(block).params[i];
+                let mut incoming = None;
+                let mut trivial = true;
+                for pred in preds {
+                    for target in function.block(*pred).terminator.targets() {
+                        if target.block != block {
+                            continue;
+                        }
+                        let arg = resolve(&replacements, target.args[i]);
+                        if arg == param || incoming == Some(arg) {
+                            continue;
+                        }
+                        trivial &= incoming.is_none();
+                        incoming = Some(arg);
+                    }
+                }
+                let Some(value) = incoming.filter(|_| trivial) else {
+                    continue;
+                };
+                replacements.insert(param, value);
+                function.block_mut(block).params.remove(i);
+                for pred in preds {
+                    for target in function.block_mut(*pred).terminator.targets_mut() {
+                        if target.block == block {
+                            target.args.remove(i);
+                        }
+                    }
+                }
+            }
+        }
+        if replacements.is_empty() {
+            break;
+        }
+        function.replace_values(&replacements);
+    }
+}
@@ -2,10 +2,12 @@
 
 extern crate alloc;
 
-trait Compiler {
+pub trait Compiler {
     type Error;
     type Module;
     fn compile(&self, module: &[u8]) -> Result<Self::Module, Self::Error>;
 }
 
+mod ir;
+pub mod trap;
 pub mod x86_64;
@@ -0,0 +1,54 @@
+/// Condition that aborts execution of WebAssembly code
+///
+/// Compiled code reports a trap to the host using the trap's code, which is
+/// never zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Trap {
+    /// `unreachable` was executed
+    Unreachable = 1,
+    IntegerDivideByZero = 2,
+    /// Signed division or truncation overflowed
+    IntegerOverflow = 3,
+    /// NaN was truncated to an integer
+    InvalidConversionToInteger = 4,
+    OutOfBoundsMemoryAccess = 5,
+    OutOfBoundsTableAccess = 6,
+    /// `call_indirect` index is outside of the table
+    UndefinedElement = 7,
+    /// `call_indirect` to a null reference
+    UninitializedElement = 8,
+    /// `call_indirect` to a function of a different type
+    IndirectCallSignatureMismatch = 9,
+    /// Exception thrown with no handler to catch it
+    UncaughtException = 10,
+    /// Atomic memory access to an address that isn't a multiple of its size
+    UnalignedAtomic = 11,
+    /// `memory.atomic.wait` on a memory that isn't shared
+    ExpectedSharedMemory = 12,
+}
+
+impl Trap {
+    pub const ALL: [Trap; 12] = [
+        Trap::Unreachable,
+        Trap::IntegerDivideByZero,
+        Trap::IntegerOverflow,
+        Trap::InvalidConversionToInteger,
+        Trap::OutOfBoundsMemoryAccess,
+        Trap::OutOfBoundsTableAccess,
+        Trap::UndefinedElement,
+        Trap::UninitializedElement,
+        Trap::IndirectCallSignatureMismatch,
+        Trap::UncaughtException,
+        Trap::UnalignedAtomic,
+        Trap::ExpectedSharedMemory,
+    ];
+
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.iter().find(|trap| trap.code() == code).copied()
+    }
+}
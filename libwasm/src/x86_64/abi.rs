@@ -0,0 +1,487 @@
+use crate::x86_64::control::{slot_size, ControlStack};
+use crate::x86_64::Error;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use iced_x86::code_asm::{
+    dword_ptr, eax, ecx, edi, edx, esi, ptr, qword_ptr, r11, r11d, r8, r8d, r9, r9d, rax, rbp, rcx,
+    rdi, rdx, rsi, rsp, xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7, xmmword_ptr, AsmRegister32,
+    AsmRegister64, AsmRegisterXmm, CodeAssembler,
+};
+use iced_x86::IcedError;
+use wasmparser_nostd::{FuncType, Type};
+
+/// Offset of the first stack argument from `rbp` of the called function,
+/// past the saved `rbp` and the return address
+const STACK_ARGUMENTS: u32 = 16;
+
+/// Offset below `rbp` of the slot keeping the address of the return area,
+/// for functions that have one
+const RETURN_AREA_SLOT: u32 = 8;
+
+/// Offset of the return address from `rbp` of the called function
+const RETURN_ADDRESS: u32 = 8;
+
+/// Canonical identifier of a function type
+///
+/// It only depends on the parameter and result types, so functions of the
+/// same type get the same identifier in every module. Compiled functions
+/// are preceded by the identifier of their type, which `call_indirect`
+/// checks before calling a table entry.
+pub(crate) fn signature(function_type: &FuncType) -> u64 {
+    // FNV-1a over the binary encoding of the types
+    let code = |ty: &Type| match ty {
+        Type::I32 => 0x7F,
+        Type::I64 => 0x7E,
+        Type::F32 => 0x7D,
+        Type::F64 => 0x7C,
+        Type::V128 => 0x7B,
+        Type::FuncRef => 0x70,
+        Type::ExternRef => 0x6F,
+        Type::ExnRef => 0x68,
+        _ => unreachable!("not a value type"),
+    };
+    let params = function_type.params.iter().map(code);
+    let returns = function_type.returns.iter().map(code);
+    // Separator, which isn't a type code
+    params
+        .chain([0x60])
+        .chain(returns)
+        .fold(0xCBF2_9CE4_8422_2325, |hash: u64, byte: u8| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+        })
+}
+
+/// Where a value is passed according to the System V calling convention
+#[derive(Clone, Copy)]
+pub(crate) enum Location {
+    Integer(AsmRegister64),
+    /// XMM register, of which a `v128` takes all 128 bits
+    Float(AsmRegisterXmm),
+    /// Stack slot, at an offset from the first stack argument, of 64 bits or
+    /// of 128 bits 16-byte aligned for a `v128`
+    Stack(u32),
+}
+
+impl Location {
+    /// Whether a value of the type is passed in XMM registers
+    pub(crate) fn is_float(ty: &Type) -> bool {
+        matches!(ty, Type::F32 | Type::F64 | Type::V128)
+    }
+
+    /// Pushes the value of type `ty` at this location onto the operand stack
+    ///
+    /// The upper half of a register holding an `i32` is unspecified by the
+    /// ABI, so it gets zeroed to keep the slot's representation. Floats go
+    /// through `r11`, with the upper half of an `f32` slot zeroed as well.
+    ///
+    /// Stack arguments are read from the caller's frame, so this is only
+    /// meant for the prologue of the called function.
+    pub(crate) fn push(&self, assembler: &mut CodeAssembler, ty: &Type) -> Result<(), IcedError> {
+        match *self {
+            Location::Integer(reg) => {
+                if *ty == Type::I32 {
+                    let reg = lower_half(reg);
+                    assembler.mov(reg, reg)?;
+                }
+                assembler.push(reg)
+            }
+            Location::Float(reg) => match ty {
+                Type::V128 => {
+                    assembler.sub(rsp, slot_size(ty) as i32)?;
+                    assembler.movdqu(xmmword_ptr(rsp), reg)
+                }
+                Type::F32 => {
+                    assembler.movd(r11d, reg)?;
+                    assembler.push(r11)
+                }
+                _ => {
+                    assembler.movq(r11, reg)?;
+                    assembler.push(r11)
+                }
+            },
+            Location::Stack(offset) => {
+                let argument = rbp + STACK_ARGUMENTS + offset;
+                match ty {
+                    Type::I32 | Type::F32 => {
+                        assembler.mov(r11d, dword_ptr(argument))?;
+                        assembler.push(r11)
+                    }
+                    Type::V128 => {
+                        assembler.push(qword_ptr(argument + 8))?;
+                        assembler.push(qword_ptr(argument))
+                    }
+                    _ => assembler.push(qword_ptr(argument)),
+                }
+            }
+        }
+    }
+
+    /// Pops a value of type `ty` from the operand stack into this location
+    pub(crate) fn pop(&self, assembler: &mut CodeAssembler, ty: &Type) -> Result<(), IcedError> {
+        match *self {
+            Location::Integer(reg) => assembler.pop(reg),
+            Location::Float(reg) if *ty == Type::V128 => {
+                assembler.movdqu(reg, xmmword_ptr(rsp))?;
+                assembler.add(rsp, slot_size(ty) as i32)
+            }
+            Location::Float(reg) => {
+                assembler.pop(r11)?;
+                assembler.movq(reg, r11)
+            }
+            Location::Stack(_) => unreachable!("results are never passed on the stack"),
+        }
+    }
+
+    /// Loads an argument of type `ty`, at `offset` from `rsp`, into this
+    /// location, for a call whose stack arguments start at `rsp`
+    fn load(&self, assembler: &mut CodeAssembler, ty: &Type, offset: u32) -> Result<(), IcedError> {
+        let argument = rsp + offset;
+        match *self {
+            Location::Integer(reg) => assembler.mov(reg, qword_ptr(argument)),
+            Location::Float(reg) if *ty == Type::V128 => {
+                assembler.movdqu(reg, xmmword_ptr(argument))
+            }
+            Location::Float(reg) => assembler.movq(reg, qword_ptr(argument)),
+            Location::Stack(stack_offset) => {
+                for part in (0..slot_size(ty)).step_by(size_of::<u64>()) {
+                    assembler.mov(r11, qword_ptr(argument + part))?;
+                    assembler.mov(qword_ptr(rsp + stack_offset + part), r11)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Offset right past the stack slot of a value of type `ty`, or zero
+    /// for registers
+    fn end(&self, ty: &Type) -> u32 {
+        match *self {
+            Location::Stack(offset) => offset + slot_size(ty),
+            _ => 0,
+        }
+    }
+}
+
+/// 32-bit register aliasing the lower half of an argument or result register
+fn lower_half(reg: AsmRegister64) -> AsmRegister32 {
+    [
+        (rdi, edi),
+        (rsi, esi),
+        (rdx, edx),
+        (rcx, ecx),
+        (r8, r8d),
+        (r9, r9d),
+        (rax, eax),
+    ]
+    .into_iter()
+    .find_map(|(full, lower)| (full == reg).then_some(lower))
+    .unwrap()
+}
+
+fn locations(
+    types: &[Type],
+    mut integer: VecDeque<AsmRegister64>,
+    mut float: VecDeque<AsmRegisterXmm>,
+) -> Vec<Location> {
+    let mut stack = 0;
+    let mut next_stack_slot = |ty: &Type| {
+        let size = slot_size(ty);
+        // A `v128` is aligned to its size
+        stack = (stack + size - 1) & !(size - 1);
+        let location = Location::Stack(stack);
+        stack += size;
+        location
+    };
+    types
+        .iter()
+        .map(|ty| {
+            if Location::is_float(ty) {
+                float
+                    .pop_front()
+                    .map(Location::Float)
+                    .unwrap_or_else(|| next_stack_slot(ty))
+            } else {
+                integer
+                    .pop_front()
+                    .map(Location::Integer)
+                    .unwrap_or_else(|| next_stack_slot(ty))
+            }
+        })
+        .collect()
+}
+
+/// Locations of function parameters, in order
+///
+/// The address of the return area, if there is one, takes the first integer
+/// register.
+pub(crate) fn parameters(function_type: &FuncType) -> Vec<Location> {
+    let mut integer = VecDeque::from(vec![rdi, rsi, rdx, rcx, r8, r9]);
+    if has_return_area(&function_type.returns) {
+        integer.pop_front();
+    }
+    locations(
+        &function_type.params,
+        integer,
+        VecDeque::from(vec![xmm0, xmm1, xmm2, xmm3, xmm4, xmm5, xmm6, xmm7]),
+    )
+}
+
+/// Size of the stack arguments of a function, padded to keep `rsp` 16-byte
+/// aligned
+///
+/// Between compiled functions, the called function pops its stack arguments
+/// as it returns, unlike with System V, so that a tail call can pass another
+/// number of them than the function it replaces got. Functions without stack
+/// arguments follow System V either way; the others are called by the host
+/// through a [`wrapper`].
+pub(crate) fn stack_arguments_size(function_type: &FuncType) -> u32 {
+    let end = parameters(function_type)
+        .iter()
+        .zip(function_type.params.iter())
+        .map(|(location, ty)| location.end(ty))
+        .max()
+        .unwrap_or(0);
+    (end + 15) & !15
+}
+
+/// Whether results are returned through a return area instead of registers
+///
+/// Same as for structures in C, results go to registers as long as there are
+/// at most two of each class. Otherwise, the caller passes the address of a
+/// return area in `rdi`, and the function stores all the results there, in
+/// slots of the operand stack's layout, in order, and returns the address in
+/// `rax`.
+pub(crate) fn has_return_area(returns: &[Type]) -> bool {
+    let floats = returns.iter().filter(|ty| Location::is_float(ty)).count();
+    floats > 2 || returns.len() - floats > 2
+}
+
+/// Offsets of results in the return area, along with its size
+fn return_area(returns: &[Type]) -> (Vec<u32>, u32) {
+    let mut size = 0;
+    let offsets = returns
+        .iter()
+        .map(|ty| {
+            let offset = size;
+            size += slot_size(ty);
+            offset
+        })
+        .collect();
+    (offsets, size)
+}
+
+/// Locations of function results returned in registers, in order
+fn results(returns: &[Type]) -> Vec<Location> {
+    locations(
+        returns,
+        VecDeque::from(vec![rax, rdx]),
+        VecDeque::from(vec![xmm0, xmm1]),
+    )
+}
+
+/// Moves the parameters of a function onto its frame, in the prologue
+///
+/// Returns the offsets below `rbp` and types of the parameters, along with
+/// the size of the frame they take.
+pub(crate) fn enter(
+    assembler: &mut CodeAssembler,
+    function_type: &FuncType,
+) -> Result<(Vec<(u32, Type)>, u32), Error> {
+    let mut params = vec![];
+    let mut size = 0;
+    if has_return_area(&function_type.returns) {
+        assembler.push(rdi)?;
+        size += RETURN_AREA_SLOT;
+    }
+    let locations = parameters(function_type);
+    for (param, location) in function_type.params.iter().zip(locations) {
+        location.push(assembler, param)?;
+        size += slot_size(param);
+        params.push((size, *param));
+    }
+    Ok((params, size))
+}
+
+/// Moves the results of a function from the top of its operand stack to
+/// their locations, in the epilogue
+pub(crate) fn leave(assembler: &mut CodeAssembler, returns: &[Type]) -> Result<(), Error> {
+    if has_return_area(returns) {
+        let (offsets, _) = return_area(returns);
+        assembler.mov(rax, qword_ptr(rbp - RETURN_AREA_SLOT))?;
+        for (ret, offset) in returns.iter().zip(offsets).rev() {
+            for part in (0..slot_size(ret)).step_by(size_of::<u64>()) {
+                assembler.pop(rcx)?;
+                assembler.mov(qword_ptr(rax + offset + part), rcx)?;
+            }
+        }
+    } else {
+        for (ret, location) in returns.iter().zip(results(returns)).rev() {
+            location.pop(assembler, ret)?;
+        }
+    }
+    Ok(())
+}
+
+/// Calls a function of type `function_type`, replacing its arguments on the
+/// operand stack with its results
+///
+/// Arguments stay on the operand stack until the function returns. Stack
+/// arguments and the return area are placed below it, padded so that `rsp`
+/// is 16-byte aligned at the call, given that `rbp` of every function is.
+/// `emit_call` must preserve argument registers, and call a function that
+/// pops its stack arguments.
+pub(crate) fn call(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    function_type: &FuncType,
+    emit_call: impl FnOnce(&mut CodeAssembler) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let params = parameters(function_type);
+    let args_size: u32 = function_type.params.iter().map(slot_size).sum();
+    let stack_size = stack_arguments_size(function_type);
+    let returns_in_memory = has_return_area(&function_type.returns);
+    let (result_offsets, area_size) = return_area(&function_type.returns);
+    let area_size = if returns_in_memory { area_size } else { 0 };
+    // Results get copied from the return area to replace the arguments, so
+    // the area is kept clear of where they end up
+    let gap = area_size.saturating_sub(args_size);
+    let unpadded = stack_size + area_size + gap;
+    let padding = (16 - (control.frame_size() + unpadded) % 16) % 16;
+    let reserved = unpadded + padding;
+    if reserved > 0 {
+        assembler.sub(rsp, reserved as i32)?;
+    }
+    // The last argument is on top of the operand stack
+    let mut offset = reserved;
+    for (param, location) in function_type.params.iter().zip(params).rev() {
+        location.load(assembler, param, offset)?;
+        offset += slot_size(param);
+    }
+    if returns_in_memory {
+        assembler.lea(rdi, ptr(rsp + stack_size))?;
+    }
+    emit_call(assembler)?;
+    for param in function_type.params.iter().rev() {
+        control.pop(*param);
+    }
+    // Stack arguments are gone
+    offset -= stack_size;
+    if returns_in_memory {
+        for (ret, result_offset) in function_type.returns.iter().zip(result_offsets) {
+            offset -= slot_size(ret);
+            for part in (0..slot_size(ret)).step_by(size_of::<u64>()) {
+                assembler.mov(rcx, qword_ptr(rsp + result_offset + part))?;
+                assembler.mov(qword_ptr(rsp + offset + part), rcx)?;
+            }
+            control.push(*ret);
+        }
+        assembler.add(rsp, offset as i32)?;
+    } else {
+        if offset > 0 {
+            assembler.add(rsp, offset as i32)?;
+        }
+        let results = results(&function_type.returns);
+        for (ret, location) in function_type.returns.iter().zip(results) {
+            location.push(assembler, ret)?;
+            control.push(*ret);
+        }
+    }
+    Ok(())
+}
+
+/// Jumps to a function of type `function_type` in place of the current one,
+/// of type `caller_type`, which must have the same results
+///
+/// The frame of the current function is dropped and the called function
+/// returns straight to the caller, with its stack arguments replacing the
+/// current ones. `emit_jump` must preserve argument registers, `rax` and
+/// `r11`, and jump to a function that pops its stack arguments.
+pub(crate) fn tail_call(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    caller_type: &FuncType,
+    function_type: &FuncType,
+    emit_jump: impl FnOnce(&mut CodeAssembler) -> Result<(), Error>,
+) -> Result<(), Error> {
+    control.leave_handlers(assembler)?;
+    let params = parameters(function_type);
+    let incoming_size = stack_arguments_size(caller_type);
+    let stack_size = stack_arguments_size(function_type);
+    // Stack arguments are first put together below the operand stack, as
+    // their final place may overlap it
+    if stack_size > 0 {
+        assembler.sub(rsp, stack_size as i32)?;
+    }
+    let mut offset = stack_size;
+    for (param, location) in function_type.params.iter().zip(params).rev() {
+        location.load(assembler, param, offset)?;
+        offset += slot_size(param);
+    }
+    if has_return_area(&function_type.returns) {
+        assembler.mov(rdi, qword_ptr(rbp - RETURN_AREA_SLOT))?;
+    }
+    // Distance from `rsp` to `rbp`
+    let depth = control.frame_size() + stack_size;
+    for param in function_type.params.iter().rev() {
+        control.pop(*param);
+    }
+    if stack_size == incoming_size {
+        // The return address stays in place
+        for part in (0..stack_size).step_by(size_of::<u64>()) {
+            assembler.mov(r11, qword_ptr(rsp + part))?;
+            assembler.mov(qword_ptr(rbp + STACK_ARGUMENTS + part), r11)?;
+        }
+        assembler.mov(rsp, rbp)?;
+        assembler.pop(rbp)?;
+    } else {
+        // Arguments end where the current ones do, and the return address
+        // moves right below them
+        let end = depth + STACK_ARGUMENTS + incoming_size;
+        assembler.mov(rax, qword_ptr(rbp + RETURN_ADDRESS))?;
+        assembler.mov(rbp, qword_ptr(rbp))?;
+        // Copied from the end, as the destination is above the source
+        for part in (0..stack_size).step_by(size_of::<u64>()).rev() {
+            assembler.mov(r11, qword_ptr(rsp + part))?;
+            assembler.mov(qword_ptr(rsp + end - stack_size + part), r11)?;
+        }
+        assembler.lea(rsp, ptr(rsp + end - stack_size))?;
+        assembler.push(rax)?;
+    }
+    emit_jump(assembler)?;
+    control.set_unreachable();
+    Ok(())
+}
+
+/// Emits a function that passes its arguments on to another one of type
+/// `function_type`, called by `emit_call`, and returns its results
+///
+/// Stack arguments are copied for the call and the wrapper's frame is
+/// dropped afterwards, so the called function may pop them or not. The
+/// wrapper itself pops its stack arguments if `pop_arguments` is set, as
+/// compiled code expects, and follows System V otherwise.
+pub(crate) fn wrapper(
+    assembler: &mut CodeAssembler,
+    function_type: &FuncType,
+    pop_arguments: bool,
+    emit_call: impl FnOnce(&mut CodeAssembler) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let stack_size = stack_arguments_size(function_type);
+    assembler.push(rbp)?;
+    assembler.mov(rbp, rsp)?;
+    assembler.sub(rsp, stack_size as i32)?;
+    for part in (0..stack_size).step_by(size_of::<u64>()) {
+        assembler.mov(r11, qword_ptr(rbp + STACK_ARGUMENTS + part))?;
+        assembler.mov(qword_ptr(rsp + part), r11)?;
+    }
+    emit_call(assembler)?;
+    assembler.mov(rsp, rbp)?;
+    assembler.pop(rbp)?;
+    if pop_arguments {
+        assembler.ret_1(stack_size as i32)?;
+    } else {
+        assembler.ret()?;
+    }
+    Ok(())
+}
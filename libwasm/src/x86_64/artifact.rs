@@ -0,0 +1,695 @@
+//! Compiled modules stored ahead of time, to be loaded without compiling
+//!
+//! An artifact holds the code of a module, as produced by the compiler,
+//! along with the tables describing it: functions and their entry points,
+//! exports, imports, globals, memories, tables, segments and tags. Compiled
+//! code is position-independent, so the only places depending on where it
+//! gets loaded are the slots the host fills in when linking (imports, the
+//! trap handler, the exception state, the futex hook and memory
+//! descriptors). Right after the code, a table of [relocations](Relocation)
+//! lists these slots and what goes in each, so that a loader can tell what
+//! to patch without interpreting the rest of the artifact. Function
+//! references, table entries and the slots of functions compiled in tiers
+//! hold absolute addresses too, but the instantiation routine computes them
+//! from where the code runs, so they need no relocation.
+//!
+//! The header identifies the format version, the version of code generation,
+//! a [hash](source_hash) of the WebAssembly module the artifact was compiled
+//! from and the processor [features](super::Features) its code needs. All
+//! integers are little-endian; strings and byte arrays are preceded by their
+//! 32-bit length, and tables by their 32-bit number of entries.
+
+use super::{AssembledModule, Features, Global, Memory, Module, Segment, SegmentMode, Table};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::mem::size_of;
+use wasmparser_nostd::{GlobalType, MemoryType, TableType, Type};
+
+/// Identifies artifacts, at their very start
+pub const MAGIC: [u8; 8] = *b"PARAWASM";
+
+/// Version of the artifact format, changed whenever the layout of
+/// artifacts changes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Version of code generation, as recorded in artifacts
+///
+/// Compiled code and the tables describing it only make sense to the code
+/// generation that produced them: any change to the code the compiler emits,
+/// to the layout of module slots, to calling conventions with the host, or
+/// to how [`Module`] describes the code must bump this, in the same commit,
+/// so that artifacts written before are rejected instead of misbehaving.
+/// Changes to the layout of artifacts themselves bump [`FORMAT_VERSION`]
+/// instead.
+pub const CODEGEN_VERSION: u32 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArtifactError {
+    /// Not an artifact, or a truncated or corrupted one
+    Malformed,
+    /// Written in another version of the format
+    UnsupportedFormat(u32),
+    /// Written by another version of code generation
+    CodegenMismatch(u32),
+    /// The code needs processor features that weren't given
+    MissingFeatures(Features),
+}
+
+/// What the host fills a slot of the code with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RelocationKind {
+    /// Entry point of an imported function
+    Function,
+    /// Address of the value of an imported global
+    Global,
+    /// Address of the descriptor of an imported memory
+    Memory,
+    /// Address of the descriptor of an imported table
+    Table,
+    /// Identity of an imported tag
+    Tag,
+    /// Descriptor of a memory defined by the module, three slots long (see
+    /// [`link_memory`](AssembledModule::link_memory))
+    MemoryDescriptor,
+    /// Address of the trap handler
+    TrapHandler,
+    /// Address of the exception state
+    ExceptionState,
+    /// Address of the futex hook
+    Futex,
+}
+
+/// Slot of the code the host fills in before instantiating the module
+///
+/// Imported slots are matched with the import names through their offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Relocation {
+    /// Offset of the slot in the code
+    pub offset: usize,
+    pub kind: RelocationKind,
+}
+
+/// What an artifact was compiled from, and for
+#[derive(Debug, PartialEq, Eq)]
+pub struct ArtifactHeader {
+    pub format_version: u32,
+    pub codegen_version: u32,
+    /// [Hash](source_hash) of the WebAssembly module
+    pub source_hash: u64,
+    /// Processor features the code needs
+    pub features: Features,
+}
+
+impl ArtifactHeader {
+    /// Reads the header of an artifact, which keeps its layout across
+    /// versions of the format
+    pub fn read(artifact: &[u8]) -> Result<Self, ArtifactError> {
+        Self::parse(&mut Reader::new(artifact))
+    }
+
+    fn parse(reader: &mut Reader) -> Result<Self, ArtifactError> {
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ArtifactError::Malformed);
+        }
+        Ok(Self {
+            format_version: reader.u32()?,
+            codegen_version: reader.u32()?,
+            source_hash: reader.u64()?,
+            features: features(reader.u32()?),
+        })
+    }
+}
+
+/// Relocations of the code of an artifact, read without loading the module
+pub fn relocations(artifact: &[u8]) -> Result<Vec<Relocation>, ArtifactError> {
+    let mut reader = Reader::new(artifact);
+    let header = ArtifactHeader::parse(&mut reader)?;
+    if header.format_version != FORMAT_VERSION {
+        return Err(ArtifactError::UnsupportedFormat(header.format_version));
+    }
+    reader.array()?;
+    reader.relocations()
+}
+
+/// Hash of a WebAssembly module, identifying what an artifact was compiled
+/// from
+///
+/// This is 64-bit FNV-1a, which tells apart modules that changed, but won't
+/// stand against ones crafted to collide.
+pub fn source_hash(wasm: &[u8]) -> u64 {
+    wasm.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+impl AssembledModule {
+    /// Writes the module as an artifact
+    ///
+    /// The module's binary holds the state of its globals, memories and
+    /// tables, and the addresses it was linked with, so it should be written
+    /// right after being compiled.
+    pub fn to_artifact(&self) -> Vec<u8> {
+        let module = &self.module;
+        let mut writer = Writer::default();
+        writer.bytes(&MAGIC);
+        writer.u32(FORMAT_VERSION);
+        writer.u32(CODEGEN_VERSION);
+        writer.u64(module.source_hash);
+        writer.u32(feature_bits(module.features));
+        writer.array(&self.assembled);
+        writer.relocations(&module.relocations());
+        writer.u8(module.floating_point as u8);
+        for offset in [
+            module.instantiation,
+            module.trap_handler,
+            module.exception_state,
+            module.futex,
+        ] {
+            writer.offset(offset);
+        }
+        for offsets in [
+            &module.functions,
+            &module.counters,
+            &module.function_bodies,
+            &module.import_stubs,
+            &module.wrappers,
+            &module.tags,
+        ] {
+            writer.offsets(offsets);
+        }
+        for exports in [
+            &module.exports,
+            &module.global_exports,
+            &module.memory_exports,
+            &module.table_exports,
+            &module.tag_exports,
+        ] {
+            writer.exports(exports);
+        }
+        for imports in [
+            &module.imports,
+            &module.global_imports,
+            &module.memory_imports,
+            &module.table_imports,
+            &module.tag_imports,
+        ] {
+            writer.imports(imports);
+        }
+        writer.u32(module.globals.len() as u32);
+        for (index, global) in module.globals.iter() {
+            writer.u32(*index);
+            writer.u8(type_code(global.content_type()));
+            writer.u8(global.is_mutable() as u8);
+            writer.u8(global.is_imported() as u8);
+            writer.offset(global.offset());
+        }
+        writer.u32(module.memories.len() as u32);
+        for (index, memory) in module.memories.iter() {
+            writer.u32(*index);
+            writer.u64(memory.initial_pages());
+            writer.option(memory.maximum_pages(), Writer::u64);
+            writer.u8(memory.is_shared() as u8);
+            writer.u8((memory.index_type() == Type::I64) as u8);
+            writer.u8(memory.is_imported() as u8);
+            writer.offset(memory.offset());
+        }
+        writer.u32(module.tables.len() as u32);
+        for (index, table) in module.tables.iter() {
+            writer.u32(*index);
+            writer.u8(type_code(table.element_type()));
+            writer.u32(table.initial_size());
+            writer.option(table.maximum_size(), Writer::u32);
+            writer.u8(table.is_imported() as u8);
+            writer.offset(table.offset());
+        }
+        for segments in [&module.data_segments, &module.element_segments] {
+            writer.segments(segments);
+        }
+        writer.0
+    }
+
+    /// Loads a module from an artifact, to run on a processor with
+    /// `features`
+    ///
+    /// The module is then linked and instantiated as if it were just
+    /// compiled.
+    pub fn from_artifact(artifact: &[u8], features: Features) -> Result<Self, ArtifactError> {
+        let mut reader = Reader::new(artifact);
+        let header = ArtifactHeader::parse(&mut reader)?;
+        if header.format_version != FORMAT_VERSION {
+            return Err(ArtifactError::UnsupportedFormat(header.format_version));
+        }
+        if header.codegen_version != CODEGEN_VERSION {
+            return Err(ArtifactError::CodegenMismatch(header.codegen_version));
+        }
+        if feature_bits(header.features) & !feature_bits(features) != 0 {
+            return Err(ArtifactError::MissingFeatures(header.features));
+        }
+        let assembled = reader.array()?.to_vec();
+        let relocations = reader.relocations()?;
+        let mut module = Module::new();
+        module.source_hash = header.source_hash;
+        module.features = header.features;
+        module.floating_point = reader.bool()?;
+        for offset in [
+            &mut module.instantiation,
+            &mut module.trap_handler,
+            &mut module.exception_state,
+            &mut module.futex,
+        ] {
+            *offset = reader.offset()?;
+        }
+        for offsets in [
+            &mut module.functions,
+            &mut module.counters,
+            &mut module.function_bodies,
+            &mut module.import_stubs,
+            &mut module.wrappers,
+            &mut module.tags,
+        ] {
+            *offsets = reader.offsets()?;
+        }
+        for exports in [
+            &mut module.exports,
+            &mut module.global_exports,
+            &mut module.memory_exports,
+            &mut module.table_exports,
+            &mut module.tag_exports,
+        ] {
+            *exports = reader.exports()?;
+        }
+        for imports in [
+            &mut module.imports,
+            &mut module.global_imports,
+            &mut module.memory_imports,
+            &mut module.table_imports,
+            &mut module.tag_imports,
+        ] {
+            *imports = reader.imports()?;
+        }
+        for _ in 0..reader.u32()? {
+            let index = reader.u32()?;
+            let ty = GlobalType {
+                content_type: value_type(reader.u8()?)?,
+                mutable: reader.bool()?,
+            };
+            let imported = reader.bool()?;
+            let offset = reader.offset()?;
+            module
+                .globals
+                .insert(index, Global::new(ty, offset, imported));
+        }
+        for _ in 0..reader.u32()? {
+            let index = reader.u32()?;
+            let initial = reader.u64()?;
+            let maximum = reader.option(Reader::u64)?;
+            let shared = reader.bool()?;
+            let memory64 = reader.bool()?;
+            let ty = MemoryType {
+                memory64,
+                shared,
+                initial,
+                maximum,
+            };
+            let imported = reader.bool()?;
+            let offset = reader.offset()?;
+            module
+                .memories
+                .insert(index, Memory::new(&ty, offset, imported));
+        }
+        for _ in 0..reader.u32()? {
+            let index = reader.u32()?;
+            let ty = TableType {
+                element_type: value_type(reader.u8()?)?,
+                initial: reader.u32()?,
+                maximum: reader.option(Reader::u32)?,
+            };
+            let imported = reader.bool()?;
+            let offset = reader.offset()?;
+            module
+                .tables
+                .insert(index, Table::new(&ty, offset, imported));
+        }
+        module.data_segments = reader.segments()?;
+        module.element_segments = reader.segments()?;
+        if !reader.is_empty()
+            || !module.offsets_within(assembled.len())
+            || relocations != module.relocations()
+        {
+            return Err(ArtifactError::Malformed);
+        }
+        Ok(module.assembled(assembled))
+    }
+
+    /// [Hash](source_hash) of the WebAssembly module this was compiled from
+    pub fn source_hash(&self) -> u64 {
+        self.module.source_hash
+    }
+
+    /// Slots of the code the host fills in, by offset
+    pub fn relocations(&self) -> Vec<Relocation> {
+        self.module.relocations()
+    }
+}
+
+impl Module {
+    fn relocations(&self) -> Vec<Relocation> {
+        let imports = [
+            (&self.imports, RelocationKind::Function),
+            (&self.global_imports, RelocationKind::Global),
+            (&self.memory_imports, RelocationKind::Memory),
+            (&self.table_imports, RelocationKind::Table),
+            (&self.tag_imports, RelocationKind::Tag),
+        ];
+        let mut relocations: Vec<Relocation> = [
+            (self.trap_handler, RelocationKind::TrapHandler),
+            (self.exception_state, RelocationKind::ExceptionState),
+            (self.futex, RelocationKind::Futex),
+        ]
+        .into_iter()
+        .chain(imports.into_iter().flat_map(|(imports, kind)| {
+            imports.values().map(move |(_, _, offset)| (*offset, kind))
+        }))
+        .chain(
+            self.memories
+                .values()
+                .filter(|memory| !memory.is_imported())
+                .map(|memory| (memory.offset(), RelocationKind::MemoryDescriptor)),
+        )
+        .map(|(offset, kind)| Relocation { offset, kind })
+        .collect();
+        relocations.sort();
+        relocations
+    }
+
+    /// Whether all slots the host accesses fit in code of `len` bytes
+    ///
+    /// Offsets come from the artifact, so they may be anywhere up to
+    /// `u64::MAX`.
+    fn offsets_within(&self, len: usize) -> bool {
+        let slots = |offset: usize, count: usize| {
+            count
+                .checked_mul(size_of::<u64>())
+                .and_then(|size| offset.checked_add(size))
+                .is_some_and(|end| end <= len)
+        };
+        let slot = |offset: usize| slots(offset, 1);
+        let imports = [
+            &self.imports,
+            &self.global_imports,
+            &self.memory_imports,
+            &self.table_imports,
+            &self.tag_imports,
+        ];
+        [
+            self.instantiation,
+            self.trap_handler,
+            self.exception_state,
+            self.futex,
+        ]
+        .into_iter()
+        .chain(self.functions.values().copied())
+        .chain(self.counters.values().copied())
+        .chain(self.function_bodies.values().copied())
+        .chain(self.wrappers.values().copied())
+        .chain(self.tags.values().copied())
+        .chain(imports.into_iter().flat_map(|i| i.values().map(|i| i.2)))
+        .chain(self.globals.values().map(Global::offset))
+        .chain(self.memories.values().map(Memory::offset))
+        .all(slot)
+            && self.memories.values().all(|memory| {
+                // Defined memories' descriptors take three slots
+                memory.is_imported() || slots(memory.offset(), 3)
+            })
+            && self.tables.values().all(|table| {
+                // Size and limit slots, followed by the entries
+                if table.is_imported() {
+                    slot(table.offset())
+                } else {
+                    slots(table.offset(), table.limit() as usize + 2)
+                }
+            })
+    }
+}
+
+fn feature_bits(features: Features) -> u32 {
+    features.sse4_1 as u32 | (features.popcnt as u32) << 1
+}
+
+fn features(bits: u32) -> Features {
+    Features {
+        sse4_1: bits & 1 != 0,
+        popcnt: bits & 2 != 0,
+    }
+}
+
+fn type_code(ty: Type) -> u8 {
+    match ty {
+        Type::I32 => 0,
+        Type::I64 => 1,
+        Type::F32 => 2,
+        Type::F64 => 3,
+        Type::V128 => 4,
+        Type::FuncRef => 5,
+        Type::ExternRef => 6,
+        _ => unreachable!("not the type of a global or table"),
+    }
+}
+
+fn value_type(code: u8) -> Result<Type, ArtifactError> {
+    match code {
+        0 => Ok(Type::I32),
+        1 => Ok(Type::I64),
+        2 => Ok(Type::F32),
+        3 => Ok(Type::F64),
+        4 => Ok(Type::V128),
+        5 => Ok(Type::FuncRef),
+        6 => Ok(Type::ExternRef),
+        _ => Err(ArtifactError::Malformed),
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        let mut bytes = [0; size_of::<u32>()];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.bytes(&bytes);
+    }
+
+    fn u64(&mut self, value: u64) {
+        let mut bytes = [0; size_of::<u64>()];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.bytes(&bytes);
+    }
+
+    fn offset(&mut self, offset: usize) {
+        self.u64(offset as u64);
+    }
+
+    fn array(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.array(string.as_bytes());
+    }
+
+    fn relocations(&mut self, relocations: &[Relocation]) {
+        self.u32(relocations.len() as u32);
+        for relocation in relocations {
+            self.offset(relocation.offset);
+            self.u8(relocation.kind as u8);
+        }
+    }
+
+    fn option<T>(&mut self, value: Option<T>, write: impl Fn(&mut Self, T)) {
+        self.u8(value.is_some() as u8);
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+
+    fn offsets(&mut self, offsets: &BTreeMap<u32, usize>) {
+        self.u32(offsets.len() as u32);
+        for (index, offset) in offsets.iter() {
+            self.u32(*index);
+            self.offset(*offset);
+        }
+    }
+
+    fn exports(&mut self, exports: &BTreeMap<String, u32>) {
+        self.u32(exports.len() as u32);
+        for (name, index) in exports.iter() {
+            self.string(name);
+            self.u32(*index);
+        }
+    }
+
+    fn imports(&mut self, imports: &BTreeMap<u32, (String, Option<String>, usize)>) {
+        self.u32(imports.len() as u32);
+        for (index, (module, name, offset)) in imports.iter() {
+            self.u32(*index);
+            self.string(module);
+            self.option(name.as_deref(), Self::string);
+            self.offset(*offset);
+        }
+    }
+
+    fn segments(&mut self, segments: &[Segment]) {
+        self.u32(segments.len() as u32);
+        for segment in segments {
+            match segment.mode() {
+                SegmentMode::Active { index } => {
+                    self.u8(0);
+                    self.u32(index);
+                }
+                SegmentMode::Passive => self.u8(1),
+                SegmentMode::Declared => self.u8(2),
+            }
+            self.u64(segment.len() as u64);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ArtifactError> {
+        if len > self.bytes.len() {
+            return Err(ArtifactError::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ArtifactError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ArtifactError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ArtifactError::Malformed),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, ArtifactError> {
+        Ok(LittleEndian::read_u32(self.take(size_of::<u32>())?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ArtifactError> {
+        Ok(LittleEndian::read_u64(self.take(size_of::<u64>())?))
+    }
+
+    fn offset(&mut self) -> Result<usize, ArtifactError> {
+        usize::try_from(self.u64()?).map_err(|_| ArtifactError::Malformed)
+    }
+
+    fn array(&mut self) -> Result<&'a [u8], ArtifactError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, ArtifactError> {
+        let bytes = self.array()?;
+        let string = core::str::from_utf8(bytes).map_err(|_| ArtifactError::Malformed)?;
+        Ok(String::from(string))
+    }
+
+    fn relocations(&mut self) -> Result<Vec<Relocation>, ArtifactError> {
+        let mut relocations = vec![];
+        for _ in 0..self.u32()? {
+            let offset = self.offset()?;
+            let kind = match self.u8()? {
+                0 => RelocationKind::Function,
+                1 => RelocationKind::Global,
+                2 => RelocationKind::Memory,
+                3 => RelocationKind::Table,
+                4 => RelocationKind::Tag,
+                5 => RelocationKind::MemoryDescriptor,
+                6 => RelocationKind::TrapHandler,
+                7 => RelocationKind::ExceptionState,
+                8 => RelocationKind::Futex,
+                _ => return Err(ArtifactError::Malformed),
+            };
+            relocations.push(Relocation { offset, kind });
+        }
+        Ok(relocations)
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl Fn(&mut Self) -> Result<T, ArtifactError>,
+    ) -> Result<Option<T>, ArtifactError> {
+        if self.bool()? {
+            Ok(Some(read(self)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn offsets(&mut self) -> Result<BTreeMap<u32, usize>, ArtifactError> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.u32()?, self.offset()?)))
+            .collect()
+    }
+
+    fn exports(&mut self) -> Result<BTreeMap<String, u32>, ArtifactError> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.string()?, self.u32()?)))
+            .collect()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn imports(&mut self) -> Result<BTreeMap<u32, (String, Option<String>, usize)>, ArtifactError> {
+        (0..self.u32()?)
+            .map(|_| {
+                let index = self.u32()?;
+                let module = self.string()?;
+                let name = self.option(Self::string)?;
+                Ok((index, (module, name, self.offset()?)))
+            })
+            .collect()
+    }
+
+    fn segments(&mut self) -> Result<Vec<Segment>, ArtifactError> {
+        (0..self.u32()?)
+            .map(|_| {
+                let mode = match self.u8()? {
+                    0 => SegmentMode::Active { index: self.u32()? },
+                    1 => SegmentMode::Passive,
+                    2 => SegmentMode::Declared,
+                    _ => return Err(ArtifactError::Malformed),
+                };
+                Ok(Segment::new(mode, self.offset()?))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,344 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::memory;
+use crate::x86_64::{Context, Error};
+use iced_x86::code_asm::{
+    al, ax, byte_ptr, cl, cx, dil, dl, dword_ptr, dx, eax, ecx, edi, edx, qword_ptr, r8, r8b, r8d,
+    r8w, r9, r9d, rax, rcx, rdi, rdx, rsi, rsp, word_ptr, AsmMemoryOperand, CodeAssembler,
+    CodeLabel,
+};
+use wasmparser_nostd::{MemoryImmediate, Type};
+
+/// Operation requested from the host's futex hook
+///
+/// The hook is linked with
+/// [`AssembledModule::link_futex`](super::AssembledModule::link_futex) and
+/// called following the System V calling convention as
+/// `hook(op, address, value, timeout) -> u32`, where `address` is the
+/// absolute address of the (naturally aligned) value waited on or notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum FutexOp {
+    /// `memory.atomic.wait32`: unless the 32-bit value at `address` differs
+    /// from `value`, blocks until notified or until `timeout` nanoseconds
+    /// have passed (forever if negative). Returns 0 if notified, 1 if the
+    /// value differed and 2 on timeout. The comparison and starting to wait
+    /// must be atomic with respect to notifications.
+    Wait32 = 0,
+    /// `memory.atomic.wait64`, same as [`Wait32`](FutexOp::Wait32) with a
+    /// 64-bit value
+    Wait64 = 1,
+    /// `memory.atomic.notify`: wakes up at most `value` waiters on
+    /// `address` and returns how many were woken up; `timeout` is zero
+    Notify = 2,
+}
+
+/// Label of the slot keeping the address of the host's futex hook
+pub(crate) struct Futex {
+    hook: CodeLabel,
+}
+
+impl Futex {
+    pub(crate) fn new(assembler: &mut CodeAssembler) -> Self {
+        Self {
+            hook: assembler.create_label(),
+        }
+    }
+
+    /// Emits the hook slot. Returns its offset in the module binary.
+    pub(crate) fn emit(&mut self, assembler: &mut CodeAssembler) -> Result<usize, Error> {
+        let offset = assembler.instructions().len();
+        assembler.set_label(&mut self.hook)?;
+        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+        Ok(offset)
+    }
+}
+
+/// Read-modify-write operation of `*.atomic.rmw*`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rmw {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+}
+
+/// Operand for `size` bytes at `rdi`
+fn operand(size: u32) -> AsmMemoryOperand {
+    match size {
+        1 => byte_ptr(rdi),
+        2 => word_ptr(rdi),
+        4 => dword_ptr(rdi),
+        _ => qword_ptr(rdi),
+    }
+}
+
+/// Zero-extends the lowest `size` bytes of `rax`
+fn zero_extend(assembler: &mut CodeAssembler, size: u32) -> Result<(), Error> {
+    match size {
+        1 => assembler.movzx(eax, al)?,
+        2 => assembler.movzx(eax, ax)?,
+        4 => assembler.mov(eax, eax)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Pops the address into `rdi`, as an absolute one, trapping if the `size`
+/// bytes there are out of bounds or not naturally aligned
+///
+/// Alignment is checked on the absolute address, so memories must be mapped
+/// at least 8-byte aligned. Clobbers `rax`, `rcx` and `r11`.
+fn address(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    memarg: &MemoryImmediate,
+    size: u32,
+) -> Result<(), Error> {
+    let mem = memory::address(assembler, context, memarg, size)?;
+    assembler.lea(rdi, mem)?;
+    if size > 1 {
+        assembler.test(dil, size as i32 - 1)?;
+        assembler.jnz(context.traps.label(Trap::UnalignedAtomic))?;
+    }
+    Ok(())
+}
+
+/// Atomic load of `size` bytes, zero-extended to `ty`
+///
+/// Plain loads are sequentially consistent on x86 as long as stores aren't
+/// plain, see [`store`].
+pub(crate) fn load(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    size: u32,
+    ty: Type,
+) -> Result<(), Error> {
+    address(assembler, context, memarg, size)?;
+    match size {
+        1 | 2 => assembler.movzx(eax, operand(size))?,
+        4 => assembler.mov(eax, operand(size))?,
+        _ => assembler.mov(rax, operand(size))?,
+    }
+    assembler.push(rax)?;
+    control.pop(context.index_type(memarg.memory));
+    control.push(ty);
+    Ok(())
+}
+
+/// Atomic store of the lowest `size` bytes of a `ty` value, with `xchg`,
+/// which acts as a full barrier
+pub(crate) fn store(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    size: u32,
+    ty: Type,
+) -> Result<(), Error> {
+    assembler.pop(rdx)?;
+    address(assembler, context, memarg, size)?;
+    match size {
+        1 => assembler.xchg(operand(size), dl)?,
+        2 => assembler.xchg(operand(size), dx)?,
+        4 => assembler.xchg(operand(size), edx)?,
+        _ => assembler.xchg(operand(size), rdx)?,
+    }
+    control.pop(ty);
+    control.pop(context.index_type(memarg.memory));
+    Ok(())
+}
+
+/// Atomic read-modify-write of `size` bytes, pushing the previous value
+/// zero-extended to `ty`
+///
+/// Additions and exchanges are single instructions, bitwise operations are
+/// `cmpxchg` loops.
+pub(crate) fn rmw(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    size: u32,
+    ty: Type,
+    op: Rmw,
+) -> Result<(), Error> {
+    assembler.pop(rdx)?;
+    address(assembler, context, memarg, size)?;
+    match op {
+        Rmw::Add | Rmw::Sub | Rmw::Xchg => {
+            if op == Rmw::Sub {
+                assembler.neg(rdx)?;
+            }
+            match (op, size) {
+                (Rmw::Xchg, 1) => assembler.xchg(operand(size), dl)?,
+                (Rmw::Xchg, 2) => assembler.xchg(operand(size), dx)?,
+                (Rmw::Xchg, 4) => assembler.xchg(operand(size), edx)?,
+                (Rmw::Xchg, _) => assembler.xchg(operand(size), rdx)?,
+                (_, 1) => assembler.lock().xadd(operand(size), dl)?,
+                (_, 2) => assembler.lock().xadd(operand(size), dx)?,
+                (_, 4) => assembler.lock().xadd(operand(size), edx)?,
+                (_, _) => assembler.lock().xadd(operand(size), rdx)?,
+            }
+            assembler.mov(rax, rdx)?;
+        }
+        Rmw::And | Rmw::Or | Rmw::Xor => {
+            let mut retry = assembler.create_label();
+            match size {
+                1 | 2 => assembler.movzx(eax, operand(size))?,
+                4 => assembler.mov(eax, operand(size))?,
+                _ => assembler.mov(rax, operand(size))?,
+            }
+            control.bind_label(assembler, &mut retry)?;
+            assembler.mov(rcx, rax)?;
+            match op {
+                Rmw::And => assembler.and(rcx, rdx)?,
+                Rmw::Or => assembler.or(rcx, rdx)?,
+                _ => assembler.xor(rcx, rdx)?,
+            }
+            // On failure, the current value is loaded into `rax`
+            match size {
+                1 => assembler.lock().cmpxchg(operand(size), cl)?,
+                2 => assembler.lock().cmpxchg(operand(size), cx)?,
+                4 => assembler.lock().cmpxchg(operand(size), ecx)?,
+                _ => assembler.lock().cmpxchg(operand(size), rcx)?,
+            }
+            assembler.jne(retry)?;
+        }
+    }
+    zero_extend(assembler, size)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    control.pop(context.index_type(memarg.memory));
+    control.push(ty);
+    Ok(())
+}
+
+/// `*.atomic.rmw*.cmpxchg`: pops the replacement and the expected value,
+/// and pushes the previous value zero-extended to `ty`
+///
+/// Only the lowest `size` bytes of the expected value are compared.
+pub(crate) fn cmpxchg(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    size: u32,
+    ty: Type,
+) -> Result<(), Error> {
+    assembler.pop(r8)?;
+    assembler.pop(rdx)?;
+    address(assembler, context, memarg, size)?;
+    assembler.mov(rax, rdx)?;
+    match size {
+        1 => assembler.lock().cmpxchg(operand(size), r8b)?,
+        2 => assembler.lock().cmpxchg(operand(size), r8w)?,
+        4 => assembler.lock().cmpxchg(operand(size), r8d)?,
+        _ => assembler.lock().cmpxchg(operand(size), r8)?,
+    }
+    zero_extend(assembler, size)?;
+    assembler.push(rax)?;
+    for _ in 0..2 {
+        control.pop(ty);
+    }
+    control.pop(context.index_type(memarg.memory));
+    control.push(ty);
+    Ok(())
+}
+
+/// Calls the futex hook with the operation, the address in `rdi`, the
+/// value in `r9` and, when waiting, the timeout in `r8`, and pushes the
+/// result
+fn call_hook(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    op: FutexOp,
+) -> Result<(), Error> {
+    let padding = (16 - control.frame_size() % 16) % 16;
+    assembler.mov(rsi, rdi)?;
+    assembler.mov(edi, op as u32)?;
+    if op == FutexOp::Wait64 {
+        assembler.mov(rdx, r9)?;
+    } else {
+        assembler.mov(edx, r9d)?;
+    }
+    if op == FutexOp::Notify {
+        assembler.xor(ecx, ecx)?;
+    } else {
+        assembler.mov(rcx, r8)?;
+    }
+    if padding > 0 {
+        assembler.sub(rsp, padding as i32)?;
+    }
+    assembler.call(qword_ptr(context.futex.hook))?;
+    if padding > 0 {
+        assembler.add(rsp, padding as i32)?;
+    }
+    assembler.mov(eax, eax)?;
+    assembler.push(rax)?;
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// `memory.atomic.wait32` and `memory.atomic.wait64`: pops the timeout and
+/// the expected value of `size` bytes, and waits through the futex hook
+///
+/// Only shared memories can be waited on, others trap.
+pub(crate) fn wait(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    size: u32,
+) -> Result<(), Error> {
+    let (ty, op) = match size {
+        4 => (Type::I32, FutexOp::Wait32),
+        _ => (Type::I64, FutexOp::Wait64),
+    };
+    assembler.pop(r8)?;
+    assembler.pop(r9)?;
+    address(assembler, context, memarg, size)?;
+    control.pop(Type::I64);
+    control.pop(ty);
+    control.pop(context.index_type(memarg.memory));
+    if !context.memories[&memarg.memory].shared {
+        assembler.jmp(context.traps.label(Trap::ExpectedSharedMemory))?;
+        control.set_unreachable();
+        return Ok(());
+    }
+    call_hook(assembler, context, control, op)
+}
+
+/// `memory.atomic.notify`: pops the maximum number of waiters to wake up,
+/// and pushes how many were woken up through the futex hook
+///
+/// Nothing can wait on memories that aren't shared, so their waiters aren't
+/// looked for.
+pub(crate) fn notify(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+) -> Result<(), Error> {
+    assembler.pop(r9)?;
+    address(assembler, context, memarg, 4)?;
+    control.pop(Type::I32);
+    control.pop(context.index_type(memarg.memory));
+    if !context.memories[&memarg.memory].shared {
+        assembler.push(0)?;
+        control.push(Type::I32);
+        return Ok(());
+    }
+    call_hook(assembler, context, control, FutexOp::Notify)
+}
+
+/// `atomic.fence`
+pub(crate) fn fence(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    assembler.mfence()?;
+    Ok(())
+}
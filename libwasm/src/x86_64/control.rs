@@ -0,0 +1,726 @@
+use crate::x86_64::exceptions::{
+    Handlers, TagSlot, EXCEPTION_PAYLOAD_SIZE, RECORD_SIZE, SAVED_SIZE,
+};
+use crate::x86_64::Error;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use iced_x86::code_asm::{
+    dword_ptr, eax, ptr, qword_ptr, rax, rbp, rcx, rsp, CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::{FuncType, Type, TypeOrFuncType};
+
+/// Size of an operand stack slot occupied by a value of a given type
+///
+/// Values take the same slots on the operand stack, in locals and in
+/// globals. Everything but a `v128` takes 64 bits; `i32` and `f32` values
+/// are kept in the lower half of their slot, with the upper half zeroed, so
+/// that slots can be copied and reinterpreted as a whole. 32-bit operators
+/// ignore the upper half of their operands.
+pub(crate) fn slot_size(ty: &Type) -> u32 {
+    match ty {
+        Type::V128 => 16,
+        _ => 8,
+    }
+}
+
+fn slots_size(types: &[Type]) -> u32 {
+    types.iter().map(slot_size).sum()
+}
+
+pub(crate) enum ControlFrameKind {
+    Function,
+    Block,
+    Loop,
+    If {
+        else_label: CodeLabel,
+        has_else: bool,
+    },
+    Try {
+        /// Where exceptions thrown in the body land
+        landing: CodeLabel,
+        /// Offset below `rbp` of the block's handler record
+        record: u32,
+        /// Set once past the body
+        catching: Option<Catching>,
+    },
+}
+
+/// State of a `try` block in its `catch` clauses
+pub(crate) struct Catching {
+    /// Tag check of the next clause, unless the last one was `catch_all`
+    next: Option<CodeLabel>,
+    /// Offset below `rbp` of the copy of the caught exception, if the
+    /// function rethrows
+    saved: Option<u32>,
+    /// Size of the caught exception's payload
+    payload_size: u32,
+}
+
+pub(crate) struct ControlFrame {
+    kind: ControlFrameKind,
+    /// Branch target: loop header for loops, end of the block otherwise
+    label: CodeLabel,
+    end_label: CodeLabel,
+    params: Vec<Type>,
+    results: Vec<Type>,
+    /// Operand stack height (in bytes) below the block's parameters
+    height: u32,
+    /// Number of operands below the block's parameters
+    operands: usize,
+}
+
+impl ControlFrame {
+    fn branch_types(&self) -> &[Type] {
+        match self.kind {
+            ControlFrameKind::Loop => &self.params,
+            _ => &self.results,
+        }
+    }
+}
+
+/// Jump table emitted for `br_table`
+///
+/// Entries are 32-bit offsets of the branch stubs relative to the start of
+/// the table. They can only be filled in once the final code layout is known.
+pub(crate) struct JumpTable {
+    /// Instruction index of the table's first entry
+    pub(crate) base: usize,
+    /// Instruction index of the branch stub for every entry
+    pub(crate) targets: Vec<usize>,
+}
+
+/// Control frames of the function being compiled, along with the static
+/// height of the operand stack it keeps on the machine stack
+///
+/// Operand stack lives right below the function's locals area, so its
+/// bottom is always at `rbp - locals_size`.
+pub(crate) struct ControlStack {
+    /// Type of the function being compiled
+    function_type: FuncType,
+    frames: Vec<ControlFrame>,
+    /// Types of values on the operand stack
+    operands: Vec<Type>,
+    height: u32,
+    locals_size: u32,
+    /// Set after an unconditional branch; counts nested blocks being skipped
+    unreachable: Option<u32>,
+    /// Instruction index a label was last bound at
+    last_label: Option<usize>,
+    jump_tables: Vec<JumpTable>,
+    /// Set once a float or a vector is pushed onto the operand stack
+    floating_point: bool,
+    handlers: Handlers,
+    /// Offset below `rbp` of the frame's area for exception handling, which
+    /// comes right after the locals
+    exception_area: u32,
+    /// Offset below `rbp` of the copies of caught exceptions, if the
+    /// function rethrows
+    saved_area: Option<u32>,
+    /// Counter incremented on every loop iteration
+    counter: Option<CodeLabel>,
+}
+
+impl ControlStack {
+    /// `locals_size` includes the area for exception handling, as given by
+    /// [`frame_area`](super::exceptions::frame_area), which starts at
+    /// `exception_area`
+    pub(crate) fn new(
+        assembler: &mut CodeAssembler,
+        function_type: &FuncType,
+        locals_size: u32,
+        handlers: Handlers,
+        exception_area: u32,
+        (records, saved): (u32, u32),
+    ) -> Self {
+        let label = assembler.create_label();
+        Self {
+            function_type: function_type.clone(),
+            frames: vec![ControlFrame {
+                kind: ControlFrameKind::Function,
+                label,
+                end_label: label,
+                params: vec![],
+                results: function_type.returns.to_vec(),
+                height: 0,
+                operands: 0,
+            }],
+            operands: vec![],
+            height: 0,
+            locals_size,
+            unreachable: None,
+            last_label: None,
+            jump_tables: vec![],
+            floating_point: false,
+            handlers,
+            exception_area,
+            saved_area: (saved > 0).then_some(exception_area + records * RECORD_SIZE),
+            counter: None,
+        }
+    }
+
+    /// Has loops increment `counter` on every iteration, as functions
+    /// compiled in tiers do
+    pub(crate) fn count_iterations(&mut self, counter: CodeLabel) {
+        self.counter = Some(counter);
+    }
+
+    pub(crate) fn function_type(&self) -> &FuncType {
+        &self.function_type
+    }
+
+    pub(crate) fn into_jump_tables(self) -> Vec<JumpTable> {
+        self.jump_tables
+    }
+
+    /// Whether the function has used floats or vectors so far
+    ///
+    /// Every float or vector operator either produces a float or a vector or
+    /// consumes one that was pushed before.
+    pub(crate) fn uses_floating_point(&self) -> bool {
+        self.floating_point
+    }
+
+    /// Records a value pushed onto the operand stack
+    pub(crate) fn push(&mut self, ty: Type) {
+        self.floating_point |= matches!(ty, Type::F32 | Type::F64 | Type::V128);
+        self.height += slot_size(&ty);
+        self.operands.push(ty);
+    }
+
+    /// Records a value popped off the operand stack
+    pub(crate) fn pop(&mut self, ty: Type) {
+        self.height -= slot_size(&ty);
+        self.operands.pop();
+    }
+
+    /// Size of the function's frame below `rbp`: its locals and operand stack
+    pub(crate) fn frame_size(&self) -> u32 {
+        self.locals_size + self.height
+    }
+
+    /// Type of the value on top of the operand stack
+    pub(crate) fn top(&self) -> Type {
+        *self.operands.last().unwrap()
+    }
+
+    pub(crate) fn is_reachable(&self) -> bool {
+        self.unreachable.is_none()
+    }
+
+    /// Marks the rest of the current block as unreachable
+    pub(crate) fn set_unreachable(&mut self) {
+        self.unreachable = Some(0);
+    }
+
+    /// Tracks block nesting while skipping unreachable code. Returns `true`
+    /// if the operator should still be compiled.
+    ///
+    /// Operators continuing a block, such as `else`, make the rest of the
+    /// innermost block reachable again, but are skipped in nested ones.
+    pub(crate) fn skip_unreachable(
+        &mut self,
+        opens_block: bool,
+        continues_block: bool,
+        closes_block: bool,
+    ) -> bool {
+        match self.unreachable {
+            None => true,
+            Some(depth) if opens_block => {
+                self.unreachable = Some(depth + 1);
+                false
+            }
+            Some(0) => closes_block || continues_block,
+            Some(depth) if closes_block => {
+                self.unreachable = Some(depth - 1);
+                false
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub(crate) fn bind_label(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        label: &mut CodeLabel,
+    ) -> Result<(), Error> {
+        // Only one label per instruction is allowed
+        if self.last_label == Some(assembler.instructions().len()) {
+            assembler.nop()?;
+        }
+        assembler.set_label(label)?;
+        self.last_label = Some(assembler.instructions().len());
+        Ok(())
+    }
+
+    fn block_type(
+        function_typedefs: &BTreeMap<u32, FuncType>,
+        ty: TypeOrFuncType,
+    ) -> (Vec<Type>, Vec<Type>) {
+        match ty {
+            TypeOrFuncType::Type(Type::EmptyBlockType) => (vec![], vec![]),
+            TypeOrFuncType::Type(ty) => (vec![], vec![ty]),
+            TypeOrFuncType::FuncType(index) => function_typedefs
+                .get(&index)
+                .map(|t| (t.params.to_vec(), t.returns.to_vec()))
+                .unwrap(),
+        }
+    }
+
+    fn enter(
+        &mut self,
+        kind: ControlFrameKind,
+        label: CodeLabel,
+        end_label: CodeLabel,
+        (params, results): (Vec<Type>, Vec<Type>),
+    ) {
+        let height = self.height - slots_size(&params);
+        let operands = self.operands.len() - params.len();
+        self.frames.push(ControlFrame {
+            kind,
+            label,
+            end_label,
+            params,
+            results,
+            height,
+            operands,
+        });
+    }
+
+    pub(crate) fn block(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        function_typedefs: &BTreeMap<u32, FuncType>,
+        ty: TypeOrFuncType,
+    ) {
+        let label = assembler.create_label();
+        let block_type = Self::block_type(function_typedefs, ty);
+        self.enter(ControlFrameKind::Block, label, label, block_type);
+    }
+
+    pub(crate) fn loop_(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        function_typedefs: &BTreeMap<u32, FuncType>,
+        ty: TypeOrFuncType,
+    ) -> Result<(), Error> {
+        let mut label = assembler.create_label();
+        let end_label = assembler.create_label();
+        self.bind_label(assembler, &mut label)?;
+        if let Some(counter) = self.counter {
+            assembler.inc(qword_ptr(counter))?;
+        }
+        let block_type = Self::block_type(function_typedefs, ty);
+        self.enter(ControlFrameKind::Loop, label, end_label, block_type);
+        Ok(())
+    }
+
+    /// Expects the condition to have been popped already
+    pub(crate) fn if_(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        function_typedefs: &BTreeMap<u32, FuncType>,
+        ty: TypeOrFuncType,
+    ) -> Result<(), Error> {
+        let label = assembler.create_label();
+        let else_label = assembler.create_label();
+        assembler.test(eax, eax)?;
+        assembler.jz(else_label)?;
+        let block_type = Self::block_type(function_typedefs, ty);
+        self.enter(
+            ControlFrameKind::If {
+                else_label,
+                has_else: false,
+            },
+            label,
+            label,
+            block_type,
+        );
+        Ok(())
+    }
+
+    pub(crate) fn else_(&mut self, assembler: &mut CodeAssembler) -> Result<(), Error> {
+        let frame = self.frames.last().unwrap();
+        let end_label = frame.end_label;
+        let height = frame.height + slots_size(&frame.params);
+        self.operands.truncate(frame.operands);
+        self.operands.extend_from_slice(&frame.params);
+        if self.is_reachable() {
+            assembler.jmp(end_label)?;
+        }
+        let mut else_label = match &mut self.frames.last_mut().unwrap().kind {
+            ControlFrameKind::If {
+                else_label,
+                has_else,
+            } => {
+                *has_else = true;
+                *else_label
+            }
+            _ => unreachable!(),
+        };
+        self.bind_label(assembler, &mut else_label)?;
+        self.height = height;
+        self.unreachable = None;
+        Ok(())
+    }
+
+    /// Closes the innermost frame. Returns `true` if it was the function's
+    /// own frame.
+    pub(crate) fn end(&mut self, assembler: &mut CodeAssembler) -> Result<bool, Error> {
+        let mut frame = self.frames.pop().unwrap();
+        match &mut frame.kind {
+            ControlFrameKind::If {
+                else_label,
+                has_else: false,
+            } => {
+                // Without `else`, the false branch falls through to the end
+                self.bind_label(assembler, else_label)?;
+            }
+            ControlFrameKind::Try {
+                landing,
+                record,
+                catching: None,
+            } => {
+                // Without `catch` clauses, exceptions go on to the next handler
+                if self.is_reachable() {
+                    self.handlers.unlink(assembler, *record)?;
+                    assembler.jmp(frame.end_label)?;
+                }
+                self.bind_label(assembler, landing)?;
+                assembler.jmp(self.handlers.raise)?;
+            }
+            ControlFrameKind::Try {
+                catching:
+                    Some(Catching {
+                        next: Some(next), ..
+                    }),
+                ..
+            } => {
+                // Exceptions no clause caught go on to the next handler
+                if self.is_reachable() {
+                    assembler.jmp(frame.end_label)?;
+                }
+                self.bind_label(assembler, next)?;
+                assembler.jmp(self.handlers.raise)?;
+            }
+            _ => (),
+        }
+        self.bind_label(assembler, &mut frame.end_label)?;
+        self.height = frame.height + slots_size(&frame.results);
+        self.operands.truncate(frame.operands);
+        self.operands.extend_from_slice(&frame.results);
+        self.unreachable = None;
+        Ok(matches!(frame.kind, ControlFrameKind::Function))
+    }
+
+    /// Branches to the frame `depth` levels up, moving the branch values
+    /// down to where the target frame expects them
+    ///
+    /// If `unless_zero` is set, the branch is only taken when `eax` is not zero.
+    pub(crate) fn branch(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        depth: u32,
+        unless_zero: bool,
+    ) -> Result<(), Error> {
+        let frame = &self.frames[self.frames.len() - 1 - depth as usize];
+        let label = frame.label;
+        let arity = slots_size(frame.branch_types());
+        let target = frame.height + arity;
+        let exited_record = self.outermost_record(depth);
+        if target == self.height && exited_record.is_none() {
+            if unless_zero {
+                assembler.test(eax, eax)?;
+                assembler.jnz(label)?;
+            } else {
+                assembler.jmp(label)?;
+            }
+            return Ok(());
+        }
+        let mut skip = assembler.create_label();
+        if unless_zero {
+            assembler.test(eax, eax)?;
+            assembler.jz(skip)?;
+        }
+        if let Some(record) = exited_record {
+            self.handlers.unlink(assembler, record)?;
+        }
+        for offset in (0..arity).step_by(8) {
+            let from = self.locals_size + self.height - arity + offset + 8;
+            let to = self.locals_size + target - arity + offset + 8;
+            assembler.mov(rax, ptr(rbp - from))?;
+            assembler.mov(ptr(rbp - to), rax)?;
+        }
+        assembler.lea(rsp, ptr(rbp - (self.locals_size + target)))?;
+        assembler.jmp(label)?;
+        if unless_zero {
+            self.bind_label(assembler, &mut skip)?;
+        }
+        Ok(())
+    }
+
+    /// Handler record of the outermost `try` block whose body a branch to
+    /// the frame `depth` levels up leaves
+    fn outermost_record(&self, depth: u32) -> Option<u32> {
+        self.frames[self.frames.len() - 1 - depth as usize..]
+            .iter()
+            .find_map(|frame| match frame.kind {
+                ControlFrameKind::Try {
+                    record,
+                    catching: None,
+                    ..
+                } => Some(record),
+                _ => None,
+            })
+    }
+
+    /// Restores the innermost exception handler the function was called
+    /// with, before it's left other than by returning
+    pub(crate) fn leave_handlers(&self, assembler: &mut CodeAssembler) -> Result<(), Error> {
+        if let Some(record) = self.outermost_record(self.function_depth()) {
+            self.handlers.unlink(assembler, record)?;
+        }
+        Ok(())
+    }
+
+    /// Number of open `try` blocks in their body, or past it if `catching`
+    fn open_tries(&self, catching: bool) -> u32 {
+        self.frames
+            .iter()
+            .filter(|frame| {
+                matches!(&frame.kind, ControlFrameKind::Try { catching: c, .. } if c.is_some() == catching)
+            })
+            .count() as u32
+    }
+
+    /// Opens a `try` block, making its handler the innermost one
+    pub(crate) fn try_(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        function_typedefs: &BTreeMap<u32, FuncType>,
+        ty: TypeOrFuncType,
+    ) -> Result<(), Error> {
+        let label = assembler.create_label();
+        let landing = assembler.create_label();
+        let record = self.exception_area + (self.open_tries(false) + 1) * RECORD_SIZE;
+        self.handlers.link(assembler, record, landing)?;
+        let block_type = Self::block_type(function_typedefs, ty);
+        self.enter(
+            ControlFrameKind::Try {
+                landing,
+                record,
+                catching: None,
+            },
+            label,
+            label,
+            block_type,
+        );
+        Ok(())
+    }
+
+    /// Starts a `catch` clause of the innermost `try` block for exceptions
+    /// with `tag`, or a `catch_all` clause without one
+    ///
+    /// Exceptions land right after the body, where the operand stack is
+    /// reset, and go through the tag checks of the clauses in order. The tag
+    /// being checked is kept in `rax`.
+    pub(crate) fn catch(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        tag: Option<&TagSlot>,
+    ) -> Result<(), Error> {
+        let saved_depth = self.open_tries(true);
+        let frame = self.frames.last_mut().unwrap();
+        let (end_label, height, operands) = (frame.end_label, frame.height, frame.operands);
+        // Either the body just ended, or the previous clause did
+        let body = match &mut frame.kind {
+            ControlFrameKind::Try {
+                landing,
+                record,
+                catching: None,
+            } => Ok((*landing, *record)),
+            ControlFrameKind::Try {
+                catching: Some(catching),
+                ..
+            } => Err(catching.next.take()),
+            _ => unreachable!(),
+        };
+        let (mut check, saved_depth) = match body {
+            Ok((mut landing, record)) => {
+                if self.is_reachable() {
+                    self.handlers.unlink(assembler, record)?;
+                    assembler.jmp(end_label)?;
+                }
+                self.bind_label(assembler, &mut landing)?;
+                assembler.lea(rsp, ptr(rbp - (self.locals_size + height)))?;
+                self.handlers.load_tag(assembler)?;
+                (None, saved_depth)
+            }
+            Err(next) => {
+                if self.is_reachable() {
+                    assembler.jmp(end_label)?;
+                }
+                // This try block is counted as catching already
+                (next, saved_depth - 1)
+            }
+        };
+        if let Some(check) = &mut check {
+            self.bind_label(assembler, check)?;
+        }
+        let next = match tag {
+            Some(tag) => {
+                let next = assembler.create_label();
+                tag.identity(assembler, rcx)?;
+                assembler.cmp(rax, rcx)?;
+                assembler.jne(next)?;
+                Some(next)
+            }
+            None => None,
+        };
+        let payload_size = tag.map_or(EXCEPTION_PAYLOAD_SIZE as u32, TagSlot::payload_size);
+        let saved = self
+            .saved_area
+            .map(|area| area + (saved_depth + 1) * SAVED_SIZE);
+        if let Some(saved) = saved {
+            self.handlers.save(assembler, saved, payload_size)?;
+        }
+        let params = tag.map_or(vec![], |tag| tag.ty.params.to_vec());
+        self.handlers.push_payload(assembler, slots_size(&params))?;
+        if let ControlFrameKind::Try { catching, .. } = &mut self.frames.last_mut().unwrap().kind {
+            *catching = Some(Catching {
+                next,
+                saved,
+                payload_size,
+            });
+        }
+        self.operands.truncate(operands);
+        self.operands.extend_from_slice(&params);
+        self.height = height + slots_size(&params);
+        self.unreachable = None;
+        Ok(())
+    }
+
+    /// Closes the innermost `try` block, handing the exceptions thrown in
+    /// its body to the handler of the frame `depth` levels up from it
+    pub(crate) fn delegate(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        depth: u32,
+    ) -> Result<(), Error> {
+        let (mut landing, record) = match self.frames.last().unwrap().kind {
+            ControlFrameKind::Try {
+                landing,
+                record,
+                catching: None,
+            } => (landing, record),
+            _ => unreachable!(),
+        };
+        let end_label = self.frames.last().unwrap().end_label;
+        if self.is_reachable() {
+            self.handlers.unlink(assembler, record)?;
+            assembler.jmp(end_label)?;
+        }
+        self.bind_label(assembler, &mut landing)?;
+        // Innermost `try` body around the target, which is the target itself
+        // if it's a `try` block, or the function's caller
+        let outer = &self.frames[..self.frames.len() - 1];
+        let target = outer.len().checked_sub(depth as usize);
+        let handler =
+            outer[..target.unwrap_or(0)]
+                .iter()
+                .rev()
+                .find_map(|frame| match frame.kind {
+                    ControlFrameKind::Try {
+                        landing,
+                        record,
+                        catching: None,
+                    } => Some((landing, record)),
+                    _ => None,
+                });
+        match handler {
+            Some((landing, record)) => {
+                self.handlers.unlink(assembler, record)?;
+                assembler.jmp(landing)?;
+            }
+            None => {
+                self.leave_handlers(assembler)?;
+                assembler.jmp(self.handlers.raise)?;
+            }
+        }
+        // The landing pad's code is over, so the block ends like any other
+        self.unreachable = Some(0);
+        self.frames.last_mut().unwrap().kind = ControlFrameKind::Block;
+        self.end(assembler)?;
+        Ok(())
+    }
+
+    /// Throws the exception caught by the `try` block `depth` levels up again
+    pub(crate) fn rethrow(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        depth: u32,
+    ) -> Result<(), Error> {
+        let frame = &self.frames[self.frames.len() - 1 - depth as usize];
+        match &frame.kind {
+            ControlFrameKind::Try {
+                catching:
+                    Some(Catching {
+                        saved: Some(saved),
+                        payload_size,
+                        ..
+                    }),
+                ..
+            } => self.handlers.rethrow(assembler, *saved, *payload_size)?,
+            _ => unreachable!(),
+        }
+        self.set_unreachable();
+        Ok(())
+    }
+
+    /// Depth of the function's own frame, for `return`
+    pub(crate) fn function_depth(&self) -> u32 {
+        self.frames.len() as u32 - 1
+    }
+
+    /// Branches to one of `targets` (or `default` if out of range) by the
+    /// index in `eax`, through a jump table
+    pub(crate) fn branch_table(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        targets: &[u32],
+        default: u32,
+    ) -> Result<(), Error> {
+        let mut table = assembler.create_label();
+        let mut default_stub = assembler.create_label();
+        assembler.cmp(eax, targets.len() as u32)?;
+        assembler.jae(default_stub)?;
+        assembler.lea(rcx, ptr(table))?;
+        assembler.movsxd(rax, dword_ptr(rcx + rax * 4))?;
+        assembler.add(rax, rcx)?;
+        assembler.jmp(rax)?;
+
+        self.bind_label(assembler, &mut table)?;
+        let base = assembler.instructions().len();
+        for _ in targets {
+            assembler.dd(&[0])?;
+        }
+
+        // One stub per distinct depth, each unwinding to its own target
+        let mut stubs: BTreeMap<u32, usize> = BTreeMap::new();
+        self.bind_label(assembler, &mut default_stub)?;
+        stubs.insert(default, assembler.instructions().len());
+        self.branch(assembler, default, false)?;
+        for depth in targets {
+            if !stubs.contains_key(depth) {
+                stubs.insert(*depth, assembler.instructions().len());
+                self.branch(assembler, *depth, false)?;
+            }
+        }
+
+        self.jump_tables.push(JumpTable {
+            base,
+            targets: targets.iter().map(|depth| stubs[depth]).collect(),
+        });
+        Ok(())
+    }
+}
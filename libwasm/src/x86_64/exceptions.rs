@@ -0,0 +1,259 @@
+use crate::trap::Trap;
+use crate::x86_64::control::{slot_size, ControlStack};
+use crate::x86_64::{Context, Error};
+use iced_x86::code_asm::{
+    ecx, ptr, qword_ptr, rax, rbp, rcx, rdi, rdx, rsi, rsp, AsmRegister64, CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::{FuncType, Operator, OperatorsReader};
+
+/// Space for the values carried by an exception, in bytes
+pub const EXCEPTION_PAYLOAD_SIZE: usize = 256;
+
+/// Size of the block of memory keeping the state of exception handling,
+/// which the host provides with
+/// [`AssembledModule::link_exception_state`](super::AssembledModule::link_exception_state)
+///
+/// It holds the address of the innermost handler, followed by the tag of
+/// the exception being thrown and its payload: the values laid out as they
+/// were on the operand stack, the last one first.
+pub const EXCEPTION_STATE_SIZE: usize = 16 + EXCEPTION_PAYLOAD_SIZE;
+
+/// Offsets of the parts of the exception state
+const HANDLER: u32 = 0;
+const TAG: u32 = 8;
+const PAYLOAD: u32 = 16;
+
+/// Handler record of a `try` block, kept in the function's frame while its
+/// body runs: the previous innermost handler, `rbp` of the function and the
+/// address of the landing pad
+pub(crate) const RECORD_SIZE: u32 = 24;
+const RECORD_PREVIOUS: u32 = 0;
+const RECORD_FRAME: u32 = 8;
+const RECORD_LANDING: u32 = 16;
+
+/// Copy of a caught exception (its tag and payload) kept by a `catch`
+/// clause in case it gets rethrown
+pub(crate) const SAVED_SIZE: u32 = TAG + EXCEPTION_PAYLOAD_SIZE as u32;
+
+/// Tag defined or imported by a module
+///
+/// A tag's identity is the address of the slot of the module defining it;
+/// imported tags' slots are filled in with that address.
+pub(crate) struct TagSlot {
+    pub(crate) label: CodeLabel,
+    pub(crate) ty: FuncType,
+    pub(crate) imported: bool,
+}
+
+impl TagSlot {
+    /// Size of the values an exception with this tag carries
+    pub(crate) fn payload_size(&self) -> u32 {
+        self.ty.params.iter().map(slot_size).sum()
+    }
+
+    /// Loads the identity of the tag into `reg`
+    pub(crate) fn identity(
+        &self,
+        assembler: &mut CodeAssembler,
+        reg: AsmRegister64,
+    ) -> Result<(), Error> {
+        if self.imported {
+            assembler.mov(reg, ptr(self.label))?;
+        } else {
+            assembler.lea(reg, ptr(self.label))?;
+        }
+        Ok(())
+    }
+}
+
+/// Labels of the slot keeping the address of the exception state, and of
+/// the routine unwinding to the innermost handler
+#[derive(Clone, Copy)]
+pub(crate) struct Handlers {
+    pub(crate) state: CodeLabel,
+    pub(crate) raise: CodeLabel,
+}
+
+impl Handlers {
+    pub(crate) fn new(assembler: &mut CodeAssembler) -> Self {
+        Self {
+            state: assembler.create_label(),
+            raise: assembler.create_label(),
+        }
+    }
+
+    /// Emits the unwinding routine, followed by the state slot. Returns the
+    /// slot's offset in the module binary.
+    ///
+    /// The routine pops the innermost handler and jumps to its landing pad,
+    /// with the exception left in the state, or traps if there is none.
+    pub(crate) fn emit(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        context: &Context,
+    ) -> Result<usize, Error> {
+        assembler.set_label(&mut self.raise)?;
+        assembler.mov(rax, ptr(self.state))?;
+        assembler.mov(rcx, qword_ptr(rax + HANDLER))?;
+        assembler.test(rcx, rcx)?;
+        assembler.jz(context.traps.label(Trap::UncaughtException))?;
+        assembler.mov(rdx, qword_ptr(rcx + RECORD_PREVIOUS))?;
+        assembler.mov(qword_ptr(rax + HANDLER), rdx)?;
+        assembler.mov(rbp, qword_ptr(rcx + RECORD_FRAME))?;
+        assembler.jmp(qword_ptr(rcx + RECORD_LANDING))?;
+        let offset = assembler.instructions().len();
+        assembler.set_label(&mut self.state)?;
+        assembler.dq(&[0])?;
+        Ok(offset)
+    }
+
+    /// Makes the handler record at `record` below `rbp` the innermost one
+    pub(crate) fn link(
+        &self,
+        assembler: &mut CodeAssembler,
+        record: u32,
+        landing: CodeLabel,
+    ) -> Result<(), Error> {
+        assembler.mov(rdx, ptr(self.state))?;
+        assembler.mov(rcx, qword_ptr(rdx + HANDLER))?;
+        assembler.mov(qword_ptr(rbp - record + RECORD_PREVIOUS), rcx)?;
+        assembler.mov(qword_ptr(rbp - record + RECORD_FRAME), rbp)?;
+        assembler.lea(rcx, ptr(landing))?;
+        assembler.mov(qword_ptr(rbp - record + RECORD_LANDING), rcx)?;
+        assembler.lea(rcx, ptr(rbp - record))?;
+        assembler.mov(qword_ptr(rdx + HANDLER), rcx)?;
+        Ok(())
+    }
+
+    /// Restores the handler that was the innermost one when the record at
+    /// `record` below `rbp` was linked
+    pub(crate) fn unlink(&self, assembler: &mut CodeAssembler, record: u32) -> Result<(), Error> {
+        assembler.mov(rcx, qword_ptr(rbp - record + RECORD_PREVIOUS))?;
+        assembler.mov(rdx, ptr(self.state))?;
+        assembler.mov(qword_ptr(rdx + HANDLER), rcx)?;
+        Ok(())
+    }
+
+    /// Loads the tag of the exception being thrown into `rax`, at a
+    /// landing pad
+    pub(crate) fn load_tag(&self, assembler: &mut CodeAssembler) -> Result<(), Error> {
+        assembler.mov(rdx, ptr(self.state))?;
+        assembler.mov(rax, qword_ptr(rdx + TAG))?;
+        Ok(())
+    }
+
+    /// Copies the exception being thrown to `saved` below `rbp`
+    pub(crate) fn save(
+        &self,
+        assembler: &mut CodeAssembler,
+        saved: u32,
+        payload_size: u32,
+    ) -> Result<(), Error> {
+        assembler.mov(rsi, ptr(self.state))?;
+        assembler.add(rsi, TAG as i32)?;
+        assembler.lea(rdi, ptr(rbp - saved))?;
+        copy(assembler, TAG + payload_size)
+    }
+
+    /// Pushes the payload of the exception being thrown onto the operand
+    /// stack
+    pub(crate) fn push_payload(
+        &self,
+        assembler: &mut CodeAssembler,
+        payload_size: u32,
+    ) -> Result<(), Error> {
+        if payload_size > 0 {
+            assembler.sub(rsp, payload_size as i32)?;
+            assembler.mov(rsi, ptr(self.state))?;
+            assembler.add(rsi, PAYLOAD as i32)?;
+            assembler.mov(rdi, rsp)?;
+            copy(assembler, payload_size)?;
+        }
+        Ok(())
+    }
+
+    /// Throws the exception saved at `saved` below `rbp` again
+    pub(crate) fn rethrow(
+        &self,
+        assembler: &mut CodeAssembler,
+        saved: u32,
+        payload_size: u32,
+    ) -> Result<(), Error> {
+        assembler.lea(rsi, ptr(rbp - saved))?;
+        assembler.mov(rdi, ptr(self.state))?;
+        assembler.add(rdi, TAG as i32)?;
+        copy(assembler, TAG + payload_size)?;
+        assembler.jmp(self.raise)?;
+        Ok(())
+    }
+}
+
+/// Copies `size` bytes from `rsi` to `rdi`
+fn copy(assembler: &mut CodeAssembler, size: u32) -> Result<(), Error> {
+    assembler.mov(ecx, size / 8)?;
+    assembler.rep().movsq()?;
+    Ok(())
+}
+
+/// `throw`: moves the tag's values from the operand stack into the
+/// exception state and unwinds to the innermost handler
+pub(crate) fn throw(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    tag: u32,
+) -> Result<(), Error> {
+    let handlers = &context.handlers;
+    let tag = &context.tags[&tag];
+    let payload_size = tag.payload_size();
+    assembler.mov(rdx, ptr(handlers.state))?;
+    tag.identity(assembler, rax)?;
+    assembler.mov(qword_ptr(rdx + TAG), rax)?;
+    if payload_size > 0 {
+        assembler.mov(rsi, rsp)?;
+        assembler.lea(rdi, ptr(rdx + PAYLOAD))?;
+        copy(assembler, payload_size)?;
+    }
+    for param in tag.ty.params.iter().rev() {
+        control.pop(*param);
+    }
+    assembler.jmp(handlers.raise)?;
+    control.set_unreachable();
+    Ok(())
+}
+
+/// Space a function's frame needs for exception handling: handler records
+/// of nested `try` blocks, and, if the function rethrows, copies of
+/// exceptions caught by nested `catch` clauses
+///
+/// Records and copies are allocated by nesting depth, as
+/// [`ControlStack`] does.
+pub(crate) fn frame_area(operators: OperatorsReader) -> Result<(u32, u32), Error> {
+    // Whether each open block is a `try` block, and if so, whether it is
+    // past its body
+    let mut blocks: alloc::vec::Vec<Option<bool>> = alloc::vec![];
+    let (mut records, mut saved, mut rethrows) = (0, 0, false);
+    for op in operators {
+        match op? {
+            Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => {
+                blocks.push(None)
+            }
+            Operator::Try { .. } => blocks.push(Some(false)),
+            Operator::Catch { .. } | Operator::CatchAll => {
+                if let Some(block) = blocks.last_mut() {
+                    *block = Some(true);
+                }
+            }
+            Operator::End | Operator::Delegate { .. } => {
+                blocks.pop();
+            }
+            Operator::Rethrow { .. } => rethrows = true,
+            _ => continue,
+        }
+        let catching = blocks.iter().filter(|b| **b == Some(true)).count() as u32;
+        let trying = blocks.iter().filter(|b| **b == Some(false)).count() as u32;
+        records = records.max(trying);
+        saved = saved.max(catching);
+    }
+    Ok((records, if rethrows { saved } else { 0 }))
+}
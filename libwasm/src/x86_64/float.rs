@@ -0,0 +1,531 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::{Context, Error};
+use iced_x86::code_asm::{
+    al, cl, eax, ecx, rax, rcx, rdx, xmm0, xmm1, xmm2, AsmRegister64, AsmRegisterXmm, CodeAssembler,
+};
+use iced_x86::IcedError;
+use wasmparser_nostd::Type;
+
+/// Canonical NaNs, produced by `min` and `max` when either operand is a NaN
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// Moves `rax` into `xmm`
+///
+/// Floats are kept on the operand stack as their bits, an `f32` in the lower
+/// half of its slot with the upper half zeroed.
+fn load(assembler: &mut CodeAssembler, ty: Type, xmm: AsmRegisterXmm) -> Result<(), IcedError> {
+    if ty == Type::F32 {
+        assembler.movd(xmm, eax)
+    } else {
+        assembler.movq(xmm, rax)
+    }
+}
+
+/// Moves `xmm0` into `rax`, zeroing the upper half for an `f32`
+fn store(assembler: &mut CodeAssembler, ty: Type) -> Result<(), IcedError> {
+    if ty == Type::F32 {
+        assembler.movd(eax, xmm0)
+    } else {
+        assembler.movq(rax, xmm0)
+    }
+}
+
+/// Pops the operands of a binary operator into `xmm0` (left) and `xmm1`
+/// (right), applies `op` and pushes `xmm0` as the result
+pub(crate) fn binary(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    load(assembler, ty, xmm0)?;
+    assembler.mov(rax, rcx)?;
+    load(assembler, ty, xmm1)?;
+    op(assembler)?;
+    store(assembler, ty)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    Ok(())
+}
+
+/// Same as [`binary`], for unary operators with the operand in `xmm0`
+pub(crate) fn unary(
+    assembler: &mut CodeAssembler,
+    ty: Type,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    load(assembler, ty, xmm0)?;
+    op(assembler)?;
+    store(assembler, ty)?;
+    assembler.push(rax)?;
+    Ok(())
+}
+
+/// `abs`, clearing the sign bit
+pub(crate) fn abs(assembler: &mut CodeAssembler, ty: Type) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    if ty == Type::F32 {
+        assembler.btr(eax, 31)?;
+    } else {
+        assembler.btr(rax, 63)?;
+    }
+    assembler.push(rax)?;
+    Ok(())
+}
+
+/// `neg`, flipping the sign bit
+pub(crate) fn neg(assembler: &mut CodeAssembler, ty: Type) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    if ty == Type::F32 {
+        assembler.btc(eax, 31)?;
+    } else {
+        assembler.btc(rax, 63)?;
+    }
+    assembler.push(rax)?;
+    Ok(())
+}
+
+/// `copysign`, combining the magnitude of the left operand with the sign of
+/// the right one
+pub(crate) fn copysign(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    if ty == Type::F32 {
+        assembler.btr(eax, 31)?;
+        assembler.and(ecx, 0x8000_0000u32 as i32)?;
+        assembler.or(eax, ecx)?;
+    } else {
+        assembler.btr(rax, 63)?;
+        assembler.shr(rcx, 63)?;
+        assembler.shl(rcx, 63)?;
+        assembler.or(rax, rcx)?;
+    }
+    assembler.push(rax)?;
+    control.pop(ty);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+/// Compares the operands of a binary operator, pushing the result as an `i32`
+///
+/// Comparisons involving a NaN are false, except for `ne`.
+pub(crate) fn compare(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    comparison: Comparison,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    load(assembler, ty, xmm0)?;
+    assembler.mov(rax, rcx)?;
+    load(assembler, ty, xmm1)?;
+    // `ucomis` sets ZF, PF and CF on unordered operands, so `lt` and `le` are
+    // checked as `gt` and `ge` with the operands swapped
+    let (left, right) = match comparison {
+        Comparison::Lt | Comparison::Le => (xmm1, xmm0),
+        _ => (xmm0, xmm1),
+    };
+    if ty == Type::F32 {
+        assembler.ucomiss(left, right)?;
+    } else {
+        assembler.ucomisd(left, right)?;
+    }
+    match comparison {
+        Comparison::Eq => {
+            assembler.sete(al)?;
+            assembler.setnp(cl)?;
+            assembler.and(al, cl)?;
+        }
+        Comparison::Ne => {
+            assembler.setne(al)?;
+            assembler.setp(cl)?;
+            assembler.or(al, cl)?;
+        }
+        Comparison::Lt | Comparison::Gt => assembler.seta(al)?,
+        Comparison::Le | Comparison::Ge => assembler.setae(al)?,
+    }
+    assembler.movzx(eax, al)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    control.pop(ty);
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// `min` (or `max`, if `max` is set)
+///
+/// `minss` and friends return the right operand if either is a NaN, and don't
+/// order zeroes of different signs, so both cases are handled beforehand.
+pub(crate) fn min_max(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    max: bool,
+) -> Result<(), Error> {
+    let mut nan = assembler.create_label();
+    let mut ordered = assembler.create_label();
+    let mut done = assembler.create_label();
+    binary(assembler, control, ty, |assembler| {
+        let f32 = ty == Type::F32;
+        if f32 {
+            assembler.ucomiss(xmm0, xmm1)?;
+        } else {
+            assembler.ucomisd(xmm0, xmm1)?;
+        }
+        assembler.jp(nan)?;
+        assembler.jne(ordered)?;
+        // Equal operands only differ in the sign of a zero
+        if max {
+            assembler.andps(xmm0, xmm1)?;
+        } else {
+            assembler.orps(xmm0, xmm1)?;
+        }
+        assembler.jmp(done)?;
+        assembler.set_label(&mut nan)?;
+        if f32 {
+            assembler.mov(eax, CANONICAL_NAN_F32)?;
+            assembler.movd(xmm0, eax)?;
+        } else {
+            assembler.mov(rax, CANONICAL_NAN_F64)?;
+            assembler.movq(xmm0, rax)?;
+        }
+        assembler.jmp(done)?;
+        assembler.set_label(&mut ordered)?;
+        match (f32, max) {
+            (true, false) => assembler.minss(xmm0, xmm1)?,
+            (true, true) => assembler.maxss(xmm0, xmm1)?,
+            (false, false) => assembler.minsd(xmm0, xmm1)?,
+            (false, true) => assembler.maxsd(xmm0, xmm1)?,
+        }
+        assembler.set_label(&mut done)?;
+        Ok(())
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rounding {
+    Ceil,
+    Floor,
+    Trunc,
+    Nearest,
+}
+
+/// `ceil`, `floor`, `trunc` and `nearest`
+///
+/// SSE2 has no rounding instructions. Values of a magnitude of at least 2^52
+/// (2^23 for `f32`) are already integral; smaller ones are rounded through
+/// an integer conversion, or by adding and subtracting 2^52 for `nearest`,
+/// relying on the default round-to-nearest-even mode. The sign of the
+/// operand is kept, so that rounding to zero gives a zero of the same sign.
+pub(crate) fn round(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    rounding: Rounding,
+) -> Result<(), Error> {
+    let f32 = ty == Type::F32;
+    let (integral, one): (u64, u64) = if f32 {
+        (0x4b00_0000, 1f32.to_bits() as u64)
+    } else {
+        (0x4330_0000_0000_0000, 1f64.to_bits())
+    };
+    let mut small = assembler.create_label();
+    let mut done = assembler.create_label();
+    assembler.pop(rax)?;
+    load(assembler, ty, xmm0)?;
+    // Compares the magnitude as an integer, which orders infinities and NaNs
+    // above all finite values
+    assembler.mov(rcx, rax)?;
+    if f32 {
+        assembler.btr(ecx, 31)?;
+    } else {
+        assembler.btr(rcx, 63)?;
+    }
+    assembler.mov(rdx, integral)?;
+    assembler.cmp(rcx, rdx)?;
+    assembler.jb(small)?;
+    // Adding a NaN to itself quiets it
+    if f32 {
+        assembler.ucomiss(xmm0, xmm0)?;
+    } else {
+        assembler.ucomisd(xmm0, xmm0)?;
+    }
+    assembler.jnp(done)?;
+    add(assembler, f32, xmm0, xmm0)?;
+    store(assembler, ty)?;
+    assembler.jmp(done)?;
+    control.bind_label(assembler, &mut small)?;
+    if rounding == Rounding::Nearest {
+        assembler.movq(xmm0, rcx)?;
+        assembler.movq(xmm1, rdx)?;
+        add(assembler, f32, xmm0, xmm1)?;
+        if f32 {
+            assembler.subss(xmm0, xmm1)?;
+        } else {
+            assembler.subsd(xmm0, xmm1)?;
+        }
+    } else {
+        let mut adjusted = assembler.create_label();
+        if f32 {
+            assembler.cvttss2si(rcx, xmm0)?;
+            assembler.cvtsi2ss(xmm1, rcx)?;
+        } else {
+            assembler.cvttsd2si(rcx, xmm0)?;
+            assembler.cvtsi2sd(xmm1, rcx)?;
+        }
+        // Truncation rounds towards zero, which is one off for `floor` of a
+        // negative and for `ceil` of a positive fraction
+        let (above, below) = match rounding {
+            Rounding::Floor => (xmm1, xmm0),
+            _ => (xmm0, xmm1),
+        };
+        if rounding != Rounding::Trunc {
+            if f32 {
+                assembler.ucomiss(above, below)?;
+            } else {
+                assembler.ucomisd(above, below)?;
+            }
+            assembler.jbe(adjusted)?;
+            assembler.mov(rcx, one)?;
+            assembler.movq(xmm2, rcx)?;
+            match (f32, rounding) {
+                (true, Rounding::Floor) => assembler.subss(xmm1, xmm2)?,
+                (true, _) => assembler.addss(xmm1, xmm2)?,
+                (false, Rounding::Floor) => assembler.subsd(xmm1, xmm2)?,
+                (false, _) => assembler.addsd(xmm1, xmm2)?,
+            }
+        }
+        control.bind_label(assembler, &mut adjusted)?;
+        assembler.movaps(xmm0, xmm1)?;
+    }
+    if f32 {
+        assembler.movd(ecx, xmm0)?;
+        assembler.and(eax, 0x8000_0000u32 as i32)?;
+    } else {
+        assembler.movq(rcx, xmm0)?;
+        assembler.shr(rax, 63)?;
+        assembler.shl(rax, 63)?;
+    }
+    assembler.or(rax, rcx)?;
+    control.bind_label(assembler, &mut done)?;
+    assembler.push(rax)?;
+    Ok(())
+}
+
+fn add(
+    assembler: &mut CodeAssembler,
+    f32: bool,
+    left: AsmRegisterXmm,
+    right: AsmRegisterXmm,
+) -> Result<(), IcedError> {
+    if f32 {
+        assembler.addss(left, right)
+    } else {
+        assembler.addsd(left, right)
+    }
+}
+
+/// `sqrt`
+pub(crate) fn sqrt(assembler: &mut CodeAssembler, ty: Type) -> Result<(), Error> {
+    unary(assembler, ty, |assembler| {
+        if ty == Type::F32 {
+            assembler.sqrtss(xmm0, xmm0)
+        } else {
+            assembler.sqrtsd(xmm0, xmm0)
+        }
+    })
+}
+
+/// `convert` of the integer operand of type `from` to `to`
+pub(crate) fn convert(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    from: Type,
+    to: Type,
+    signed: bool,
+) -> Result<(), Error> {
+    let f32 = to == Type::F32;
+    let convert = |assembler: &mut CodeAssembler, source: AsmRegister64| {
+        if f32 {
+            assembler.cvtsi2ss(xmm0, source)
+        } else {
+            assembler.cvtsi2sd(xmm0, source)
+        }
+    };
+    assembler.pop(rax)?;
+    match (from, signed) {
+        (Type::I32, true) => {
+            assembler.movsxd(rax, eax)?;
+            convert(assembler, rax)?;
+        }
+        (Type::I32, false) => {
+            // Fits a signed 64-bit conversion
+            assembler.mov(eax, eax)?;
+            convert(assembler, rax)?;
+        }
+        (_, true) => convert(assembler, rax)?,
+        (_, false) => {
+            // Values with the top bit set are halved for a signed conversion
+            // and doubled afterwards, keeping the lowest bit so that the
+            // result rounds the same way
+            let mut large = assembler.create_label();
+            let mut done = assembler.create_label();
+            assembler.test(rax, rax)?;
+            assembler.js(large)?;
+            convert(assembler, rax)?;
+            assembler.jmp(done)?;
+            control.bind_label(assembler, &mut large)?;
+            assembler.mov(rcx, rax)?;
+            assembler.shr(rcx, 1)?;
+            assembler.and(eax, 1)?;
+            assembler.or(rcx, rax)?;
+            convert(assembler, rcx)?;
+            add(assembler, f32, xmm0, xmm0)?;
+            control.bind_label(assembler, &mut done)?;
+        }
+    }
+    store(assembler, to)?;
+    assembler.push(rax)?;
+    control.pop(from);
+    control.push(to);
+    Ok(())
+}
+
+/// `demote` and `promote`
+pub(crate) fn reformat(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    from: Type,
+    to: Type,
+) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    load(assembler, from, xmm0)?;
+    if to == Type::F32 {
+        assembler.cvtsd2ss(xmm0, xmm0)?;
+    } else {
+        assembler.cvtss2sd(xmm0, xmm0)?;
+    }
+    store(assembler, to)?;
+    assembler.push(rax)?;
+    control.pop(from);
+    control.push(to);
+    Ok(())
+}
+
+/// `trunc` of the float operand of type `from` to an integer of type `to`
+///
+/// A NaN or a value out of range of `to` traps, unless `saturate` is set, in
+/// which case a NaN gives 0 and other values are clamped to the range.
+pub(crate) fn truncate(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    from: Type,
+    to: Type,
+    signed: bool,
+    saturate: bool,
+) -> Result<(), Error> {
+    // Exclusive bounds of the integer range, as f64, which represents them
+    // exactly; the lower bound of i64 is itself in range
+    let (lower, lower_inclusive, upper, min, max): (f64, bool, f64, u64, u64) = match (to, signed) {
+        (Type::I32, true) => (-2147483649.0, false, 2147483648.0, 0x8000_0000, 0x7fff_ffff),
+        (Type::I32, false) => (-1.0, false, 4294967296.0, 0, 0xffff_ffff),
+        (_, true) => (
+            -9223372036854775808.0,
+            true,
+            9223372036854775808.0,
+            i64::MIN as u64,
+            i64::MAX as u64,
+        ),
+        (_, false) => (-1.0, false, 18446744073709551616.0, 0, u64::MAX),
+    };
+    let mut done = assembler.create_label();
+    let (mut nan, mut underflow, mut overflow) = if saturate {
+        (
+            assembler.create_label(),
+            assembler.create_label(),
+            assembler.create_label(),
+        )
+    } else {
+        (
+            context.traps.label(Trap::InvalidConversionToInteger),
+            context.traps.label(Trap::IntegerOverflow),
+            context.traps.label(Trap::IntegerOverflow),
+        )
+    };
+
+    assembler.pop(rax)?;
+    load(assembler, from, xmm0)?;
+    // An f32 converts to f64 exactly
+    if from == Type::F32 {
+        assembler.cvtss2sd(xmm0, xmm0)?;
+    }
+    assembler.ucomisd(xmm0, xmm0)?;
+    assembler.jp(nan)?;
+    assembler.mov(rcx, lower.to_bits())?;
+    assembler.movq(xmm1, rcx)?;
+    assembler.ucomisd(xmm0, xmm1)?;
+    if lower_inclusive {
+        assembler.jb(underflow)?;
+    } else {
+        assembler.jbe(underflow)?;
+    }
+    assembler.mov(rcx, upper.to_bits())?;
+    assembler.movq(xmm1, rcx)?;
+    assembler.ucomisd(xmm0, xmm1)?;
+    assembler.jae(overflow)?;
+    if to == Type::I64 && !signed {
+        // Values from 2^63 are converted with 2^63 subtracted
+        let mut small = assembler.create_label();
+        assembler.mov(rcx, 9223372036854775808f64.to_bits())?;
+        assembler.movq(xmm1, rcx)?;
+        assembler.ucomisd(xmm0, xmm1)?;
+        assembler.jb(small)?;
+        assembler.subsd(xmm0, xmm1)?;
+        assembler.cvttsd2si(rax, xmm0)?;
+        assembler.btc(rax, 63)?;
+        assembler.jmp(done)?;
+        control.bind_label(assembler, &mut small)?;
+        assembler.cvttsd2si(rax, xmm0)?;
+    } else {
+        assembler.cvttsd2si(rax, xmm0)?;
+        if to == Type::I32 {
+            assembler.mov(eax, eax)?;
+        }
+    }
+    if saturate {
+        assembler.jmp(done)?;
+        control.bind_label(assembler, &mut nan)?;
+        assembler.xor(eax, eax)?;
+        assembler.jmp(done)?;
+        control.bind_label(assembler, &mut underflow)?;
+        assembler.mov(rax, min)?;
+        assembler.jmp(done)?;
+        control.bind_label(assembler, &mut overflow)?;
+        assembler.mov(rax, max)?;
+    }
+    control.bind_label(assembler, &mut done)?;
+    assembler.push(rax)?;
+    control.pop(from);
+    control.push(to);
+    Ok(())
+}
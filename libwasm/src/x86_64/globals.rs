@@ -0,0 +1,160 @@
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::{Context, Error};
+use iced_x86::code_asm::{
+    dword_ptr, eax, ptr, qword_ptr, rax, rcx, rsp, xmm0, xmmword_ptr, CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::{GlobalType, Type};
+
+/// Global variable of a module, either defined or imported
+///
+/// Values of defined globals are stored in the module binary, taking up
+/// 64 bits each, or 128 bits for a `v128`; 32-bit values occupy the lower
+/// half. Imported globals are
+/// referred to through a slot holding the address of the value, which the
+/// host fills in using
+/// [`AssembledModule::link_import`](super::AssembledModule::link_import).
+pub struct Global {
+    ty: GlobalType,
+    offset: usize,
+    imported: bool,
+}
+
+impl Global {
+    pub(crate) fn new(ty: GlobalType, offset: usize, imported: bool) -> Self {
+        Self {
+            ty,
+            offset,
+            imported,
+        }
+    }
+
+    pub fn content_type(&self) -> Type {
+        self.ty.content_type
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.ty.mutable
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+
+    pub(crate) fn relocate(&mut self, offsets: &[u32]) {
+        self.offset = offsets[self.offset] as usize;
+    }
+
+    /// Offset of the value (or the address slot, for imported globals) in
+    /// the module binary
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+pub(crate) struct GlobalSlot {
+    pub(crate) label: CodeLabel,
+    pub(crate) ty: Type,
+    pub(crate) imported: bool,
+}
+
+impl GlobalSlot {
+    /// Emits storage for a defined global
+    pub(crate) fn emit(
+        assembler: &mut CodeAssembler,
+        ty: Type,
+        value: u128,
+    ) -> Result<Self, Error> {
+        let mut label = assembler.create_label();
+        assembler.set_label(&mut label)?;
+        match ty {
+            Type::V128 => assembler.dq(&[value as u64, (value >> 64) as u64])?,
+            _ => assembler.dq(&[value as u64])?,
+        }
+        Ok(Self {
+            label,
+            ty,
+            imported: false,
+        })
+    }
+}
+
+/// Loads the global's value into `rax`, or `xmm0` for a `v128`
+///
+/// Clobbers `rcx`.
+pub(crate) fn load(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    index: u32,
+) -> Result<Type, Error> {
+    let slot = &context.globals[&index];
+    let value = if slot.imported {
+        assembler.mov(rcx, ptr(slot.label))?;
+        ptr(rcx)
+    } else {
+        ptr(slot.label)
+    };
+    match slot.ty {
+        Type::I32 | Type::F32 => assembler.mov(eax, dword_ptr(value))?,
+        Type::V128 => assembler.movdqu(xmm0, xmmword_ptr(value))?,
+        _ => assembler.mov(rax, qword_ptr(value))?,
+    }
+    Ok(slot.ty)
+}
+
+/// Stores `rax`, or `xmm0` for a `v128`, into the global
+///
+/// Clobbers `rcx`.
+pub(crate) fn store(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    index: u32,
+) -> Result<Type, Error> {
+    let slot = &context.globals[&index];
+    let value = if slot.imported {
+        assembler.mov(rcx, ptr(slot.label))?;
+        ptr(rcx)
+    } else {
+        ptr(slot.label)
+    };
+    match slot.ty {
+        Type::I32 | Type::F32 => assembler.mov(dword_ptr(value), eax)?,
+        Type::V128 => assembler.movdqu(xmmword_ptr(value), xmm0)?,
+        _ => assembler.mov(qword_ptr(value), rax)?,
+    }
+    Ok(slot.ty)
+}
+
+pub(crate) fn get(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    index: u32,
+) -> Result<(), Error> {
+    let ty = load(assembler, context, index)?;
+    if ty == Type::V128 {
+        assembler.sub(rsp, 16)?;
+        assembler.movdqu(xmmword_ptr(rsp), xmm0)?;
+    } else {
+        assembler.push(rax)?;
+    }
+    control.push(ty);
+    Ok(())
+}
+
+pub(crate) fn set(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    index: u32,
+) -> Result<(), Error> {
+    let ty = context.globals[&index].ty;
+    if ty == Type::V128 {
+        assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+        assembler.add(rsp, 16)?;
+    } else {
+        assembler.pop(rax)?;
+    }
+    store(assembler, context, index)?;
+    control.pop(ty);
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use crate::trap::Trap;
+use crate::x86_64::globals;
+use crate::x86_64::memory;
+use crate::x86_64::{Context, Error};
+use alloc::vec::Vec;
+use iced_x86::code_asm::{eax, ptr, r10, rax, rbp, rcx, rdi, rdx, rsi, rsp, CodeAssembler};
+use wasmparser_nostd::{ElementItem, InitExpr, Operator};
+
+/// Segment to be copied on instantiation
+struct ActiveSegment<'a> {
+    segment: u32,
+    /// Index of the target memory or table
+    index: u32,
+    offset: InitExpr<'a>,
+    len: usize,
+}
+
+/// Everything the module needs done once it's linked, before any of its
+/// functions can be called
+///
+/// Compiled into a routine following the System V calling convention, taking
+/// no arguments: it points the slots of functions compiled in tiers at their
+/// code, initializes globals whose values aren't known at compile time,
+/// resolves element segments' references, copies active
+/// segments into tables and memories, and calls the start function.
+#[derive(Default)]
+pub(crate) struct Instantiation<'a> {
+    globals: Vec<(u32, InitExpr<'a>)>,
+    elements: Vec<(u32, Vec<ElementItem<'a>>)>,
+    active_elements: Vec<ActiveSegment<'a>>,
+    active_data: Vec<ActiveSegment<'a>>,
+    start: Option<u32>,
+}
+
+impl<'a> Instantiation<'a> {
+    pub(crate) fn global(&mut self, index: u32, init_expr: InitExpr<'a>) {
+        self.globals.push((index, init_expr));
+    }
+
+    pub(crate) fn elements(&mut self, segment: u32, items: Vec<ElementItem<'a>>) {
+        self.elements.push((segment, items));
+    }
+
+    pub(crate) fn active_elements(
+        &mut self,
+        segment: u32,
+        table: u32,
+        offset: InitExpr<'a>,
+        len: usize,
+    ) {
+        self.active_elements.push(ActiveSegment {
+            segment,
+            index: table,
+            offset,
+            len,
+        });
+    }
+
+    pub(crate) fn active_data(
+        &mut self,
+        segment: u32,
+        memory: u32,
+        offset: InitExpr<'a>,
+        len: usize,
+    ) {
+        self.active_data.push(ActiveSegment {
+            segment,
+            index: memory,
+            offset,
+            len,
+        });
+    }
+
+    pub(crate) fn start(&mut self, function_index: u32) {
+        self.start = Some(function_index);
+    }
+
+    pub(crate) fn emit(
+        self,
+        assembler: &mut CodeAssembler,
+        context: &Context,
+    ) -> Result<(), Error> {
+        assembler.push(rbp)?;
+        assembler.mov(rbp, rsp)?;
+
+        for (function_index, slot) in context.function_slots.iter() {
+            assembler.lea(rax, ptr(context.got[function_index]))?;
+            assembler.mov(ptr(*slot), rax)?;
+        }
+
+        for (index, expr) in self.globals.iter() {
+            evaluate(assembler, context, expr)?;
+            globals::store(assembler, context, *index)?;
+        }
+
+        for (segment, items) in self.elements.iter() {
+            let slots = &context.element_segments[segment];
+            for (i, item) in items.iter().enumerate() {
+                match item {
+                    ElementItem::Func(function_index) => {
+                        function_reference(assembler, context, *function_index)?
+                    }
+                    ElementItem::Expr(expr) => evaluate(assembler, context, expr)?,
+                }
+                assembler.lea(rdi, ptr(slots.items))?;
+                assembler.mov(ptr(rdi + i * 8), rax)?;
+            }
+        }
+
+        // Element segments go first, as prescribed by the spec
+        for active in self.active_elements.iter() {
+            let slots = &context.element_segments[&active.segment];
+            evaluate(assembler, context, &active.offset)?;
+            let [size, _, entries] = context.tables[&active.index].descriptor(assembler, rdx)?;
+            assembler.mov(eax, eax)?;
+            assembler.lea(rcx, ptr(rax + active.len))?;
+            assembler.cmp(rcx, size)?;
+            assembler.ja(context.traps.label(Trap::OutOfBoundsTableAccess))?;
+            assembler.lea(rdi, entries)?;
+            assembler.lea(rdi, ptr(rdi + rax * 8))?;
+            assembler.lea(rsi, ptr(slots.items))?;
+            assembler.mov(rcx, active.len as u64)?;
+            assembler.rep().movsq()?;
+        }
+
+        for active in self.active_data.iter() {
+            let slots = &context.data_segments[&active.segment];
+            let memory = &context.memories[&active.index];
+            evaluate(assembler, context, &active.offset)?;
+            if !memory.memory64 {
+                assembler.mov(eax, eax)?;
+            }
+            assembler.mov(rdi, rax)?;
+            assembler.mov(rcx, active.len as u64)?;
+            memory::range_end(assembler, context, active.index, rdi)?;
+            memory::add_base(assembler, context, active.index, rdi)?;
+            assembler.lea(rsi, ptr(slots.items))?;
+            assembler.rep().movsb()?;
+        }
+
+        if let Some(function_index) = self.start {
+            if let Some(label) = context.ils.get(&function_index) {
+                assembler.mov(r10, ptr(*label))?;
+                assembler.call(r10)?;
+            } else {
+                assembler.call(context.got[&function_index])?;
+            }
+        }
+
+        assembler.mov(rsp, rbp)?;
+        assembler.pop(rbp)?;
+        assembler.ret()?;
+        Ok(())
+    }
+}
+
+/// Loads a reference to a function into `rax`
+pub(crate) fn function_reference(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    function_index: u32,
+) -> Result<(), Error> {
+    if let Some(label) = context.import_stubs.get(&function_index) {
+        assembler.lea(rax, ptr(*label))?;
+    } else {
+        assembler.lea(rax, ptr(context.got[&function_index]))?;
+    }
+    Ok(())
+}
+
+/// Evaluates a constant expression into `rax`
+pub(crate) fn evaluate(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    expr: &InitExpr,
+) -> Result<(), Error> {
+    for op in expr.get_operators_reader() {
+        match op? {
+            Operator::I32Const { value } => assembler.mov(eax, value as u32)?,
+            Operator::I64Const { value } => assembler.mov(rax, value as u64)?,
+            Operator::RefNull { .. } => assembler.xor(eax, eax)?,
+            Operator::RefFunc { function_index } => {
+                function_reference(assembler, context, function_index)?
+            }
+            Operator::GlobalGet { global_index } => {
+                globals::load(assembler, context, global_index)?;
+            }
+            Operator::End => (),
+            _ => unreachable!("not a constant expression"),
+        }
+    }
+    Ok(())
+}
+
+/// Value of a constant expression, if it can be computed at compile time
+pub(crate) fn constant(expr: &InitExpr) -> Result<Option<u128>, Error> {
+    let mut value = None;
+    for op in expr.get_operators_reader() {
+        value = match op? {
+            Operator::I32Const { value } => Some(value as u32 as u128),
+            Operator::I64Const { value } => Some(value as u64 as u128),
+            Operator::F32Const { value } => Some(value.bits() as u128),
+            Operator::F64Const { value } => Some(value.bits() as u128),
+            Operator::V128Const { value } => Some(u128::from_le_bytes(*value.bytes())),
+            Operator::End => value,
+            _ => return Ok(None),
+        }
+    }
+    Ok(value)
+}
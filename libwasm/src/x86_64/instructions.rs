@@ -1,627 +1,1775 @@
-use crate::x86_64::Error;
-use alloc::collections::{BTreeMap, VecDeque};
-use alloc::vec;
+use crate::trap::Trap;
+use crate::x86_64::abi;
+use crate::x86_64::atomics::{self, Rmw};
+use crate::x86_64::control::{self, ControlStack};
+use crate::x86_64::exceptions;
+use crate::x86_64::float::{self, Comparison, Rounding};
+use crate::x86_64::globals;
+use crate::x86_64::init;
+use crate::x86_64::integer::{self, Division};
+use crate::x86_64::memory;
+use crate::x86_64::registers::{self, ValueStack};
+use crate::x86_64::segments;
+use crate::x86_64::simd::{self, Lane, Load, Shift};
+use crate::x86_64::table;
+use crate::x86_64::{Context, Error};
 use alloc::vec::Vec;
 use iced_x86::code_asm::{
-    eax, ebx, ptr, r10, r11, r8, r9, rax, rbp, rbx, rcx, rdi, rdx, rsi, AsmRegister64,
-    CodeAssembler, CodeLabel,
+    al, ax, byte_ptr, cl, dl, dword_ptr, dx, eax, ecx, edx, ptr, qword_ptr, r10, rax, rbp, rcx,
+    rdx, rsp, word_ptr, xmm0, xmm1, xmmword_ptr, CodeAssembler,
 };
-use wasmparser_nostd::{FuncType, Operator, Type};
+use wasmparser_nostd::{Operator, Type};
 
 pub(crate) fn handle_instruction(
     assembler: &mut CodeAssembler,
-    got: &mut BTreeMap<u32, CodeLabel>,
-    ils: &mut BTreeMap<u32, CodeLabel>,
-    function_typedefs: &mut BTreeMap<u32, FuncType>,
-    function_types: &mut BTreeMap<u32, u32>,
-    locals: &Vec<u32>,
+    context: &Context,
+    locals: &Vec<(u32, Type)>,
+    control: &mut ControlStack,
+    values: &mut ValueStack,
     op: Operator,
 ) -> Result<(), Error> {
+    let opens_block = matches!(
+        op,
+        Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } | Operator::Try { .. }
+    );
+    let continues_block = matches!(
+        op,
+        Operator::Else | Operator::Catch { .. } | Operator::CatchAll
+    );
+    let closes_block = matches!(op, Operator::End | Operator::Delegate { .. });
+    if !control.skip_unreachable(opens_block, continues_block, closes_block) {
+        return Ok(());
+    }
+    if registers::handle(assembler, context, locals, control, values, &op)? {
+        return Ok(());
+    }
+    // Conditions of branches can stay in registers
+    let condition = matches!(
+        op,
+        Operator::If { .. } | Operator::BrIf { .. } | Operator::BrTable { .. }
+    );
+    values.flush_below(assembler, condition as usize)?;
     match op {
         Operator::I64Const { value } => {
             assembler.mov(rax, value)?;
             assembler.push(rax)?;
+            control.push(Type::I64);
         }
-        Operator::I64Add => {
-            assembler.pop(rax)?;
-            assembler.pop(rbx)?;
-            assembler.add(rax, rbx)?;
+        Operator::I32Const { value } => {
+            // Upper half of the slot is kept zeroed
+            assembler.mov(eax, value as u32)?;
             assembler.push(rax)?;
+            control.push(Type::I32);
+        }
+        Operator::I64Add => integer::binary(assembler, control, Type::I64, |a| a.add(rax, rcx))?,
+        Operator::I32Add => integer::binary(assembler, control, Type::I32, |a| a.add(eax, ecx))?,
+        Operator::I64Sub => integer::binary(assembler, control, Type::I64, |a| a.sub(rax, rcx))?,
+        Operator::I32Sub => integer::binary(assembler, control, Type::I32, |a| a.sub(eax, ecx))?,
+        Operator::Call { function_index } => {
+            let called_function_type = context.function_type(function_index).cloned().unwrap();
+            abi::call(assembler, control, &called_function_type, |assembler| {
+                context.call(assembler, function_index)
+            })?;
+        }
+        Operator::Unreachable => {
+            assembler.jmp(context.traps.label(Trap::Unreachable))?;
+            control.set_unreachable();
+        }
+        Operator::Nop => assembler.nop()?,
+        Operator::Block { ty } => control.block(assembler, &context.function_typedefs, ty),
+        Operator::Loop { ty } => control.loop_(assembler, &context.function_typedefs, ty)?,
+        Operator::If { ty } => {
+            values.pop_rax(assembler, control)?;
+            control.if_(assembler, &context.function_typedefs, ty)?;
         }
-        Operator::I32Add => {
-            assembler.pop(eax)?;
-            assembler.pop(ebx)?;
-            assembler.add(eax, ebx)?;
-            assembler.push(eax)?;
+        Operator::Else => control.else_(assembler)?,
+        Operator::Try { ty } => control.try_(assembler, &context.function_typedefs, ty)?,
+        Operator::Catch { index } => control.catch(assembler, Some(&context.tags[&index]))?,
+        Operator::CatchAll => control.catch(assembler, None)?,
+        Operator::Throw { index } => exceptions::throw(assembler, context, control, index)?,
+        Operator::Rethrow { relative_depth } => control.rethrow(assembler, relative_depth)?,
+        Operator::Delegate { relative_depth } => control.delegate(assembler, relative_depth)?,
+        Operator::End => {
+            control.end(assembler)?;
         }
-        Operator::I64Sub => {
+        Operator::Br { relative_depth } => {
+            control.branch(assembler, relative_depth, false)?;
+            control.set_unreachable();
+        }
+        Operator::BrIf { relative_depth } => {
+            values.pop_rax(assembler, control)?;
+            control.branch(assembler, relative_depth, true)?;
+        }
+        Operator::BrTable { table } => {
+            let targets = table.targets().collect::<Result<Vec<_>, _>>()?;
+            values.pop_rax(assembler, control)?;
+            control.branch_table(assembler, &targets, table.default())?;
+            control.set_unreachable();
+        }
+        Operator::Return => {
+            control.branch(assembler, control.function_depth(), false)?;
+            control.set_unreachable();
+        }
+        Operator::CallIndirect { index, table_index } => {
+            let called_function_type = context.function_typedefs[&index].clone();
+            // Element index, zero-extended as any i32 slot
             assembler.pop(rax)?;
-            assembler.pop(rbx)?;
-            assembler.sub(rax, rbx)?;
-            assembler.push(rax)?;
+            control.pop(Type::I32);
+            table::indirect_callee(
+                assembler,
+                context,
+                table_index,
+                abi::signature(&called_function_type),
+            )?;
+            abi::call(assembler, control, &called_function_type, |assembler| {
+                assembler.call(r10)?;
+                Ok(())
+            })?;
         }
-        Operator::I32Sub => {
-            assembler.pop(eax)?;
-            assembler.pop(ebx)?;
-            assembler.sub(eax, ebx)?;
-            assembler.push(eax)?;
+        Operator::ReturnCall { function_index } => {
+            let called_function_type = context.function_type(function_index).cloned().unwrap();
+            let caller_type = control.function_type().clone();
+            abi::tail_call(
+                assembler,
+                control,
+                &caller_type,
+                &called_function_type,
+                |assembler| context.jump(assembler, function_index),
+            )?;
         }
-        Operator::Call { function_index } => {
-            let called_function_type = function_types
-                .get(&function_index)
-                .and_then(|i| function_types.get(&i))
-                .and_then(|t| function_typedefs.get(t))
-                .cloned()
-                .unwrap();
-            let mut integer_order: VecDeque<AsmRegister64> = vec![rdi, rsi, rdx, rcx, r8, r9]
-                .drain(0..called_function_type.params.len())
-                .collect();
-            for param in called_function_type.params.iter() {
-                match param {
-                    Type::I64 | Type::I32 => match integer_order.pop_back() {
-                        Some(reg) => assembler.pop(reg)?,
-                        None => todo!(),
-                    },
-                    _ => todo!(),
-                }
-            }
-            match got.get(&function_index) {
-                None => {
-                    if let Some(import_label) = ils.get(&function_index) {
-                        assembler.mov(r10, ptr(*import_label))?;
-                        assembler.call(r10)?;
-                    }
-                }
-                Some(label) => {
-                    assembler.call(*label)?;
-                }
-            }
-            let mut integer_order = VecDeque::from([rax, rdx]);
-            for ret in called_function_type.returns.iter() {
-                match ret {
-                    Type::I64 | Type::I32 => match integer_order.pop_front() {
-                        Some(reg) => assembler.push(reg)?,
-                        None => (),
-                    },
-                    _ => todo!(),
-                }
+        Operator::ReturnCallIndirect { index, table_index } => {
+            let called_function_type = context.function_typedefs[&index].clone();
+            let caller_type = control.function_type().clone();
+            assembler.pop(rax)?;
+            control.pop(Type::I32);
+            table::indirect_callee(
+                assembler,
+                context,
+                table_index,
+                abi::signature(&called_function_type),
+            )?;
+            abi::tail_call(
+                assembler,
+                control,
+                &caller_type,
+                &called_function_type,
+                |assembler| {
+                    assembler.jmp(r10)?;
+                    Ok(())
+                },
+            )?;
+        }
+        Operator::Drop => {
+            let ty = control.top();
+            assembler.add(rsp, control::slot_size(&ty) as i32)?;
+            control.pop(ty);
+        }
+        Operator::Select | Operator::TypedSelect { .. } => {
+            assembler.pop(rax)?;
+            control.pop(Type::I32);
+            let ty = control.top();
+            if ty == Type::V128 {
+                let mut keep = assembler.create_label();
+                assembler.test(eax, eax)?;
+                assembler.jnz(keep)?;
+                assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+                assembler.movdqu(xmmword_ptr(rsp + 16), xmm0)?;
+                control.bind_label(assembler, &mut keep)?;
+                assembler.add(rsp, 16)?;
+            } else {
+                assembler.pop(rcx)?;
+                assembler.mov(rdx, ptr(rsp))?;
+                assembler.test(eax, eax)?;
+                assembler.cmovz(rdx, rcx)?;
+                assembler.mov(ptr(rsp), rdx)?;
             }
+            control.pop(ty);
         }
-        Operator::Unreachable => todo!(),
-        Operator::Nop => assembler.nop()?,
-        Operator::Block { .. } => todo!(),
-        Operator::Loop { .. } => todo!(),
-        Operator::If { .. } => todo!(),
-        Operator::Else => todo!(),
-        Operator::Try { .. } => todo!(),
-        Operator::Catch { .. } => todo!(),
-        Operator::Throw { .. } => todo!(),
-        Operator::Rethrow { .. } => todo!(),
-        Operator::End => {}
-        Operator::Br { .. } => todo!(),
-        Operator::BrIf { .. } => todo!(),
-        Operator::BrTable { .. } => todo!(),
-        Operator::Return => todo!(),
-        Operator::CallIndirect { .. } => todo!(),
-        Operator::ReturnCall { .. } => todo!(),
-        Operator::ReturnCallIndirect { .. } => todo!(),
-        Operator::Delegate { .. } => todo!(),
-        Operator::CatchAll => todo!(),
-        Operator::Drop => todo!(),
-        Operator::Select => todo!(),
-        Operator::TypedSelect { .. } => todo!(),
         Operator::LocalGet { local_index } => match locals.get(local_index as usize) {
-            Some(offset) => {
-                assembler.mov(rax, ptr(rbp - *offset))?;
-                assembler.push(rax)?;
+            Some((offset, ty)) => {
+                // Pushed from the upper part down
+                for part in (0..control::slot_size(ty)).step_by(8).rev() {
+                    assembler.push(qword_ptr(rbp - *offset + part))?;
+                }
+                control.push(*ty);
             }
-            None => todo!(),
+            None => unreachable!("validated local index"),
         },
         Operator::LocalSet { local_index } => match locals.get(local_index as usize) {
-            Some(offset) => {
-                assembler.pop(rax)?;
+            Some((offset, ty)) => {
+                for part in (0..control::slot_size(ty)).step_by(8) {
+                    assembler.pop(qword_ptr(rbp - *offset + part))?;
+                }
+                control.pop(*ty);
+            }
+            None => unreachable!("validated local index"),
+        },
+        Operator::LocalTee { local_index } => match locals.get(local_index as usize) {
+            Some((offset, Type::V128)) => {
+                assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+                assembler.movdqu(xmmword_ptr(rbp - *offset), xmm0)?;
+            }
+            Some((offset, _)) => {
+                assembler.mov(rax, ptr(rsp))?;
                 assembler.mov(ptr(rbp - *offset), rax)?;
             }
-            None => todo!(),
+            None => unreachable!("validated local index"),
         },
-        Operator::LocalTee { .. } => todo!(),
-        Operator::GlobalGet { .. } => todo!(),
-        Operator::GlobalSet { .. } => todo!(),
-        Operator::I32Load { .. } => todo!(),
-        Operator::I64Load { .. } => todo!(),
-        Operator::F32Load { .. } => todo!(),
-        Operator::F64Load { .. } => todo!(),
-        Operator::I32Load8S { .. } => todo!(),
-        Operator::I32Load8U { .. } => todo!(),
-        Operator::I32Load16S { .. } => todo!(),
-        Operator::I32Load16U { .. } => todo!(),
-        Operator::I64Load8S { .. } => todo!(),
-        Operator::I64Load8U { .. } => todo!(),
-        Operator::I64Load16S { .. } => todo!(),
-        Operator::I64Load16U { .. } => todo!(),
-        Operator::I64Load32S { .. } => todo!(),
-        Operator::I64Load32U { .. } => todo!(),
-        Operator::I32Store { .. } => todo!(),
-        Operator::I64Store { .. } => todo!(),
-        Operator::F32Store { .. } => todo!(),
-        Operator::F64Store { .. } => todo!(),
-        Operator::I32Store8 { .. } => todo!(),
-        Operator::I32Store16 { .. } => todo!(),
-        Operator::I64Store8 { .. } => todo!(),
-        Operator::I64Store16 { .. } => todo!(),
-        Operator::I64Store32 { .. } => todo!(),
-        Operator::MemorySize { .. } => todo!(),
-        Operator::MemoryGrow { .. } => todo!(),
-        Operator::I32Const { .. } => todo!(),
-        Operator::F32Const { .. } => todo!(),
-        Operator::F64Const { .. } => todo!(),
-        Operator::RefNull { .. } => todo!(),
-        Operator::RefIsNull => todo!(),
-        Operator::RefFunc { .. } => todo!(),
-        Operator::I32Eqz => todo!(),
-        Operator::I32Eq => todo!(),
-        Operator::I32Ne => todo!(),
-        Operator::I32LtS => todo!(),
-        Operator::I32LtU => todo!(),
-        Operator::I32GtS => todo!(),
-        Operator::I32GtU => todo!(),
-        Operator::I32LeS => todo!(),
-        Operator::I32LeU => todo!(),
-        Operator::I32GeS => todo!(),
-        Operator::I32GeU => todo!(),
-        Operator::I64Eqz => todo!(),
-        Operator::I64Eq => todo!(),
-        Operator::I64Ne => todo!(),
-        Operator::I64LtS => todo!(),
-        Operator::I64LtU => todo!(),
-        Operator::I64GtS => todo!(),
-        Operator::I64GtU => todo!(),
-        Operator::I64LeS => todo!(),
-        Operator::I64LeU => todo!(),
-        Operator::I64GeS => todo!(),
-        Operator::I64GeU => todo!(),
-        Operator::F32Eq => todo!(),
-        Operator::F32Ne => todo!(),
-        Operator::F32Lt => todo!(),
-        Operator::F32Gt => todo!(),
-        Operator::F32Le => todo!(),
-        Operator::F32Ge => todo!(),
-        Operator::F64Eq => todo!(),
-        Operator::F64Ne => todo!(),
-        Operator::F64Lt => todo!(),
-        Operator::F64Gt => todo!(),
-        Operator::F64Le => todo!(),
-        Operator::F64Ge => todo!(),
-        Operator::I32Clz => todo!(),
-        Operator::I32Ctz => todo!(),
-        Operator::I32Popcnt => todo!(),
-        Operator::I32Mul => todo!(),
-        Operator::I32DivS => todo!(),
-        Operator::I32DivU => todo!(),
-        Operator::I32RemS => todo!(),
-        Operator::I32RemU => todo!(),
-        Operator::I32And => todo!(),
-        Operator::I32Or => todo!(),
-        Operator::I32Xor => todo!(),
-        Operator::I32Shl => todo!(),
-        Operator::I32ShrS => todo!(),
-        Operator::I32ShrU => todo!(),
-        Operator::I32Rotl => todo!(),
-        Operator::I32Rotr => todo!(),
-        Operator::I64Clz => todo!(),
-        Operator::I64Ctz => todo!(),
-        Operator::I64Popcnt => todo!(),
-        Operator::I64Mul => todo!(),
-        Operator::I64DivS => todo!(),
-        Operator::I64DivU => todo!(),
-        Operator::I64RemS => todo!(),
-        Operator::I64RemU => todo!(),
-        Operator::I64And => todo!(),
-        Operator::I64Or => todo!(),
-        Operator::I64Xor => todo!(),
-        Operator::I64Shl => todo!(),
-        Operator::I64ShrS => todo!(),
-        Operator::I64ShrU => todo!(),
-        Operator::I64Rotl => todo!(),
-        Operator::I64Rotr => todo!(),
-        Operator::F32Abs => todo!(),
-        Operator::F32Neg => todo!(),
-        Operator::F32Ceil => todo!(),
-        Operator::F32Floor => todo!(),
-        Operator::F32Trunc => todo!(),
-        Operator::F32Nearest => todo!(),
-        Operator::F32Sqrt => todo!(),
-        Operator::F32Add => todo!(),
-        Operator::F32Sub => todo!(),
-        Operator::F32Mul => todo!(),
-        Operator::F32Div => todo!(),
-        Operator::F32Min => todo!(),
-        Operator::F32Max => todo!(),
-        Operator::F32Copysign => todo!(),
-        Operator::F64Abs => todo!(),
-        Operator::F64Neg => todo!(),
-        Operator::F64Ceil => todo!(),
-        Operator::F64Floor => todo!(),
-        Operator::F64Trunc => todo!(),
-        Operator::F64Nearest => todo!(),
-        Operator::F64Sqrt => todo!(),
-        Operator::F64Add => todo!(),
-        Operator::F64Sub => todo!(),
-        Operator::F64Mul => todo!(),
-        Operator::F64Div => todo!(),
-        Operator::F64Min => todo!(),
-        Operator::F64Max => todo!(),
-        Operator::F64Copysign => todo!(),
-        Operator::I32WrapI64 => todo!(),
-        Operator::I32TruncF32S => todo!(),
-        Operator::I32TruncF32U => todo!(),
-        Operator::I32TruncF64S => todo!(),
-        Operator::I32TruncF64U => todo!(),
-        Operator::I64ExtendI32S => todo!(),
-        Operator::I64ExtendI32U => todo!(),
-        Operator::I64TruncF32S => todo!(),
-        Operator::I64TruncF32U => todo!(),
-        Operator::I64TruncF64S => todo!(),
-        Operator::I64TruncF64U => todo!(),
-        Operator::F32ConvertI32S => todo!(),
-        Operator::F32ConvertI32U => todo!(),
-        Operator::F32ConvertI64S => todo!(),
-        Operator::F32ConvertI64U => todo!(),
-        Operator::F32DemoteF64 => todo!(),
-        Operator::F64ConvertI32S => todo!(),
-        Operator::F64ConvertI32U => todo!(),
-        Operator::F64ConvertI64S => todo!(),
-        Operator::F64ConvertI64U => todo!(),
-        Operator::F64PromoteF32 => todo!(),
-        Operator::I32ReinterpretF32 => todo!(),
-        Operator::I64ReinterpretF64 => todo!(),
-        Operator::F32ReinterpretI32 => todo!(),
-        Operator::F64ReinterpretI64 => todo!(),
-        Operator::I32Extend8S => todo!(),
-        Operator::I32Extend16S => todo!(),
-        Operator::I64Extend8S => todo!(),
-        Operator::I64Extend16S => todo!(),
-        Operator::I64Extend32S => todo!(),
-        Operator::I32TruncSatF32S => todo!(),
-        Operator::I32TruncSatF32U => todo!(),
-        Operator::I32TruncSatF64S => todo!(),
-        Operator::I32TruncSatF64U => todo!(),
-        Operator::I64TruncSatF32S => todo!(),
-        Operator::I64TruncSatF32U => todo!(),
-        Operator::I64TruncSatF64S => todo!(),
-        Operator::I64TruncSatF64U => todo!(),
-        Operator::MemoryInit { .. } => todo!(),
-        Operator::DataDrop { .. } => todo!(),
-        Operator::MemoryCopy { .. } => todo!(),
-        Operator::MemoryFill { .. } => todo!(),
-        Operator::TableInit { .. } => todo!(),
-        Operator::ElemDrop { .. } => todo!(),
-        Operator::TableCopy { .. } => todo!(),
-        Operator::TableFill { .. } => todo!(),
-        Operator::TableGet { .. } => todo!(),
-        Operator::TableSet { .. } => todo!(),
-        Operator::TableGrow { .. } => todo!(),
-        Operator::TableSize { .. } => todo!(),
-        Operator::MemoryAtomicNotify { .. } => todo!(),
-        Operator::MemoryAtomicWait32 { .. } => todo!(),
-        Operator::MemoryAtomicWait64 { .. } => todo!(),
-        Operator::AtomicFence { .. } => todo!(),
-        Operator::I32AtomicLoad { .. } => todo!(),
-        Operator::I64AtomicLoad { .. } => todo!(),
-        Operator::I32AtomicLoad8U { .. } => todo!(),
-        Operator::I32AtomicLoad16U { .. } => todo!(),
-        Operator::I64AtomicLoad8U { .. } => todo!(),
-        Operator::I64AtomicLoad16U { .. } => todo!(),
-        Operator::I64AtomicLoad32U { .. } => todo!(),
-        Operator::I32AtomicStore { .. } => todo!(),
-        Operator::I64AtomicStore { .. } => todo!(),
-        Operator::I32AtomicStore8 { .. } => todo!(),
-        Operator::I32AtomicStore16 { .. } => todo!(),
-        Operator::I64AtomicStore8 { .. } => todo!(),
-        Operator::I64AtomicStore16 { .. } => todo!(),
-        Operator::I64AtomicStore32 { .. } => todo!(),
-        Operator::I32AtomicRmwAdd { .. } => todo!(),
-        Operator::I64AtomicRmwAdd { .. } => todo!(),
-        Operator::I32AtomicRmw8AddU { .. } => todo!(),
-        Operator::I32AtomicRmw16AddU { .. } => todo!(),
-        Operator::I64AtomicRmw8AddU { .. } => todo!(),
-        Operator::I64AtomicRmw16AddU { .. } => todo!(),
-        Operator::I64AtomicRmw32AddU { .. } => todo!(),
-        Operator::I32AtomicRmwSub { .. } => todo!(),
-        Operator::I64AtomicRmwSub { .. } => todo!(),
-        Operator::I32AtomicRmw8SubU { .. } => todo!(),
-        Operator::I32AtomicRmw16SubU { .. } => todo!(),
-        Operator::I64AtomicRmw8SubU { .. } => todo!(),
-        Operator::I64AtomicRmw16SubU { .. } => todo!(),
-        Operator::I64AtomicRmw32SubU { .. } => todo!(),
-        Operator::I32AtomicRmwAnd { .. } => todo!(),
-        Operator::I64AtomicRmwAnd { .. } => todo!(),
-        Operator::I32AtomicRmw8AndU { .. } => todo!(),
-        Operator::I32AtomicRmw16AndU { .. } => todo!(),
-        Operator::I64AtomicRmw8AndU { .. } => todo!(),
-        Operator::I64AtomicRmw16AndU { .. } => todo!(),
-        Operator::I64AtomicRmw32AndU { .. } => todo!(),
-        Operator::I32AtomicRmwOr { .. } => todo!(),
-        Operator::I64AtomicRmwOr { .. } => todo!(),
-        Operator::I32AtomicRmw8OrU { .. } => todo!(),
-        Operator::I32AtomicRmw16OrU { .. } => todo!(),
-        Operator::I64AtomicRmw8OrU { .. } => todo!(),
-        Operator::I64AtomicRmw16OrU { .. } => todo!(),
-        Operator::I64AtomicRmw32OrU { .. } => todo!(),
-        Operator::I32AtomicRmwXor { .. } => todo!(),
-        Operator::I64AtomicRmwXor { .. } => todo!(),
-        Operator::I32AtomicRmw8XorU { .. } => todo!(),
-        Operator::I32AtomicRmw16XorU { .. } => todo!(),
-        Operator::I64AtomicRmw8XorU { .. } => todo!(),
-        Operator::I64AtomicRmw16XorU { .. } => todo!(),
-        Operator::I64AtomicRmw32XorU { .. } => todo!(),
-        Operator::I32AtomicRmwXchg { .. } => todo!(),
-        Operator::I64AtomicRmwXchg { .. } => todo!(),
-        Operator::I32AtomicRmw8XchgU { .. } => todo!(),
-        Operator::I32AtomicRmw16XchgU { .. } => todo!(),
-        Operator::I64AtomicRmw8XchgU { .. } => todo!(),
-        Operator::I64AtomicRmw16XchgU { .. } => todo!(),
-        Operator::I64AtomicRmw32XchgU { .. } => todo!(),
-        Operator::I32AtomicRmwCmpxchg { .. } => todo!(),
-        Operator::I64AtomicRmwCmpxchg { .. } => todo!(),
-        Operator::I32AtomicRmw8CmpxchgU { .. } => todo!(),
-        Operator::I32AtomicRmw16CmpxchgU { .. } => todo!(),
-        Operator::I64AtomicRmw8CmpxchgU { .. } => todo!(),
-        Operator::I64AtomicRmw16CmpxchgU { .. } => todo!(),
-        Operator::I64AtomicRmw32CmpxchgU { .. } => todo!(),
-        Operator::V128Load { .. } => todo!(),
-        Operator::V128Load8x8S { .. } => todo!(),
-        Operator::V128Load8x8U { .. } => todo!(),
-        Operator::V128Load16x4S { .. } => todo!(),
-        Operator::V128Load16x4U { .. } => todo!(),
-        Operator::V128Load32x2S { .. } => todo!(),
-        Operator::V128Load32x2U { .. } => todo!(),
-        Operator::V128Load8Splat { .. } => todo!(),
-        Operator::V128Load16Splat { .. } => todo!(),
-        Operator::V128Load32Splat { .. } => todo!(),
-        Operator::V128Load64Splat { .. } => todo!(),
-        Operator::V128Load32Zero { .. } => todo!(),
-        Operator::V128Load64Zero { .. } => todo!(),
-        Operator::V128Store { .. } => todo!(),
-        Operator::V128Load8Lane { .. } => todo!(),
-        Operator::V128Load16Lane { .. } => todo!(),
-        Operator::V128Load32Lane { .. } => todo!(),
-        Operator::V128Load64Lane { .. } => todo!(),
-        Operator::V128Store8Lane { .. } => todo!(),
-        Operator::V128Store16Lane { .. } => todo!(),
-        Operator::V128Store32Lane { .. } => todo!(),
-        Operator::V128Store64Lane { .. } => todo!(),
-        Operator::V128Const { .. } => todo!(),
-        Operator::I8x16Shuffle { .. } => todo!(),
-        Operator::I8x16ExtractLaneS { .. } => todo!(),
-        Operator::I8x16ExtractLaneU { .. } => todo!(),
-        Operator::I8x16ReplaceLane { .. } => todo!(),
-        Operator::I16x8ExtractLaneS { .. } => todo!(),
-        Operator::I16x8ExtractLaneU { .. } => todo!(),
-        Operator::I16x8ReplaceLane { .. } => todo!(),
-        Operator::I32x4ExtractLane { .. } => todo!(),
-        Operator::I32x4ReplaceLane { .. } => todo!(),
-        Operator::I64x2ExtractLane { .. } => todo!(),
-        Operator::I64x2ReplaceLane { .. } => todo!(),
-        Operator::F32x4ExtractLane { .. } => todo!(),
-        Operator::F32x4ReplaceLane { .. } => todo!(),
-        Operator::F64x2ExtractLane { .. } => todo!(),
-        Operator::F64x2ReplaceLane { .. } => todo!(),
-        Operator::I8x16Swizzle => todo!(),
-        Operator::I8x16Splat => todo!(),
-        Operator::I16x8Splat => todo!(),
-        Operator::I32x4Splat => todo!(),
-        Operator::I64x2Splat => todo!(),
-        Operator::F32x4Splat => todo!(),
-        Operator::F64x2Splat => todo!(),
-        Operator::I8x16Eq => todo!(),
-        Operator::I8x16Ne => todo!(),
-        Operator::I8x16LtS => todo!(),
-        Operator::I8x16LtU => todo!(),
-        Operator::I8x16GtS => todo!(),
-        Operator::I8x16GtU => todo!(),
-        Operator::I8x16LeS => todo!(),
-        Operator::I8x16LeU => todo!(),
-        Operator::I8x16GeS => todo!(),
-        Operator::I8x16GeU => todo!(),
-        Operator::I16x8Eq => todo!(),
-        Operator::I16x8Ne => todo!(),
-        Operator::I16x8LtS => todo!(),
-        Operator::I16x8LtU => todo!(),
-        Operator::I16x8GtS => todo!(),
-        Operator::I16x8GtU => todo!(),
-        Operator::I16x8LeS => todo!(),
-        Operator::I16x8LeU => todo!(),
-        Operator::I16x8GeS => todo!(),
-        Operator::I16x8GeU => todo!(),
-        Operator::I32x4Eq => todo!(),
-        Operator::I32x4Ne => todo!(),
-        Operator::I32x4LtS => todo!(),
-        Operator::I32x4LtU => todo!(),
-        Operator::I32x4GtS => todo!(),
-        Operator::I32x4GtU => todo!(),
-        Operator::I32x4LeS => todo!(),
-        Operator::I32x4LeU => todo!(),
-        Operator::I32x4GeS => todo!(),
-        Operator::I32x4GeU => todo!(),
-        Operator::I64x2Eq => todo!(),
-        Operator::I64x2Ne => todo!(),
-        Operator::I64x2LtS => todo!(),
-        Operator::I64x2GtS => todo!(),
-        Operator::I64x2LeS => todo!(),
-        Operator::I64x2GeS => todo!(),
-        Operator::F32x4Eq => todo!(),
-        Operator::F32x4Ne => todo!(),
-        Operator::F32x4Lt => todo!(),
-        Operator::F32x4Gt => todo!(),
-        Operator::F32x4Le => todo!(),
-        Operator::F32x4Ge => todo!(),
-        Operator::F64x2Eq => todo!(),
-        Operator::F64x2Ne => todo!(),
-        Operator::F64x2Lt => todo!(),
-        Operator::F64x2Gt => todo!(),
-        Operator::F64x2Le => todo!(),
-        Operator::F64x2Ge => todo!(),
-        Operator::V128Not => todo!(),
-        Operator::V128And => todo!(),
-        Operator::V128AndNot => todo!(),
-        Operator::V128Or => todo!(),
-        Operator::V128Xor => todo!(),
-        Operator::V128Bitselect => todo!(),
-        Operator::V128AnyTrue => todo!(),
-        Operator::I8x16Abs => todo!(),
-        Operator::I8x16Neg => todo!(),
-        Operator::I8x16Popcnt => todo!(),
-        Operator::I8x16AllTrue => todo!(),
-        Operator::I8x16Bitmask => todo!(),
-        Operator::I8x16NarrowI16x8S => todo!(),
-        Operator::I8x16NarrowI16x8U => todo!(),
-        Operator::I8x16Shl => todo!(),
-        Operator::I8x16ShrS => todo!(),
-        Operator::I8x16ShrU => todo!(),
-        Operator::I8x16Add => todo!(),
-        Operator::I8x16AddSatS => todo!(),
-        Operator::I8x16AddSatU => todo!(),
-        Operator::I8x16Sub => todo!(),
-        Operator::I8x16SubSatS => todo!(),
-        Operator::I8x16SubSatU => todo!(),
-        Operator::I8x16MinS => todo!(),
-        Operator::I8x16MinU => todo!(),
-        Operator::I8x16MaxS => todo!(),
-        Operator::I8x16MaxU => todo!(),
-        Operator::I8x16RoundingAverageU => todo!(),
-        Operator::I16x8ExtAddPairwiseI8x16S => todo!(),
-        Operator::I16x8ExtAddPairwiseI8x16U => todo!(),
-        Operator::I16x8Abs => todo!(),
-        Operator::I16x8Neg => todo!(),
-        Operator::I16x8Q15MulrSatS => todo!(),
-        Operator::I16x8AllTrue => todo!(),
-        Operator::I16x8Bitmask => todo!(),
-        Operator::I16x8NarrowI32x4S => todo!(),
-        Operator::I16x8NarrowI32x4U => todo!(),
-        Operator::I16x8ExtendLowI8x16S => todo!(),
-        Operator::I16x8ExtendHighI8x16S => todo!(),
-        Operator::I16x8ExtendLowI8x16U => todo!(),
-        Operator::I16x8ExtendHighI8x16U => todo!(),
-        Operator::I16x8Shl => todo!(),
-        Operator::I16x8ShrS => todo!(),
-        Operator::I16x8ShrU => todo!(),
-        Operator::I16x8Add => todo!(),
-        Operator::I16x8AddSatS => todo!(),
-        Operator::I16x8AddSatU => todo!(),
-        Operator::I16x8Sub => todo!(),
-        Operator::I16x8SubSatS => todo!(),
-        Operator::I16x8SubSatU => todo!(),
-        Operator::I16x8Mul => todo!(),
-        Operator::I16x8MinS => todo!(),
-        Operator::I16x8MinU => todo!(),
-        Operator::I16x8MaxS => todo!(),
-        Operator::I16x8MaxU => todo!(),
-        Operator::I16x8RoundingAverageU => todo!(),
-        Operator::I16x8ExtMulLowI8x16S => todo!(),
-        Operator::I16x8ExtMulHighI8x16S => todo!(),
-        Operator::I16x8ExtMulLowI8x16U => todo!(),
-        Operator::I16x8ExtMulHighI8x16U => todo!(),
-        Operator::I32x4ExtAddPairwiseI16x8S => todo!(),
-        Operator::I32x4ExtAddPairwiseI16x8U => todo!(),
-        Operator::I32x4Abs => todo!(),
-        Operator::I32x4Neg => todo!(),
-        Operator::I32x4AllTrue => todo!(),
-        Operator::I32x4Bitmask => todo!(),
-        Operator::I32x4ExtendLowI16x8S => todo!(),
-        Operator::I32x4ExtendHighI16x8S => todo!(),
-        Operator::I32x4ExtendLowI16x8U => todo!(),
-        Operator::I32x4ExtendHighI16x8U => todo!(),
-        Operator::I32x4Shl => todo!(),
-        Operator::I32x4ShrS => todo!(),
-        Operator::I32x4ShrU => todo!(),
-        Operator::I32x4Add => todo!(),
-        Operator::I32x4Sub => todo!(),
-        Operator::I32x4Mul => todo!(),
-        Operator::I32x4MinS => todo!(),
-        Operator::I32x4MinU => todo!(),
-        Operator::I32x4MaxS => todo!(),
-        Operator::I32x4MaxU => todo!(),
-        Operator::I32x4DotI16x8S => todo!(),
-        Operator::I32x4ExtMulLowI16x8S => todo!(),
-        Operator::I32x4ExtMulHighI16x8S => todo!(),
-        Operator::I32x4ExtMulLowI16x8U => todo!(),
-        Operator::I32x4ExtMulHighI16x8U => todo!(),
-        Operator::I64x2Abs => todo!(),
-        Operator::I64x2Neg => todo!(),
-        Operator::I64x2AllTrue => todo!(),
-        Operator::I64x2Bitmask => todo!(),
-        Operator::I64x2ExtendLowI32x4S => todo!(),
-        Operator::I64x2ExtendHighI32x4S => todo!(),
-        Operator::I64x2ExtendLowI32x4U => todo!(),
-        Operator::I64x2ExtendHighI32x4U => todo!(),
-        Operator::I64x2Shl => todo!(),
-        Operator::I64x2ShrS => todo!(),
-        Operator::I64x2ShrU => todo!(),
-        Operator::I64x2Add => todo!(),
-        Operator::I64x2Sub => todo!(),
-        Operator::I64x2Mul => todo!(),
-        Operator::I64x2ExtMulLowI32x4S => todo!(),
-        Operator::I64x2ExtMulHighI32x4S => todo!(),
-        Operator::I64x2ExtMulLowI32x4U => todo!(),
-        Operator::I64x2ExtMulHighI32x4U => todo!(),
-        Operator::F32x4Ceil => todo!(),
-        Operator::F32x4Floor => todo!(),
-        Operator::F32x4Trunc => todo!(),
-        Operator::F32x4Nearest => todo!(),
-        Operator::F32x4Abs => todo!(),
-        Operator::F32x4Neg => todo!(),
-        Operator::F32x4Sqrt => todo!(),
-        Operator::F32x4Add => todo!(),
-        Operator::F32x4Sub => todo!(),
-        Operator::F32x4Mul => todo!(),
-        Operator::F32x4Div => todo!(),
-        Operator::F32x4Min => todo!(),
-        Operator::F32x4Max => todo!(),
-        Operator::F32x4PMin => todo!(),
-        Operator::F32x4PMax => todo!(),
-        Operator::F64x2Ceil => todo!(),
-        Operator::F64x2Floor => todo!(),
-        Operator::F64x2Trunc => todo!(),
-        Operator::F64x2Nearest => todo!(),
-        Operator::F64x2Abs => todo!(),
-        Operator::F64x2Neg => todo!(),
-        Operator::F64x2Sqrt => todo!(),
-        Operator::F64x2Add => todo!(),
-        Operator::F64x2Sub => todo!(),
-        Operator::F64x2Mul => todo!(),
-        Operator::F64x2Div => todo!(),
-        Operator::F64x2Min => todo!(),
-        Operator::F64x2Max => todo!(),
-        Operator::F64x2PMin => todo!(),
-        Operator::F64x2PMax => todo!(),
-        Operator::I32x4TruncSatF32x4S => todo!(),
-        Operator::I32x4TruncSatF32x4U => todo!(),
-        Operator::F32x4ConvertI32x4S => todo!(),
-        Operator::F32x4ConvertI32x4U => todo!(),
-        Operator::I32x4TruncSatF64x2SZero => todo!(),
-        Operator::I32x4TruncSatF64x2UZero => todo!(),
-        Operator::F64x2ConvertLowI32x4S => todo!(),
-        Operator::F64x2ConvertLowI32x4U => todo!(),
-        Operator::F32x4DemoteF64x2Zero => todo!(),
-        Operator::F64x2PromoteLowF32x4 => todo!(),
-        Operator::I8x16SwizzleRelaxed => todo!(),
-        Operator::I32x4TruncSatF32x4SRelaxed => todo!(),
-        Operator::I32x4TruncSatF32x4URelaxed => todo!(),
-        Operator::I32x4TruncSatF64x2SZeroRelaxed => todo!(),
-        Operator::I32x4TruncSatF64x2UZeroRelaxed => todo!(),
-        Operator::F32x4FmaRelaxed => todo!(),
-        Operator::F32x4FmsRelaxed => todo!(),
-        Operator::F64x2FmaRelaxed => todo!(),
-        Operator::F64x2FmsRelaxed => todo!(),
-        Operator::I8x16LaneSelect => todo!(),
-        Operator::I16x8LaneSelect => todo!(),
-        Operator::I32x4LaneSelect => todo!(),
-        Operator::I64x2LaneSelect => todo!(),
-        Operator::F32x4MinRelaxed => todo!(),
-        Operator::F32x4MaxRelaxed => todo!(),
-        Operator::F64x2MinRelaxed => todo!(),
-        Operator::F64x2MaxRelaxed => todo!(),
+        Operator::GlobalGet { global_index } => {
+            globals::get(assembler, context, control, global_index)?
+        }
+        Operator::GlobalSet { global_index } => {
+            globals::set(assembler, context, control, global_index)?
+        }
+        Operator::I32Load { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 4)?;
+            assembler.mov(eax, dword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I32);
+        }
+        Operator::I64Load { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 8)?;
+            assembler.mov(rax, qword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::F32Load { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 4)?;
+            assembler.mov(eax, dword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::F32);
+        }
+        Operator::F64Load { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 8)?;
+            assembler.mov(rax, qword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::F64);
+        }
+        Operator::I32Load8S { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 1)?;
+            assembler.movsx(eax, byte_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I32);
+        }
+        Operator::I32Load8U { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 1)?;
+            assembler.movzx(eax, byte_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I32);
+        }
+        Operator::I32Load16S { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 2)?;
+            assembler.movsx(eax, word_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I32);
+        }
+        Operator::I32Load16U { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 2)?;
+            assembler.movzx(eax, word_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I32);
+        }
+        Operator::I64Load8S { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 1)?;
+            assembler.movsx(rax, byte_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I64Load8U { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 1)?;
+            assembler.movzx(eax, byte_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I64Load16S { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 2)?;
+            assembler.movsx(rax, word_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I64Load16U { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 2)?;
+            assembler.movzx(eax, word_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I64Load32S { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 4)?;
+            assembler.movsxd(rax, dword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I64Load32U { memarg } => {
+            let mem = memory::address(assembler, context, &memarg, 4)?;
+            assembler.mov(eax, dword_ptr(mem))?;
+            assembler.push(rax)?;
+            control.pop(context.index_type(memarg.memory));
+            control.push(Type::I64);
+        }
+        Operator::I32Store { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 4)?;
+            assembler.mov(dword_ptr(mem), edx)?;
+            control.pop(Type::I32);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I64Store { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 8)?;
+            assembler.mov(qword_ptr(mem), rdx)?;
+            control.pop(Type::I64);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::F32Store { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 4)?;
+            assembler.mov(dword_ptr(mem), edx)?;
+            control.pop(Type::F32);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::F64Store { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 8)?;
+            assembler.mov(qword_ptr(mem), rdx)?;
+            control.pop(Type::F64);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I32Store8 { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 1)?;
+            assembler.mov(byte_ptr(mem), dl)?;
+            control.pop(Type::I32);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I32Store16 { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 2)?;
+            assembler.mov(word_ptr(mem), dx)?;
+            control.pop(Type::I32);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I64Store8 { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 1)?;
+            assembler.mov(byte_ptr(mem), dl)?;
+            control.pop(Type::I64);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I64Store16 { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 2)?;
+            assembler.mov(word_ptr(mem), dx)?;
+            control.pop(Type::I64);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::I64Store32 { memarg } => {
+            let mem = memory::store_address(assembler, context, &memarg, 4)?;
+            assembler.mov(dword_ptr(mem), edx)?;
+            control.pop(Type::I64);
+            control.pop(context.index_type(memarg.memory));
+        }
+        Operator::MemorySize { mem, .. } => memory::size(assembler, context, control, mem)?,
+        Operator::MemoryGrow { mem, .. } => memory::grow(assembler, context, control, mem)?,
+        Operator::F32Const { value } => {
+            assembler.mov(eax, value.bits())?;
+            assembler.push(rax)?;
+            control.push(Type::F32);
+        }
+        Operator::F64Const { value } => {
+            assembler.mov(rax, value.bits())?;
+            assembler.push(rax)?;
+            control.push(Type::F64);
+        }
+        Operator::RefNull { ty } => {
+            assembler.push(0)?;
+            control.push(ty);
+        }
+        Operator::RefIsNull => {
+            assembler.pop(rcx)?;
+            control.pop(control.top());
+            assembler.xor(eax, eax)?;
+            assembler.test(rcx, rcx)?;
+            assembler.sete(al)?;
+            assembler.push(rax)?;
+            control.push(Type::I32);
+        }
+        Operator::RefFunc { function_index } => {
+            init::function_reference(assembler, context, function_index)?;
+            assembler.push(rax)?;
+            control.push(Type::FuncRef);
+        }
+        Operator::I32Eqz => integer::eqz(assembler, control, Type::I32)?,
+        Operator::I32Eq => integer::compare(assembler, control, Type::I32, |a| a.sete(al))?,
+        Operator::I32Ne => integer::compare(assembler, control, Type::I32, |a| a.setne(al))?,
+        Operator::I32LtS => integer::compare(assembler, control, Type::I32, |a| a.setl(al))?,
+        Operator::I32LtU => integer::compare(assembler, control, Type::I32, |a| a.setb(al))?,
+        Operator::I32GtS => integer::compare(assembler, control, Type::I32, |a| a.setg(al))?,
+        Operator::I32GtU => integer::compare(assembler, control, Type::I32, |a| a.seta(al))?,
+        Operator::I32LeS => integer::compare(assembler, control, Type::I32, |a| a.setle(al))?,
+        Operator::I32LeU => integer::compare(assembler, control, Type::I32, |a| a.setbe(al))?,
+        Operator::I32GeS => integer::compare(assembler, control, Type::I32, |a| a.setge(al))?,
+        Operator::I32GeU => integer::compare(assembler, control, Type::I32, |a| a.setae(al))?,
+        Operator::I64Eqz => integer::eqz(assembler, control, Type::I64)?,
+        Operator::I64Eq => integer::compare(assembler, control, Type::I64, |a| a.sete(al))?,
+        Operator::I64Ne => integer::compare(assembler, control, Type::I64, |a| a.setne(al))?,
+        Operator::I64LtS => integer::compare(assembler, control, Type::I64, |a| a.setl(al))?,
+        Operator::I64LtU => integer::compare(assembler, control, Type::I64, |a| a.setb(al))?,
+        Operator::I64GtS => integer::compare(assembler, control, Type::I64, |a| a.setg(al))?,
+        Operator::I64GtU => integer::compare(assembler, control, Type::I64, |a| a.seta(al))?,
+        Operator::I64LeS => integer::compare(assembler, control, Type::I64, |a| a.setle(al))?,
+        Operator::I64LeU => integer::compare(assembler, control, Type::I64, |a| a.setbe(al))?,
+        Operator::I64GeS => integer::compare(assembler, control, Type::I64, |a| a.setge(al))?,
+        Operator::I64GeU => integer::compare(assembler, control, Type::I64, |a| a.setae(al))?,
+        Operator::F32Eq => float::compare(assembler, control, Type::F32, Comparison::Eq)?,
+        Operator::F32Ne => float::compare(assembler, control, Type::F32, Comparison::Ne)?,
+        Operator::F32Lt => float::compare(assembler, control, Type::F32, Comparison::Lt)?,
+        Operator::F32Gt => float::compare(assembler, control, Type::F32, Comparison::Gt)?,
+        Operator::F32Le => float::compare(assembler, control, Type::F32, Comparison::Le)?,
+        Operator::F32Ge => float::compare(assembler, control, Type::F32, Comparison::Ge)?,
+        Operator::F64Eq => float::compare(assembler, control, Type::F64, Comparison::Eq)?,
+        Operator::F64Ne => float::compare(assembler, control, Type::F64, Comparison::Ne)?,
+        Operator::F64Lt => float::compare(assembler, control, Type::F64, Comparison::Lt)?,
+        Operator::F64Gt => float::compare(assembler, control, Type::F64, Comparison::Gt)?,
+        Operator::F64Le => float::compare(assembler, control, Type::F64, Comparison::Le)?,
+        Operator::F64Ge => float::compare(assembler, control, Type::F64, Comparison::Ge)?,
+        Operator::I32Clz => integer::unary(assembler, |a| integer::clz(a, Type::I32))?,
+        Operator::I32Ctz => integer::unary(assembler, |a| integer::ctz(a, Type::I32))?,
+        Operator::I32Popcnt => integer::unary(assembler, |a| {
+            integer::popcnt(a, context.features, Type::I32)
+        })?,
+        Operator::I32Mul => integer::binary(assembler, control, Type::I32, |a| a.imul_2(eax, ecx))?,
+        Operator::I32DivS => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I32,
+            Division::Signed,
+            false,
+        )?,
+        Operator::I32DivU => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I32,
+            Division::Unsigned,
+            false,
+        )?,
+        Operator::I32RemS => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I32,
+            Division::Signed,
+            true,
+        )?,
+        Operator::I32RemU => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I32,
+            Division::Unsigned,
+            true,
+        )?,
+        Operator::I32And => integer::binary(assembler, control, Type::I32, |a| a.and(eax, ecx))?,
+        Operator::I32Or => integer::binary(assembler, control, Type::I32, |a| a.or(eax, ecx))?,
+        Operator::I32Xor => integer::binary(assembler, control, Type::I32, |a| a.xor(eax, ecx))?,
+        Operator::I32Shl => integer::binary(assembler, control, Type::I32, |a| a.shl(eax, cl))?,
+        Operator::I32ShrS => integer::binary(assembler, control, Type::I32, |a| a.sar(eax, cl))?,
+        Operator::I32ShrU => integer::binary(assembler, control, Type::I32, |a| a.shr(eax, cl))?,
+        Operator::I32Rotl => integer::binary(assembler, control, Type::I32, |a| a.rol(eax, cl))?,
+        Operator::I32Rotr => integer::binary(assembler, control, Type::I32, |a| a.ror(eax, cl))?,
+        Operator::I64Clz => integer::unary(assembler, |a| integer::clz(a, Type::I64))?,
+        Operator::I64Ctz => integer::unary(assembler, |a| integer::ctz(a, Type::I64))?,
+        Operator::I64Popcnt => integer::unary(assembler, |a| {
+            integer::popcnt(a, context.features, Type::I64)
+        })?,
+        Operator::I64Mul => integer::binary(assembler, control, Type::I64, |a| a.imul_2(rax, rcx))?,
+        Operator::I64DivS => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I64,
+            Division::Signed,
+            false,
+        )?,
+        Operator::I64DivU => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I64,
+            Division::Unsigned,
+            false,
+        )?,
+        Operator::I64RemS => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I64,
+            Division::Signed,
+            true,
+        )?,
+        Operator::I64RemU => integer::divide(
+            assembler,
+            context,
+            control,
+            Type::I64,
+            Division::Unsigned,
+            true,
+        )?,
+        Operator::I64And => integer::binary(assembler, control, Type::I64, |a| a.and(rax, rcx))?,
+        Operator::I64Or => integer::binary(assembler, control, Type::I64, |a| a.or(rax, rcx))?,
+        Operator::I64Xor => integer::binary(assembler, control, Type::I64, |a| a.xor(rax, rcx))?,
+        Operator::I64Shl => integer::binary(assembler, control, Type::I64, |a| a.shl(rax, cl))?,
+        Operator::I64ShrS => integer::binary(assembler, control, Type::I64, |a| a.sar(rax, cl))?,
+        Operator::I64ShrU => integer::binary(assembler, control, Type::I64, |a| a.shr(rax, cl))?,
+        Operator::I64Rotl => integer::binary(assembler, control, Type::I64, |a| a.rol(rax, cl))?,
+        Operator::I64Rotr => integer::binary(assembler, control, Type::I64, |a| a.ror(rax, cl))?,
+        Operator::F32Abs => float::abs(assembler, Type::F32)?,
+        Operator::F32Neg => float::neg(assembler, Type::F32)?,
+        Operator::F32Ceil => float::round(assembler, control, Type::F32, Rounding::Ceil)?,
+        Operator::F32Floor => float::round(assembler, control, Type::F32, Rounding::Floor)?,
+        Operator::F32Trunc => float::round(assembler, control, Type::F32, Rounding::Trunc)?,
+        Operator::F32Nearest => float::round(assembler, control, Type::F32, Rounding::Nearest)?,
+        Operator::F32Sqrt => float::sqrt(assembler, Type::F32)?,
+        Operator::F32Add => float::binary(assembler, control, Type::F32, |a| a.addss(xmm0, xmm1))?,
+        Operator::F32Sub => float::binary(assembler, control, Type::F32, |a| a.subss(xmm0, xmm1))?,
+        Operator::F32Mul => float::binary(assembler, control, Type::F32, |a| a.mulss(xmm0, xmm1))?,
+        Operator::F32Div => float::binary(assembler, control, Type::F32, |a| a.divss(xmm0, xmm1))?,
+        Operator::F32Min => float::min_max(assembler, control, Type::F32, false)?,
+        Operator::F32Max => float::min_max(assembler, control, Type::F32, true)?,
+        Operator::F32Copysign => float::copysign(assembler, control, Type::F32)?,
+        Operator::F64Abs => float::abs(assembler, Type::F64)?,
+        Operator::F64Neg => float::neg(assembler, Type::F64)?,
+        Operator::F64Ceil => float::round(assembler, control, Type::F64, Rounding::Ceil)?,
+        Operator::F64Floor => float::round(assembler, control, Type::F64, Rounding::Floor)?,
+        Operator::F64Trunc => float::round(assembler, control, Type::F64, Rounding::Trunc)?,
+        Operator::F64Nearest => float::round(assembler, control, Type::F64, Rounding::Nearest)?,
+        Operator::F64Sqrt => float::sqrt(assembler, Type::F64)?,
+        Operator::F64Add => float::binary(assembler, control, Type::F64, |a| a.addsd(xmm0, xmm1))?,
+        Operator::F64Sub => float::binary(assembler, control, Type::F64, |a| a.subsd(xmm0, xmm1))?,
+        Operator::F64Mul => float::binary(assembler, control, Type::F64, |a| a.mulsd(xmm0, xmm1))?,
+        Operator::F64Div => float::binary(assembler, control, Type::F64, |a| a.divsd(xmm0, xmm1))?,
+        Operator::F64Min => float::min_max(assembler, control, Type::F64, false)?,
+        Operator::F64Max => float::min_max(assembler, control, Type::F64, true)?,
+        Operator::F64Copysign => float::copysign(assembler, control, Type::F64)?,
+        Operator::I32WrapI64 => {
+            integer::unary(assembler, |a| a.mov(eax, eax))?;
+            control.pop(Type::I64);
+            control.push(Type::I32);
+        }
+        Operator::I32TruncF32S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I32,
+            true,
+            false,
+        )?,
+        Operator::I32TruncF32U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I32,
+            false,
+            false,
+        )?,
+        Operator::I32TruncF64S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I32,
+            true,
+            false,
+        )?,
+        Operator::I32TruncF64U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I32,
+            false,
+            false,
+        )?,
+        Operator::I64ExtendI32S => {
+            integer::unary(assembler, |a| a.movsxd(rax, eax))?;
+            control.pop(Type::I32);
+            control.push(Type::I64);
+        }
+        Operator::I64ExtendI32U => {
+            integer::unary(assembler, |a| a.mov(eax, eax))?;
+            control.pop(Type::I32);
+            control.push(Type::I64);
+        }
+        Operator::I64TruncF32S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I64,
+            true,
+            false,
+        )?,
+        Operator::I64TruncF32U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I64,
+            false,
+            false,
+        )?,
+        Operator::I64TruncF64S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I64,
+            true,
+            false,
+        )?,
+        Operator::I64TruncF64U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I64,
+            false,
+            false,
+        )?,
+        Operator::F32ConvertI32S => float::convert(assembler, control, Type::I32, Type::F32, true)?,
+        Operator::F32ConvertI32U => {
+            float::convert(assembler, control, Type::I32, Type::F32, false)?
+        }
+        Operator::F32ConvertI64S => float::convert(assembler, control, Type::I64, Type::F32, true)?,
+        Operator::F32ConvertI64U => {
+            float::convert(assembler, control, Type::I64, Type::F32, false)?
+        }
+        Operator::F32DemoteF64 => float::reformat(assembler, control, Type::F64, Type::F32)?,
+        Operator::F64ConvertI32S => float::convert(assembler, control, Type::I32, Type::F64, true)?,
+        Operator::F64ConvertI32U => {
+            float::convert(assembler, control, Type::I32, Type::F64, false)?
+        }
+        Operator::F64ConvertI64S => float::convert(assembler, control, Type::I64, Type::F64, true)?,
+        Operator::F64ConvertI64U => {
+            float::convert(assembler, control, Type::I64, Type::F64, false)?
+        }
+        Operator::F64PromoteF32 => float::reformat(assembler, control, Type::F32, Type::F64)?,
+        // Both types keep the same bits in a slot
+        Operator::I32ReinterpretF32 => {
+            control.pop(Type::F32);
+            control.push(Type::I32);
+        }
+        Operator::I64ReinterpretF64 => {
+            control.pop(Type::F64);
+            control.push(Type::I64);
+        }
+        Operator::F32ReinterpretI32 => {
+            control.pop(Type::I32);
+            control.push(Type::F32);
+        }
+        Operator::F64ReinterpretI64 => {
+            control.pop(Type::I64);
+            control.push(Type::F64);
+        }
+        Operator::I32Extend8S => integer::unary(assembler, |a| a.movsx(eax, al))?,
+        Operator::I32Extend16S => integer::unary(assembler, |a| a.movsx(eax, ax))?,
+        Operator::I64Extend8S => integer::unary(assembler, |a| a.movsx(rax, al))?,
+        Operator::I64Extend16S => integer::unary(assembler, |a| a.movsx(rax, ax))?,
+        Operator::I64Extend32S => integer::unary(assembler, |a| a.movsxd(rax, eax))?,
+        Operator::I32TruncSatF32S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I32,
+            true,
+            true,
+        )?,
+        Operator::I32TruncSatF32U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I32,
+            false,
+            true,
+        )?,
+        Operator::I32TruncSatF64S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I32,
+            true,
+            true,
+        )?,
+        Operator::I32TruncSatF64U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I32,
+            false,
+            true,
+        )?,
+        Operator::I64TruncSatF32S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I64,
+            true,
+            true,
+        )?,
+        Operator::I64TruncSatF32U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F32,
+            Type::I64,
+            false,
+            true,
+        )?,
+        Operator::I64TruncSatF64S => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I64,
+            true,
+            true,
+        )?,
+        Operator::I64TruncSatF64U => float::truncate(
+            assembler,
+            context,
+            control,
+            Type::F64,
+            Type::I64,
+            false,
+            true,
+        )?,
+        Operator::MemoryInit { segment, mem } => {
+            segments::memory_init(assembler, context, control, segment, mem)?
+        }
+        Operator::DataDrop { segment } => {
+            segments::drop(assembler, &context.data_segments[&segment])?
+        }
+        Operator::MemoryCopy { src, dst } => memory::copy(assembler, context, control, src, dst)?,
+        Operator::MemoryFill { mem } => memory::fill(assembler, context, control, mem)?,
+        Operator::TableInit { segment, table } => {
+            segments::table_init(assembler, context, control, segment, table)?
+        }
+        Operator::ElemDrop { segment } => {
+            segments::drop(assembler, &context.element_segments[&segment])?
+        }
+        Operator::TableCopy {
+            dst_table,
+            src_table,
+        } => table::copy(assembler, context, control, dst_table, src_table)?,
+        Operator::TableFill { table } => table::fill(assembler, context, control, table)?,
+        Operator::TableGet { table } => table::get(assembler, context, control, table)?,
+        Operator::TableSet { table } => table::set(assembler, context, control, table)?,
+        Operator::TableGrow { table } => table::grow(assembler, context, control, table)?,
+        Operator::TableSize { table } => table::size(assembler, context, control, table)?,
+        Operator::MemoryAtomicNotify { memarg } => {
+            atomics::notify(assembler, context, control, &memarg)?
+        }
+        Operator::MemoryAtomicWait32 { memarg } => {
+            atomics::wait(assembler, context, control, &memarg, 4)?
+        }
+        Operator::MemoryAtomicWait64 { memarg } => {
+            atomics::wait(assembler, context, control, &memarg, 8)?
+        }
+        Operator::AtomicFence { .. } => atomics::fence(assembler)?,
+        Operator::I32AtomicLoad { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 4, Type::I32)?
+        }
+        Operator::I64AtomicLoad { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 8, Type::I64)?
+        }
+        Operator::I32AtomicLoad8U { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 1, Type::I32)?
+        }
+        Operator::I32AtomicLoad16U { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 2, Type::I32)?
+        }
+        Operator::I64AtomicLoad8U { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 1, Type::I64)?
+        }
+        Operator::I64AtomicLoad16U { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 2, Type::I64)?
+        }
+        Operator::I64AtomicLoad32U { memarg } => {
+            atomics::load(assembler, context, control, &memarg, 4, Type::I64)?
+        }
+        Operator::I32AtomicStore { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 4, Type::I32)?
+        }
+        Operator::I64AtomicStore { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 8, Type::I64)?
+        }
+        Operator::I32AtomicStore8 { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 1, Type::I32)?
+        }
+        Operator::I32AtomicStore16 { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 2, Type::I32)?
+        }
+        Operator::I64AtomicStore8 { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 1, Type::I64)?
+        }
+        Operator::I64AtomicStore16 { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 2, Type::I64)?
+        }
+        Operator::I64AtomicStore32 { memarg } => {
+            atomics::store(assembler, context, control, &memarg, 4, Type::I64)?
+        }
+        Operator::I32AtomicRmwAdd { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I32, Rmw::Add)?
+        }
+        Operator::I64AtomicRmwAdd { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 8, Type::I64, Rmw::Add)?
+        }
+        Operator::I32AtomicRmw8AddU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I32, Rmw::Add)?
+        }
+        Operator::I32AtomicRmw16AddU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I32, Rmw::Add)?
+        }
+        Operator::I64AtomicRmw8AddU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I64, Rmw::Add)?
+        }
+        Operator::I64AtomicRmw16AddU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I64, Rmw::Add)?
+        }
+        Operator::I64AtomicRmw32AddU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I64, Rmw::Add)?
+        }
+        Operator::I32AtomicRmwSub { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I32, Rmw::Sub)?
+        }
+        Operator::I64AtomicRmwSub { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 8, Type::I64, Rmw::Sub)?
+        }
+        Operator::I32AtomicRmw8SubU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I32, Rmw::Sub)?
+        }
+        Operator::I32AtomicRmw16SubU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I32, Rmw::Sub)?
+        }
+        Operator::I64AtomicRmw8SubU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I64, Rmw::Sub)?
+        }
+        Operator::I64AtomicRmw16SubU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I64, Rmw::Sub)?
+        }
+        Operator::I64AtomicRmw32SubU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I64, Rmw::Sub)?
+        }
+        Operator::I32AtomicRmwAnd { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I32, Rmw::And)?
+        }
+        Operator::I64AtomicRmwAnd { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 8, Type::I64, Rmw::And)?
+        }
+        Operator::I32AtomicRmw8AndU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I32, Rmw::And)?
+        }
+        Operator::I32AtomicRmw16AndU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I32, Rmw::And)?
+        }
+        Operator::I64AtomicRmw8AndU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I64, Rmw::And)?
+        }
+        Operator::I64AtomicRmw16AndU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I64, Rmw::And)?
+        }
+        Operator::I64AtomicRmw32AndU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I64, Rmw::And)?
+        }
+        Operator::I32AtomicRmwOr { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I32, Rmw::Or)?
+        }
+        Operator::I64AtomicRmwOr { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 8, Type::I64, Rmw::Or)?
+        }
+        Operator::I32AtomicRmw8OrU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I32, Rmw::Or)?
+        }
+        Operator::I32AtomicRmw16OrU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I32, Rmw::Or)?
+        }
+        Operator::I64AtomicRmw8OrU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I64, Rmw::Or)?
+        }
+        Operator::I64AtomicRmw16OrU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I64, Rmw::Or)?
+        }
+        Operator::I64AtomicRmw32OrU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I64, Rmw::Or)?
+        }
+        Operator::I32AtomicRmwXor { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I32, Rmw::Xor)?
+        }
+        Operator::I64AtomicRmwXor { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 8, Type::I64, Rmw::Xor)?
+        }
+        Operator::I32AtomicRmw8XorU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I32, Rmw::Xor)?
+        }
+        Operator::I32AtomicRmw16XorU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I32, Rmw::Xor)?
+        }
+        Operator::I64AtomicRmw8XorU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 1, Type::I64, Rmw::Xor)?
+        }
+        Operator::I64AtomicRmw16XorU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 2, Type::I64, Rmw::Xor)?
+        }
+        Operator::I64AtomicRmw32XorU { memarg } => {
+            atomics::rmw(assembler, context, control, &memarg, 4, Type::I64, Rmw::Xor)?
+        }
+        Operator::I32AtomicRmwXchg { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            4,
+            Type::I32,
+            Rmw::Xchg,
+        )?,
+        Operator::I64AtomicRmwXchg { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            8,
+            Type::I64,
+            Rmw::Xchg,
+        )?,
+        Operator::I32AtomicRmw8XchgU { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            1,
+            Type::I32,
+            Rmw::Xchg,
+        )?,
+        Operator::I32AtomicRmw16XchgU { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            2,
+            Type::I32,
+            Rmw::Xchg,
+        )?,
+        Operator::I64AtomicRmw8XchgU { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            1,
+            Type::I64,
+            Rmw::Xchg,
+        )?,
+        Operator::I64AtomicRmw16XchgU { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            2,
+            Type::I64,
+            Rmw::Xchg,
+        )?,
+        Operator::I64AtomicRmw32XchgU { memarg } => atomics::rmw(
+            assembler,
+            context,
+            control,
+            &memarg,
+            4,
+            Type::I64,
+            Rmw::Xchg,
+        )?,
+        Operator::I32AtomicRmwCmpxchg { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 4, Type::I32)?
+        }
+        Operator::I64AtomicRmwCmpxchg { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 8, Type::I64)?
+        }
+        Operator::I32AtomicRmw8CmpxchgU { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 1, Type::I32)?
+        }
+        Operator::I32AtomicRmw16CmpxchgU { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 2, Type::I32)?
+        }
+        Operator::I64AtomicRmw8CmpxchgU { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 1, Type::I64)?
+        }
+        Operator::I64AtomicRmw16CmpxchgU { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 2, Type::I64)?
+        }
+        Operator::I64AtomicRmw32CmpxchgU { memarg } => {
+            atomics::cmpxchg(assembler, context, control, &memarg, 4, Type::I64)?
+        }
+        Operator::V128Load { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Vector)?
+        }
+        Operator::V128Load8x8S { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I16, true),
+        )?,
+        Operator::V128Load8x8U { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I16, false),
+        )?,
+        Operator::V128Load16x4S { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I32, true),
+        )?,
+        Operator::V128Load16x4U { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I32, false),
+        )?,
+        Operator::V128Load32x2S { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I64, true),
+        )?,
+        Operator::V128Load32x2U { memarg } => simd::load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            Load::Extend(Lane::I64, false),
+        )?,
+        Operator::V128Load8Splat { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Splat(Lane::I8))?
+        }
+        Operator::V128Load16Splat { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Splat(Lane::I16))?
+        }
+        Operator::V128Load32Splat { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Splat(Lane::I32))?
+        }
+        Operator::V128Load64Splat { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Splat(Lane::I64))?
+        }
+        Operator::V128Load32Zero { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Zero(Lane::I32))?
+        }
+        Operator::V128Load64Zero { memarg } => {
+            simd::load(assembler, context, control, &memarg, Load::Zero(Lane::I64))?
+        }
+        Operator::V128Store { memarg } => simd::store(assembler, context, control, &memarg)?,
+        Operator::V128Load8Lane { memarg, lane } => {
+            simd::load_lane(assembler, context, control, &memarg, Lane::I8, lane)?
+        }
+        Operator::V128Load16Lane { memarg, lane } => {
+            simd::load_lane(assembler, context, control, &memarg, Lane::I16, lane)?
+        }
+        Operator::V128Load32Lane { memarg, lane } => {
+            simd::load_lane(assembler, context, control, &memarg, Lane::I32, lane)?
+        }
+        Operator::V128Load64Lane { memarg, lane } => {
+            simd::load_lane(assembler, context, control, &memarg, Lane::I64, lane)?
+        }
+        Operator::V128Store8Lane { memarg, lane } => {
+            simd::store_lane(assembler, context, control, &memarg, Lane::I8, lane)?
+        }
+        Operator::V128Store16Lane { memarg, lane } => {
+            simd::store_lane(assembler, context, control, &memarg, Lane::I16, lane)?
+        }
+        Operator::V128Store32Lane { memarg, lane } => {
+            simd::store_lane(assembler, context, control, &memarg, Lane::I32, lane)?
+        }
+        Operator::V128Store64Lane { memarg, lane } => {
+            simd::store_lane(assembler, context, control, &memarg, Lane::I64, lane)?
+        }
+        Operator::V128Const { value } => simd::constant(assembler, control, *value.bytes())?,
+        Operator::I8x16Shuffle { lanes } => {
+            simd::shuffle(assembler, context.features, control, lanes)?
+        }
+        Operator::I8x16ExtractLaneS { lane } => {
+            simd::extract_lane(assembler, control, Type::I32, Lane::I8, true, lane)?
+        }
+        Operator::I8x16ExtractLaneU { lane } => {
+            simd::extract_lane(assembler, control, Type::I32, Lane::I8, false, lane)?
+        }
+        Operator::I8x16ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::I32, Lane::I8, lane)?
+        }
+        Operator::I16x8ExtractLaneS { lane } => {
+            simd::extract_lane(assembler, control, Type::I32, Lane::I16, true, lane)?
+        }
+        Operator::I16x8ExtractLaneU { lane } => {
+            simd::extract_lane(assembler, control, Type::I32, Lane::I16, false, lane)?
+        }
+        Operator::I16x8ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::I32, Lane::I16, lane)?
+        }
+        Operator::I32x4ExtractLane { lane } => {
+            simd::extract_lane(assembler, control, Type::I32, Lane::I32, false, lane)?
+        }
+        Operator::I32x4ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::I32, Lane::I32, lane)?
+        }
+        Operator::I64x2ExtractLane { lane } => {
+            simd::extract_lane(assembler, control, Type::I64, Lane::I64, false, lane)?
+        }
+        Operator::I64x2ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::I64, Lane::I64, lane)?
+        }
+        Operator::F32x4ExtractLane { lane } => {
+            simd::extract_lane(assembler, control, Type::F32, Lane::I32, false, lane)?
+        }
+        Operator::F32x4ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::F32, Lane::I32, lane)?
+        }
+        Operator::F64x2ExtractLane { lane } => {
+            simd::extract_lane(assembler, control, Type::F64, Lane::I64, false, lane)?
+        }
+        Operator::F64x2ReplaceLane { lane } => {
+            simd::replace_lane(assembler, control, Type::F64, Lane::I64, lane)?
+        }
+        Operator::I8x16Swizzle => simd::swizzle(assembler, context.features, control)?,
+        Operator::I8x16Splat => {
+            simd::splat(assembler, context.features, control, Type::I32, Lane::I8)?
+        }
+        Operator::I16x8Splat => {
+            simd::splat(assembler, context.features, control, Type::I32, Lane::I16)?
+        }
+        Operator::I32x4Splat => {
+            simd::splat(assembler, context.features, control, Type::I32, Lane::I32)?
+        }
+        Operator::I64x2Splat => {
+            simd::splat(assembler, context.features, control, Type::I64, Lane::I64)?
+        }
+        Operator::F32x4Splat => {
+            simd::splat(assembler, context.features, control, Type::F32, Lane::I32)?
+        }
+        Operator::F64x2Splat => {
+            simd::splat(assembler, context.features, control, Type::F64, Lane::I64)?
+        }
+        Operator::I8x16Eq => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Eq,
+            true,
+        )?,
+        Operator::I8x16Ne => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Ne,
+            true,
+        )?,
+        Operator::I8x16LtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Lt,
+            true,
+        )?,
+        Operator::I8x16LtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Lt,
+            false,
+        )?,
+        Operator::I8x16GtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Gt,
+            true,
+        )?,
+        Operator::I8x16GtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Gt,
+            false,
+        )?,
+        Operator::I8x16LeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Le,
+            true,
+        )?,
+        Operator::I8x16LeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Le,
+            false,
+        )?,
+        Operator::I8x16GeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Ge,
+            true,
+        )?,
+        Operator::I8x16GeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I8,
+            Comparison::Ge,
+            false,
+        )?,
+        Operator::I16x8Eq => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Eq,
+            true,
+        )?,
+        Operator::I16x8Ne => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Ne,
+            true,
+        )?,
+        Operator::I16x8LtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Lt,
+            true,
+        )?,
+        Operator::I16x8LtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Lt,
+            false,
+        )?,
+        Operator::I16x8GtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Gt,
+            true,
+        )?,
+        Operator::I16x8GtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Gt,
+            false,
+        )?,
+        Operator::I16x8LeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Le,
+            true,
+        )?,
+        Operator::I16x8LeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Le,
+            false,
+        )?,
+        Operator::I16x8GeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Ge,
+            true,
+        )?,
+        Operator::I16x8GeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            Comparison::Ge,
+            false,
+        )?,
+        Operator::I32x4Eq => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Eq,
+            true,
+        )?,
+        Operator::I32x4Ne => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Ne,
+            true,
+        )?,
+        Operator::I32x4LtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Lt,
+            true,
+        )?,
+        Operator::I32x4LtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Lt,
+            false,
+        )?,
+        Operator::I32x4GtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Gt,
+            true,
+        )?,
+        Operator::I32x4GtU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Gt,
+            false,
+        )?,
+        Operator::I32x4LeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Le,
+            true,
+        )?,
+        Operator::I32x4LeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Le,
+            false,
+        )?,
+        Operator::I32x4GeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Ge,
+            true,
+        )?,
+        Operator::I32x4GeU => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            Comparison::Ge,
+            false,
+        )?,
+        Operator::I64x2Eq => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Eq,
+            true,
+        )?,
+        Operator::I64x2Ne => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Ne,
+            true,
+        )?,
+        Operator::I64x2LtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Lt,
+            true,
+        )?,
+        Operator::I64x2GtS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Gt,
+            true,
+        )?,
+        Operator::I64x2LeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Le,
+            true,
+        )?,
+        Operator::I64x2GeS => simd::compare(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            Comparison::Ge,
+            true,
+        )?,
+        Operator::F32x4Eq => simd::compare_float(assembler, control, Type::F32, Comparison::Eq)?,
+        Operator::F32x4Ne => simd::compare_float(assembler, control, Type::F32, Comparison::Ne)?,
+        Operator::F32x4Lt => simd::compare_float(assembler, control, Type::F32, Comparison::Lt)?,
+        Operator::F32x4Gt => simd::compare_float(assembler, control, Type::F32, Comparison::Gt)?,
+        Operator::F32x4Le => simd::compare_float(assembler, control, Type::F32, Comparison::Le)?,
+        Operator::F32x4Ge => simd::compare_float(assembler, control, Type::F32, Comparison::Ge)?,
+        Operator::F64x2Eq => simd::compare_float(assembler, control, Type::F64, Comparison::Eq)?,
+        Operator::F64x2Ne => simd::compare_float(assembler, control, Type::F64, Comparison::Ne)?,
+        Operator::F64x2Lt => simd::compare_float(assembler, control, Type::F64, Comparison::Lt)?,
+        Operator::F64x2Gt => simd::compare_float(assembler, control, Type::F64, Comparison::Gt)?,
+        Operator::F64x2Le => simd::compare_float(assembler, control, Type::F64, Comparison::Le)?,
+        Operator::F64x2Ge => simd::compare_float(assembler, control, Type::F64, Comparison::Ge)?,
+        Operator::V128Not => simd::unary(assembler, |assembler| {
+            assembler.pcmpeqd(xmm1, xmm1)?;
+            assembler.pxor(xmm0, xmm1)
+        })?,
+        Operator::V128And => simd::binary(assembler, control, |a| a.pand(xmm0, xmm1))?,
+        Operator::V128AndNot => simd::binary(assembler, control, |assembler| {
+            assembler.pandn(xmm1, xmm0)?;
+            assembler.movdqa(xmm0, xmm1)
+        })?,
+        Operator::V128Or => simd::binary(assembler, control, |a| a.por(xmm0, xmm1))?,
+        Operator::V128Xor => simd::binary(assembler, control, |a| a.pxor(xmm0, xmm1))?,
+        Operator::V128Bitselect => simd::bitselect(assembler, control)?,
+        Operator::V128AnyTrue => simd::any_true(assembler, control)?,
+        Operator::I8x16Abs => simd::abs(assembler, context.features, Lane::I8)?,
+        Operator::I8x16Neg => simd::neg(assembler, Lane::I8)?,
+        Operator::I8x16Popcnt => simd::popcnt(assembler)?,
+        Operator::I8x16AllTrue => simd::all_true(assembler, control, Lane::I8)?,
+        Operator::I8x16Bitmask => simd::bitmask(assembler, control, Lane::I8)?,
+        Operator::I8x16NarrowI16x8S => {
+            simd::narrow(assembler, context.features, control, Lane::I8, true)?
+        }
+        Operator::I8x16NarrowI16x8U => {
+            simd::narrow(assembler, context.features, control, Lane::I8, false)?
+        }
+        Operator::I8x16Shl => simd::shift(assembler, control, Lane::I8, Shift::Left)?,
+        Operator::I8x16ShrS => simd::shift(assembler, control, Lane::I8, Shift::RightSigned)?,
+        Operator::I8x16ShrU => simd::shift(assembler, control, Lane::I8, Shift::RightUnsigned)?,
+        Operator::I8x16Add => simd::binary(assembler, control, |a| a.paddb(xmm0, xmm1))?,
+        Operator::I8x16AddSatS => simd::binary(assembler, control, |a| a.paddsb(xmm0, xmm1))?,
+        Operator::I8x16AddSatU => simd::binary(assembler, control, |a| a.paddusb(xmm0, xmm1))?,
+        Operator::I8x16Sub => simd::binary(assembler, control, |a| a.psubb(xmm0, xmm1))?,
+        Operator::I8x16SubSatS => simd::binary(assembler, control, |a| a.psubsb(xmm0, xmm1))?,
+        Operator::I8x16SubSatU => simd::binary(assembler, control, |a| a.psubusb(xmm0, xmm1))?,
+        Operator::I8x16MinS => {
+            simd::min_max(assembler, context.features, control, Lane::I8, true, false)?
+        }
+        Operator::I8x16MinU => {
+            simd::min_max(assembler, context.features, control, Lane::I8, false, false)?
+        }
+        Operator::I8x16MaxS => {
+            simd::min_max(assembler, context.features, control, Lane::I8, true, true)?
+        }
+        Operator::I8x16MaxU => {
+            simd::min_max(assembler, context.features, control, Lane::I8, false, true)?
+        }
+        Operator::I8x16RoundingAverageU => {
+            simd::binary(assembler, control, |a| a.pavgb(xmm0, xmm1))?
+        }
+        Operator::I16x8ExtAddPairwiseI8x16S => {
+            simd::extend_add_pairwise(assembler, Lane::I16, true)?
+        }
+        Operator::I16x8ExtAddPairwiseI8x16U => {
+            simd::extend_add_pairwise(assembler, Lane::I16, false)?
+        }
+        Operator::I16x8Abs => simd::abs(assembler, context.features, Lane::I16)?,
+        Operator::I16x8Neg => simd::neg(assembler, Lane::I16)?,
+        Operator::I16x8Q15MulrSatS => simd::q15_mulr(assembler, context.features, control)?,
+        Operator::I16x8AllTrue => simd::all_true(assembler, control, Lane::I16)?,
+        Operator::I16x8Bitmask => simd::bitmask(assembler, control, Lane::I16)?,
+        Operator::I16x8NarrowI32x4S => {
+            simd::narrow(assembler, context.features, control, Lane::I16, true)?
+        }
+        Operator::I16x8NarrowI32x4U => {
+            simd::narrow(assembler, context.features, control, Lane::I16, false)?
+        }
+        Operator::I16x8ExtendLowI8x16S => {
+            simd::extend(assembler, context.features, Lane::I16, true, false)?
+        }
+        Operator::I16x8ExtendHighI8x16S => {
+            simd::extend(assembler, context.features, Lane::I16, true, true)?
+        }
+        Operator::I16x8ExtendLowI8x16U => {
+            simd::extend(assembler, context.features, Lane::I16, false, false)?
+        }
+        Operator::I16x8ExtendHighI8x16U => {
+            simd::extend(assembler, context.features, Lane::I16, false, true)?
+        }
+        Operator::I16x8Shl => simd::shift(assembler, control, Lane::I16, Shift::Left)?,
+        Operator::I16x8ShrS => simd::shift(assembler, control, Lane::I16, Shift::RightSigned)?,
+        Operator::I16x8ShrU => simd::shift(assembler, control, Lane::I16, Shift::RightUnsigned)?,
+        Operator::I16x8Add => simd::binary(assembler, control, |a| a.paddw(xmm0, xmm1))?,
+        Operator::I16x8AddSatS => simd::binary(assembler, control, |a| a.paddsw(xmm0, xmm1))?,
+        Operator::I16x8AddSatU => simd::binary(assembler, control, |a| a.paddusw(xmm0, xmm1))?,
+        Operator::I16x8Sub => simd::binary(assembler, control, |a| a.psubw(xmm0, xmm1))?,
+        Operator::I16x8SubSatS => simd::binary(assembler, control, |a| a.psubsw(xmm0, xmm1))?,
+        Operator::I16x8SubSatU => simd::binary(assembler, control, |a| a.psubusw(xmm0, xmm1))?,
+        Operator::I16x8Mul => simd::binary(assembler, control, |a| a.pmullw(xmm0, xmm1))?,
+        Operator::I16x8MinS => {
+            simd::min_max(assembler, context.features, control, Lane::I16, true, false)?
+        }
+        Operator::I16x8MinU => simd::min_max(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            false,
+            false,
+        )?,
+        Operator::I16x8MaxS => {
+            simd::min_max(assembler, context.features, control, Lane::I16, true, true)?
+        }
+        Operator::I16x8MaxU => {
+            simd::min_max(assembler, context.features, control, Lane::I16, false, true)?
+        }
+        Operator::I16x8RoundingAverageU => {
+            simd::binary(assembler, control, |a| a.pavgw(xmm0, xmm1))?
+        }
+        Operator::I16x8ExtMulLowI8x16S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I16, true, false)?
+        }
+        Operator::I16x8ExtMulHighI8x16S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I16, true, true)?
+        }
+        Operator::I16x8ExtMulLowI8x16U => simd::extend_mul(
+            assembler,
+            context.features,
+            control,
+            Lane::I16,
+            false,
+            false,
+        )?,
+        Operator::I16x8ExtMulHighI8x16U => {
+            simd::extend_mul(assembler, context.features, control, Lane::I16, false, true)?
+        }
+        Operator::I32x4ExtAddPairwiseI16x8S => {
+            simd::extend_add_pairwise(assembler, Lane::I32, true)?
+        }
+        Operator::I32x4ExtAddPairwiseI16x8U => {
+            simd::extend_add_pairwise(assembler, Lane::I32, false)?
+        }
+        Operator::I32x4Abs => simd::abs(assembler, context.features, Lane::I32)?,
+        Operator::I32x4Neg => simd::neg(assembler, Lane::I32)?,
+        Operator::I32x4AllTrue => simd::all_true(assembler, control, Lane::I32)?,
+        Operator::I32x4Bitmask => simd::bitmask(assembler, control, Lane::I32)?,
+        Operator::I32x4ExtendLowI16x8S => {
+            simd::extend(assembler, context.features, Lane::I32, true, false)?
+        }
+        Operator::I32x4ExtendHighI16x8S => {
+            simd::extend(assembler, context.features, Lane::I32, true, true)?
+        }
+        Operator::I32x4ExtendLowI16x8U => {
+            simd::extend(assembler, context.features, Lane::I32, false, false)?
+        }
+        Operator::I32x4ExtendHighI16x8U => {
+            simd::extend(assembler, context.features, Lane::I32, false, true)?
+        }
+        Operator::I32x4Shl => simd::shift(assembler, control, Lane::I32, Shift::Left)?,
+        Operator::I32x4ShrS => simd::shift(assembler, control, Lane::I32, Shift::RightSigned)?,
+        Operator::I32x4ShrU => simd::shift(assembler, control, Lane::I32, Shift::RightUnsigned)?,
+        Operator::I32x4Add => simd::binary(assembler, control, |a| a.paddd(xmm0, xmm1))?,
+        Operator::I32x4Sub => simd::binary(assembler, control, |a| a.psubd(xmm0, xmm1))?,
+        Operator::I32x4Mul => simd::mul(assembler, context.features, control, Lane::I32)?,
+        Operator::I32x4MinS => {
+            simd::min_max(assembler, context.features, control, Lane::I32, true, false)?
+        }
+        Operator::I32x4MinU => simd::min_max(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            false,
+            false,
+        )?,
+        Operator::I32x4MaxS => {
+            simd::min_max(assembler, context.features, control, Lane::I32, true, true)?
+        }
+        Operator::I32x4MaxU => {
+            simd::min_max(assembler, context.features, control, Lane::I32, false, true)?
+        }
+        Operator::I32x4DotI16x8S => simd::binary(assembler, control, |a| a.pmaddwd(xmm0, xmm1))?,
+        Operator::I32x4ExtMulLowI16x8S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I32, true, false)?
+        }
+        Operator::I32x4ExtMulHighI16x8S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I32, true, true)?
+        }
+        Operator::I32x4ExtMulLowI16x8U => simd::extend_mul(
+            assembler,
+            context.features,
+            control,
+            Lane::I32,
+            false,
+            false,
+        )?,
+        Operator::I32x4ExtMulHighI16x8U => {
+            simd::extend_mul(assembler, context.features, control, Lane::I32, false, true)?
+        }
+        Operator::I64x2Abs => simd::abs(assembler, context.features, Lane::I64)?,
+        Operator::I64x2Neg => simd::neg(assembler, Lane::I64)?,
+        Operator::I64x2AllTrue => simd::all_true(assembler, control, Lane::I64)?,
+        Operator::I64x2Bitmask => simd::bitmask(assembler, control, Lane::I64)?,
+        Operator::I64x2ExtendLowI32x4S => {
+            simd::extend(assembler, context.features, Lane::I64, true, false)?
+        }
+        Operator::I64x2ExtendHighI32x4S => {
+            simd::extend(assembler, context.features, Lane::I64, true, true)?
+        }
+        Operator::I64x2ExtendLowI32x4U => {
+            simd::extend(assembler, context.features, Lane::I64, false, false)?
+        }
+        Operator::I64x2ExtendHighI32x4U => {
+            simd::extend(assembler, context.features, Lane::I64, false, true)?
+        }
+        Operator::I64x2Shl => simd::shift(assembler, control, Lane::I64, Shift::Left)?,
+        Operator::I64x2ShrS => simd::shift(assembler, control, Lane::I64, Shift::RightSigned)?,
+        Operator::I64x2ShrU => simd::shift(assembler, control, Lane::I64, Shift::RightUnsigned)?,
+        Operator::I64x2Add => simd::binary(assembler, control, |a| a.paddq(xmm0, xmm1))?,
+        Operator::I64x2Sub => simd::binary(assembler, control, |a| a.psubq(xmm0, xmm1))?,
+        Operator::I64x2Mul => simd::mul(assembler, context.features, control, Lane::I64)?,
+        Operator::I64x2ExtMulLowI32x4S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I64, true, false)?
+        }
+        Operator::I64x2ExtMulHighI32x4S => {
+            simd::extend_mul(assembler, context.features, control, Lane::I64, true, true)?
+        }
+        Operator::I64x2ExtMulLowI32x4U => simd::extend_mul(
+            assembler,
+            context.features,
+            control,
+            Lane::I64,
+            false,
+            false,
+        )?,
+        Operator::I64x2ExtMulHighI32x4U => {
+            simd::extend_mul(assembler, context.features, control, Lane::I64, false, true)?
+        }
+        Operator::F32x4Ceil => simd::round(assembler, context.features, Type::F32, Rounding::Ceil)?,
+        Operator::F32x4Floor => {
+            simd::round(assembler, context.features, Type::F32, Rounding::Floor)?
+        }
+        Operator::F32x4Trunc => {
+            simd::round(assembler, context.features, Type::F32, Rounding::Trunc)?
+        }
+        Operator::F32x4Nearest => {
+            simd::round(assembler, context.features, Type::F32, Rounding::Nearest)?
+        }
+        Operator::F32x4Abs => simd::abs_float(assembler, Type::F32)?,
+        Operator::F32x4Neg => simd::neg_float(assembler, Type::F32)?,
+        Operator::F32x4Sqrt => simd::unary(assembler, |a| a.sqrtps(xmm0, xmm0))?,
+        Operator::F32x4Add => simd::binary(assembler, control, |a| a.addps(xmm0, xmm1))?,
+        Operator::F32x4Sub => simd::binary(assembler, control, |a| a.subps(xmm0, xmm1))?,
+        Operator::F32x4Mul => simd::binary(assembler, control, |a| a.mulps(xmm0, xmm1))?,
+        Operator::F32x4Div => simd::binary(assembler, control, |a| a.divps(xmm0, xmm1))?,
+        Operator::F32x4Min => simd::min_max_float(assembler, control, Type::F32, false)?,
+        Operator::F32x4Max => simd::min_max_float(assembler, control, Type::F32, true)?,
+        Operator::F32x4PMin => simd::pseudo_min_max(assembler, control, Type::F32, false)?,
+        Operator::F32x4PMax => simd::pseudo_min_max(assembler, control, Type::F32, true)?,
+        Operator::F64x2Ceil => simd::round(assembler, context.features, Type::F64, Rounding::Ceil)?,
+        Operator::F64x2Floor => {
+            simd::round(assembler, context.features, Type::F64, Rounding::Floor)?
+        }
+        Operator::F64x2Trunc => {
+            simd::round(assembler, context.features, Type::F64, Rounding::Trunc)?
+        }
+        Operator::F64x2Nearest => {
+            simd::round(assembler, context.features, Type::F64, Rounding::Nearest)?
+        }
+        Operator::F64x2Abs => simd::abs_float(assembler, Type::F64)?,
+        Operator::F64x2Neg => simd::neg_float(assembler, Type::F64)?,
+        Operator::F64x2Sqrt => simd::unary(assembler, |a| a.sqrtpd(xmm0, xmm0))?,
+        Operator::F64x2Add => simd::binary(assembler, control, |a| a.addpd(xmm0, xmm1))?,
+        Operator::F64x2Sub => simd::binary(assembler, control, |a| a.subpd(xmm0, xmm1))?,
+        Operator::F64x2Mul => simd::binary(assembler, control, |a| a.mulpd(xmm0, xmm1))?,
+        Operator::F64x2Div => simd::binary(assembler, control, |a| a.divpd(xmm0, xmm1))?,
+        Operator::F64x2Min => simd::min_max_float(assembler, control, Type::F64, false)?,
+        Operator::F64x2Max => simd::min_max_float(assembler, control, Type::F64, true)?,
+        Operator::F64x2PMin => simd::pseudo_min_max(assembler, control, Type::F64, false)?,
+        Operator::F64x2PMax => simd::pseudo_min_max(assembler, control, Type::F64, true)?,
+        Operator::I32x4TruncSatF32x4S => simd::truncate_signed(assembler)?,
+        Operator::I32x4TruncSatF32x4U => simd::truncate_unsigned(assembler)?,
+        Operator::F32x4ConvertI32x4S => simd::unary(assembler, |a| a.cvtdq2ps(xmm0, xmm0))?,
+        Operator::F32x4ConvertI32x4U => simd::convert_unsigned(assembler)?,
+        Operator::I32x4TruncSatF64x2SZero => simd::truncate_signed_zero(assembler)?,
+        Operator::I32x4TruncSatF64x2UZero => simd::truncate_unsigned_zero(assembler)?,
+        Operator::F64x2ConvertLowI32x4S => simd::unary(assembler, |a| a.cvtdq2pd(xmm0, xmm0))?,
+        Operator::F64x2ConvertLowI32x4U => simd::convert_low_unsigned(assembler)?,
+        Operator::F32x4DemoteF64x2Zero => simd::unary(assembler, |a| a.cvtpd2ps(xmm0, xmm0))?,
+        Operator::F64x2PromoteLowF32x4 => simd::unary(assembler, |a| a.cvtps2pd(xmm0, xmm0))?,
+        Operator::I8x16SwizzleRelaxed
+        | Operator::I32x4TruncSatF32x4SRelaxed
+        | Operator::I32x4TruncSatF32x4URelaxed
+        | Operator::I32x4TruncSatF64x2SZeroRelaxed
+        | Operator::I32x4TruncSatF64x2UZeroRelaxed
+        | Operator::F32x4FmaRelaxed
+        | Operator::F32x4FmsRelaxed
+        | Operator::F64x2FmaRelaxed
+        | Operator::F64x2FmsRelaxed
+        | Operator::I8x16LaneSelect
+        | Operator::I16x8LaneSelect
+        | Operator::I32x4LaneSelect
+        | Operator::I64x2LaneSelect
+        | Operator::F32x4MinRelaxed
+        | Operator::F32x4MaxRelaxed
+        | Operator::F64x2MinRelaxed
+        | Operator::F64x2MaxRelaxed => {
+            unreachable!("relaxed SIMD is rejected by validation")
+        }
     }
     Ok(())
 }
@@ -0,0 +1,243 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::{Context, Error, Features};
+use iced_x86::code_asm::{al, eax, ecx, edx, rax, rcx, rdx, CodeAssembler};
+use iced_x86::IcedError;
+use wasmparser_nostd::Type;
+
+/// Pops the operands of a binary operator into `rax` (left) and `rcx` (right),
+/// applies `op` and pushes `rax` as the result
+///
+/// Operators on `i32` work on the lower halves of the registers, which zeroes
+/// the upper half of the result.
+pub(crate) fn binary(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    op(assembler)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    Ok(())
+}
+
+/// Same as [`binary`], for unary operators with the operand in `rax`
+pub(crate) fn unary(
+    assembler: &mut CodeAssembler,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    op(assembler)?;
+    assembler.push(rax)?;
+    Ok(())
+}
+
+/// Compares the operands of a binary operator, pushing the flag set by
+/// `set` (one of the `setcc` instructions on `al`) as an `i32`
+pub(crate) fn compare(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    set: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    match ty {
+        Type::I32 => assembler.cmp(eax, ecx)?,
+        _ => assembler.cmp(rax, rcx)?,
+    }
+    set(assembler)?;
+    assembler.movzx(eax, al)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    control.pop(ty);
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// `eqz`
+pub(crate) fn eqz(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    match ty {
+        Type::I32 => assembler.test(eax, eax)?,
+        _ => assembler.test(rax, rax)?,
+    }
+    assembler.sete(al)?;
+    assembler.movzx(eax, al)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    control.push(Type::I32);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Division {
+    Signed,
+    Unsigned,
+}
+
+/// Pushes the quotient (or the remainder, if `remainder` is set) of the
+/// operands, trapping on division by zero and on signed division overflow
+pub(crate) fn divide(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    ty: Type,
+    division: Division,
+    remainder: bool,
+) -> Result<(), Error> {
+    let wide = ty == Type::I64;
+    let mut done = assembler.create_label();
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    if wide {
+        assembler.test(rcx, rcx)?;
+    } else {
+        assembler.test(ecx, ecx)?;
+    }
+    assembler.jz(context.traps.label(Trap::IntegerDivideByZero))?;
+    match division {
+        Division::Signed => {
+            // The minimum value divided by -1 doesn't fit; its remainder is 0
+            let mut divide = assembler.create_label();
+            if wide {
+                assembler.cmp(rcx, -1)?;
+            } else {
+                assembler.cmp(ecx, -1)?;
+            }
+            assembler.jne(divide)?;
+            if remainder {
+                assembler.xor(eax, eax)?;
+            } else {
+                if wide {
+                    assembler.neg(rax)?;
+                } else {
+                    assembler.neg(eax)?;
+                }
+                assembler.jo(context.traps.label(Trap::IntegerOverflow))?;
+            }
+            assembler.jmp(done)?;
+            control.bind_label(assembler, &mut divide)?;
+            if wide {
+                assembler.cqo()?;
+                assembler.idiv(rcx)?;
+            } else {
+                assembler.cdq()?;
+                assembler.idiv(ecx)?;
+            }
+        }
+        Division::Unsigned => {
+            assembler.xor(edx, edx)?;
+            if wide {
+                assembler.div(rcx)?;
+            } else {
+                assembler.div(ecx)?;
+            }
+        }
+    }
+    if remainder {
+        assembler.mov(rax, rdx)?;
+    }
+    control.bind_label(assembler, &mut done)?;
+    assembler.push(rax)?;
+    control.pop(ty);
+    Ok(())
+}
+
+/// Counts leading zeros of `rax` into `rax`
+pub(crate) fn clz(assembler: &mut CodeAssembler, ty: Type) -> Result<(), IcedError> {
+    // `bsr` leaves the destination undefined for zero, and gives the index of
+    // the highest set bit otherwise; width - 1 - index is the count
+    assembler.mov(rcx, -1i64)?;
+    if ty == Type::I32 {
+        assembler.bsr(eax, eax)?;
+        assembler.cmovz(eax, ecx)?;
+        assembler.neg(eax)?;
+        assembler.add(eax, 31)?;
+    } else {
+        assembler.bsr(rax, rax)?;
+        assembler.cmovz(rax, rcx)?;
+        assembler.neg(rax)?;
+        assembler.add(rax, 63)?;
+    }
+    Ok(())
+}
+
+/// Counts the bits set in `rax` into `rax`
+///
+/// Without `popcnt`, bits are summed in pairs, then nibbles, then bytes,
+/// and the bytes added up by a multiplication, clobbering `rcx` and `rdx`.
+pub(crate) fn popcnt(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    ty: Type,
+) -> Result<(), IcedError> {
+    let wide = ty == Type::I64;
+    if features.popcnt {
+        return if wide {
+            assembler.popcnt(rax, rax)
+        } else {
+            assembler.popcnt(eax, eax)
+        };
+    }
+    let [pairs, nibbles, bytes, ones] =
+        [0x55u8, 0x33, 0x0F, 0x01].map(|b| u64::from_ne_bytes([b; 8]));
+    if wide {
+        assembler.mov(rcx, rax)?;
+        assembler.shr(rcx, 1)?;
+        assembler.mov(rdx, pairs)?;
+        assembler.and(rcx, rdx)?;
+        assembler.sub(rax, rcx)?;
+        assembler.mov(rdx, nibbles)?;
+        assembler.mov(rcx, rax)?;
+        assembler.shr(rcx, 2)?;
+        assembler.and(rcx, rdx)?;
+        assembler.and(rax, rdx)?;
+        assembler.add(rax, rcx)?;
+        assembler.mov(rcx, rax)?;
+        assembler.shr(rcx, 4)?;
+        assembler.add(rax, rcx)?;
+        assembler.mov(rdx, bytes)?;
+        assembler.and(rax, rdx)?;
+        assembler.mov(rdx, ones)?;
+        assembler.imul_2(rax, rdx)?;
+        assembler.shr(rax, 56)
+    } else {
+        assembler.mov(ecx, eax)?;
+        assembler.shr(ecx, 1)?;
+        assembler.and(ecx, pairs as u32)?;
+        assembler.sub(eax, ecx)?;
+        assembler.mov(ecx, eax)?;
+        assembler.shr(ecx, 2)?;
+        assembler.and(ecx, nibbles as u32)?;
+        assembler.and(eax, nibbles as u32)?;
+        assembler.add(eax, ecx)?;
+        assembler.mov(ecx, eax)?;
+        assembler.shr(ecx, 4)?;
+        assembler.add(eax, ecx)?;
+        assembler.and(eax, bytes as u32)?;
+        assembler.imul_3(eax, eax, ones as u32)?;
+        assembler.shr(eax, 24)
+    }
+}
+
+/// Counts trailing zeros of `rax` into `rax`
+pub(crate) fn ctz(assembler: &mut CodeAssembler, ty: Type) -> Result<(), IcedError> {
+    if ty == Type::I32 {
+        assembler.mov(ecx, 32)?;
+        assembler.bsf(eax, eax)?;
+        assembler.cmovz(eax, ecx)?;
+    } else {
+        assembler.mov(ecx, 64)?;
+        assembler.bsf(rax, rax)?;
+        assembler.cmovz(rax, rcx)?;
+    }
+    Ok(())
+}
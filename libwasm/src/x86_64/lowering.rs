@@ -0,0 +1,1064 @@
+//! Code generation from the [IR](crate::ir), for the functions it covers
+//!
+//! Every value gets one location for its whole lifetime: a register, a slot
+//! of the frame, or none at all for constants, which instructions take as
+//! immediates. Locations are allocated by linear scan over the blocks in
+//! reverse postorder, which is also the order they are laid out in. Values
+//! live across calls go to slots, as calls clobber every register. Branches
+//! move their arguments to the locations of their target's parameters.
+
+use crate::ir::{
+    Access, BasicBlock, BinaryOp, Block, CompareOp, Function, Inst, Op, Target, Terminator,
+    UnaryOp, Value,
+};
+use crate::trap::Trap;
+use crate::x86_64::control::{ControlStack, JumpTable};
+use crate::x86_64::integer::{self, Division};
+use crate::x86_64::operands::{apply, shift, Operand, BYTES, DWORDS, QWORDS, WORDS};
+use crate::x86_64::registers::fits_immediate;
+use crate::x86_64::{abi, globals, memory, Context, Error};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use iced_x86::code_asm::{
+    al, byte_ptr, dword_ptr, eax, ptr, qword_ptr, rax, rbp, rcx, rsp, word_ptr, AsmMemoryOperand,
+    AsmRegister8, CodeAssembler, CodeLabel,
+};
+use iced_x86::IcedError;
+use wasmparser_nostd::{MemoryImmediate, Type};
+
+/// Scratch registers of the [tables](crate::x86_64::operands), which
+/// operator lowerings clobber along with `r11`
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RDX: usize = 2;
+/// Registers values are allocated to
+const ALLOCATED: Range<usize> = 3..8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Location {
+    Register(usize),
+    /// Slot at this offset below `rbp`
+    Slot(u32),
+    Constant(u64),
+    /// Unused value, or comparison done by the branch on it
+    None,
+}
+
+/// Flags condition, as set by `cmp` or `test`
+#[derive(Clone, Copy)]
+enum Condition {
+    Equal,
+    NotEqual,
+    Less,
+    Below,
+    Greater,
+    Above,
+    LessOrEqual,
+    BelowOrEqual,
+    GreaterOrEqual,
+    AboveOrEqual,
+}
+
+impl Condition {
+    fn of(op: CompareOp) -> Self {
+        match op {
+            CompareOp::Eq => Condition::Equal,
+            CompareOp::Ne => Condition::NotEqual,
+            CompareOp::LtS => Condition::Less,
+            CompareOp::LtU => Condition::Below,
+            CompareOp::GtS => Condition::Greater,
+            CompareOp::GtU => Condition::Above,
+            CompareOp::LeS => Condition::LessOrEqual,
+            CompareOp::LeU => Condition::BelowOrEqual,
+            CompareOp::GeS => Condition::GreaterOrEqual,
+            CompareOp::GeU => Condition::AboveOrEqual,
+        }
+    }
+
+    fn negate(self) -> Self {
+        match self {
+            Condition::Equal => Condition::NotEqual,
+            Condition::NotEqual => Condition::Equal,
+            Condition::Less => Condition::GreaterOrEqual,
+            Condition::Below => Condition::AboveOrEqual,
+            Condition::Greater => Condition::LessOrEqual,
+            Condition::Above => Condition::BelowOrEqual,
+            Condition::LessOrEqual => Condition::Greater,
+            Condition::BelowOrEqual => Condition::Above,
+            Condition::GreaterOrEqual => Condition::Less,
+            Condition::AboveOrEqual => Condition::Below,
+        }
+    }
+
+    fn set(self, assembler: &mut CodeAssembler, dst: AsmRegister8) -> Result<(), IcedError> {
+        match self {
+            Condition::Equal => assembler.sete(dst),
+            Condition::NotEqual => assembler.setne(dst),
+            Condition::Less => assembler.setl(dst),
+            Condition::Below => assembler.setb(dst),
+            Condition::Greater => assembler.setg(dst),
+            Condition::Above => assembler.seta(dst),
+            Condition::LessOrEqual => assembler.setle(dst),
+            Condition::BelowOrEqual => assembler.setbe(dst),
+            Condition::GreaterOrEqual => assembler.setge(dst),
+            Condition::AboveOrEqual => assembler.setae(dst),
+        }
+    }
+
+    fn jump(self, assembler: &mut CodeAssembler, label: CodeLabel) -> Result<(), IcedError> {
+        match self {
+            Condition::Equal => assembler.je(label),
+            Condition::NotEqual => assembler.jne(label),
+            Condition::Less => assembler.jl(label),
+            Condition::Below => assembler.jb(label),
+            Condition::Greater => assembler.jg(label),
+            Condition::Above => assembler.ja(label),
+            Condition::LessOrEqual => assembler.jle(label),
+            Condition::BelowOrEqual => assembler.jbe(label),
+            Condition::GreaterOrEqual => assembler.jge(label),
+            Condition::AboveOrEqual => assembler.jae(label),
+        }
+    }
+}
+
+/// Whether the last instruction of a block is a comparison that only the
+/// branch ending the block uses, which then does the comparison itself
+fn is_fused(block: &BasicBlock, use_counts: &[u32]) -> bool {
+    match (block.insts.last(), &block.terminator) {
+        (
+            Some(Inst {
+                op: Op::Compare(..) | Op::Unary(UnaryOp::Eqz, _),
+                result: Some(result),
+            }),
+            Terminator::Branch(condition, _, _),
+        ) => result == condition && use_counts[result.index()] == 1,
+        _ => false,
+    }
+}
+
+/// Values live at the end of every block
+fn liveness(function: &Function, order: &[Block]) -> Vec<BTreeSet<Value>> {
+    let mut live_in = vec![BTreeSet::new(); function.blocks.len()];
+    let mut live_out = vec![BTreeSet::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in order.iter().rev() {
+            let mut live = BTreeSet::new();
+            for successor in function.successors(*block) {
+                live.extend(live_in[successor.index()].iter().copied());
+            }
+            live_out[block.index()] = live.clone();
+            let b = function.block(*block);
+            live.extend(b.terminator.uses());
+            for inst in b.insts.iter().rev() {
+                if let Some(result) = inst.result {
+                    live.remove(&result);
+                }
+                live.extend(inst.op.operands());
+            }
+            for param in &b.params {
+                live.remove(param);
+            }
+            if live != live_in[block.index()] {
+                live_in[block.index()] = live;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+/// Slots of the frame below the parameters, reused once their values die
+struct Slots {
+    base: u32,
+    count: u32,
+    /// Offsets of free slots, along with where their last value died
+    free: Vec<(u32, usize)>,
+}
+
+impl Slots {
+    /// Slot for a value living from `start`
+    fn take(&mut self, start: usize) -> u32 {
+        match self.free.iter().position(|(_, end)| *end < start) {
+            Some(i) => self.free.swap_remove(i).0,
+            None => {
+                self.count += 1;
+                self.base + self.count * 8
+            }
+        }
+    }
+}
+
+/// Allocates locations to the values of a function laid out in `order`,
+/// returning them along with the size of the slots they take
+///
+/// Positions are numbered along the layout, with one for the start of every
+/// block, where its parameters are defined, one for each instruction and one
+/// for the terminator. Every value lives over the hull of the positions it
+/// is defined, used or live at. Parameters of the function that end up in a
+/// slot keep the one the prologue put them in, which `params` are the
+/// offsets of.
+fn allocate(
+    function: &Function,
+    order: &[Block],
+    params: &[(u32, Type)],
+    params_size: u32,
+) -> (Vec<Location>, u32) {
+    let use_counts = function.use_counts();
+    let live_out = liveness(function, order);
+    let mut locations = vec![Location::None; function.values.len()];
+    let mut intervals = vec![(usize::MAX, 0); function.values.len()];
+    let mut extend = |value: Value, position: usize| {
+        let (start, end) = &mut intervals[value.index()];
+        *start = (*start).min(position);
+        *end = (*end).max(position);
+    };
+    let mut calls = vec![];
+    // Operand whose register the result of an instruction can take if it
+    // dies there, as the instruction reads it before writing the result
+    let mut tied = vec![None; function.values.len()];
+    let mut position = 0;
+    for block in order {
+        let b = function.block(*block);
+        let start = position;
+        let end = start + b.insts.len() + 1;
+        position = end + 1;
+        for param in &b.params {
+            extend(*param, start);
+        }
+        for value in &live_out[block.index()] {
+            extend(*value, end);
+        }
+        let fused = is_fused(b, &use_counts);
+        for (i, inst) in b.insts.iter().enumerate() {
+            let position = start + 1 + i;
+            let fused = fused && i == b.insts.len() - 1;
+            if let Some(result) = inst.result {
+                extend(result, position);
+                if let Op::Const(c) = inst.op {
+                    locations[result.index()] = Location::Constant(c);
+                }
+            }
+            match (&inst.op, inst.result) {
+                (Op::Call(..), _) => calls.push(position),
+                (
+                    Op::Binary(_, a, _)
+                    | Op::Compare(_, a, _)
+                    | Op::Unary(_, a)
+                    | Op::Select(_, a, _)
+                    | Op::Load(_, a),
+                    Some(result),
+                ) => tied[result.index()] = Some(*a),
+                _ => (),
+            }
+            // Fused comparisons are done at the branch
+            for operand in inst.op.operands() {
+                extend(operand, if fused { end } else { position });
+            }
+        }
+        for value in b.terminator.uses() {
+            extend(value, end);
+        }
+    }
+
+    let mut homes = vec![None; function.values.len()];
+    for (param, (offset, _)) in function.block(Function::ENTRY).params.iter().zip(params) {
+        homes[param.index()] = Some(*offset);
+    }
+    // Values that are never used need no location, whatever defines them
+    // writes to a scratch register
+    let mut values: Vec<Value> = (0..function.values.len())
+        .map(|v| Value(v as u32))
+        .filter(|v| {
+            let (start, end) = intervals[v.index()];
+            locations[v.index()] == Location::None && start < end
+        })
+        .filter(|v| {
+            !order.iter().any(|block| {
+                let b = function.block(*block);
+                is_fused(b, &use_counts) && b.insts.last().unwrap().result == Some(*v)
+            })
+        })
+        .collect();
+    values.sort_by_key(|v| intervals[v.index()].0);
+
+    let mut free: Vec<usize> = ALLOCATED.rev().collect();
+    let mut slots = Slots {
+        base: params_size,
+        count: 0,
+        free: vec![],
+    };
+    // Values in registers, and those in slots that can be reused
+    let mut active: Vec<Value> = vec![];
+    let mut spilled: Vec<Value> = vec![];
+    for value in values {
+        let (start, end) = intervals[value.index()];
+        active.retain(|v| {
+            let expired = intervals[v.index()].1 < start;
+            if let (true, Location::Register(r)) = (expired, locations[v.index()]) {
+                free.push(r);
+            }
+            !expired
+        });
+        spilled.retain(|v| {
+            let expired = intervals[v.index()].1 < start;
+            if let (true, Location::Slot(o)) = (expired, locations[v.index()]) {
+                slots.free.push((o, intervals[v.index()].1));
+            }
+            !expired
+        });
+        // Values taking a register from another one started earlier, which
+        // slots have to be free since
+        let mut spill = |value: Value, locations: &mut Vec<Location>| match homes[value.index()] {
+            Some(offset) => locations[value.index()] = Location::Slot(offset),
+            None => {
+                let slot = slots.take(intervals[value.index()].0);
+                locations[value.index()] = Location::Slot(slot);
+                spilled.push(value);
+            }
+        };
+        let dying = tied[value.index()]
+            .filter(|t| intervals[t.index()].1 == start)
+            .and_then(|t| active.iter().position(|v| *v == t));
+        if calls.iter().any(|c| start < *c && *c < end) {
+            spill(value, &mut locations);
+        } else if let Some(i) = dying {
+            locations[value.index()] = locations[active[i].index()];
+            active[i] = value;
+        } else if let Some(r) = free.pop() {
+            locations[value.index()] = Location::Register(r);
+            active.push(value);
+        } else {
+            // The register goes to whichever value lives longest
+            let longest = (0..active.len())
+                .max_by_key(|i| intervals[active[*i].index()].1)
+                .unwrap();
+            let stolen = active[longest];
+            if intervals[stolen.index()].1 > end {
+                locations[value.index()] = locations[stolen.index()];
+                active[longest] = value;
+                spill(stolen, &mut locations);
+            } else {
+                spill(value, &mut locations);
+            }
+        }
+    }
+    (locations, slots.count * 8)
+}
+
+/// Moves a value at a location into a register
+fn load(assembler: &mut CodeAssembler, dst: usize, location: Location) -> Result<(), Error> {
+    match location {
+        Location::Register(r) if r == dst => (),
+        Location::Register(r) => assembler.mov(QWORDS[dst], QWORDS[r])?,
+        Location::Slot(o) => assembler.mov(QWORDS[dst], qword_ptr(rbp - o))?,
+        Location::Constant(0) => assembler.xor(DWORDS[dst], DWORDS[dst])?,
+        Location::Constant(c) if c <= u32::MAX as u64 => assembler.mov(DWORDS[dst], c as u32)?,
+        Location::Constant(c) => assembler.mov(QWORDS[dst], c)?,
+        Location::None => unreachable!("value without a location"),
+    }
+    Ok(())
+}
+
+/// Pushes a value at a location onto the machine stack
+///
+/// Clobbers `rax`.
+fn push(assembler: &mut CodeAssembler, location: Location) -> Result<(), Error> {
+    match location {
+        Location::Register(r) => assembler.push(QWORDS[r])?,
+        Location::Slot(o) => assembler.push(qword_ptr(rbp - o))?,
+        Location::Constant(c) if fits_immediate(c) => assembler.push(c as i32)?,
+        _ => {
+            load(assembler, RAX, location)?;
+            assembler.push(rax)?;
+        }
+    }
+    Ok(())
+}
+
+struct Lowering<'a> {
+    context: &'a Context,
+    function: &'a Function,
+    locations: Vec<Location>,
+    labels: Vec<CodeLabel>,
+    control: ControlStack,
+    jump_tables: Vec<JumpTable>,
+}
+
+/// Emits a function from its IR, prologue and epilogue included, returning
+/// the jump tables of its `br_table`s
+pub(crate) fn lower(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    function: &Function,
+) -> Result<Vec<JumpTable>, Error> {
+    let order = function.reverse_postorder();
+    assembler.push(rbp)?;
+    assembler.mov(rbp, rsp)?;
+    let (params, params_size) = abi::enter(assembler, &function.ty)?;
+    let (locations, slots_size) = allocate(function, &order, &params, params_size);
+    if slots_size > 0 {
+        assembler.sub(rsp, slots_size as i32)?;
+    }
+    let frame_size = params_size + slots_size;
+    let control = ControlStack::new(
+        assembler,
+        &function.ty,
+        frame_size,
+        context.handlers,
+        frame_size,
+        (0, 0),
+    );
+    let mut lowering = Lowering {
+        context,
+        function,
+        locations,
+        labels: function
+            .blocks
+            .iter()
+            .map(|_| assembler.create_label())
+            .collect(),
+        control,
+        jump_tables: vec![],
+    };
+    for (param, (offset, _)) in function.block(Function::ENTRY).params.iter().zip(&params) {
+        if let Location::Register(r) = lowering.locations[param.index()] {
+            assembler.mov(QWORDS[r], qword_ptr(rbp - *offset))?;
+        }
+    }
+    let use_counts = function.use_counts();
+    for (i, block) in order.iter().enumerate() {
+        lowering
+            .control
+            .bind_label(assembler, &mut lowering.labels[block.index()])?;
+        let b = function.block(*block);
+        let fused = is_fused(b, &use_counts);
+        let compiled = if fused {
+            &b.insts[..b.insts.len() - 1]
+        } else {
+            &b.insts[..]
+        };
+        for inst in compiled {
+            lowering.inst(assembler, inst)?;
+        }
+        let fused = fused.then(|| &b.insts.last().unwrap().op);
+        lowering.terminator(assembler, &b.terminator, fused, order.get(i + 1).copied())?;
+    }
+    Ok(lowering.jump_tables)
+}
+
+impl<'a> Lowering<'a> {
+    fn location(&self, value: Value) -> Location {
+        self.locations[value.index()]
+    }
+
+    fn is_wide(&self, value: Value) -> bool {
+        self.function.value_type(value) == Type::I64
+    }
+
+    fn load(&self, assembler: &mut CodeAssembler, dst: usize, value: Value) -> Result<(), Error> {
+        load(assembler, dst, self.location(value))
+    }
+
+    /// Register holding the value, which is loaded into `scratch` unless it
+    /// is in one
+    fn register(
+        &self,
+        assembler: &mut CodeAssembler,
+        value: Value,
+        scratch: usize,
+    ) -> Result<usize, Error> {
+        match self.location(value) {
+            Location::Register(r) => Ok(r),
+            _ => {
+                self.load(assembler, scratch, value)?;
+                Ok(scratch)
+            }
+        }
+    }
+
+    /// Source operand for the value, which is loaded into `scratch` if it is
+    /// a constant that doesn't fit in an immediate
+    fn operand(
+        &self,
+        assembler: &mut CodeAssembler,
+        value: Value,
+        scratch: usize,
+    ) -> Result<Operand, Error> {
+        Ok(match self.location(value) {
+            Location::Register(r) => Operand::Register(r),
+            Location::Slot(o) => Operand::Memory(o),
+            Location::Constant(c) if !self.is_wide(value) => Operand::Immediate(c as u32 as i32),
+            Location::Constant(c) if fits_immediate(c) => Operand::Immediate(c as i32),
+            _ => Operand::Register(self.register(assembler, value, scratch)?),
+        })
+    }
+
+    /// Register an instruction defining `result` computes it into
+    fn destination(&self, result: Option<Value>) -> usize {
+        match result.map(|r| self.location(r)) {
+            Some(Location::Register(r)) => r,
+            _ => RAX,
+        }
+    }
+
+    /// Moves the result computed into `register` to its location
+    fn define(
+        &self,
+        assembler: &mut CodeAssembler,
+        result: Option<Value>,
+        register: usize,
+    ) -> Result<(), Error> {
+        match result.map(|r| self.location(r)) {
+            Some(Location::Register(r)) if r != register => {
+                assembler.mov(QWORDS[r], QWORDS[register])?
+            }
+            Some(Location::Slot(o)) => assembler.mov(qword_ptr(rbp - o), QWORDS[register])?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Pushes the value onto the operand stack, for code that works on it
+    fn push(&mut self, assembler: &mut CodeAssembler, value: Value) -> Result<(), Error> {
+        push(assembler, self.location(value))?;
+        self.control.push(self.function.value_type(value));
+        Ok(())
+    }
+
+    /// Pops a value of type `ty` off the operand stack into `result`
+    fn pop(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        result: Option<Value>,
+        ty: Type,
+    ) -> Result<(), Error> {
+        let dst = self.destination(result);
+        assembler.pop(QWORDS[dst])?;
+        self.control.pop(ty);
+        self.define(assembler, result, dst)
+    }
+
+    fn inst(&mut self, assembler: &mut CodeAssembler, inst: &Inst) -> Result<(), Error> {
+        let result = inst.result;
+        match &inst.op {
+            Op::Const(_) => (),
+            Op::LocalGet(_) | Op::LocalSet(..) => unreachable!("locals are promoted to values"),
+            Op::Binary(op, a, b) => self.binary(assembler, *op, *a, *b, result)?,
+            Op::Compare(op, a, b) => {
+                let condition = self.compare(assembler, *op, *a, *b)?;
+                self.set(assembler, condition, result)?;
+            }
+            Op::Unary(UnaryOp::Eqz, a) => {
+                let condition = self.eqz(assembler, *a)?;
+                self.set(assembler, condition, result)?;
+            }
+            Op::Unary(op, a) => self.unary(assembler, *op, *a, result)?,
+            Op::Select(condition, a, b) => self.select(assembler, *condition, *a, *b, result)?,
+            Op::Load(access, address) => {
+                let dst = self.destination(result);
+                let wide = result.is_some_and(|r| self.is_wide(r));
+                let mem = self.address(assembler, access, *address)?;
+                match (access.size, access.signed, wide) {
+                    (1, false, _) => assembler.movzx(DWORDS[dst], byte_ptr(mem))?,
+                    (1, true, false) => assembler.movsx(DWORDS[dst], byte_ptr(mem))?,
+                    (1, true, true) => assembler.movsx(QWORDS[dst], byte_ptr(mem))?,
+                    (2, false, _) => assembler.movzx(DWORDS[dst], word_ptr(mem))?,
+                    (2, true, false) => assembler.movsx(DWORDS[dst], word_ptr(mem))?,
+                    (2, true, true) => assembler.movsx(QWORDS[dst], word_ptr(mem))?,
+                    (4, true, true) => assembler.movsxd(QWORDS[dst], dword_ptr(mem))?,
+                    (4, _, _) => assembler.mov(DWORDS[dst], dword_ptr(mem))?,
+                    _ => assembler.mov(QWORDS[dst], qword_ptr(mem))?,
+                }
+                self.define(assembler, result, dst)?;
+            }
+            Op::Store(access, address, value) => {
+                let src = self.register(assembler, *value, RDX)?;
+                let mem = self.address(assembler, access, *address)?;
+                match access.size {
+                    1 => assembler.mov(byte_ptr(mem), BYTES[src])?,
+                    2 => assembler.mov(word_ptr(mem), WORDS[src])?,
+                    4 => assembler.mov(dword_ptr(mem), DWORDS[src])?,
+                    _ => assembler.mov(qword_ptr(mem), QWORDS[src])?,
+                }
+            }
+            Op::MemorySize(mem) => {
+                memory::size(assembler, self.context, &mut self.control, *mem)?;
+                self.pop(assembler, result, self.context.index_type(*mem))?;
+            }
+            Op::MemoryGrow(mem, delta) => {
+                self.push(assembler, *delta)?;
+                memory::grow(assembler, self.context, &mut self.control, *mem)?;
+                self.pop(assembler, result, self.context.index_type(*mem))?;
+            }
+            Op::GlobalGet(index) => {
+                globals::load(assembler, self.context, *index)?;
+                self.define(assembler, result, RAX)?;
+            }
+            Op::GlobalSet(index, value) => {
+                self.load(assembler, RAX, *value)?;
+                globals::store(assembler, self.context, *index)?;
+            }
+            Op::Call(function_index, args) => {
+                for arg in args {
+                    self.push(assembler, *arg)?;
+                }
+                let context = self.context;
+                let ty = context.function_type(*function_index).cloned().unwrap();
+                abi::call(assembler, &mut self.control, &ty, |assembler| {
+                    context.call(assembler, *function_index)
+                })?;
+                for ret in ty.returns.iter() {
+                    self.pop(assembler, result, *ret)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        op: BinaryOp,
+        a: Value,
+        b: Value,
+        result: Option<Value>,
+    ) -> Result<(), Error> {
+        let ty = self.function.value_type(a);
+        let wide = ty == Type::I64;
+        let division = match op {
+            BinaryOp::DivS | BinaryOp::RemS => Some(Division::Signed),
+            BinaryOp::DivU | BinaryOp::RemU => Some(Division::Unsigned),
+            _ => None,
+        };
+        if let Some(division) = division {
+            self.push(assembler, a)?;
+            self.push(assembler, b)?;
+            let remainder = matches!(op, BinaryOp::RemS | BinaryOp::RemU);
+            integer::divide(
+                assembler,
+                self.context,
+                &mut self.control,
+                ty,
+                division,
+                remainder,
+            )?;
+            return self.pop(assembler, result, ty);
+        }
+        let dst = self.destination(result);
+        if matches!(
+            op,
+            BinaryOp::Shl | BinaryOp::ShrS | BinaryOp::ShrU | BinaryOp::Rotl | BinaryOp::Rotr
+        ) {
+            // Counts are taken modulo the width, as x86 does
+            let count = match self.location(b) {
+                Location::Constant(c) => Some(c as u32 & if wide { 63 } else { 31 }),
+                _ => {
+                    self.load(assembler, RCX, b)?;
+                    None
+                }
+            };
+            self.load(assembler, dst, a)?;
+            match op {
+                BinaryOp::Shl => shift!(assembler, shl, wide, dst, count)?,
+                BinaryOp::ShrS => shift!(assembler, sar, wide, dst, count)?,
+                BinaryOp::ShrU => shift!(assembler, shr, wide, dst, count)?,
+                BinaryOp::Rotl => shift!(assembler, rol, wide, dst, count)?,
+                _ => shift!(assembler, ror, wide, dst, count)?,
+            }
+            return self.define(assembler, result, dst);
+        }
+        self.load(assembler, dst, a)?;
+        let src = self.operand(assembler, b, RCX)?;
+        match op {
+            BinaryOp::Add => apply!(assembler, add, wide, dst, src)?,
+            BinaryOp::Sub => apply!(assembler, sub, wide, dst, src)?,
+            BinaryOp::And => apply!(assembler, and, wide, dst, src)?,
+            BinaryOp::Or => apply!(assembler, or, wide, dst, src)?,
+            BinaryOp::Xor => apply!(assembler, xor, wide, dst, src)?,
+            _ => match (wide, src) {
+                (true, Operand::Register(r)) => assembler.imul_2(QWORDS[dst], QWORDS[r])?,
+                (true, Operand::Memory(o)) => assembler.imul_2(QWORDS[dst], qword_ptr(rbp - o))?,
+                (true, Operand::Immediate(i)) => assembler.imul_3(QWORDS[dst], QWORDS[dst], i)?,
+                (false, Operand::Register(r)) => assembler.imul_2(DWORDS[dst], DWORDS[r])?,
+                (false, Operand::Memory(o)) => assembler.imul_2(DWORDS[dst], dword_ptr(rbp - o))?,
+                (false, Operand::Immediate(i)) => assembler.imul_3(DWORDS[dst], DWORDS[dst], i)?,
+            },
+        }
+        self.define(assembler, result, dst)
+    }
+
+    /// Compares the operands, returning the condition under which the
+    /// comparison holds
+    fn compare(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        op: CompareOp,
+        a: Value,
+        b: Value,
+    ) -> Result<Condition, Error> {
+        let wide = self.is_wide(a);
+        let lhs = self.register(assembler, a, RAX)?;
+        let rhs = self.operand(assembler, b, RCX)?;
+        apply!(assembler, cmp, wide, lhs, rhs)?;
+        Ok(Condition::of(op))
+    }
+
+    /// Tests the operand, returning the condition under which it is zero
+    fn eqz(&mut self, assembler: &mut CodeAssembler, a: Value) -> Result<Condition, Error> {
+        match (self.location(a), self.is_wide(a)) {
+            (Location::Slot(o), true) => assembler.cmp(qword_ptr(rbp - o), 0)?,
+            (Location::Slot(o), false) => assembler.cmp(dword_ptr(rbp - o), 0)?,
+            (_, wide) => {
+                let r = self.register(assembler, a, RAX)?;
+                if wide {
+                    assembler.test(QWORDS[r], QWORDS[r])?;
+                } else {
+                    assembler.test(DWORDS[r], DWORDS[r])?;
+                }
+            }
+        }
+        Ok(Condition::Equal)
+    }
+
+    /// Sets `result` to whether the condition holds
+    fn set(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        condition: Condition,
+        result: Option<Value>,
+    ) -> Result<(), Error> {
+        let dst = self.destination(result);
+        condition.set(assembler, al)?;
+        assembler.movzx(DWORDS[dst], al)?;
+        self.define(assembler, result, dst)
+    }
+
+    fn unary(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        op: UnaryOp,
+        a: Value,
+        result: Option<Value>,
+    ) -> Result<(), Error> {
+        let ty = self.function.value_type(a);
+        let wide = ty == Type::I64;
+        let dst = self.destination(result);
+        match op {
+            UnaryOp::Clz | UnaryOp::Ctz => {
+                self.load(assembler, RAX, a)?;
+                if op == UnaryOp::Clz {
+                    integer::clz(assembler, ty)?;
+                } else {
+                    integer::ctz(assembler, ty)?;
+                }
+                return self.define(assembler, result, RAX);
+            }
+            UnaryOp::Popcnt if !self.context.features.popcnt => {
+                self.load(assembler, RAX, a)?;
+                integer::popcnt(assembler, self.context.features, ty)?;
+                return self.define(assembler, result, RAX);
+            }
+            UnaryOp::Popcnt => {
+                let src = self.register(assembler, a, RCX)?;
+                if wide {
+                    assembler.popcnt(QWORDS[dst], QWORDS[src])?;
+                } else {
+                    assembler.popcnt(DWORDS[dst], DWORDS[src])?;
+                }
+            }
+            UnaryOp::Extend8S => {
+                self.load(assembler, dst, a)?;
+                if wide {
+                    assembler.movsx(QWORDS[dst], BYTES[dst])?;
+                } else {
+                    assembler.movsx(DWORDS[dst], BYTES[dst])?;
+                }
+            }
+            UnaryOp::Extend16S => {
+                self.load(assembler, dst, a)?;
+                if wide {
+                    assembler.movsx(QWORDS[dst], WORDS[dst])?;
+                } else {
+                    assembler.movsx(DWORDS[dst], WORDS[dst])?;
+                }
+            }
+            UnaryOp::Extend32S | UnaryOp::ExtendI32S => {
+                self.load(assembler, dst, a)?;
+                assembler.movsxd(QWORDS[dst], DWORDS[dst])?;
+            }
+            // `i32` values are kept zero-extended
+            UnaryOp::ExtendI32U => self.load(assembler, dst, a)?,
+            UnaryOp::Wrap => {
+                self.load(assembler, dst, a)?;
+                assembler.mov(DWORDS[dst], DWORDS[dst])?;
+            }
+            UnaryOp::Eqz => unreachable!("eqz is a comparison"),
+        }
+        self.define(assembler, result, dst)
+    }
+
+    fn select(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        condition: Value,
+        a: Value,
+        b: Value,
+        result: Option<Value>,
+    ) -> Result<(), Error> {
+        let dst = self.destination(result);
+        let wide = self.is_wide(a);
+        // Loads may clear registers with `xor`, so they come before the test
+        self.load(assembler, dst, a)?;
+        let src = match self.operand(assembler, b, RCX)? {
+            Operand::Immediate(_) => Operand::Register(self.register(assembler, b, RCX)?),
+            src => src,
+        };
+        match self.location(condition) {
+            Location::Slot(o) => assembler.cmp(dword_ptr(rbp - o), 0)?,
+            _ => {
+                let r = self.register(assembler, condition, RDX)?;
+                assembler.test(DWORDS[r], DWORDS[r])?;
+            }
+        }
+        match src {
+            Operand::Register(r) if wide => assembler.cmovz(QWORDS[dst], QWORDS[r])?,
+            Operand::Register(r) => assembler.cmovz(DWORDS[dst], DWORDS[r])?,
+            Operand::Memory(o) if wide => assembler.cmovz(QWORDS[dst], qword_ptr(rbp - o))?,
+            Operand::Memory(o) => assembler.cmovz(DWORDS[dst], dword_ptr(rbp - o))?,
+            Operand::Immediate(_) => unreachable!("immediates are loaded"),
+        }
+        self.define(assembler, result, dst)
+    }
+
+    /// Operand for the memory an access at the address refers to, trapping
+    /// first if it's out of bounds and still checked
+    ///
+    /// Clobbers `rax`, `rcx` and `r11`.
+    fn address(
+        &self,
+        assembler: &mut CodeAssembler,
+        access: &Access,
+        address: Value,
+    ) -> Result<AsmMemoryOperand, Error> {
+        // Constant addresses of accesses known to be within bounds only add
+        // to the displacement
+        let displacement = match self.location(address) {
+            Location::Constant(c) if !access.checked => c
+                .checked_add(access.offset)
+                .filter(|d| *d <= i32::MAX as u64),
+            _ => None,
+        };
+        if let Some(displacement) = displacement {
+            let [base, _, _] = self.context.memories[&access.memory].descriptor(assembler, rcx)?;
+            assembler.mov(rcx, base)?;
+            return Ok(rcx + displacement as i32);
+        }
+        self.load(assembler, RAX, address)?;
+        let memarg = MemoryImmediate {
+            align: 0,
+            offset: access.offset,
+            memory: access.memory,
+        };
+        if access.checked {
+            memory::effective_address(assembler, self.context, &memarg, access.size)
+        } else {
+            memory::unchecked_address(assembler, self.context, &memarg)
+        }
+    }
+
+    fn terminator(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        terminator: &Terminator,
+        fused: Option<&Op>,
+        next: Option<Block>,
+    ) -> Result<(), Error> {
+        match terminator {
+            Terminator::Jump(target) => self.jump(assembler, target, next)?,
+            Terminator::Branch(condition, then, else_) => {
+                let condition = match fused {
+                    Some(Op::Compare(op, a, b)) => self.compare(assembler, *op, *a, *b)?,
+                    Some(Op::Unary(_, a)) => self.eqz(assembler, *a)?,
+                    _ => self.eqz(assembler, *condition)?.negate(),
+                };
+                self.branch(assembler, condition, then, else_, next)?;
+            }
+            Terminator::Table(index, targets, default) => {
+                self.table(assembler, *index, targets, default)?
+            }
+            Terminator::Return(values) => {
+                if abi::has_return_area(&self.function.ty.returns) {
+                    for value in values {
+                        self.push(assembler, *value)?;
+                    }
+                    abi::leave(assembler, &self.function.ty.returns)?;
+                } else {
+                    // Integer results go to `rax` and `rdx`, which hold no value
+                    for (value, register) in values.iter().zip([RAX, RDX]) {
+                        self.load(assembler, register, *value)?;
+                    }
+                }
+                assembler.mov(rsp, rbp)?;
+                assembler.pop(rbp)?;
+                let stack_size = abi::stack_arguments_size(&self.function.ty);
+                if stack_size > 0 {
+                    assembler.ret_1(stack_size as i32)?;
+                } else {
+                    assembler.ret()?;
+                }
+            }
+            Terminator::Trap => assembler.jmp(self.context.traps.label(Trap::Unreachable))?,
+        }
+        Ok(())
+    }
+
+    /// Whether going to the target moves any argument
+    fn has_moves(&self, target: &Target) -> bool {
+        let params = &self.function.block(target.block).params;
+        target.args.iter().zip(params).any(|(arg, param)| {
+            let dst = self.location(*param);
+            dst != Location::None && dst != self.location(*arg)
+        })
+    }
+
+    /// Moves the arguments to the parameters of the target, all at once, and
+    /// jumps there unless it comes `next`
+    fn jump(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        target: &Target,
+        next: Option<Block>,
+    ) -> Result<(), Error> {
+        let params = &self.function.block(target.block).params;
+        let moves: Vec<(Location, Location)> = target
+            .args
+            .iter()
+            .zip(params)
+            .map(|(arg, param)| (self.location(*arg), self.location(*param)))
+            .filter(|(src, dst)| *dst != Location::None && src != dst)
+            .collect();
+        // Parameters may take the locations of other arguments, which then
+        // all go through the stack
+        if moves
+            .iter()
+            .any(|(_, dst)| moves.iter().any(|(src, _)| src == dst))
+        {
+            for (src, _) in &moves {
+                push(assembler, *src)?;
+            }
+            for (_, dst) in moves.iter().rev() {
+                match dst {
+                    Location::Register(r) => assembler.pop(QWORDS[*r])?,
+                    Location::Slot(o) => assembler.pop(qword_ptr(rbp - *o))?,
+                    _ => unreachable!("parameters are in registers or slots"),
+                }
+            }
+        } else {
+            for (src, dst) in moves {
+                match (dst, src) {
+                    (Location::Register(r), _) => load(assembler, r, src)?,
+                    (Location::Slot(o), Location::Register(r)) => {
+                        assembler.mov(qword_ptr(rbp - o), QWORDS[r])?
+                    }
+                    (Location::Slot(o), Location::Constant(c)) if fits_immediate(c) => {
+                        assembler.mov(qword_ptr(rbp - o), c as i32)?
+                    }
+                    (Location::Slot(o), _) => {
+                        load(assembler, RAX, src)?;
+                        assembler.mov(qword_ptr(rbp - o), rax)?;
+                    }
+                    _ => unreachable!("parameters are in registers or slots"),
+                }
+            }
+        }
+        if next != Some(target.block) {
+            assembler.jmp(self.labels[target.block.index()])?;
+        }
+        Ok(())
+    }
+
+    /// Goes to `then` if the flags meet the condition, and to `else_`
+    /// otherwise, with the arguments of either moved on the way
+    fn branch(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        condition: Condition,
+        then: &Target,
+        else_: &Target,
+        next: Option<Block>,
+    ) -> Result<(), Error> {
+        let (then_moves, else_moves) = (self.has_moves(then), self.has_moves(else_));
+        if !then_moves && (else_moves || next == Some(else_.block)) {
+            condition.jump(assembler, self.labels[then.block.index()])?;
+            return self.jump(assembler, else_, next);
+        }
+        if !else_moves {
+            condition
+                .negate()
+                .jump(assembler, self.labels[else_.block.index()])?;
+            return self.jump(assembler, then, next);
+        }
+        if !then_moves {
+            condition.jump(assembler, self.labels[then.block.index()])?;
+            return self.jump(assembler, else_, next);
+        }
+        let mut moves = assembler.create_label();
+        condition.jump(assembler, moves)?;
+        self.jump(assembler, else_, None)?;
+        self.control.bind_label(assembler, &mut moves)?;
+        self.jump(assembler, then, next)
+    }
+
+    /// Goes to the target at the index, or to `default` past them, through
+    /// a jump table
+    fn table(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        index: Value,
+        targets: &[Target],
+        default: &Target,
+    ) -> Result<(), Error> {
+        if targets.is_empty() {
+            return self.jump(assembler, default, None);
+        }
+        let mut table = assembler.create_label();
+        let mut default_stub = assembler.create_label();
+        self.load(assembler, RAX, index)?;
+        assembler.cmp(eax, targets.len() as u32)?;
+        assembler.jae(default_stub)?;
+        assembler.lea(rcx, ptr(table))?;
+        assembler.movsxd(rax, dword_ptr(rcx + rax * 4))?;
+        assembler.add(rax, rcx)?;
+        assembler.jmp(rax)?;
+
+        self.control.bind_label(assembler, &mut table)?;
+        let base = assembler.instructions().len();
+        for _ in targets {
+            assembler.dd(&[0])?;
+        }
+
+        // One stub per distinct target, moving its arguments
+        self.control.bind_label(assembler, &mut default_stub)?;
+        let mut stubs = vec![(default, assembler.instructions().len())];
+        self.jump(assembler, default, None)?;
+        for target in targets {
+            if !stubs.iter().any(|(t, _)| *t == target) {
+                stubs.push((target, assembler.instructions().len()));
+                self.jump(assembler, target, None)?;
+            }
+        }
+
+        self.jump_tables.push(JumpTable {
+            base,
+            targets: targets
+                .iter()
+                .map(|target| stubs.iter().find(|(t, _)| *t == target).unwrap().1)
+                .collect(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::segments::pop_copy_operands;
+use crate::x86_64::{Context, Error};
+use iced_x86::code_asm::{
+    eax, ecx, edx, ptr, qword_ptr, r11, rax, rcx, rdi, rdx, rsi, sil, AsmMemoryOperand,
+    AsmRegister64, CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::{MemoryImmediate, MemoryType, Type};
+
+/// Size of a WebAssembly page
+pub const PAGE_SIZE: u64 = 65536;
+
+/// Linear memory defined by a module
+///
+/// Compiled code reaches the memory through a descriptor embedded into the
+/// module binary, consisting of three 64-bit slots: base address of the memory,
+/// its current size and the size it is allowed to grow up to (both in pages).
+/// The host is expected to map the memory and fill in the descriptor using
+/// [`AssembledModule::link_memory`](super::AssembledModule::link_memory).
+///
+/// Imported memories are referred to through a slot holding the address of
+/// a descriptor, which may belong to another module (see
+/// [`AssembledModule::memory_descriptor`](super::AssembledModule::memory_descriptor))
+/// or be set up by the host, for example to hand a DMA region to the module.
+/// The host fills in the slot using
+/// [`AssembledModule::link_import`](super::AssembledModule::link_import).
+///
+/// A shared memory is shared by all threads of execution running the same
+/// module binary, which sees it grow atomically. A 64-bit memory
+/// (`memory64`) is addressed with `i64` values, so it can be larger than
+/// 4 GiB.
+pub struct Memory {
+    initial: u64,
+    maximum: Option<u64>,
+    shared: bool,
+    memory64: bool,
+    offset: usize,
+    imported: bool,
+}
+
+impl Memory {
+    pub(crate) fn new(memory_type: &MemoryType, offset: usize, imported: bool) -> Self {
+        Self {
+            initial: memory_type.initial,
+            maximum: memory_type.maximum,
+            shared: memory_type.shared,
+            memory64: memory_type.memory64,
+            offset,
+            imported,
+        }
+    }
+
+    /// Number of pages the memory starts with
+    pub fn initial_pages(&self) -> u64 {
+        self.initial
+    }
+
+    /// Number of pages the memory can grow up to, if declared
+    pub fn maximum_pages(&self) -> Option<u64> {
+        self.maximum
+    }
+
+    /// Whether the memory is declared `shared`, to be accessed by several
+    /// threads of execution
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Type of the memory's addresses and sizes: `i64` for 64-bit memories,
+    /// `i32` otherwise
+    pub fn index_type(&self) -> Type {
+        index_type(self.memory64)
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+
+    pub(crate) fn relocate(&mut self, offsets: &[u32]) {
+        self.offset = offsets[self.offset] as usize;
+    }
+
+    /// Offset of the descriptor (or the address slot, for imported
+    /// memories) in the module binary
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+fn index_type(memory64: bool) -> Type {
+    if memory64 {
+        Type::I64
+    } else {
+        Type::I32
+    }
+}
+
+/// Labels of a memory descriptor's slots, along with whether the memory is
+/// shared or 64-bit
+///
+/// For an imported memory, `base` labels the slot holding the address of
+/// the descriptor, and the other labels are unused.
+pub(crate) struct MemorySlots {
+    base: CodeLabel,
+    pages: CodeLabel,
+    limit: CodeLabel,
+    imported: bool,
+    pub(crate) shared: bool,
+    pub(crate) memory64: bool,
+}
+
+impl MemorySlots {
+    /// Emits an empty descriptor
+    pub(crate) fn emit(
+        assembler: &mut CodeAssembler,
+        memory_type: &MemoryType,
+    ) -> Result<Self, Error> {
+        let mut base = assembler.create_label();
+        let mut pages = assembler.create_label();
+        let mut limit = assembler.create_label();
+        assembler.set_label(&mut base)?;
+        assembler.dq(&[0])?;
+        assembler.set_label(&mut pages)?;
+        assembler.dq(&[0])?;
+        assembler.set_label(&mut limit)?;
+        assembler.dq(&[0])?;
+        Ok(Self {
+            base,
+            pages,
+            limit,
+            imported: false,
+            shared: memory_type.shared,
+            memory64: memory_type.memory64,
+        })
+    }
+
+    /// Emits the address slot of an imported memory
+    pub(crate) fn emit_import(
+        assembler: &mut CodeAssembler,
+        memory_type: &MemoryType,
+    ) -> Result<Self, Error> {
+        let mut base = assembler.create_label();
+        assembler.set_label(&mut base)?;
+        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+        Ok(Self {
+            base,
+            pages: assembler.create_label(),
+            limit: assembler.create_label(),
+            imported: true,
+            shared: memory_type.shared,
+            memory64: memory_type.memory64,
+        })
+    }
+
+    /// Operands for the base, pages and limit slots of the descriptor
+    ///
+    /// The address of an imported memory's descriptor is loaded into
+    /// `scratch`, which the operands refer to.
+    pub(crate) fn descriptor(
+        &self,
+        assembler: &mut CodeAssembler,
+        scratch: AsmRegister64,
+    ) -> Result<[AsmMemoryOperand; 3], Error> {
+        if self.imported {
+            assembler.mov(scratch, ptr(self.base))?;
+            Ok([
+                qword_ptr(scratch),
+                qword_ptr(scratch + 8),
+                qword_ptr(scratch + 16),
+            ])
+        } else {
+            Ok([
+                qword_ptr(self.base),
+                qword_ptr(self.pages),
+                qword_ptr(self.limit),
+            ])
+        }
+    }
+
+    pub(crate) fn index_type(&self) -> Type {
+        index_type(self.memory64)
+    }
+}
+
+/// Pops the address off the operand stack and returns the operand for the
+/// `size` bytes of memory it refers to, trapping if they are out of bounds
+///
+/// Clobbers `rax`, `rcx` and `r11`.
+pub(crate) fn address(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    memarg: &MemoryImmediate,
+    size: u32,
+) -> Result<AsmMemoryOperand, Error> {
+    assembler.pop(rax)?;
+    effective_address(assembler, context, memarg, size)
+}
+
+/// Same as [`address`], with the address in `rax` rather than on the
+/// operand stack
+pub(crate) fn effective_address(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    memarg: &MemoryImmediate,
+    size: u32,
+) -> Result<AsmMemoryOperand, Error> {
+    let slots = &context.memories[&memarg.memory];
+    let out_of_bounds = context.traps.label(Trap::OutOfBoundsMemoryAccess);
+    if !slots.memory64 {
+        // Addresses are unsigned
+        assembler.mov(eax, eax)?;
+    }
+    // The sums can only overflow with 64-bit addresses and offsets
+    let end = match memarg.offset.checked_add(size as u64) {
+        Some(end) => end,
+        None => {
+            assembler.jmp(out_of_bounds)?;
+            return Ok(rcx + rax);
+        }
+    };
+    let offset = if end > i32::MAX as u64 {
+        assembler.mov(r11, memarg.offset)?;
+        assembler.add(rax, r11)?;
+        if slots.memory64 {
+            assembler.jc(out_of_bounds)?;
+        }
+        0
+    } else {
+        memarg.offset
+    };
+    if slots.memory64 {
+        assembler.mov(rcx, rax)?;
+        assembler.add(rcx, (offset + size as u64) as i32)?;
+        assembler.jc(out_of_bounds)?;
+    } else {
+        assembler.lea(rcx, ptr(rax + (offset + size as u64)))?;
+    }
+    bounds_check(assembler, context, memarg.memory, rcx)?;
+    let [base, _, _] = slots.descriptor(assembler, rcx)?;
+    assembler.mov(rcx, base)?;
+    Ok(rcx + rax + offset)
+}
+
+/// Same as [`effective_address`], for accesses known to be within bounds,
+/// which aren't checked
+///
+/// Clobbers `rax`, `rcx` and `r11`.
+pub(crate) fn unchecked_address(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    memarg: &MemoryImmediate,
+) -> Result<AsmMemoryOperand, Error> {
+    let slots = &context.memories[&memarg.memory];
+    if !slots.memory64 {
+        assembler.mov(eax, eax)?;
+    }
+    let offset = if memarg.offset > i32::MAX as u64 {
+        assembler.mov(r11, memarg.offset)?;
+        assembler.add(rax, r11)?;
+        0
+    } else {
+        memarg.offset
+    };
+    let [base, _, _] = slots.descriptor(assembler, rcx)?;
+    assembler.mov(rcx, base)?;
+    Ok(rcx + rax + offset)
+}
+
+/// Pops the value to store into `rdx` and returns the operand for the memory
+/// it should be stored to
+pub(crate) fn store_address(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    memarg: &MemoryImmediate,
+    size: u32,
+) -> Result<AsmMemoryOperand, Error> {
+    assembler.pop(rdx)?;
+    address(assembler, context, memarg, size)
+}
+
+/// Traps unless `end` (exclusive) is within the memory
+///
+/// Clobbers `r11`.
+pub(crate) fn bounds_check(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    mem: u32,
+    end: AsmRegister64,
+) -> Result<(), Error> {
+    let [_, pages, _] = context.memories[&mem].descriptor(assembler, r11)?;
+    assembler.mov(r11, pages)?;
+    assembler.shl(r11, PAGE_SIZE.trailing_zeros())?;
+    assembler.cmp(end, r11)?;
+    assembler.ja(context.traps.label(Trap::OutOfBoundsMemoryAccess))?;
+    Ok(())
+}
+
+pub(crate) fn size(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    mem: u32,
+) -> Result<(), Error> {
+    let slots = &context.memories[&mem];
+    let [_, pages, _] = slots.descriptor(assembler, rax)?;
+    assembler.push(pages)?;
+    control.push(slots.index_type());
+    Ok(())
+}
+
+/// Sets `rax` to the end of the `rcx` bytes from `start` in a memory,
+/// trapping if the sum overflows, which only 64-bit operands can
+///
+/// Clobbers `r11`.
+pub(crate) fn range_end(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    mem: u32,
+    start: AsmRegister64,
+) -> Result<(), Error> {
+    if context.memories[&mem].memory64 {
+        assembler.mov(rax, start)?;
+        assembler.add(rax, rcx)?;
+        assembler.jc(context.traps.label(Trap::OutOfBoundsMemoryAccess))?;
+    } else {
+        assembler.lea(rax, ptr(start + rcx))?;
+    }
+    bounds_check(assembler, context, mem, rax)
+}
+
+/// Turns the address in `register` into an absolute one
+///
+/// Clobbers `r11`.
+pub(crate) fn add_base(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    mem: u32,
+    register: AsmRegister64,
+) -> Result<(), Error> {
+    let [base, _, _] = context.memories[&mem].descriptor(assembler, r11)?;
+    assembler.add(register, base)?;
+    Ok(())
+}
+
+/// Grows the memory within the limit set by the host, pushing the previous
+/// size, or -1 if the memory can't grow that much
+///
+/// Shared memories are grown with `cmpxchg`, retrying if another thread
+/// grew the memory in the meantime.
+pub(crate) fn grow(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    mem: u32,
+) -> Result<(), Error> {
+    let slots = &context.memories[&mem];
+    let mut failed = assembler.create_label();
+    let mut done = assembler.create_label();
+    let mut retry = assembler.create_label();
+    assembler.pop(rdx)?;
+    control.pop(slots.index_type());
+    if !slots.memory64 {
+        assembler.mov(edx, edx)?;
+    }
+    let [_, pages, limit] = slots.descriptor(assembler, r11)?;
+    assembler.mov(rax, pages)?;
+    if slots.shared {
+        control.bind_label(assembler, &mut retry)?;
+    }
+    assembler.mov(rcx, rax)?;
+    assembler.add(rcx, rdx)?;
+    assembler.jc(failed)?;
+    assembler.cmp(rcx, limit)?;
+    assembler.ja(failed)?;
+    if slots.shared {
+        // On failure, the current size is loaded into `rax`
+        assembler.lock().cmpxchg(pages, rcx)?;
+        assembler.jne(retry)?;
+    } else {
+        assembler.mov(pages, rcx)?;
+    }
+    assembler.push(rax)?;
+    assembler.jmp(done)?;
+    control.bind_label(assembler, &mut failed)?;
+    if slots.memory64 {
+        assembler.push(-1)?;
+    } else {
+        assembler.mov(eax, u32::MAX)?;
+        assembler.push(rax)?;
+    }
+    control.bind_label(assembler, &mut done)?;
+    control.push(slots.index_type());
+    Ok(())
+}
+
+/// `memory.copy`: pops the length, source and destination addresses, and
+/// copies the bytes, as if through a temporary buffer
+pub(crate) fn copy(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    src: u32,
+    dst: u32,
+) -> Result<(), Error> {
+    let dst_type = context.memories[&dst].index_type();
+    let src_type = context.memories[&src].index_type();
+    // The length is 64-bit only when both memories are
+    let len_type = if dst_type == Type::I64 && src_type == Type::I64 {
+        Type::I64
+    } else {
+        Type::I32
+    };
+    pop_copy_operands(assembler, control, [dst_type, src_type, len_type])?;
+    range_end(assembler, context, src, rsi)?;
+    range_end(assembler, context, dst, rdi)?;
+    add_base(assembler, context, src, rsi)?;
+    add_base(assembler, context, dst, rdi)?;
+    copy_items(assembler, control, 1)
+}
+
+/// `memory.fill`: pops the length, the byte value and the destination
+/// address, and sets the bytes to the value
+pub(crate) fn fill(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    mem: u32,
+) -> Result<(), Error> {
+    let index_type = context.memories[&mem].index_type();
+    pop_copy_operands(assembler, control, [index_type, Type::I32, index_type])?;
+    range_end(assembler, context, mem, rdi)?;
+    add_base(assembler, context, mem, rdi)?;
+    // Whole quadwords are filled with the byte broadcast, the rest bytewise
+    assembler.movzx(eax, sil)?;
+    assembler.mov(rsi, 0x0101_0101_0101_0101u64)?;
+    assembler.imul_2(rax, rsi)?;
+    assembler.mov(rdx, rcx)?;
+    assembler.shr(rcx, 3)?;
+    assembler.rep().stosq()?;
+    assembler.mov(ecx, edx)?;
+    assembler.and(ecx, 7)?;
+    assembler.rep().stosb()?;
+    Ok(())
+}
+
+/// Copies `rcx` items of `size` bytes (1 or 8) from `rsi` to `rdi`
+///
+/// The copy goes backwards when the destination overlaps the end of the
+/// source, so that the source is read before it gets overwritten. Bytes are
+/// copied as quadwords where possible. Clobbers `rax` and `rdx`.
+pub(crate) fn copy_items(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    size: u32,
+) -> Result<(), Error> {
+    let mut backwards = assembler.create_label();
+    let mut done = assembler.create_label();
+    assembler.mov(rax, rdi)?;
+    assembler.sub(rax, rsi)?;
+    if size == 1 {
+        assembler.cmp(rax, rcx)?;
+    } else {
+        assembler.lea(rdx, ptr(rcx * 8))?;
+        assembler.cmp(rax, rdx)?;
+    }
+    // The difference is below the length only if the destination is in the
+    // source, not before it
+    assembler.jb(backwards)?;
+    if size == 1 {
+        assembler.mov(rax, rcx)?;
+        assembler.shr(rcx, 3)?;
+        assembler.rep().movsq()?;
+        assembler.mov(ecx, eax)?;
+        assembler.and(ecx, 7)?;
+        assembler.rep().movsb()?;
+    } else {
+        assembler.rep().movsq()?;
+    }
+    assembler.jmp(done)?;
+    control.bind_label(assembler, &mut backwards)?;
+    assembler.std()?;
+    if size == 1 {
+        // The bytes past the last whole quadword first, then the quadwords
+        assembler.lea(rsi, ptr(rsi + rcx - 1))?;
+        assembler.lea(rdi, ptr(rdi + rcx - 1))?;
+        assembler.mov(rax, rcx)?;
+        assembler.and(ecx, 7)?;
+        assembler.rep().movsb()?;
+        assembler.sub(rsi, 7)?;
+        assembler.sub(rdi, 7)?;
+        assembler.mov(rcx, rax)?;
+        assembler.shr(rcx, 3)?;
+    } else {
+        assembler.lea(rsi, ptr(rsi + rcx * 8 - 8))?;
+        assembler.lea(rdi, ptr(rdi + rcx * 8 - 8))?;
+    }
+    assembler.rep().movsq()?;
+    assembler.cld()?;
+    control.bind_label(assembler, &mut done)?;
+    Ok(())
+}
@@ -1,46 +1,81 @@
+use crate::ir;
+use crate::trap::Trap;
 use crate::Compiler;
 use alloc::borrow::ToOwned;
-use alloc::collections::{BTreeMap, VecDeque};
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use byteorder::{ByteOrder, LittleEndian};
 use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
-use iced_x86::code_asm::{
-    dword_ptr, ptr, qword_ptr, r11, r8, r9, rax, rbp, rcx, rdi, rdx, rsi, rsp, AsmRegister64,
-    CodeAssembler,
-};
-use iced_x86::IcedError;
+use iced_x86::code_asm::{qword_ptr, rbp, rsp, CodeAssembler, CodeLabel};
+use iced_x86::{BlockEncoder, BlockEncoderOptions, IcedError, InstructionBlock};
 use wasmparser_nostd::*;
 
+mod abi;
+mod artifact;
+mod atomics;
+mod control;
+mod exceptions;
+mod float;
+mod globals;
+mod init;
 mod instructions;
+mod integer;
+mod lowering;
+mod memory;
+mod operands;
+mod registers;
+mod segments;
+mod simd;
+mod table;
+mod traps;
+mod validation;
 
-trait EncodingSize {
-    fn encoding_size(&self) -> u32;
-}
-
-impl EncodingSize for Type {
-    fn encoding_size(&self) -> u32 {
-        match self {
-            Type::I32 => 4,
-            Type::I64 => 8,
-            Type::F32 => 4,
-            Type::F64 => 8,
-            Type::V128 => 16,
-            Type::FuncRef => todo!(),
-            Type::ExternRef => todo!(),
-            Type::ExnRef => todo!(),
-            Type::Func => todo!(),
-            Type::EmptyBlockType => todo!(),
-        }
-    }
-}
+pub use artifact::{
+    relocations, source_hash, ArtifactError, ArtifactHeader, Relocation, RelocationKind,
+    CODEGEN_VERSION, FORMAT_VERSION, MAGIC,
+};
+use atomics::Futex;
+pub use atomics::FutexOp;
+use control::{ControlStack, JumpTable};
+use exceptions::{Handlers, TagSlot};
+pub use exceptions::{EXCEPTION_PAYLOAD_SIZE, EXCEPTION_STATE_SIZE};
+pub use globals::Global;
+use globals::GlobalSlot;
+use init::Instantiation;
+use memory::MemorySlots;
+pub use memory::{Memory, PAGE_SIZE};
+use registers::ValueStack;
+use segments::SegmentSlots;
+pub use segments::{Segment, SegmentMode};
+pub use table::Table;
+use table::TableSlots;
+use traps::TrapStubs;
 
 #[derive(Debug)]
 pub enum Error {
     WasmReaderError(BinaryReaderError),
     AssemblerError(IcedError),
+    /// Values of the tag at this index don't fit in
+    /// [`EXCEPTION_PAYLOAD_SIZE`]
+    TagPayloadTooLarge(u32),
+    /// The module is malformed or invalid, as found at this byte offset
+    InvalidModule {
+        offset: usize,
+        message: String,
+    },
+    /// The body of the function at this index is invalid, as found at this
+    /// byte offset
+    InvalidFunction {
+        function: u32,
+        offset: usize,
+        message: String,
+    },
+    /// The module being recompiled wasn't compiled from the given code, or
+    /// with the same configuration
+    ModuleMismatch,
 }
 
 impl From<BinaryReaderError> for Error {
@@ -55,19 +90,282 @@ impl From<IcedError> for Error {
     }
 }
 
-pub struct X86_64Compiler;
+/// Module-wide state shared by all function bodies during compilation
+pub(crate) struct Context {
+    /// Entry points of functions defined in the module
+    pub(crate) got: BTreeMap<u32, CodeLabel>,
+    /// Slots holding the address of the current code of functions defined in
+    /// the module, which calls go through when compiling in tiers
+    pub(crate) function_slots: BTreeMap<u32, CodeLabel>,
+    /// Counters of calls and loop iterations of functions defined in the
+    /// module, when compiling in tiers
+    pub(crate) counters: BTreeMap<u32, CodeLabel>,
+    /// Address slots of imported functions
+    pub(crate) ils: BTreeMap<u32, CodeLabel>,
+    /// Stubs jumping to imported functions, which references to them point
+    /// at, as they need to be preceded by their signature
+    pub(crate) import_stubs: BTreeMap<u32, CodeLabel>,
+    pub(crate) function_typedefs: BTreeMap<u32, FuncType>,
+    pub(crate) function_types: BTreeMap<u32, u32>,
+    pub(crate) memories: BTreeMap<u32, MemorySlots>,
+    pub(crate) tables: BTreeMap<u32, TableSlots>,
+    pub(crate) globals: BTreeMap<u32, GlobalSlot>,
+    pub(crate) data_segments: BTreeMap<u32, SegmentSlots>,
+    pub(crate) element_segments: BTreeMap<u32, SegmentSlots>,
+    pub(crate) tags: BTreeMap<u32, TagSlot>,
+    pub(crate) handlers: Handlers,
+    pub(crate) traps: TrapStubs,
+    pub(crate) futex: Futex,
+    pub(crate) features: Features,
+}
+
+impl Context {
+    fn new(assembler: &mut CodeAssembler, features: Features) -> Self {
+        Self {
+            got: BTreeMap::new(),
+            function_slots: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            ils: BTreeMap::new(),
+            import_stubs: BTreeMap::new(),
+            function_typedefs: BTreeMap::new(),
+            function_types: BTreeMap::new(),
+            memories: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            globals: BTreeMap::new(),
+            data_segments: BTreeMap::new(),
+            element_segments: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            handlers: Handlers::new(assembler),
+            traps: TrapStubs::new(assembler),
+            futex: Futex::new(assembler),
+            features,
+        }
+    }
+
+    pub(crate) fn function_type(&self, function_index: u32) -> Option<&FuncType> {
+        self.function_types
+            .get(&function_index)
+            .and_then(|t| self.function_typedefs.get(t))
+    }
+
+    /// Calls a function, defined in the module or imported
+    pub(crate) fn call(
+        &self,
+        assembler: &mut CodeAssembler,
+        function_index: u32,
+    ) -> Result<(), Error> {
+        // Imported functions follow System V, so they are called through
+        // their stubs, which pop stack arguments
+        if let Some(slot) = self.function_slots.get(&function_index) {
+            assembler.call(qword_ptr(*slot))?;
+        } else if let Some(label) = self.got.get(&function_index) {
+            assembler.call(*label)?;
+        } else {
+            assembler.call(self.import_stubs[&function_index])?;
+        }
+        Ok(())
+    }
+
+    /// Jumps to a function, for tail calls
+    pub(crate) fn jump(
+        &self,
+        assembler: &mut CodeAssembler,
+        function_index: u32,
+    ) -> Result<(), Error> {
+        if let Some(slot) = self.function_slots.get(&function_index) {
+            assembler.jmp(qword_ptr(*slot))?;
+        } else if let Some(label) = self.got.get(&function_index) {
+            assembler.jmp(*label)?;
+        } else {
+            assembler.jmp(self.import_stubs[&function_index])?;
+        }
+        Ok(())
+    }
+
+    /// Type of the addresses of a memory
+    pub(crate) fn index_type(&self, mem: u32) -> Type {
+        self.memories[&mem].index_type()
+    }
+}
+
+/// Instruction set extensions the generated code may use
+///
+/// The baseline is SSE2, which every x86_64 processor has. Operators
+/// lacking a baseline instruction are emulated, with longer sequences or
+/// lane by lane.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    /// SSE4.1, and the SSSE3 instructions that come with it
+    pub sse4_1: bool,
+    /// `popcnt`
+    pub popcnt: bool,
+}
+
+impl Features {
+    /// Extensions of the processor this runs on
+    pub fn detect() -> Self {
+        let info = core::arch::x86_64::__cpuid(1);
+        Self {
+            sse4_1: info.ecx & (1 << 19) != 0 && info.ecx & (1 << 9) != 0,
+            popcnt: info.ecx & (1 << 23) != 0,
+        }
+    }
+}
+
+pub struct X86_64Compiler {
+    features: Features,
+    register_allocation: bool,
+    optimize: bool,
+    tiered: bool,
+}
 
-impl core::default::Default for X86_64Compiler {
+impl Default for X86_64Compiler {
     fn default() -> Self {
-        X86_64Compiler
+        Self::new(Features::default())
+    }
+}
+
+impl X86_64Compiler {
+    /// Compiler generating code for processors with `features`
+    pub fn new(features: Features) -> Self {
+        Self {
+            features,
+            register_allocation: true,
+            optimize: false,
+            tiered: false,
+        }
+    }
+
+    /// Sets whether integer operands are kept in registers where possible,
+    /// which is the default, rather than always going through the machine
+    /// stack
+    pub fn register_allocation(mut self, enabled: bool) -> Self {
+        self.register_allocation = enabled;
+        self
+    }
+
+    /// Sets whether functions are optimized, which they aren't by default
+    ///
+    /// Integer functions are then compiled through an SSA form, where
+    /// constants are folded, dead code is removed, locals become values kept
+    /// in registers across blocks, small functions are inlined and bounds
+    /// checks proven redundant are dropped. Other functions are compiled as
+    /// usual.
+    pub fn optimize(mut self, enabled: bool) -> Self {
+        self.optimize = enabled;
+        self
+    }
+
+    /// Sets whether code is compiled in tiers, which it isn't by default
+    ///
+    /// All functions are then compiled as if [unoptimized](Self::optimize),
+    /// and count how many times they get called or go around a loop. The
+    /// host can look for [hot](AssembledModule::hot_functions) ones once in
+    /// a while, have them [recompiled](Self::recompile) with optimizations
+    /// and [linked](AssembledModule::link_function) in their place.
+    pub fn tiered(mut self, enabled: bool) -> Self {
+        self.tiered = enabled;
+        self
+    }
+
+    /// Compiles an optimized version of a function of a module compiled in
+    /// tiers, to be placed at `address`, given the address the module is
+    /// loaded at
+    ///
+    /// `module` must have been compiled from `wasm` by a compiler with the
+    /// same configuration, or this fails with
+    /// [`ModuleMismatch`](Error::ModuleMismatch). Returns `None` if the
+    /// function can't be optimized, as it uses floats or vectors, for
+    /// example; either way, it isn't reported as
+    /// [hot](AssembledModule::hot_functions) anymore, unless this fails.
+    ///
+    /// Every call translates the whole module again, to resolve the
+    /// function's references to the rest of it, so recompiling a function
+    /// costs about as much as compiling the module.
+    ///
+    /// The code has to stay within 2 GiB of the module, as it refers to the
+    /// module's code and data relative to where it is.
+    pub fn recompile(
+        &self,
+        module: &mut AssembledModule,
+        wasm: &[u8],
+        function_index: u32,
+        base: u64,
+        address: u64,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if module.source_hash != source_hash(wasm) || module.features != self.features {
+            return Err(Error::ModuleMismatch);
+        }
+        validation::validate(wasm)?;
+        let mut ir = ir::Module::build(wasm)?;
+        ir.optimize();
+        let Some(function) = ir.functions.get(&function_index) else {
+            module.recompiled.insert(function_index);
+            return Ok(None);
+        };
+        let mut translation = self.translate(wasm, None)?;
+        let assembler = &mut translation.assembler;
+        let start = assembler.instructions().len();
+        let jump_tables = lowering::lower(assembler, &translation.context, function)?;
+        // Makes sure there are no dangling labels or prefixes
+        assembler.assemble(0)?;
+        // The module's code is only encoded again to resolve references to it
+        let (module_code, code) = assembler.instructions().split_at(start);
+        let mut results = BlockEncoder::encode_slice(
+            64,
+            &[
+                InstructionBlock::new(module_code, base),
+                InstructionBlock::new(code, address),
+            ],
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )?;
+        let result = results.pop().unwrap();
+        if results[0].code_buffer.len() != module.binary().len() {
+            return Err(Error::ModuleMismatch);
+        }
+        let mut code = result.code_buffer;
+        let offsets = result.new_instruction_offsets;
+        fill_jump_tables(&mut code, &jump_tables, |index| offsets[index - start]);
+        module.recompiled.insert(function_index);
+        Ok(Some(code))
     }
 }
 
 pub struct Module {
+    /// Slots holding the address of functions' current code, when compiled in
+    /// tiers
     functions: BTreeMap<u32, usize>,
+    /// Counters of calls and loop iterations, when compiled in tiers
+    counters: BTreeMap<u32, usize>,
+    /// Functions compiled in tiers that were recompiled
+    recompiled: BTreeSet<u32>,
     function_bodies: BTreeMap<u32, usize>,
     exports: BTreeMap<String, u32>,
     imports: BTreeMap<u32, (String, Option<String>, usize)>,
+    import_stubs: BTreeMap<u32, usize>,
+    /// System V entry points of functions that pop their stack arguments
+    wrappers: BTreeMap<u32, usize>,
+    globals: BTreeMap<u32, Global>,
+    global_imports: BTreeMap<u32, (String, Option<String>, usize)>,
+    global_exports: BTreeMap<String, u32>,
+    memories: BTreeMap<u32, Memory>,
+    memory_imports: BTreeMap<u32, (String, Option<String>, usize)>,
+    memory_exports: BTreeMap<String, u32>,
+    tables: BTreeMap<u32, Table>,
+    table_imports: BTreeMap<u32, (String, Option<String>, usize)>,
+    table_exports: BTreeMap<String, u32>,
+    data_segments: Vec<Segment>,
+    element_segments: Vec<Segment>,
+    tags: BTreeMap<u32, usize>,
+    tag_imports: BTreeMap<u32, (String, Option<String>, usize)>,
+    tag_exports: BTreeMap<String, u32>,
+    instantiation: usize,
+    trap_handler: usize,
+    exception_state: usize,
+    futex: usize,
+    floating_point: bool,
+    source_hash: u64,
+    features: Features,
 }
 
 pub struct FunctionIndex(u32);
@@ -95,12 +393,74 @@ impl Module {
     fn new() -> Self {
         Self {
             functions: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            recompiled: BTreeSet::new(),
             function_bodies: BTreeMap::new(),
             exports: BTreeMap::new(),
             imports: BTreeMap::new(),
+            import_stubs: BTreeMap::new(),
+            wrappers: BTreeMap::new(),
+            globals: BTreeMap::new(),
+            global_imports: BTreeMap::new(),
+            global_exports: BTreeMap::new(),
+            memories: BTreeMap::new(),
+            memory_imports: BTreeMap::new(),
+            memory_exports: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            table_imports: BTreeMap::new(),
+            table_exports: BTreeMap::new(),
+            data_segments: vec![],
+            element_segments: vec![],
+            tags: BTreeMap::new(),
+            tag_imports: BTreeMap::new(),
+            tag_exports: BTreeMap::new(),
+            instantiation: 0,
+            trap_handler: 0,
+            exception_state: 0,
+            futex: 0,
+            floating_point: false,
+            source_hash: 0,
+            features: Features::default(),
         }
     }
 
+    /// Turns recorded instruction indices into offsets in the final code
+    ///
+    /// Offsets can only be known once the code is assembled, as branches
+    /// (for example, to trap stubs at the end of the module) get shortened
+    /// whenever possible.
+    fn relocate(&mut self, offsets: &[u32]) {
+        let relocate = |index: &mut usize| *index = offsets[*index] as usize;
+        self.functions
+            .values_mut()
+            .chain(self.counters.values_mut())
+            .chain(self.function_bodies.values_mut())
+            .chain(self.import_stubs.values_mut())
+            .chain(self.wrappers.values_mut())
+            .for_each(relocate);
+        self.imports
+            .values_mut()
+            .chain(self.global_imports.values_mut())
+            .chain(self.memory_imports.values_mut())
+            .chain(self.table_imports.values_mut())
+            .chain(self.tag_imports.values_mut())
+            .for_each(|(_, _, index)| relocate(index));
+        self.tags.values_mut().for_each(relocate);
+        self.globals
+            .values_mut()
+            .for_each(|global| global.relocate(offsets));
+        self.memories
+            .values_mut()
+            .for_each(|memory| memory.relocate(offsets));
+        self.tables
+            .values_mut()
+            .for_each(|table| table.relocate(offsets));
+        relocate(&mut self.instantiation);
+        relocate(&mut self.trap_handler);
+        relocate(&mut self.exception_state);
+        relocate(&mut self.futex);
+    }
+
     fn assembled(self, assembled: Vec<u8>) -> AssembledModule {
         AssembledModule {
             module: self,
@@ -108,10 +468,100 @@ impl Module {
         }
     }
 
+    /// Offset of a function's code
+    ///
+    /// Functions follow the System V calling convention, with `i32` and `i64`
+    /// values passed as integers, and `f32` and `f64` as floats. Results are
+    /// returned in `rax`, `rdx`, `xmm0` and `xmm1` when there are at most
+    /// two of each class. Otherwise, the caller passes the address of a
+    /// return area as a hidden first argument in `rdi`; all results are
+    /// stored there in order, in 8-byte slots, and the address is returned
+    /// in `rax`.
+    ///
+    /// Functions with stack arguments are entered through a wrapper, as
+    /// compiled code calls them with the arguments popped by the callee.
     pub fn function_entry_point<I: FunctionIdentifier>(&self, identifier: I) -> Option<usize> {
-        identifier
-            .find_function(self)
-            .and_then(|idx| self.function_bodies.get(&idx).cloned())
+        identifier.find_function(self).and_then(|idx| {
+            self.wrappers
+                .get(&idx)
+                .or_else(|| self.function_bodies.get(&idx))
+                .cloned()
+        })
+    }
+
+    /// Offset of the code a reference to a function points at
+    ///
+    /// That is the function's code, or a stub calling an imported function.
+    /// Either is preceded by the identifier of the function's type, so only
+    /// such addresses (relative to where the module is loaded) can be stored
+    /// in tables. Unlike [entry points](Module::function_entry_point), they
+    /// pop their stack arguments, so the host shouldn't call them directly.
+    pub fn function_reference(&self, function_index: u32) -> Option<usize> {
+        self.function_bodies
+            .get(&function_index)
+            .or_else(|| self.import_stubs.get(&function_index))
+            .cloned()
+    }
+
+    /// Linear memory of the module, defined or imported
+    pub fn memory(&self, index: u32) -> Option<&Memory> {
+        self.memories.get(&index)
+    }
+
+    /// Number of linear memories of the module, including imported ones
+    pub fn memory_count(&self) -> u32 {
+        self.memories.len() as u32
+    }
+
+    /// Index of the memory exported under `name`
+    pub fn memory_export(&self, name: &str) -> Option<u32> {
+        self.memory_exports.get(name).cloned()
+    }
+
+    /// Global exported under `name`
+    pub fn global(&self, name: &str) -> Option<&Global> {
+        self.global_exports
+            .get(name)
+            .and_then(|index| self.globals.get(index))
+    }
+
+    pub fn table(&self, index: u32) -> Option<&Table> {
+        self.tables.get(&index)
+    }
+
+    /// Index of the table exported under `name`
+    pub fn table_export(&self, name: &str) -> Option<u32> {
+        self.table_exports.get(name).cloned()
+    }
+
+    pub fn data_segments(&self) -> &[Segment] {
+        &self.data_segments
+    }
+
+    pub fn element_segments(&self) -> &[Segment] {
+        &self.element_segments
+    }
+
+    /// Offset of the instantiation routine
+    ///
+    /// Once all imports and memories are linked, the host must call it
+    /// (with no arguments) before calling any of the module's functions.
+    /// It initializes tables and memories from active segments and runs
+    /// the start function.
+    pub fn instantiation_entry_point(&self) -> usize {
+        self.instantiation
+    }
+
+    /// Whether the module's code uses floating-point or vector instructions
+    ///
+    /// Floats and vectors are handled with SSE, in XMM registers, and assume the
+    /// default MXCSR (round to nearest even, all exceptions masked, no
+    /// flushing of denormals). The host must enable SSE before running such
+    /// a module, and save and restore XMM registers and MXCSR (for example,
+    /// with `fxsave` and `fxrstor`) when it switches away from the module's
+    /// code. Modules that don't use floats leave that state untouched.
+    pub fn uses_floating_point(&self) -> bool {
+        self.floating_point
     }
 }
 
@@ -139,11 +589,23 @@ impl AssembledModule {
         &self.assembled
     }
 
+    /// Fills in the address of an imported function, global, memory, table
+    /// or tag
+    ///
+    /// For globals, `addr` is the address of the value. For memories and
+    /// tables, it is the address of the descriptor (see
+    /// [`memory_descriptor`](AssembledModule::memory_descriptor) and
+    /// [`table_descriptor`](AssembledModule::table_descriptor)). For tags, it
+    /// is the [identity](AssembledModule::tag_identity) of the tag.
     pub fn link_import(&mut self, module: &str, name: Option<&str>, addr: u64) {
         let relocation = self
             .imports
-            .iter()
-            .find_map(|(_, (module_, name_, offset))| {
+            .values()
+            .chain(self.global_imports.values())
+            .chain(self.memory_imports.values())
+            .chain(self.table_imports.values())
+            .chain(self.tag_imports.values())
+            .find_map(|(module_, name_, offset)| {
                 let names_equal = match (name, name_) {
                     (None, None) => false,
                     (None, Some(_)) => false,
@@ -164,6 +626,207 @@ impl AssembledModule {
             None => (),
         }
     }
+
+    /// Sets the address of the handler called by compiled code to raise a trap
+    ///
+    /// The handler is called following the System V calling convention with
+    /// the [code](crate::trap::Trap::code) of the trap as its only argument,
+    /// and must not return.
+    pub fn link_trap_handler(&mut self, addr: u64) {
+        let offset = self.module.trap_handler;
+        LittleEndian::write_u64(&mut self.assembled[offset..offset + size_of::<u64>()], addr);
+    }
+
+    /// Sets the address of the exception state, a block of
+    /// [`EXCEPTION_STATE_SIZE`] zeroed bytes provided by the host
+    ///
+    /// Handlers of `try` blocks are kept in a chain that starts there, so
+    /// modules throwing exceptions at each other must share the state, and
+    /// every thread of execution needs its own. Exceptions unwind straight
+    /// to the handler, through any frames in between, so host functions
+    /// called by compiled code must not keep state that unwinding would
+    /// leave inconsistent. Exceptions that nothing catches raise
+    /// [`UncaughtException`](crate::trap::Trap::UncaughtException), with the
+    /// exception left in the state.
+    pub fn link_exception_state(&mut self, addr: u64) {
+        let offset = self.module.exception_state;
+        LittleEndian::write_u64(&mut self.assembled[offset..offset + size_of::<u64>()], addr);
+    }
+
+    /// Sets the address of the hook called by compiled code for
+    /// `memory.atomic.wait*` and `memory.atomic.notify` on shared memories
+    ///
+    /// The hook follows the System V calling convention, with the
+    /// operations described by [`FutexOp`]. Waiting blocks the calling
+    /// thread of execution, so the host should run other ones meanwhile.
+    pub fn link_futex(&mut self, addr: u64) {
+        let offset = self.module.futex;
+        LittleEndian::write_u64(&mut self.assembled[offset..offset + size_of::<u64>()], addr);
+    }
+
+    /// Identity of the tag exported under `name`, given the address the
+    /// module is loaded at
+    ///
+    /// Exceptions are matched against tags by identity, which is the
+    /// address of a tag defined by a module. Modules importing the tag must
+    /// be linked with it.
+    pub fn tag_identity(&self, name: &str, base: u64) -> Option<u64> {
+        let index = self.module.tag_exports.get(name)?;
+        let offset = *self.module.tags.get(index)?;
+        if self.module.tag_imports.contains_key(index) {
+            Some(LittleEndian::read_u64(&self.assembled[offset..]))
+        } else {
+            Some(base + offset as u64)
+        }
+    }
+
+    /// Address of the descriptor of the memory exported under `name`, given
+    /// the address the module is loaded at
+    ///
+    /// Modules importing the memory must be linked with it, so that they
+    /// all see it grow. A descriptor set up by the host consists of three
+    /// 64-bit values: the base address, the current size and the size the
+    /// memory can grow up to, in pages.
+    pub fn memory_descriptor(&self, name: &str, base: u64) -> Option<u64> {
+        let memory = self
+            .module
+            .memories
+            .get(self.module.memory_exports.get(name)?)?;
+        if memory.is_imported() {
+            Some(LittleEndian::read_u64(&self.assembled[memory.offset()..]))
+        } else {
+            Some(base + memory.offset() as u64)
+        }
+    }
+
+    /// Makes the memory mapped by the host at `base` available to the module
+    ///
+    /// The host must have reserved `capacity` pages of zeroed memory there;
+    /// the memory can grow up to that size or its declared maximum,
+    /// whichever is smaller. Imported memories are linked with
+    /// [`link_import`](AssembledModule::link_import) instead.
+    pub fn link_memory(&mut self, index: u32, base: u64, capacity: u64) {
+        if let Some(memory) = self
+            .module
+            .memories
+            .get(&index)
+            .filter(|memory| !memory.is_imported())
+        {
+            let limit = memory
+                .maximum_pages()
+                .map_or(capacity, |maximum| maximum.min(capacity));
+            let descriptor = [base, memory.initial_pages(), limit];
+            let offset = memory.offset();
+            for (i, value) in descriptor.iter().enumerate() {
+                let slot = offset + i * size_of::<u64>();
+                LittleEndian::write_u64(&mut self.assembled[slot..slot + size_of::<u64>()], *value);
+            }
+        }
+    }
+
+    /// Address of the descriptor of the table exported under `name`, given
+    /// the address the module is loaded at
+    ///
+    /// Modules importing the table are linked with it, and share its entries
+    /// with this module.
+    pub fn table_descriptor(&self, name: &str, base: u64) -> Option<u64> {
+        let table = self
+            .module
+            .tables
+            .get(self.module.table_exports.get(name)?)?;
+        if table.is_imported() {
+            Some(LittleEndian::read_u64(&self.assembled[table.offset()..]))
+        } else {
+            Some(base + table.offset() as u64)
+        }
+    }
+
+    /// Current number of entries of a table defined by the module
+    ///
+    /// Imported tables live in the binary of the module exporting them.
+    pub fn table_size(&self, table: u32) -> Option<u32> {
+        let table = self.module.tables.get(&table)?;
+        if table.is_imported() {
+            return None;
+        }
+        Some(LittleEndian::read_u64(&self.assembled[table.offset()..]) as u32)
+    }
+
+    /// Offset of a table entry in the binary, if the index is within the
+    /// table's current size
+    fn table_entry_offset(&self, table: u32, index: u32) -> Result<usize, Trap> {
+        match (self.module.tables.get(&table), self.table_size(table)) {
+            (Some(t), Some(size)) if index < size => {
+                // Entries follow the size and limit slots
+                Ok(t.offset() + (2 + index as usize) * size_of::<u64>())
+            }
+            _ => Err(Trap::OutOfBoundsTableAccess),
+        }
+    }
+
+    /// Entry of a table, as an absolute address, or zero for a null reference
+    pub fn table_entry(&self, table: u32, index: u32) -> Result<u64, Trap> {
+        let offset = self.table_entry_offset(table, index)?;
+        Ok(LittleEndian::read_u64(&self.assembled[offset..]))
+    }
+
+    /// Sets an entry of a table
+    ///
+    /// `reference` must be zero or the absolute address of a
+    /// [function reference](Module::function_reference) of a module, which
+    /// may be another one than this.
+    pub fn set_table_entry(&mut self, table: u32, index: u32, reference: u64) -> Result<(), Trap> {
+        let offset = self.table_entry_offset(table, index)?;
+        LittleEndian::write_u64(
+            &mut self.assembled[offset..offset + size_of::<u64>()],
+            reference,
+        );
+        Ok(())
+    }
+
+    /// Number of times a function compiled in tiers was called or went
+    /// around one of its loops
+    pub fn execution_count(&self, function_index: u32) -> Option<u64> {
+        let offset = *self.module.counters.get(&function_index)?;
+        Some(LittleEndian::read_u64(&self.assembled[offset..]))
+    }
+
+    /// Functions compiled in tiers that were called or went around their
+    /// loops at least `threshold` times, and weren't recompiled yet
+    pub fn hot_functions(&self, threshold: u64) -> Vec<u32> {
+        self.module
+            .counters
+            .keys()
+            .copied()
+            .filter(|index| !self.module.recompiled.contains(index))
+            .filter(|index| self.execution_count(*index) >= Some(threshold))
+            .collect()
+    }
+
+    /// Sets the address of the code of a function compiled in tiers, once
+    /// the module is instantiated
+    ///
+    /// Calls from the module's code then go there. Calls already made keep
+    /// running the code they entered, so that code must stay in place. The
+    /// function's [entry point](Module::function_entry_point) and
+    /// [references](Module::function_reference) to it keep leading to the
+    /// code it was first compiled to.
+    pub fn link_function(&mut self, function_index: u32, addr: u64) {
+        if !self.module.counters.contains_key(&function_index) {
+            return;
+        }
+        if let Some(offset) = self.module.functions.get(&function_index).cloned() {
+            LittleEndian::write_u64(&mut self.assembled[offset..offset + size_of::<u64>()], addr);
+        }
+    }
+}
+
+/// Module code as emitted, before it gets assembled
+struct Translation {
+    assembler: CodeAssembler,
+    context: Context,
+    module: Module,
+    jump_tables: Vec<JumpTable>,
 }
 
 impl Compiler for X86_64Compiler {
@@ -171,18 +834,41 @@ impl Compiler for X86_64Compiler {
     type Module = AssembledModule;
 
     fn compile(&self, module: &[u8]) -> Result<Self::Module, Self::Error> {
+        validation::validate(module)?;
+        // Functions get optimized later on when compiling in tiers
+        let ir = if self.optimize && !self.tiered {
+            let mut ir = ir::Module::build(module)?;
+            ir.optimize();
+            Some(ir)
+        } else {
+            None
+        };
+        let mut translation = self.translate(module, ir.as_ref())?;
+        let (code, offsets) = assemble(&mut translation.assembler, &translation.jump_tables)?;
+        translation.module.relocate(&offsets);
+        translation.module.source_hash = source_hash(module);
+        translation.module.features = self.features;
+        Ok(translation.module.assembled(code))
+    }
+}
+
+impl X86_64Compiler {
+    /// Emits the code of a module, with functions found in `ir` lowered
+    /// from it
+    fn translate(&self, module: &[u8], ir: Option<&ir::Module>) -> Result<Translation, Error> {
         let mut assembler = CodeAssembler::new(64)?;
-        let mut got = BTreeMap::new();
-        let mut ils = BTreeMap::new();
+        let mut context = Context::new(&mut assembler, self.features);
+        let mut instantiation = Instantiation::default();
+        let mut jump_tables = vec![];
         let mut parser = wasmparser_nostd::Parser::new(0);
         let mut data: &[u8] = &module;
         let mut eof = false;
         let mut module = Module::new();
         let mut function_index = 0;
         let mut function_body_index = 0;
-        let mut function_typedefs = BTreeMap::new();
         let mut function_type_index = 0;
-        let mut function_types = BTreeMap::new();
+        let mut global_index = 0;
+        let mut tag_index = 0;
         loop {
             let parsed = parser.parse(&data, eof)?;
 
@@ -195,7 +881,9 @@ impl Compiler for X86_64Compiler {
                                 let typedef = t?;
                                 match typedef {
                                     TypeDef::Func(func_type) => {
-                                        function_typedefs.insert(function_type_index, func_type);
+                                        context
+                                            .function_typedefs
+                                            .insert(function_type_index, func_type);
                                         function_type_index += 1;
                                     }
                                     _ => {}
@@ -205,7 +893,7 @@ impl Compiler for X86_64Compiler {
                         Payload::ImportSection(is) => {
                             for i in is {
                                 let import = i?;
-                                let offset = assembler.assemble(0)?.len();
+                                let offset = assembler.instructions().len();
                                 let reference = (
                                     import.module.to_owned(),
                                     import.field.map(str::to_owned),
@@ -217,11 +905,75 @@ impl Compiler for X86_64Compiler {
                                         let mut label = assembler.create_label();
                                         assembler.set_label(&mut label)?;
                                         assembler.dq(&[0xBADC0FFEE0DDF00D])?;
-                                        ils.insert(function_index, label);
-                                        function_types.insert(function_index, function_type);
+                                        context.ils.insert(function_index, label);
+                                        context
+                                            .import_stubs
+                                            .insert(function_index, assembler.create_label());
+                                        context
+                                            .function_types
+                                            .insert(function_index, function_type);
                                         function_index += 1;
                                         function_body_index += 1;
                                     }
+                                    ImportSectionEntryType::Global(global_type) => {
+                                        module.global_imports.insert(global_index, reference);
+                                        module.globals.insert(
+                                            global_index,
+                                            Global::new(global_type, offset, true),
+                                        );
+                                        let mut label = assembler.create_label();
+                                        assembler.set_label(&mut label)?;
+                                        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+                                        context.globals.insert(
+                                            global_index,
+                                            GlobalSlot {
+                                                label,
+                                                ty: global_type.content_type,
+                                                imported: true,
+                                            },
+                                        );
+                                        global_index += 1;
+                                    }
+                                    ImportSectionEntryType::Memory(memory_type) => {
+                                        let index = module.memories.len() as u32;
+                                        module.memory_imports.insert(index, reference);
+                                        module
+                                            .memories
+                                            .insert(index, Memory::new(&memory_type, offset, true));
+                                        context.memories.insert(
+                                            index,
+                                            MemorySlots::emit_import(&mut assembler, &memory_type)?,
+                                        );
+                                    }
+                                    ImportSectionEntryType::Table(table_type) => {
+                                        let index = module.tables.len() as u32;
+                                        module.table_imports.insert(index, reference);
+                                        module
+                                            .tables
+                                            .insert(index, Table::new(&table_type, offset, true));
+                                        context.tables.insert(
+                                            index,
+                                            TableSlots::emit_import(&mut assembler, &table_type)?,
+                                        );
+                                    }
+                                    ImportSectionEntryType::Tag(tag_type) => {
+                                        module.tag_imports.insert(tag_index, reference);
+                                        module.tags.insert(tag_index, offset);
+                                        let mut label = assembler.create_label();
+                                        assembler.set_label(&mut label)?;
+                                        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+                                        let tag = TagSlot {
+                                            label,
+                                            ty: context.function_typedefs[&tag_type.type_index]
+                                                .clone(),
+                                            imported: true,
+                                        };
+                                        if tag.payload_size() > EXCEPTION_PAYLOAD_SIZE as u32 {
+                                            return Err(Error::TagPayloadTooLarge(tag_index));
+                                        }
+                                        context.tags.insert(tag_index, tag);
+                                        tag_index += 1;
+                                    }
                                     _ => (),
                                 }
                             }
@@ -233,119 +985,304 @@ impl Compiler for X86_64Compiler {
                                 let offset = assembler.instructions().len();
                                 assembler.dq(&[0])?;
                                 module.functions.insert(function_index, offset);
-                                got.insert(function_index, assembler.create_label());
-                                function_types.insert(function_index, function_type?);
+                                if self.tiered {
+                                    // Filled in on instantiation
+                                    context.function_slots.insert(function_index, label);
+                                    let mut counter = assembler.create_label();
+                                    assembler.set_label(&mut counter)?;
+                                    let offset = assembler.instructions().len();
+                                    assembler.dq(&[0])?;
+                                    module.counters.insert(function_index, offset);
+                                    context.counters.insert(function_index, counter);
+                                }
+                                context.got.insert(function_index, assembler.create_label());
+                                context
+                                    .function_types
+                                    .insert(function_index, function_type?);
                                 function_index += 1;
                             }
                         }
+                        Payload::MemorySection(ms) => {
+                            for m in ms.into_iter() {
+                                let memory_type = m?;
+                                let index = module.memories.len() as u32;
+                                let offset = assembler.instructions().len();
+                                context.memories.insert(
+                                    index,
+                                    MemorySlots::emit(&mut assembler, &memory_type)?,
+                                );
+                                module
+                                    .memories
+                                    .insert(index, Memory::new(&memory_type, offset, false));
+                            }
+                        }
+                        Payload::TableSection(ts) => {
+                            for t in ts.into_iter() {
+                                let table_type = t?;
+                                let index = module.tables.len() as u32;
+                                let offset = assembler.instructions().len();
+                                context
+                                    .tables
+                                    .insert(index, TableSlots::emit(&mut assembler, &table_type)?);
+                                module
+                                    .tables
+                                    .insert(index, Table::new(&table_type, offset, false));
+                            }
+                        }
+                        Payload::TagSection(ts) => {
+                            for t in ts.into_iter() {
+                                let tag_type = t?;
+                                let offset = assembler.instructions().len();
+                                let mut label = assembler.create_label();
+                                assembler.set_label(&mut label)?;
+                                // Only the slot's address matters
+                                assembler.dq(&[0])?;
+                                let tag = TagSlot {
+                                    label,
+                                    ty: context.function_typedefs[&tag_type.type_index].clone(),
+                                    imported: false,
+                                };
+                                if tag.payload_size() > EXCEPTION_PAYLOAD_SIZE as u32 {
+                                    return Err(Error::TagPayloadTooLarge(tag_index));
+                                }
+                                module.tags.insert(tag_index, offset);
+                                context.tags.insert(tag_index, tag);
+                                tag_index += 1;
+                            }
+                        }
+                        Payload::GlobalSection(gs) => {
+                            for g in gs.into_iter() {
+                                let global = g?;
+                                let offset = assembler.instructions().len();
+                                let value = init::constant(&global.init_expr)?;
+                                if value.is_none() {
+                                    instantiation.global(global_index, global.init_expr);
+                                }
+                                context.globals.insert(
+                                    global_index,
+                                    GlobalSlot::emit(
+                                        &mut assembler,
+                                        global.ty.content_type,
+                                        value.unwrap_or(0),
+                                    )?,
+                                );
+                                module
+                                    .globals
+                                    .insert(global_index, Global::new(global.ty, offset, false));
+                                global_index += 1;
+                            }
+                        }
                         Payload::ExportSection(es) => {
                             for e in es.into_iter() {
                                 let export = e?;
+                                match export.kind {
+                                    ExternalKind::Function => {
+                                        module
+                                            .exports
+                                            .insert(String::from(export.field), export.index);
+                                    }
+                                    ExternalKind::Global => {
+                                        module
+                                            .global_exports
+                                            .insert(String::from(export.field), export.index);
+                                    }
+                                    ExternalKind::Memory => {
+                                        module
+                                            .memory_exports
+                                            .insert(String::from(export.field), export.index);
+                                    }
+                                    ExternalKind::Table => {
+                                        module
+                                            .table_exports
+                                            .insert(String::from(export.field), export.index);
+                                    }
+                                    ExternalKind::Tag => {
+                                        module
+                                            .tag_exports
+                                            .insert(String::from(export.field), export.index);
+                                    }
+                                    _ => (),
+                                }
+                            }
+                        }
+                        Payload::StartSection { func, .. } => {
+                            instantiation.start(func);
+                        }
+                        Payload::ElementSection(es) => {
+                            for e in es.into_iter() {
+                                let element = e?;
+                                let index = module.element_segments.len() as u32;
+                                let items = element
+                                    .items
+                                    .get_items_reader()?
+                                    .into_iter()
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                let mode = match element.kind {
+                                    ElementKind::Active {
+                                        table_index,
+                                        init_expr,
+                                    } => {
+                                        instantiation.active_elements(
+                                            index,
+                                            table_index,
+                                            init_expr,
+                                            items.len(),
+                                        );
+                                        SegmentMode::Active { index: table_index }
+                                    }
+                                    ElementKind::Passive => SegmentMode::Passive,
+                                    ElementKind::Declared => SegmentMode::Declared,
+                                };
+                                let slots = SegmentSlots::new(&mut assembler);
+                                slots.emit_elements(
+                                    &mut assembler,
+                                    items.len(),
+                                    mode != SegmentMode::Passive,
+                                )?;
+                                context.element_segments.insert(index, slots);
                                 module
-                                    .exports
-                                    .insert(String::from(export.field), export.index);
+                                    .element_segments
+                                    .push(Segment::new(mode, items.len()));
+                                instantiation.elements(index, items);
                             }
                         }
-                        Payload::CodeSectionEntry(cs) => {
-                            let function_type = function_types
-                                .get(&function_body_index)
-                                .and_then(|i| function_types.get(&i))
-                                .and_then(|t| function_typedefs.get(t))
-                                .cloned()
-                                .unwrap();
-                            let offset = assembler.assemble(0)?.len();
-                            module.function_bodies.insert(function_body_index, offset);
-                            let fun_label = got.get_mut(&function_body_index).unwrap();
-                            assembler.set_label(fun_label)?;
-                            let rd = cs.get_operators_reader()?;
-                            assembler.push(rbp)?;
-                            assembler.mov(rbp, rsp)?;
-                            let mut integer_order: VecDeque<AsmRegister64> =
-                                vec![rdi, rsi, rdx, rcx, r8, r9]
-                                    .drain(0..function_type.params.len())
-                                    .collect();
-                            let mut extra_args_offset: u32 = 8; // past return address
-                            for param in function_type.params.iter() {
-                                match param {
-                                    Type::I64 => match integer_order.pop_back() {
-                                        Some(reg) => assembler.push(reg)?,
-                                        None => {
-                                            assembler.push(qword_ptr(rbp + extra_args_offset))?;
-                                            extra_args_offset += param.encoding_size();
-                                        }
-                                    },
-                                    Type::I32 => match integer_order.pop_back() {
-                                        Some(reg) => assembler.push(reg)?,
-                                        None => {
-                                            assembler.push(dword_ptr(rbp + extra_args_offset))?;
-                                            extra_args_offset += param.encoding_size();
-                                        }
-                                    },
-                                    _ => todo!(),
-                                }
+                        Payload::DataCountSection { count, .. } => {
+                            // Data segments come after the code, which may refer to them
+                            for index in 0..count {
+                                context
+                                    .data_segments
+                                    .insert(index, SegmentSlots::new(&mut assembler));
                             }
-
-                            let (locals_size, locals) = cs.get_locals_reader()?.into_iter().fold(
-                                Ok((0, vec![])),
-                                |sz, local| match (sz, local) {
-                                    (Ok((size, mut vec)), Ok((count, ty))) => {
-                                        let sz = ty.encoding_size();
-                                        let new_size = size + sz * count;
-                                        for i in 0..count {
-                                            vec.push(size + sz * i);
+                        }
+                        Payload::DataSection(ds) => {
+                            for d in ds.into_iter() {
+                                let data = d?;
+                                let index = module.data_segments.len() as u32;
+                                let mode = match data.kind {
+                                    DataKind::Active {
+                                        memory_index,
+                                        init_expr,
+                                    } => {
+                                        instantiation.active_data(
+                                            index,
+                                            memory_index,
+                                            init_expr,
+                                            data.data.len(),
+                                        );
+                                        SegmentMode::Active {
+                                            index: memory_index,
                                         }
-                                        Ok((new_size, vec))
                                     }
-                                    (Err(err), _) => Err(err),
-                                    (_, Err(err)) => Err(err),
-                                },
-                            )?;
-
-                            if locals_size > 0 {
-                                // Allocate stack for locals
-                                assembler.add_instruction(iced_x86::Instruction::with2(
-                                    iced_x86::Code::Sub_rm64_imm32,
-                                    iced_x86::Register::RSP,
-                                    locals_size,
-                                )?)?;
-                            }
-
-                            for op in rd.into_iter() {
-                                let op = op?;
-                                instructions::handle_instruction(
+                                    DataKind::Passive => SegmentMode::Passive,
+                                };
+                                let slots = *context
+                                    .data_segments
+                                    .entry(index)
+                                    .or_insert_with(|| SegmentSlots::new(&mut assembler));
+                                slots.emit_data(
                                     &mut assembler,
-                                    &mut got,
-                                    &mut ils,
-                                    &mut function_typedefs,
-                                    &mut function_types,
-                                    &locals,
-                                    op,
+                                    data.data,
+                                    mode != SegmentMode::Passive,
                                 )?;
+                                module
+                                    .data_segments
+                                    .push(Segment::new(mode, data.data.len()));
                             }
+                        }
+                        Payload::CodeSectionEntry(mut cs) => {
+                            // memory64 accesses carry 64-bit offsets in their memargs
+                            cs.allow_memarg64(context.memories.values().any(|m| m.memory64));
+                            let function_type =
+                                context.function_type(function_body_index).cloned().unwrap();
+                            assembler.dq(&[abi::signature(&function_type)])?;
+                            let offset = assembler.instructions().len();
+                            module.function_bodies.insert(function_body_index, offset);
+                            let fun_label = context.got.get_mut(&function_body_index).unwrap();
+                            assembler.set_label(fun_label)?;
+                            if let Some(function) =
+                                ir.and_then(|ir| ir.functions.get(&function_body_index))
+                            {
+                                jump_tables.extend(lowering::lower(
+                                    &mut assembler,
+                                    &context,
+                                    function,
+                                )?);
+                            } else {
+                                let rd = cs.get_operators_reader()?;
+                                let counter = context.counters.get(&function_body_index).cloned();
+                                if let Some(counter) = counter {
+                                    assembler.inc(qword_ptr(counter))?;
+                                }
+                                assembler.push(rbp)?;
+                                assembler.mov(rbp, rsp)?;
+                                // Parameters and locals are addressed as slots below `rbp`,
+                                // in order of their indices
+                                let (mut locals, mut locals_size) =
+                                    abi::enter(&mut assembler, &function_type)?;
+
+                                for local in cs.get_locals_reader()?.into_iter() {
+                                    let (count, ty) = local?;
+                                    for _ in 0..count {
+                                        // Locals start zeroed
+                                        for _ in 0..control::slot_size(&ty) / 8 {
+                                            assembler.push(0)?;
+                                        }
+                                        locals_size += control::slot_size(&ty);
+                                        locals.push((locals_size, ty));
+                                    }
+                                }
 
-                            let mut integer_order = VecDeque::from([rax, rdx]);
-                            for ret in function_type.returns.iter() {
-                                match ret {
-                                    Type::I64 | Type::I32 => match integer_order.pop_front() {
-                                        Some(reg) => assembler.pop(reg)?,
-                                        None => (),
-                                    },
-                                    _ => todo!(),
+                                let exception_area = locals_size;
+                                let area = exceptions::frame_area(cs.get_operators_reader()?)?;
+                                let area_size = area.0 * exceptions::RECORD_SIZE
+                                    + area.1 * exceptions::SAVED_SIZE;
+                                if area_size > 0 {
+                                    assembler.sub(rsp, area_size as i32)?;
+                                    locals_size += area_size;
                                 }
-                            }
 
-                            if locals_size > 0 {
-                                // Deallocate stack for locals
-                                assembler.add_instruction(iced_x86::Instruction::with2(
-                                    iced_x86::Code::Add_rm64_imm32,
-                                    iced_x86::Register::RSP,
+                                let mut control = ControlStack::new(
+                                    &mut assembler,
+                                    &function_type,
                                     locals_size,
-                                )?)?;
-                            }
+                                    context.handlers,
+                                    exception_area,
+                                    area,
+                                );
+                                if let Some(counter) = counter {
+                                    control.count_iterations(counter);
+                                }
+                                let mut values = ValueStack::new(self.register_allocation);
+                                for op in rd.into_iter() {
+                                    let op = op?;
+                                    instructions::handle_instruction(
+                                        &mut assembler,
+                                        &context,
+                                        &locals,
+                                        &mut control,
+                                        &mut values,
+                                        op,
+                                    )?;
+                                }
+
+                                abi::leave(&mut assembler, &function_type.returns)?;
 
-                            assembler.mov(rsp, rbp)?;
-                            assembler.pop(rbp)?;
-                            assembler.ret()?;
+                                assembler.mov(rsp, rbp)?;
+                                assembler.pop(rbp)?;
+                                let stack_size = abi::stack_arguments_size(&function_type);
+                                if stack_size > 0 {
+                                    assembler.ret_1(stack_size as i32)?;
+                                } else {
+                                    assembler.ret()?;
+                                }
+                                module.floating_point |= control.uses_floating_point()
+                                    || function_type
+                                        .params
+                                        .iter()
+                                        .chain(function_type.returns.iter())
+                                        .any(abi::Location::is_float);
+                                jump_tables.extend(control.into_jump_tables());
+                            }
                             function_body_index += 1;
                         }
                         _ => (),
@@ -356,7 +1293,102 @@ impl Compiler for X86_64Compiler {
                 _ => (),
             }
         }
-        Ok(module.assembled(assembler.assemble(0)?))
+        emit_import_stubs(&mut assembler, &mut module, &mut context)?;
+        emit_wrappers(&mut assembler, &mut module, &context)?;
+        module.instantiation = assembler.instructions().len();
+        instantiation.emit(&mut assembler, &context)?;
+        let mut handlers = context.handlers;
+        module.exception_state = handlers.emit(&mut assembler, &context)?;
+        module.trap_handler = context.traps.emit(&mut assembler)?;
+        module.futex = context.futex.emit(&mut assembler)?;
+        Ok(Translation {
+            assembler,
+            context,
+            module,
+            jump_tables,
+        })
+    }
+}
+
+/// Emits stubs of imported functions, each preceded by its signature
+///
+/// Stubs pop stack arguments, adapting imported functions, which follow
+/// System V, to calls from compiled code.
+fn emit_import_stubs(
+    assembler: &mut CodeAssembler,
+    module: &mut Module,
+    context: &mut Context,
+) -> Result<(), Error> {
+    for (function_index, label) in context.import_stubs.iter_mut() {
+        let function_type = &context.function_typedefs[&context.function_types[function_index]];
+        let slot = context.ils[function_index];
+        assembler.dq(&[abi::signature(function_type)])?;
+        module
+            .import_stubs
+            .insert(*function_index, assembler.instructions().len());
+        assembler.set_label(label)?;
+        if abi::stack_arguments_size(function_type) > 0 {
+            abi::wrapper(assembler, function_type, true, |assembler| {
+                assembler.call(qword_ptr(slot))?;
+                Ok(())
+            })?;
+        } else {
+            assembler.jmp(qword_ptr(slot))?;
+        }
+    }
+    Ok(())
+}
+
+/// Emits System V entry points of functions with stack arguments, which
+/// compiled code expects the called function to pop
+fn emit_wrappers(
+    assembler: &mut CodeAssembler,
+    module: &mut Module,
+    context: &Context,
+) -> Result<(), Error> {
+    for (function_index, label) in context.got.iter() {
+        let function_type = context.function_type(*function_index).unwrap();
+        if abi::stack_arguments_size(function_type) > 0 {
+            module
+                .wrappers
+                .insert(*function_index, assembler.instructions().len());
+            abi::wrapper(assembler, function_type, false, |assembler| {
+                assembler.call(*label)?;
+                Ok(())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Assembles the final module code, filling in jump tables. Returns the code
+/// along with offsets of all instructions in it.
+fn assemble(
+    assembler: &mut CodeAssembler,
+    jump_tables: &[JumpTable],
+) -> Result<(Vec<u8>, Vec<u32>), Error> {
+    // Makes sure there are no dangling labels or prefixes
+    assembler.assemble(0)?;
+    let result = BlockEncoder::encode(
+        64,
+        InstructionBlock::new(assembler.instructions(), 0),
+        BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+    )?;
+    let mut code = result.code_buffer;
+    let offsets = result.new_instruction_offsets;
+    fill_jump_tables(&mut code, jump_tables, |index| offsets[index]);
+    Ok((code, offsets))
+}
+
+/// Fills in jump tables, given the offset in `code` of every instruction
+fn fill_jump_tables(code: &mut [u8], jump_tables: &[JumpTable], offset: impl Fn(usize) -> u32) {
+    for table in jump_tables {
+        let base = offset(table.base) as usize;
+        for (i, target) in table.targets.iter().enumerate() {
+            let entry = base + i * size_of::<u32>();
+            let relative = offset(*target) as i32 - base as i32;
+            LittleEndian::write_i32(&mut code[entry..entry + size_of::<u32>()], relative);
+        }
     }
 }
 
@@ -0,0 +1,61 @@
+//! Registers and operands shared by the code generators that keep values in
+//! registers
+
+use iced_x86::code_asm::{
+    al, ax, cl, cx, di, dil, dl, dx, eax, ecx, edi, edx, esi, r10, r10b, r10d, r10w, r8, r8b, r8d,
+    r8w, r9, r9b, r9d, r9w, rax, rcx, rdi, rdx, rsi, si, sil, AsmRegister16, AsmRegister32,
+    AsmRegister64, AsmRegister8,
+};
+
+/// Registers values can be kept in, by width, starting with the ones every
+/// operator lowering may clobber
+pub(crate) const QWORDS: [AsmRegister64; 8] = [rax, rcx, rdx, rsi, rdi, r8, r9, r10];
+pub(crate) const DWORDS: [AsmRegister32; 8] = [eax, ecx, edx, esi, edi, r8d, r9d, r10d];
+pub(crate) const WORDS: [AsmRegister16; 8] = [ax, cx, dx, si, di, r8w, r9w, r10w];
+pub(crate) const BYTES: [AsmRegister8; 8] = [al, cl, dl, sil, dil, r8b, r9b, r10b];
+
+/// Source operand of an instruction
+#[derive(Clone, Copy)]
+pub(crate) enum Operand {
+    /// Register at this index of the tables
+    Register(usize),
+    /// Local or slot at this offset below `rbp`
+    Memory(u32),
+    Immediate(i32),
+}
+
+/// Applies an instruction to a register and an operand, at the width of
+/// the operator
+macro_rules! apply {
+    ($assembler:expr, $instruction:ident, $wide:expr, $dst:expr, $src:expr) => {{
+        use iced_x86::code_asm::{dword_ptr, qword_ptr, rbp};
+        use $crate::x86_64::operands::{Operand, DWORDS, QWORDS};
+        match ($wide, $src) {
+            (true, Operand::Register(r)) => $assembler.$instruction(QWORDS[$dst], QWORDS[r]),
+            (true, Operand::Memory(o)) => $assembler.$instruction(QWORDS[$dst], qword_ptr(rbp - o)),
+            (true, Operand::Immediate(i)) => $assembler.$instruction(QWORDS[$dst], i),
+            (false, Operand::Register(r)) => $assembler.$instruction(DWORDS[$dst], DWORDS[r]),
+            (false, Operand::Memory(o)) => {
+                $assembler.$instruction(DWORDS[$dst], dword_ptr(rbp - o))
+            }
+            (false, Operand::Immediate(i)) => $assembler.$instruction(DWORDS[$dst], i),
+        }
+    }};
+}
+
+/// Shifts a register by an immediate count, or by `cl`
+macro_rules! shift {
+    ($assembler:expr, $instruction:ident, $wide:expr, $dst:expr, $count:expr) => {{
+        use iced_x86::code_asm::cl;
+        use $crate::x86_64::operands::{DWORDS, QWORDS};
+        match ($wide, $count) {
+            (true, Some(n)) => $assembler.$instruction(QWORDS[$dst], n),
+            (true, None) => $assembler.$instruction(QWORDS[$dst], cl),
+            (false, Some(n)) => $assembler.$instruction(DWORDS[$dst], n),
+            (false, None) => $assembler.$instruction(DWORDS[$dst], cl),
+        }
+    }};
+}
+
+pub(crate) use apply;
+pub(crate) use shift;
@@ -0,0 +1,616 @@
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::memory;
+use crate::x86_64::operands::{apply, shift, Operand, BYTES, DWORDS, QWORDS, WORDS};
+use crate::x86_64::{Context, Error};
+use alloc::vec::Vec;
+use core::ops::Range;
+use iced_x86::code_asm::{
+    al, byte_ptr, dword_ptr, ecx, qword_ptr, rax, rbp, rsp, word_ptr, AsmMemoryOperand,
+    AsmRegister64, CodeAssembler,
+};
+use iced_x86::IcedError;
+use wasmparser_nostd::{MemoryImmediate, Operator, Type};
+
+/// Registers of the [tables](crate::x86_64::operands) operands are kept in
+///
+/// Operator lowerings use `rax`, `rcx` and `r11` as scratch, along with
+/// these for operators that work on the machine stack only, which pending
+/// values are flushed to first.
+const POOL: Range<usize> = 2..8;
+
+/// Operand stack value that hasn't been pushed onto the machine stack
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Value {
+    /// Constant, zero-extended for `i32`
+    Constant(u64),
+    /// Local at this offset below `rbp`, as it is now
+    Local(u32),
+    /// Value in the register at this index of the tables
+    Register(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Alu {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Clone, Copy)]
+enum Shift {
+    Left,
+    RightSigned,
+    RightUnsigned,
+    RotateLeft,
+    RotateRight,
+}
+
+/// Values on top of the operand stack that are kept in registers, or not
+/// computed at all yet, rather than pushed onto the machine stack
+///
+/// The rest of the compiler sees them as pushed: the control stack counts
+/// them, and they are flushed to the machine stack before any operator that
+/// doesn't know about them. Constants and locals are only loaded once
+/// something uses them, often as an immediate or memory operand. Branches
+/// flush everything but their condition, so that no value is pending where
+/// control flow merges.
+pub(crate) struct ValueStack {
+    enabled: bool,
+    values: Vec<(Value, Type)>,
+}
+
+impl ValueStack {
+    /// Stack that keeps no values pending unless `enabled`
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            values: Vec::new(),
+        }
+    }
+
+    /// Pushes every pending value onto the machine stack
+    pub(crate) fn flush(&mut self, assembler: &mut CodeAssembler) -> Result<(), Error> {
+        self.flush_below(assembler, 0)
+    }
+
+    /// Pushes the pending values onto the machine stack, except for the top
+    /// `keep` ones
+    pub(crate) fn flush_below(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        keep: usize,
+    ) -> Result<(), Error> {
+        let count = self.values.len().saturating_sub(keep);
+        for (value, _) in self.values.drain(..count) {
+            match value {
+                Value::Constant(c) if fits_immediate(c) => assembler.push(c as i32)?,
+                Value::Constant(c) => {
+                    assembler.mov(rax, c)?;
+                    assembler.push(rax)?;
+                }
+                Value::Local(offset) => assembler.push(qword_ptr(rbp - offset))?,
+                Value::Register(r) => assembler.push(QWORDS[r])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops the top of the operand stack into `rax`
+    pub(crate) fn pop_rax(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+    ) -> Result<(), Error> {
+        control.pop(control.top());
+        match self.values.pop() {
+            Some((value, _)) => load(assembler, rax, value),
+            None => Ok(assembler.pop(rax)?),
+        }
+    }
+
+    fn push(&mut self, control: &mut ControlStack, value: Value, ty: Type) {
+        self.values.push((value, ty));
+        control.push(ty);
+    }
+
+    /// Register of the pool no pending value nor the caller uses
+    fn free_register(&self, reserved: &[usize]) -> Option<usize> {
+        POOL.into_iter().find(|r| {
+            !reserved.contains(r)
+                && !self
+                    .values
+                    .iter()
+                    .any(|(value, _)| *value == Value::Register(*r))
+        })
+    }
+
+    /// Picks a free register, spilling all pending values if there's none
+    fn allocate(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        reserved: &[usize],
+    ) -> Result<usize, Error> {
+        if let Some(r) = self.free_register(reserved) {
+            return Ok(r);
+        }
+        self.flush(assembler)?;
+        Ok(self.free_register(reserved).unwrap())
+    }
+
+    /// Pops the top of the operand stack into a register, other than
+    /// `reserved` ones
+    fn pop_register(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        reserved: &[usize],
+    ) -> Result<usize, Error> {
+        control.pop(control.top());
+        match self.values.pop() {
+            Some((Value::Register(r), _)) => Ok(r),
+            Some((value, _)) => {
+                let r = self.allocate(assembler, reserved)?;
+                load(assembler, QWORDS[r], value)?;
+                Ok(r)
+            }
+            None => {
+                let r = self.allocate(assembler, reserved)?;
+                assembler.pop(QWORDS[r])?;
+                Ok(r)
+            }
+        }
+    }
+
+    /// Pops the top of the operand stack as the source operand of an
+    /// instruction working at the width of its type
+    fn pop_operand(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+    ) -> Result<Operand, Error> {
+        let operand = match self.values.last() {
+            Some((Value::Constant(c), Type::I32)) => Operand::Immediate(*c as u32 as i32),
+            Some((Value::Constant(c), _)) if fits_immediate(*c) => Operand::Immediate(*c as i32),
+            Some((Value::Local(offset), _)) => Operand::Memory(*offset),
+            Some((Value::Register(r), _)) => Operand::Register(*r),
+            _ => {
+                return Ok(Operand::Register(self.pop_register(
+                    assembler,
+                    control,
+                    &[],
+                )?))
+            }
+        };
+        self.values.pop();
+        control.pop(control.top());
+        Ok(operand)
+    }
+
+    fn binary(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        ty: Type,
+        alu: Alu,
+    ) -> Result<(), Error> {
+        let wide = ty == Type::I64;
+        let src = self.pop_operand(assembler, control)?;
+        let dst = self.pop_register(assembler, control, &reserved(src))?;
+        match alu {
+            Alu::Add => apply!(assembler, add, wide, dst, src)?,
+            Alu::Sub => apply!(assembler, sub, wide, dst, src)?,
+            Alu::Mul => match (wide, src) {
+                (true, Operand::Register(r)) => assembler.imul_2(QWORDS[dst], QWORDS[r])?,
+                (true, Operand::Memory(o)) => assembler.imul_2(QWORDS[dst], qword_ptr(rbp - o))?,
+                (true, Operand::Immediate(i)) => assembler.imul_3(QWORDS[dst], QWORDS[dst], i)?,
+                (false, Operand::Register(r)) => assembler.imul_2(DWORDS[dst], DWORDS[r])?,
+                (false, Operand::Memory(o)) => assembler.imul_2(DWORDS[dst], dword_ptr(rbp - o))?,
+                (false, Operand::Immediate(i)) => assembler.imul_3(DWORDS[dst], DWORDS[dst], i)?,
+            },
+            Alu::And => apply!(assembler, and, wide, dst, src)?,
+            Alu::Or => apply!(assembler, or, wide, dst, src)?,
+            Alu::Xor => apply!(assembler, xor, wide, dst, src)?,
+        }
+        self.push(control, Value::Register(dst), ty);
+        Ok(())
+    }
+
+    fn shift(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        ty: Type,
+        shift: Shift,
+    ) -> Result<(), Error> {
+        let wide = ty == Type::I64;
+        let count = self.pop_operand(assembler, control)?;
+        let dst = self.pop_register(assembler, control, &reserved(count))?;
+        // Counts are taken modulo the width, as x86 does
+        let count = match count {
+            Operand::Immediate(i) => Some(i as u32 & if wide { 63 } else { 31 }),
+            Operand::Register(r) => {
+                assembler.mov(ecx, DWORDS[r])?;
+                None
+            }
+            Operand::Memory(offset) => {
+                assembler.mov(ecx, dword_ptr(rbp - offset))?;
+                None
+            }
+        };
+        match shift {
+            Shift::Left => shift!(assembler, shl, wide, dst, count)?,
+            Shift::RightSigned => shift!(assembler, sar, wide, dst, count)?,
+            Shift::RightUnsigned => shift!(assembler, shr, wide, dst, count)?,
+            Shift::RotateLeft => shift!(assembler, rol, wide, dst, count)?,
+            Shift::RotateRight => shift!(assembler, ror, wide, dst, count)?,
+        }
+        self.push(control, Value::Register(dst), ty);
+        Ok(())
+    }
+
+    /// Compares the operands, pushing the flag set by `set` (one of the
+    /// `setcc` instructions on `al`) as an `i32`
+    fn compare(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        ty: Type,
+        set: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+    ) -> Result<(), Error> {
+        let src = self.pop_operand(assembler, control)?;
+        let dst = self.pop_register(assembler, control, &reserved(src))?;
+        apply!(assembler, cmp, ty == Type::I64, dst, src)?;
+        set(assembler)?;
+        assembler.movzx(DWORDS[dst], al)?;
+        self.push(control, Value::Register(dst), Type::I32);
+        Ok(())
+    }
+
+    fn eqz(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        ty: Type,
+    ) -> Result<(), Error> {
+        let dst = self.pop_register(assembler, control, &[])?;
+        if ty == Type::I64 {
+            assembler.test(QWORDS[dst], QWORDS[dst])?;
+        } else {
+            assembler.test(DWORDS[dst], DWORDS[dst])?;
+        }
+        assembler.sete(al)?;
+        assembler.movzx(DWORDS[dst], al)?;
+        self.push(control, Value::Register(dst), Type::I32);
+        Ok(())
+    }
+
+    /// Pops the address, loads `size` bytes with `load` into a register and
+    /// pushes it as `ty`
+    fn load(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        context: &Context,
+        control: &mut ControlStack,
+        memarg: &MemoryImmediate,
+        (size, ty): (u32, Type),
+        load: impl FnOnce(&mut CodeAssembler, usize, AsmMemoryOperand) -> Result<(), IcedError>,
+    ) -> Result<(), Error> {
+        // Allocated first, as spilling would need `rax`
+        let dst = self.allocate(assembler, &[])?;
+        self.pop_rax(assembler, control)?;
+        let mem = memory::effective_address(assembler, context, memarg, size)?;
+        load(assembler, dst, mem)?;
+        self.push(control, Value::Register(dst), ty);
+        Ok(())
+    }
+
+    /// Pops the value and the address, and stores `size` bytes of the
+    /// value with `store`
+    fn store(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        context: &Context,
+        control: &mut ControlStack,
+        memarg: &MemoryImmediate,
+        size: u32,
+        store: impl FnOnce(&mut CodeAssembler, usize, AsmMemoryOperand) -> Result<(), IcedError>,
+    ) -> Result<(), Error> {
+        let src = self.pop_register(assembler, control, &[])?;
+        self.pop_rax(assembler, control)?;
+        let mem = memory::effective_address(assembler, context, memarg, size)?;
+        store(assembler, src, mem)?;
+        Ok(())
+    }
+
+    /// `local.set` and `local.tee` of the local at `offset` below `rbp`
+    fn set_local(
+        &mut self,
+        assembler: &mut CodeAssembler,
+        control: &mut ControlStack,
+        offset: u32,
+        tee: bool,
+    ) -> Result<(), Error> {
+        // Values read from the local before have to keep the old value
+        for i in 0..self.values.len().saturating_sub(1) {
+            if self.values[i].0 == Value::Local(offset) {
+                match self.free_register(&[]) {
+                    Some(r) => {
+                        assembler.mov(QWORDS[r], qword_ptr(rbp - offset))?;
+                        self.values[i].0 = Value::Register(r);
+                    }
+                    None => {
+                        self.flush_below(assembler, 1)?;
+                        break;
+                    }
+                }
+            }
+        }
+        let local = qword_ptr(rbp - offset);
+        match self.values.last() {
+            Some((Value::Register(r), _)) => assembler.mov(local, QWORDS[*r])?,
+            Some((Value::Constant(c), _)) if fits_immediate(*c) => {
+                assembler.mov(local, *c as i32)?
+            }
+            Some((value, _)) => {
+                load(assembler, rax, *value)?;
+                assembler.mov(local, rax)?;
+            }
+            None if tee => {
+                assembler.mov(rax, qword_ptr(rsp))?;
+                assembler.mov(local, rax)?;
+            }
+            None => assembler.pop(local)?,
+        }
+        if !tee {
+            self.values.pop();
+            control.pop(control.top());
+        }
+        Ok(())
+    }
+}
+
+/// Whether a constant is the sign extension of an immediate, and so can be
+/// an operand of 64-bit instructions
+pub(crate) fn fits_immediate(c: u64) -> bool {
+    c as i64 == c as i32 as i64
+}
+
+fn reserved(operand: Operand) -> Vec<usize> {
+    match operand {
+        Operand::Register(r) => alloc::vec![r],
+        _ => Vec::new(),
+    }
+}
+
+/// Moves a pending value into `register`
+fn load(assembler: &mut CodeAssembler, register: AsmRegister64, value: Value) -> Result<(), Error> {
+    match value {
+        Value::Constant(c) => assembler.mov(register, c)?,
+        Value::Local(offset) => assembler.mov(register, qword_ptr(rbp - offset))?,
+        Value::Register(r) => assembler.mov(register, QWORDS[r])?,
+    }
+    Ok(())
+}
+
+/// Lowers `op` keeping its operands and result in registers, if it's an
+/// operator that can. Returns `false` for other operators, which have to
+/// be lowered with the operand stack flushed.
+pub(crate) fn handle(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    locals: &[(u32, Type)],
+    control: &mut ControlStack,
+    values: &mut ValueStack,
+    op: &Operator,
+) -> Result<bool, Error> {
+    use Type::{I32, I64};
+    if !values.enabled {
+        return Ok(false);
+    }
+    match *op {
+        Operator::I32Const { value } => {
+            values.push(control, Value::Constant(value as u32 as u64), I32)
+        }
+        Operator::I64Const { value } => values.push(control, Value::Constant(value as u64), I64),
+        Operator::LocalGet { local_index } => {
+            let (offset, ty) = locals[local_index as usize];
+            if ty == Type::V128 {
+                return Ok(false);
+            }
+            values.push(control, Value::Local(offset), ty);
+        }
+        Operator::LocalSet { local_index } | Operator::LocalTee { local_index } => {
+            let (offset, ty) = locals[local_index as usize];
+            if ty == Type::V128 {
+                return Ok(false);
+            }
+            let tee = matches!(op, Operator::LocalTee { .. });
+            values.set_local(assembler, control, offset, tee)?;
+        }
+        Operator::Drop if !values.values.is_empty() => {
+            values.values.pop();
+            control.pop(control.top());
+        }
+        Operator::I32Add => values.binary(assembler, control, I32, Alu::Add)?,
+        Operator::I64Add => values.binary(assembler, control, I64, Alu::Add)?,
+        Operator::I32Sub => values.binary(assembler, control, I32, Alu::Sub)?,
+        Operator::I64Sub => values.binary(assembler, control, I64, Alu::Sub)?,
+        Operator::I32Mul => values.binary(assembler, control, I32, Alu::Mul)?,
+        Operator::I64Mul => values.binary(assembler, control, I64, Alu::Mul)?,
+        Operator::I32And => values.binary(assembler, control, I32, Alu::And)?,
+        Operator::I64And => values.binary(assembler, control, I64, Alu::And)?,
+        Operator::I32Or => values.binary(assembler, control, I32, Alu::Or)?,
+        Operator::I64Or => values.binary(assembler, control, I64, Alu::Or)?,
+        Operator::I32Xor => values.binary(assembler, control, I32, Alu::Xor)?,
+        Operator::I64Xor => values.binary(assembler, control, I64, Alu::Xor)?,
+        Operator::I32Shl => values.shift(assembler, control, I32, Shift::Left)?,
+        Operator::I64Shl => values.shift(assembler, control, I64, Shift::Left)?,
+        Operator::I32ShrS => values.shift(assembler, control, I32, Shift::RightSigned)?,
+        Operator::I64ShrS => values.shift(assembler, control, I64, Shift::RightSigned)?,
+        Operator::I32ShrU => values.shift(assembler, control, I32, Shift::RightUnsigned)?,
+        Operator::I64ShrU => values.shift(assembler, control, I64, Shift::RightUnsigned)?,
+        Operator::I32Rotl => values.shift(assembler, control, I32, Shift::RotateLeft)?,
+        Operator::I64Rotl => values.shift(assembler, control, I64, Shift::RotateLeft)?,
+        Operator::I32Rotr => values.shift(assembler, control, I32, Shift::RotateRight)?,
+        Operator::I64Rotr => values.shift(assembler, control, I64, Shift::RotateRight)?,
+        Operator::I32Eqz => values.eqz(assembler, control, I32)?,
+        Operator::I64Eqz => values.eqz(assembler, control, I64)?,
+        Operator::I32Eq => values.compare(assembler, control, I32, |a| a.sete(al))?,
+        Operator::I64Eq => values.compare(assembler, control, I64, |a| a.sete(al))?,
+        Operator::I32Ne => values.compare(assembler, control, I32, |a| a.setne(al))?,
+        Operator::I64Ne => values.compare(assembler, control, I64, |a| a.setne(al))?,
+        Operator::I32LtS => values.compare(assembler, control, I32, |a| a.setl(al))?,
+        Operator::I64LtS => values.compare(assembler, control, I64, |a| a.setl(al))?,
+        Operator::I32LtU => values.compare(assembler, control, I32, |a| a.setb(al))?,
+        Operator::I64LtU => values.compare(assembler, control, I64, |a| a.setb(al))?,
+        Operator::I32GtS => values.compare(assembler, control, I32, |a| a.setg(al))?,
+        Operator::I64GtS => values.compare(assembler, control, I64, |a| a.setg(al))?,
+        Operator::I32GtU => values.compare(assembler, control, I32, |a| a.seta(al))?,
+        Operator::I64GtU => values.compare(assembler, control, I64, |a| a.seta(al))?,
+        Operator::I32LeS => values.compare(assembler, control, I32, |a| a.setle(al))?,
+        Operator::I64LeS => values.compare(assembler, control, I64, |a| a.setle(al))?,
+        Operator::I32LeU => values.compare(assembler, control, I32, |a| a.setbe(al))?,
+        Operator::I64LeU => values.compare(assembler, control, I64, |a| a.setbe(al))?,
+        Operator::I32GeS => values.compare(assembler, control, I32, |a| a.setge(al))?,
+        Operator::I64GeS => values.compare(assembler, control, I64, |a| a.setge(al))?,
+        Operator::I32GeU => values.compare(assembler, control, I32, |a| a.setae(al))?,
+        Operator::I64GeU => values.compare(assembler, control, I64, |a| a.setae(al))?,
+        Operator::I64ExtendI32U => {
+            // The upper half of `i32` values is zeroed already
+            control.pop(I32);
+            control.push(I64);
+            if let Some((_, ty)) = values.values.last_mut() {
+                *ty = I64;
+            }
+        }
+        Operator::I64ExtendI32S => {
+            let dst = values.pop_register(assembler, control, &[])?;
+            assembler.movsxd(QWORDS[dst], DWORDS[dst])?;
+            values.push(control, Value::Register(dst), I64);
+        }
+        Operator::I32WrapI64 => {
+            let dst = values.pop_register(assembler, control, &[])?;
+            assembler.mov(DWORDS[dst], DWORDS[dst])?;
+            values.push(control, Value::Register(dst), I32);
+        }
+        Operator::I32Load { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (4, I32),
+            |a, r, mem| a.mov(DWORDS[r], dword_ptr(mem)),
+        )?,
+        Operator::I64Load { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (8, I64),
+            |a, r, mem| a.mov(QWORDS[r], qword_ptr(mem)),
+        )?,
+        Operator::I32Load8S { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (1, I32),
+            |a, r, mem| a.movsx(DWORDS[r], byte_ptr(mem)),
+        )?,
+        Operator::I32Load8U { memarg } | Operator::I64Load8U { memarg } => {
+            let ty = if matches!(op, Operator::I32Load8U { .. }) {
+                I32
+            } else {
+                I64
+            };
+            values.load(
+                assembler,
+                context,
+                control,
+                &memarg,
+                (1, ty),
+                |a, r, mem| a.movzx(DWORDS[r], byte_ptr(mem)),
+            )?
+        }
+        Operator::I32Load16S { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (2, I32),
+            |a, r, mem| a.movsx(DWORDS[r], word_ptr(mem)),
+        )?,
+        Operator::I32Load16U { memarg } | Operator::I64Load16U { memarg } => {
+            let ty = if matches!(op, Operator::I32Load16U { .. }) {
+                I32
+            } else {
+                I64
+            };
+            values.load(
+                assembler,
+                context,
+                control,
+                &memarg,
+                (2, ty),
+                |a, r, mem| a.movzx(DWORDS[r], word_ptr(mem)),
+            )?
+        }
+        Operator::I64Load8S { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (1, I64),
+            |a, r, mem| a.movsx(QWORDS[r], byte_ptr(mem)),
+        )?,
+        Operator::I64Load16S { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (2, I64),
+            |a, r, mem| a.movsx(QWORDS[r], word_ptr(mem)),
+        )?,
+        Operator::I64Load32S { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (4, I64),
+            |a, r, mem| a.movsxd(QWORDS[r], dword_ptr(mem)),
+        )?,
+        Operator::I64Load32U { memarg } => values.load(
+            assembler,
+            context,
+            control,
+            &memarg,
+            (4, I64),
+            |a, r, mem| a.mov(DWORDS[r], dword_ptr(mem)),
+        )?,
+        Operator::I32Store { memarg } | Operator::I64Store32 { memarg } => {
+            values.store(assembler, context, control, &memarg, 4, |a, r, mem| {
+                a.mov(dword_ptr(mem), DWORDS[r])
+            })?
+        }
+        Operator::I64Store { memarg } => {
+            values.store(assembler, context, control, &memarg, 8, |a, r, mem| {
+                a.mov(qword_ptr(mem), QWORDS[r])
+            })?
+        }
+        Operator::I32Store8 { memarg } | Operator::I64Store8 { memarg } => {
+            values.store(assembler, context, control, &memarg, 1, |a, r, mem| {
+                a.mov(byte_ptr(mem), BYTES[r])
+            })?
+        }
+        Operator::I32Store16 { memarg } | Operator::I64Store16 { memarg } => {
+            values.store(assembler, context, control, &memarg, 2, |a, r, mem| {
+                a.mov(word_ptr(mem), WORDS[r])
+            })?
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
@@ -0,0 +1,186 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::memory;
+use crate::x86_64::{Context, Error};
+use alloc::vec;
+use iced_x86::code_asm::{
+    ecx, edi, esi, ptr, qword_ptr, r11, rax, rcx, rdi, rsi, CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::Type;
+
+/// How a segment is used on instantiation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentMode {
+    /// Copied into the memory or table with the given index on instantiation
+    /// and dropped afterwards
+    Active { index: u32 },
+    /// Only available to `memory.init` and `table.init`
+    Passive,
+    /// Only declares function references, dropped on instantiation
+    Declared,
+}
+
+/// Data or element segment of a module
+///
+/// Compiled code keeps a segment in the module binary as a 64-bit length
+/// slot (zeroed once the segment is dropped) followed by its contents:
+/// the bytes of a data segment, or 64-bit references of an element segment.
+/// Element references are resolved by the instantiation routine.
+pub struct Segment {
+    mode: SegmentMode,
+    len: usize,
+}
+
+impl Segment {
+    pub(crate) fn new(mode: SegmentMode, len: usize) -> Self {
+        Self { mode, len }
+    }
+
+    pub fn mode(&self) -> SegmentMode {
+        self.mode
+    }
+
+    /// Number of bytes (for data segments) or elements (for element segments)
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Labels of a segment's length slot and contents
+///
+/// They can be created before the segment is emitted, as data segments come
+/// after the code referring to them.
+#[derive(Clone, Copy)]
+pub(crate) struct SegmentSlots {
+    pub(crate) len: CodeLabel,
+    pub(crate) items: CodeLabel,
+}
+
+impl SegmentSlots {
+    pub(crate) fn new(assembler: &mut CodeAssembler) -> Self {
+        Self {
+            len: assembler.create_label(),
+            items: assembler.create_label(),
+        }
+    }
+
+    pub(crate) fn emit_data(
+        mut self,
+        assembler: &mut CodeAssembler,
+        data: &[u8],
+        dropped: bool,
+    ) -> Result<(), Error> {
+        assembler.set_label(&mut self.len)?;
+        assembler.dq(&[if dropped { 0 } else { data.len() as u64 }])?;
+        assembler.set_label(&mut self.items)?;
+        if data.is_empty() {
+            // Something for the label to point at
+            assembler.db(&[0])?;
+        } else {
+            assembler.db(data)?;
+        }
+        Ok(())
+    }
+
+    /// Emits `count` null references
+    pub(crate) fn emit_elements(
+        mut self,
+        assembler: &mut CodeAssembler,
+        count: usize,
+        dropped: bool,
+    ) -> Result<(), Error> {
+        assembler.set_label(&mut self.len)?;
+        assembler.dq(&[if dropped { 0 } else { count as u64 }])?;
+        assembler.set_label(&mut self.items)?;
+        assembler.dq(&vec![0; count.max(1)])?;
+        Ok(())
+    }
+}
+
+/// Pops the length, source offset and destination offset of a bulk copy
+/// into `rcx`, `rsi` and `rdi`, given their types in reverse order
+pub(crate) fn pop_copy_operands(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    types: [Type; 3],
+) -> Result<(), Error> {
+    let registers = [(rdi, edi), (rsi, esi), (rcx, ecx)];
+    for ((register, low), ty) in registers.into_iter().zip(types).rev() {
+        assembler.pop(register)?;
+        // Offsets and lengths are unsigned
+        if ty == Type::I32 {
+            assembler.mov(low, low)?;
+        }
+        control.pop(ty);
+    }
+    Ok(())
+}
+
+/// `memory.init`: copies a part of a data segment into the memory
+pub(crate) fn memory_init(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    segment: u32,
+    mem: u32,
+) -> Result<(), Error> {
+    let slots = &context.data_segments[&segment];
+    let memory = &context.memories[&mem];
+    let types = [memory.index_type(), Type::I32, Type::I32];
+    pop_copy_operands(assembler, control, types)?;
+    bounds_check(assembler, context, slots, Trap::OutOfBoundsMemoryAccess)?;
+    memory::range_end(assembler, context, mem, rdi)?;
+    assembler.lea(rax, ptr(slots.items))?;
+    assembler.add(rsi, rax)?;
+    memory::add_base(assembler, context, mem, rdi)?;
+    assembler.rep().movsb()?;
+    Ok(())
+}
+
+/// `table.init`: copies a part of an element segment into the table
+pub(crate) fn table_init(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    segment: u32,
+    table: u32,
+) -> Result<(), Error> {
+    let slots = &context.element_segments[&segment];
+    pop_copy_operands(assembler, control, [Type::I32; 3])?;
+    bounds_check(assembler, context, slots, Trap::OutOfBoundsTableAccess)?;
+    let [size, _, entries] = context.tables[&table].descriptor(assembler, r11)?;
+    assembler.lea(rax, ptr(rdi + rcx))?;
+    assembler.cmp(rax, size)?;
+    assembler.ja(context.traps.label(Trap::OutOfBoundsTableAccess))?;
+    assembler.lea(rax, ptr(slots.items))?;
+    assembler.lea(rsi, ptr(rax + rsi * 8))?;
+    assembler.lea(rax, entries)?;
+    assembler.lea(rdi, ptr(rax + rdi * 8))?;
+    assembler.rep().movsq()?;
+    Ok(())
+}
+
+/// Traps with `trap` unless the `rcx` items at `rsi` are within the segment
+///
+/// Clobbers `rax`.
+fn bounds_check(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    slots: &SegmentSlots,
+    trap: Trap,
+) -> Result<(), Error> {
+    assembler.lea(rax, ptr(rsi + rcx))?;
+    assembler.cmp(rax, ptr(slots.len))?;
+    assembler.ja(context.traps.label(trap))?;
+    Ok(())
+}
+
+/// `data.drop` and `elem.drop`
+pub(crate) fn drop(assembler: &mut CodeAssembler, slots: &SegmentSlots) -> Result<(), Error> {
+    assembler.mov(qword_ptr(slots.len), 0)?;
+    Ok(())
+}
@@ -0,0 +1,1429 @@
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::float::{Comparison, Rounding};
+use crate::x86_64::memory;
+use crate::x86_64::{Context, Error, Features};
+use iced_x86::code_asm::{
+    al, byte_ptr, cl, ecx, edx, qword_ptr, r11, r11d, rax, rcx, rdx, rsp, word_ptr, xmm0, xmm1,
+    xmm2, xmm3, xmm4, xmm5, xmmword_ptr, AsmMemoryOperand, AsmRegisterXmm, CodeAssembler,
+};
+use iced_x86::code_asm::{dl, dword_ptr, dx, eax};
+use iced_x86::IcedError;
+use wasmparser_nostd::{MemoryImmediate, Type};
+
+/// Size of a `v128` operand stack slot
+///
+/// Vectors are kept on the operand stack in memory order, their first lane
+/// at the lowest address, and get moved through XMM registers with
+/// unaligned loads and stores, as the operand stack is only 8-byte aligned.
+const VECTOR: u32 = 16;
+
+/// Width of the lanes of an integer vector
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lane {
+    I8,
+    I16,
+    I32,
+    I64,
+}
+
+impl Lane {
+    fn size(self) -> u32 {
+        match self {
+            Lane::I8 => 1,
+            Lane::I16 => 2,
+            Lane::I32 => 4,
+            Lane::I64 => 8,
+        }
+    }
+
+    /// Value with only the sign bit of the lane set
+    fn sign_bit(self) -> u64 {
+        1 << (self.size() * 8 - 1)
+    }
+}
+
+/// Pushes `xmm0` onto the operand stack
+fn push(assembler: &mut CodeAssembler) -> Result<(), IcedError> {
+    assembler.sub(rsp, VECTOR as i32)?;
+    assembler.movdqu(xmmword_ptr(rsp), xmm0)
+}
+
+/// Loads `xmm` with every lane set to `value`
+///
+/// Clobbers `r11`.
+fn splat_constant(
+    assembler: &mut CodeAssembler,
+    xmm: AsmRegisterXmm,
+    lane: Lane,
+    value: u64,
+) -> Result<(), IcedError> {
+    if lane == Lane::I64 {
+        assembler.mov(r11, value)?;
+        assembler.movq(xmm, r11)?;
+        assembler.punpcklqdq(xmm, xmm)
+    } else {
+        let mut pattern = value as u32;
+        let mut width = lane.size() * 8;
+        while width < 32 {
+            pattern |= pattern << width;
+            width *= 2;
+        }
+        assembler.mov(r11d, pattern)?;
+        assembler.movd(xmm, r11d)?;
+        assembler.pshufd(xmm, xmm, 0)
+    }
+}
+
+/// Loads `xmm` with a vector made of `bytes`, through the stack
+///
+/// Clobbers `r11`.
+fn load_constant(
+    assembler: &mut CodeAssembler,
+    xmm: AsmRegisterXmm,
+    bytes: [u8; 16],
+) -> Result<(), IcedError> {
+    let value = u128::from_le_bytes(bytes);
+    assembler.mov(r11, (value >> 64) as u64)?;
+    assembler.push(r11)?;
+    assembler.mov(r11, value as u64)?;
+    assembler.push(r11)?;
+    assembler.movdqu(xmm, xmmword_ptr(rsp))?;
+    assembler.add(rsp, VECTOR as i32)
+}
+
+/// Sets every bit of `xmm`
+fn all_ones(assembler: &mut CodeAssembler, xmm: AsmRegisterXmm) -> Result<(), IcedError> {
+    assembler.pcmpeqd(xmm, xmm)
+}
+
+/// Flips every bit of `xmm0`, clobbering `xmm1`
+fn not(assembler: &mut CodeAssembler) -> Result<(), IcedError> {
+    all_ones(assembler, xmm1)?;
+    assembler.pxor(xmm0, xmm1)
+}
+
+/// Pops the operands of a binary operator into `xmm0` (left) and `xmm1`
+/// (right), applies `op` and pushes `xmm0` as the result
+pub(crate) fn binary(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.movdqu(xmm1, xmmword_ptr(rsp))?;
+    assembler.movdqu(xmm0, xmmword_ptr(rsp + VECTOR))?;
+    op(assembler)?;
+    assembler.add(rsp, VECTOR as i32)?;
+    assembler.movdqu(xmmword_ptr(rsp), xmm0)?;
+    control.pop(Type::V128);
+    Ok(())
+}
+
+/// Same as [`binary`], for unary operators with the operand in `xmm0`
+pub(crate) fn unary(
+    assembler: &mut CodeAssembler,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+    op(assembler)?;
+    assembler.movdqu(xmmword_ptr(rsp), xmm0)?;
+    Ok(())
+}
+
+/// Pops a vector into `xmm0` and pushes the `i32` that `op` leaves in `eax`
+fn reduce(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    op: impl FnOnce(&mut CodeAssembler) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+    op(assembler)?;
+    assembler.add(rsp, VECTOR as i32)?;
+    assembler.push(rax)?;
+    control.pop(Type::V128);
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// Offsets from `rsp` of a lane of the result and of the operands, in
+/// [`lanes`]
+#[derive(Clone, Copy)]
+struct Lanes {
+    result: u32,
+    /// Lane of the only operand of a unary operator
+    left: u32,
+    right: u32,
+}
+
+/// Offset from `rsp` of the left operand of a binary operator in [`lanes`]
+const LEFT: u32 = 2 * VECTOR;
+
+/// Computes a vector lane by lane with general-purpose registers, for
+/// operators SSE has no instructions for
+///
+/// The result is put together in a scratch vector at `rsp`, right below the
+/// `operands` (one or two) it replaces, and `op` is called for every lane.
+fn lanes(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    operands: u32,
+    lane: Lane,
+    mut op: impl FnMut(&mut CodeAssembler, Lanes) -> Result<(), IcedError>,
+) -> Result<(), Error> {
+    assembler.sub(rsp, VECTOR as i32)?;
+    for offset in (0..VECTOR).step_by(lane.size() as usize) {
+        let lanes = Lanes {
+            result: offset,
+            left: operands * VECTOR + offset,
+            right: VECTOR + offset,
+        };
+        op(assembler, lanes)?;
+    }
+    assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+    assembler.add(rsp, (operands * VECTOR) as i32)?;
+    assembler.movdqu(xmmword_ptr(rsp), xmm0)?;
+    for _ in 1..operands {
+        control.pop(Type::V128);
+    }
+    Ok(())
+}
+
+/// Moves a lane between `rdx` and memory
+fn move_lane(
+    assembler: &mut CodeAssembler,
+    lane: Lane,
+    mem: AsmMemoryOperand,
+    to_memory: bool,
+) -> Result<(), IcedError> {
+    match (lane, to_memory) {
+        (Lane::I8, true) => assembler.mov(byte_ptr(mem), dl),
+        (Lane::I8, false) => assembler.mov(dl, byte_ptr(mem)),
+        (Lane::I16, true) => assembler.mov(word_ptr(mem), dx),
+        (Lane::I16, false) => assembler.mov(dx, word_ptr(mem)),
+        (Lane::I32, true) => assembler.mov(dword_ptr(mem), edx),
+        (Lane::I32, false) => assembler.mov(edx, dword_ptr(mem)),
+        (Lane::I64, true) => assembler.mov(qword_ptr(mem), rdx),
+        (Lane::I64, false) => assembler.mov(rdx, qword_ptr(mem)),
+    }
+}
+
+/// Sets every lane of `xmm0` to its first lane
+///
+/// Clobbers `xmm1`.
+fn broadcast(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    lane: Lane,
+) -> Result<(), IcedError> {
+    match lane {
+        Lane::I8 if features.sse4_1 => {
+            assembler.pxor(xmm1, xmm1)?;
+            assembler.pshufb(xmm0, xmm1)
+        }
+        Lane::I8 => {
+            assembler.punpcklbw(xmm0, xmm0)?;
+            assembler.pshuflw(xmm0, xmm0, 0)?;
+            assembler.pshufd(xmm0, xmm0, 0)
+        }
+        Lane::I16 => {
+            assembler.pshuflw(xmm0, xmm0, 0)?;
+            assembler.pshufd(xmm0, xmm0, 0)
+        }
+        Lane::I32 => assembler.pshufd(xmm0, xmm0, 0),
+        Lane::I64 => assembler.punpcklqdq(xmm0, xmm0),
+    }
+}
+
+/// Extends the lanes of the lower half of `xmm` to twice their width,
+/// `lane` being the resulting width
+///
+/// Clobbers `scratch`.
+fn extend_low(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    lane: Lane,
+    signed: bool,
+    xmm: AsmRegisterXmm,
+    scratch: AsmRegisterXmm,
+) -> Result<(), IcedError> {
+    match (lane, signed, features.sse4_1) {
+        (Lane::I16, true, true) => assembler.pmovsxbw(xmm, xmm),
+        (Lane::I16, false, true) => assembler.pmovzxbw(xmm, xmm),
+        (Lane::I32, true, true) => assembler.pmovsxwd(xmm, xmm),
+        (Lane::I32, false, true) => assembler.pmovzxwd(xmm, xmm),
+        (Lane::I64, true, true) => assembler.pmovsxdq(xmm, xmm),
+        (Lane::I64, false, true) => assembler.pmovzxdq(xmm, xmm),
+        // Lanes are interleaved with themselves and shifted into place for
+        // a sign extension, or with zeroes
+        (Lane::I16, true, false) => {
+            assembler.punpcklbw(xmm, xmm)?;
+            assembler.psraw(xmm, 8)
+        }
+        (Lane::I32, true, false) => {
+            assembler.punpcklwd(xmm, xmm)?;
+            assembler.psrad(xmm, 16)
+        }
+        (Lane::I64, true, false) => {
+            assembler.movdqa(scratch, xmm)?;
+            assembler.psrad(scratch, 31)?;
+            assembler.punpckldq(xmm, scratch)
+        }
+        (_, false, false) => {
+            assembler.pxor(scratch, scratch)?;
+            match lane {
+                Lane::I16 => assembler.punpcklbw(xmm, scratch),
+                Lane::I32 => assembler.punpcklwd(xmm, scratch),
+                _ => assembler.punpckldq(xmm, scratch),
+            }
+        }
+        (Lane::I8, ..) => unreachable!("no lanes are extended to 8 bits"),
+    }
+}
+
+/// Kind of a vector load
+#[derive(Clone, Copy)]
+pub(crate) enum Load {
+    Vector,
+    /// Eight bytes extended to lanes of the given width
+    Extend(Lane, bool),
+    /// A lane repeated across the vector
+    Splat(Lane),
+    /// A lane followed by zeroes
+    Zero(Lane),
+}
+
+/// `v128.load` and its variants
+pub(crate) fn load(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    kind: Load,
+) -> Result<(), Error> {
+    let size = match kind {
+        Load::Vector => VECTOR,
+        Load::Extend(..) => 8,
+        Load::Splat(lane) | Load::Zero(lane) => lane.size(),
+    };
+    let mem = memory::address(assembler, context, memarg, size)?;
+    match kind {
+        Load::Vector => assembler.movdqu(xmm0, xmmword_ptr(mem))?,
+        Load::Extend(lane, signed) => {
+            assembler.movq(xmm0, qword_ptr(mem))?;
+            extend_low(assembler, context.features, lane, signed, xmm0, xmm1)?;
+        }
+        Load::Splat(lane) => {
+            move_lane(assembler, lane, mem, false)?;
+            assembler.movq(xmm0, rdx)?;
+            broadcast(assembler, context.features, lane)?;
+        }
+        Load::Zero(Lane::I32) => assembler.movd(xmm0, dword_ptr(mem))?,
+        Load::Zero(_) => assembler.movq(xmm0, qword_ptr(mem))?,
+    }
+    push(assembler)?;
+    control.pop(context.index_type(memarg.memory));
+    control.push(Type::V128);
+    Ok(())
+}
+
+/// `v128.store`
+pub(crate) fn store(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+) -> Result<(), Error> {
+    assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+    assembler.add(rsp, VECTOR as i32)?;
+    let mem = memory::address(assembler, context, memarg, VECTOR)?;
+    assembler.movdqu(xmmword_ptr(mem), xmm0)?;
+    control.pop(Type::V128);
+    control.pop(context.index_type(memarg.memory));
+    Ok(())
+}
+
+/// `v128.load*_lane`, replacing a lane of the vector with a value loaded
+/// from memory
+pub(crate) fn load_lane(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    lane: Lane,
+    index: u8,
+) -> Result<(), Error> {
+    // The vector is on top of the address
+    assembler.movdqu(xmm0, xmmword_ptr(rsp))?;
+    assembler.add(rsp, VECTOR as i32)?;
+    let mem = memory::address(assembler, context, memarg, lane.size())?;
+    move_lane(assembler, lane, mem, false)?;
+    push(assembler)?;
+    move_lane(assembler, lane, rsp + index as u32 * lane.size(), true)?;
+    control.pop(Type::V128);
+    control.pop(context.index_type(memarg.memory));
+    control.push(Type::V128);
+    Ok(())
+}
+
+/// `v128.store*_lane`
+pub(crate) fn store_lane(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    memarg: &MemoryImmediate,
+    lane: Lane,
+    index: u8,
+) -> Result<(), Error> {
+    move_lane(assembler, lane, rsp + index as u32 * lane.size(), false)?;
+    assembler.add(rsp, VECTOR as i32)?;
+    let mem = memory::address(assembler, context, memarg, lane.size())?;
+    move_lane(assembler, lane, mem, true)?;
+    control.pop(Type::V128);
+    control.pop(context.index_type(memarg.memory));
+    Ok(())
+}
+
+/// `v128.const`
+pub(crate) fn constant(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    bytes: [u8; 16],
+) -> Result<(), Error> {
+    let value = u128::from_le_bytes(bytes);
+    assembler.mov(rax, (value >> 64) as u64)?;
+    assembler.push(rax)?;
+    assembler.mov(rax, value as u64)?;
+    assembler.push(rax)?;
+    control.push(Type::V128);
+    Ok(())
+}
+
+/// `extract_lane`, pushing the lane as a value of type `ty`, sign-extended
+/// if `signed`
+pub(crate) fn extract_lane(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    lane: Lane,
+    signed: bool,
+    index: u8,
+) -> Result<(), Error> {
+    let value = rsp + index as u32 * lane.size();
+    match (lane, signed) {
+        (Lane::I8, true) => assembler.movsx(eax, byte_ptr(value))?,
+        (Lane::I8, false) => assembler.movzx(eax, byte_ptr(value))?,
+        (Lane::I16, true) => assembler.movsx(eax, word_ptr(value))?,
+        (Lane::I16, false) => assembler.movzx(eax, word_ptr(value))?,
+        (Lane::I32, _) => assembler.mov(eax, dword_ptr(value))?,
+        (Lane::I64, _) => assembler.mov(rax, qword_ptr(value))?,
+    }
+    assembler.add(rsp, VECTOR as i32)?;
+    assembler.push(rax)?;
+    control.pop(Type::V128);
+    control.push(ty);
+    Ok(())
+}
+
+/// `replace_lane` with a value of type `ty`
+pub(crate) fn replace_lane(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    lane: Lane,
+    index: u8,
+) -> Result<(), Error> {
+    assembler.pop(rdx)?;
+    move_lane(assembler, lane, rsp + index as u32 * lane.size(), true)?;
+    control.pop(ty);
+    Ok(())
+}
+
+/// `splat` of a value of type `ty`
+pub(crate) fn splat(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    ty: Type,
+    lane: Lane,
+) -> Result<(), Error> {
+    assembler.pop(rax)?;
+    assembler.movq(xmm0, rax)?;
+    broadcast(assembler, features, lane)?;
+    push(assembler)?;
+    control.pop(ty);
+    control.push(Type::V128);
+    Ok(())
+}
+
+/// `i8x16.shuffle`, picking the bytes of the result from both operands
+pub(crate) fn shuffle(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    indices: [u8; 16],
+) -> Result<(), Error> {
+    if features.sse4_1 {
+        // Bytes with the top bit set in a `pshufb` mask are zeroed, so each
+        // operand contributes its own bytes
+        let mut left = [0x80; 16];
+        let mut right = [0x80; 16];
+        for (i, index) in indices.iter().enumerate() {
+            if *index < 16 {
+                left[i] = *index;
+            } else {
+                right[i] = *index - 16;
+            }
+        }
+        binary(assembler, control, |assembler| {
+            load_constant(assembler, xmm2, left)?;
+            assembler.pshufb(xmm0, xmm2)?;
+            load_constant(assembler, xmm2, right)?;
+            assembler.pshufb(xmm1, xmm2)?;
+            assembler.por(xmm0, xmm1)
+        })
+    } else {
+        lanes(assembler, control, 2, Lane::I8, |assembler, lanes| {
+            let index = indices[lanes.result as usize] as u32;
+            let source = if index < 16 {
+                LEFT + index
+            } else {
+                VECTOR + index - 16
+            };
+            assembler.mov(al, byte_ptr(rsp + source))?;
+            assembler.mov(byte_ptr(rsp + lanes.result), al)
+        })
+    }
+}
+
+/// `i8x16.swizzle`, picking the bytes of the result from the left operand
+/// by the indices in the right one, or zero for indices out of range
+pub(crate) fn swizzle(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+) -> Result<(), Error> {
+    if features.sse4_1 {
+        binary(assembler, control, |assembler| {
+            // Indices from 16 get the top bit set
+            splat_constant(assembler, xmm2, Lane::I8, 0x70)?;
+            assembler.paddusb(xmm1, xmm2)?;
+            assembler.pshufb(xmm0, xmm1)
+        })
+    } else {
+        lanes(assembler, control, 2, Lane::I8, |assembler, lanes| {
+            assembler.movzx(ecx, byte_ptr(rsp + lanes.right))?;
+            assembler.cmp(ecx, 16)?;
+            // All ones if the index is in range
+            assembler.sbb(edx, edx)?;
+            assembler.and(ecx, 15)?;
+            assembler.movzx(eax, byte_ptr(rsp + rcx + LEFT))?;
+            assembler.and(eax, edx)?;
+            assembler.mov(byte_ptr(rsp + lanes.result), al)
+        })
+    }
+}
+
+/// Compares lanes of integer vectors, setting the lanes of the result to
+/// all ones where the comparison holds and to zero elsewhere
+pub(crate) fn compare(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    lane: Lane,
+    comparison: Comparison,
+    signed: bool,
+) -> Result<(), Error> {
+    let equality = matches!(comparison, Comparison::Eq | Comparison::Ne);
+    if lane == Lane::I64 && !equality {
+        // Signed 64-bit comparisons need SSE4.2
+        return lanes(assembler, control, 2, Lane::I64, |assembler, lanes| {
+            assembler.mov(rax, qword_ptr(rsp + lanes.left))?;
+            assembler.xor(ecx, ecx)?;
+            assembler.cmp(rax, qword_ptr(rsp + lanes.right))?;
+            match comparison {
+                Comparison::Lt => assembler.setl(cl)?,
+                Comparison::Gt => assembler.setg(cl)?,
+                Comparison::Le => assembler.setle(cl)?,
+                _ => assembler.setge(cl)?,
+            }
+            assembler.neg(rcx)?;
+            assembler.mov(qword_ptr(rsp + lanes.result), rcx)
+        });
+    }
+    binary(assembler, control, |assembler| {
+        let greater = |assembler: &mut CodeAssembler, left, right| match lane {
+            Lane::I8 => assembler.pcmpgtb(left, right),
+            Lane::I16 => assembler.pcmpgtw(left, right),
+            _ => assembler.pcmpgtd(left, right),
+        };
+        if equality {
+            match lane {
+                Lane::I8 => assembler.pcmpeqb(xmm0, xmm1)?,
+                Lane::I16 => assembler.pcmpeqw(xmm0, xmm1)?,
+                Lane::I32 => assembler.pcmpeqd(xmm0, xmm1)?,
+                Lane::I64 if features.sse4_1 => assembler.pcmpeqq(xmm0, xmm1)?,
+                Lane::I64 => {
+                    // Both halves of a lane must be equal
+                    assembler.pcmpeqd(xmm0, xmm1)?;
+                    assembler.pshufd(xmm1, xmm0, 0xB1)?;
+                    assembler.pand(xmm0, xmm1)?;
+                }
+            }
+            if comparison == Comparison::Ne {
+                not(assembler)?;
+            }
+            return Ok(());
+        }
+        if !signed {
+            // Flipping the sign bits orders unsigned values as signed ones
+            splat_constant(assembler, xmm2, lane, lane.sign_bit())?;
+            assembler.pxor(xmm0, xmm2)?;
+            assembler.pxor(xmm1, xmm2)?;
+        }
+        match comparison {
+            Comparison::Gt | Comparison::Le => greater(assembler, xmm0, xmm1)?,
+            _ => {
+                greater(assembler, xmm1, xmm0)?;
+                assembler.movdqa(xmm0, xmm1)?;
+            }
+        }
+        if matches!(comparison, Comparison::Le | Comparison::Ge) {
+            not(assembler)?;
+        }
+        Ok(())
+    })
+}
+
+/// Compares lanes of float vectors of type `ty`, same as [`compare`]
+///
+/// Comparisons involving a NaN are false, except for `ne`.
+pub(crate) fn compare_float(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    comparison: Comparison,
+) -> Result<(), Error> {
+    binary(assembler, control, |assembler| {
+        let f32 = ty == Type::F32;
+        let (left, right, predicate) = match comparison {
+            Comparison::Eq => (xmm0, xmm1, 0),
+            Comparison::Lt => (xmm0, xmm1, 1),
+            Comparison::Le => (xmm0, xmm1, 2),
+            Comparison::Ne => (xmm0, xmm1, 4),
+            Comparison::Gt => (xmm1, xmm0, 1),
+            Comparison::Ge => (xmm1, xmm0, 2),
+        };
+        if f32 {
+            assembler.cmpps(left, right, predicate)?;
+        } else {
+            assembler.cmppd(left, right, predicate)?;
+        }
+        if left != xmm0 {
+            assembler.movaps(xmm0, left)?;
+        }
+        Ok(())
+    })
+}
+
+/// `v128.bitselect`, taking bits from the first operand where the third one
+/// has them set, and from the second one elsewhere
+pub(crate) fn bitselect(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+) -> Result<(), Error> {
+    assembler.movdqu(xmm2, xmmword_ptr(rsp))?;
+    assembler.movdqu(xmm1, xmmword_ptr(rsp + VECTOR))?;
+    assembler.movdqu(xmm0, xmmword_ptr(rsp + 2 * VECTOR))?;
+    assembler.pand(xmm0, xmm2)?;
+    assembler.pandn(xmm2, xmm1)?;
+    assembler.por(xmm0, xmm2)?;
+    assembler.add(rsp, (2 * VECTOR) as i32)?;
+    assembler.movdqu(xmmword_ptr(rsp), xmm0)?;
+    control.pop(Type::V128);
+    control.pop(Type::V128);
+    Ok(())
+}
+
+/// `v128.any_true`
+pub(crate) fn any_true(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+) -> Result<(), Error> {
+    reduce(assembler, control, |assembler| {
+        assembler.mov(rax, qword_ptr(rsp))?;
+        assembler.or(rax, qword_ptr(rsp + 8))?;
+        assembler.setne(al)?;
+        assembler.movzx(eax, al)
+    })
+}
+
+/// `all_true`
+pub(crate) fn all_true(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    lane: Lane,
+) -> Result<(), Error> {
+    reduce(assembler, control, |assembler| {
+        // Marks lanes that are zero
+        assembler.pxor(xmm1, xmm1)?;
+        match lane {
+            Lane::I8 => assembler.pcmpeqb(xmm1, xmm0)?,
+            Lane::I16 => assembler.pcmpeqw(xmm1, xmm0)?,
+            Lane::I32 => assembler.pcmpeqd(xmm1, xmm0)?,
+            Lane::I64 => {
+                assembler.pcmpeqd(xmm1, xmm0)?;
+                assembler.pshufd(xmm2, xmm1, 0xB1)?;
+                assembler.pand(xmm1, xmm2)?;
+            }
+        }
+        assembler.pmovmskb(eax, xmm1)?;
+        assembler.test(eax, eax)?;
+        assembler.sete(al)?;
+        assembler.movzx(eax, al)
+    })
+}
+
+/// `bitmask`, gathering the top bits of the lanes
+pub(crate) fn bitmask(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    lane: Lane,
+) -> Result<(), Error> {
+    reduce(assembler, control, |assembler| match lane {
+        Lane::I8 => assembler.pmovmskb(eax, xmm0),
+        Lane::I16 => {
+            // Saturation keeps the sign of every lane
+            assembler.packsswb(xmm0, xmm0)?;
+            assembler.pmovmskb(eax, xmm0)?;
+            assembler.movzx(eax, al)
+        }
+        Lane::I32 => assembler.movmskps(eax, xmm0),
+        Lane::I64 => assembler.movmskpd(eax, xmm0),
+    })
+}
+
+/// `abs` of integer lanes
+pub(crate) fn abs(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    lane: Lane,
+) -> Result<(), Error> {
+    unary(assembler, |assembler| match (lane, features.sse4_1) {
+        (Lane::I8, true) => assembler.pabsb(xmm0, xmm0),
+        (Lane::I16, true) => assembler.pabsw(xmm0, xmm0),
+        (Lane::I32, true) => assembler.pabsd(xmm0, xmm0),
+        // The smaller of a value and its negation, as unsigned
+        (Lane::I8, false) => {
+            assembler.pxor(xmm1, xmm1)?;
+            assembler.psubb(xmm1, xmm0)?;
+            assembler.pminub(xmm0, xmm1)
+        }
+        (Lane::I16, false) => {
+            assembler.pxor(xmm1, xmm1)?;
+            assembler.psubw(xmm1, xmm0)?;
+            assembler.pmaxsw(xmm0, xmm1)
+        }
+        // Negative lanes are flipped and incremented
+        (Lane::I32, false) => {
+            assembler.movdqa(xmm1, xmm0)?;
+            assembler.psrad(xmm1, 31)?;
+            assembler.pxor(xmm0, xmm1)?;
+            assembler.psubd(xmm0, xmm1)
+        }
+        (Lane::I64, _) => {
+            assembler.pshufd(xmm1, xmm0, 0xF5)?;
+            assembler.psrad(xmm1, 31)?;
+            assembler.pxor(xmm0, xmm1)?;
+            assembler.psubq(xmm0, xmm1)
+        }
+    })
+}
+
+/// `neg` of integer lanes
+pub(crate) fn neg(assembler: &mut CodeAssembler, lane: Lane) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        assembler.pxor(xmm1, xmm1)?;
+        match lane {
+            Lane::I8 => assembler.psubb(xmm1, xmm0)?,
+            Lane::I16 => assembler.psubw(xmm1, xmm0)?,
+            Lane::I32 => assembler.psubd(xmm1, xmm0)?,
+            Lane::I64 => assembler.psubq(xmm1, xmm0)?,
+        }
+        assembler.movdqa(xmm0, xmm1)
+    })
+}
+
+/// `i8x16.popcnt`, summing bits in pairs, then nibbles, then bytes
+pub(crate) fn popcnt(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        // Shifting 16-bit lanes moves bits across bytes, which the masks
+        // clear
+        splat_constant(assembler, xmm2, Lane::I8, 0x55)?;
+        assembler.movdqa(xmm1, xmm0)?;
+        assembler.psrlw(xmm1, 1)?;
+        assembler.pand(xmm1, xmm2)?;
+        assembler.psubb(xmm0, xmm1)?;
+        splat_constant(assembler, xmm2, Lane::I8, 0x33)?;
+        assembler.movdqa(xmm1, xmm0)?;
+        assembler.psrlw(xmm1, 2)?;
+        assembler.pand(xmm0, xmm2)?;
+        assembler.pand(xmm1, xmm2)?;
+        assembler.paddb(xmm0, xmm1)?;
+        assembler.movdqa(xmm1, xmm0)?;
+        assembler.psrlw(xmm1, 4)?;
+        assembler.paddb(xmm0, xmm1)?;
+        splat_constant(assembler, xmm2, Lane::I8, 0x0F)?;
+        assembler.pand(xmm0, xmm2)
+    })
+}
+
+/// `narrow`, packing the lanes of both operands into lanes of `lane` width
+/// with saturation
+pub(crate) fn narrow(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    lane: Lane,
+    signed: bool,
+) -> Result<(), Error> {
+    binary(assembler, control, |assembler| {
+        match (lane, signed, features.sse4_1) {
+            (Lane::I8, true, _) => assembler.packsswb(xmm0, xmm1),
+            (Lane::I8, false, _) => assembler.packuswb(xmm0, xmm1),
+            (_, true, _) => assembler.packssdw(xmm0, xmm1),
+            (_, false, true) => assembler.packusdw(xmm0, xmm1),
+            (_, false, false) => {
+                // Negative lanes are zeroed, and the rest is moved down to
+                // the signed range and back around a signed pack
+                for xmm in [xmm0, xmm1] {
+                    assembler.pxor(xmm2, xmm2)?;
+                    assembler.pcmpgtd(xmm2, xmm)?;
+                    assembler.pandn(xmm2, xmm)?;
+                    assembler.movdqa(xmm, xmm2)?;
+                }
+                splat_constant(assembler, xmm2, Lane::I32, 0x8000)?;
+                assembler.psubd(xmm0, xmm2)?;
+                assembler.psubd(xmm1, xmm2)?;
+                assembler.packssdw(xmm0, xmm1)?;
+                splat_constant(assembler, xmm2, Lane::I16, 0x8000)?;
+                assembler.paddw(xmm0, xmm2)
+            }
+        }
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shift {
+    Left,
+    RightSigned,
+    RightUnsigned,
+}
+
+/// Shifts the lanes of a vector by an `i32`, modulo the width of the lanes
+pub(crate) fn shift(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    lane: Lane,
+    shift: Shift,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    assembler.and(ecx, lane.size() * 8 - 1)?;
+    control.pop(Type::I32);
+    if lane == Lane::I64 && shift == Shift::RightSigned {
+        // SSE has no arithmetic shift of 64-bit lanes
+        assembler.sar(qword_ptr(rsp), cl)?;
+        assembler.sar(qword_ptr(rsp + 8), cl)?;
+        return Ok(());
+    }
+    unary(assembler, |assembler| {
+        assembler.movd(xmm1, ecx)?;
+        match (lane, shift) {
+            (Lane::I8, Shift::RightSigned) => {
+                // Bytes are doubled into 16-bit lanes, shifted from the
+                // upper half and packed back
+                assembler.movdqa(xmm2, xmm0)?;
+                assembler.punpcklbw(xmm2, xmm0)?;
+                assembler.punpckhbw(xmm0, xmm0)?;
+                assembler.add(ecx, 8)?;
+                assembler.movd(xmm1, ecx)?;
+                assembler.psraw(xmm2, xmm1)?;
+                assembler.psraw(xmm0, xmm1)?;
+                assembler.packsswb(xmm2, xmm0)?;
+                assembler.movdqa(xmm0, xmm2)
+            }
+            (Lane::I8, _) => {
+                // 16-bit lanes are shifted, and the bits crossing bytes are
+                // cleared
+                assembler.mov(r11d, 0xFF)?;
+                if shift == Shift::Left {
+                    assembler.psllw(xmm0, xmm1)?;
+                    assembler.shl(r11d, cl)?;
+                } else {
+                    assembler.psrlw(xmm0, xmm1)?;
+                    assembler.shr(r11d, cl)?;
+                }
+                assembler.movzx(r11d, iced_x86::code_asm::r11b)?;
+                assembler.imul_3(r11d, r11d, 0x0101_0101)?;
+                assembler.movd(xmm2, r11d)?;
+                assembler.pshufd(xmm2, xmm2, 0)?;
+                assembler.pand(xmm0, xmm2)
+            }
+            (Lane::I16, Shift::Left) => assembler.psllw(xmm0, xmm1),
+            (Lane::I16, Shift::RightSigned) => assembler.psraw(xmm0, xmm1),
+            (Lane::I16, Shift::RightUnsigned) => assembler.psrlw(xmm0, xmm1),
+            (Lane::I32, Shift::Left) => assembler.pslld(xmm0, xmm1),
+            (Lane::I32, Shift::RightSigned) => assembler.psrad(xmm0, xmm1),
+            (Lane::I32, Shift::RightUnsigned) => assembler.psrld(xmm0, xmm1),
+            (Lane::I64, Shift::Left) => assembler.psllq(xmm0, xmm1),
+            (Lane::I64, _) => assembler.psrlq(xmm0, xmm1),
+        }
+    })
+}
+
+/// `mul` of 32-bit and 64-bit lanes
+pub(crate) fn mul(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    lane: Lane,
+) -> Result<(), Error> {
+    match (lane, features.sse4_1) {
+        (Lane::I32, true) => binary(assembler, control, |a| a.pmulld(xmm0, xmm1)),
+        (Lane::I32, false) => binary(assembler, control, |assembler| {
+            // Even and odd lanes are multiplied into 64-bit products, whose
+            // lower halves are put back together
+            assembler.movdqa(xmm2, xmm0)?;
+            assembler.pmuludq(xmm0, xmm1)?;
+            assembler.psrlq(xmm2, 32)?;
+            assembler.psrlq(xmm1, 32)?;
+            assembler.pmuludq(xmm2, xmm1)?;
+            assembler.pshufd(xmm0, xmm0, 0x08)?;
+            assembler.pshufd(xmm2, xmm2, 0x08)?;
+            assembler.punpckldq(xmm0, xmm2)
+        }),
+        _ => lanes(assembler, control, 2, Lane::I64, |assembler, lanes| {
+            assembler.mov(rax, qword_ptr(rsp + lanes.left))?;
+            assembler.imul_2(rax, qword_ptr(rsp + lanes.right))?;
+            assembler.mov(qword_ptr(rsp + lanes.result), rax)
+        }),
+    }
+}
+
+/// `min` (or `max`, if `max` is set) of integer lanes
+pub(crate) fn min_max(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    lane: Lane,
+    signed: bool,
+    max: bool,
+) -> Result<(), Error> {
+    binary(assembler, control, |assembler| {
+        match (lane, signed, max, features.sse4_1) {
+            (Lane::I8, false, false, _) => return assembler.pminub(xmm0, xmm1),
+            (Lane::I8, false, true, _) => return assembler.pmaxub(xmm0, xmm1),
+            (Lane::I16, true, false, _) => return assembler.pminsw(xmm0, xmm1),
+            (Lane::I16, true, true, _) => return assembler.pmaxsw(xmm0, xmm1),
+            (Lane::I8, true, false, true) => return assembler.pminsb(xmm0, xmm1),
+            (Lane::I8, true, true, true) => return assembler.pmaxsb(xmm0, xmm1),
+            (Lane::I16, false, false, true) => return assembler.pminuw(xmm0, xmm1),
+            (Lane::I16, false, true, true) => return assembler.pmaxuw(xmm0, xmm1),
+            (Lane::I32, true, false, true) => return assembler.pminsd(xmm0, xmm1),
+            (Lane::I32, true, true, true) => return assembler.pmaxsd(xmm0, xmm1),
+            (Lane::I32, false, false, true) => return assembler.pminud(xmm0, xmm1),
+            (Lane::I32, false, true, true) => return assembler.pmaxud(xmm0, xmm1),
+            _ => (),
+        }
+        // Lanes are picked by a mask of those where the left one is greater
+        assembler.movdqa(xmm2, xmm0)?;
+        assembler.movdqa(xmm3, xmm1)?;
+        if !signed {
+            splat_constant(assembler, xmm4, lane, lane.sign_bit())?;
+            assembler.pxor(xmm2, xmm4)?;
+            assembler.pxor(xmm3, xmm4)?;
+        }
+        match lane {
+            Lane::I8 => assembler.pcmpgtb(xmm2, xmm3)?,
+            Lane::I16 => assembler.pcmpgtw(xmm2, xmm3)?,
+            _ => assembler.pcmpgtd(xmm2, xmm3)?,
+        }
+        let (greater, other) = if max { (xmm0, xmm1) } else { (xmm1, xmm0) };
+        assembler.pand(greater, xmm2)?;
+        assembler.pandn(xmm2, other)?;
+        assembler.por(greater, xmm2)?;
+        if greater != xmm0 {
+            assembler.movdqa(xmm0, greater)?;
+        }
+        Ok(())
+    })
+}
+
+/// `extadd_pairwise`, adding pairs of adjacent lanes into lanes of twice
+/// their width, `lane` being the resulting width
+pub(crate) fn extend_add_pairwise(
+    assembler: &mut CodeAssembler,
+    lane: Lane,
+    signed: bool,
+) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        // Even lanes are shifted to the top and back, odd ones down
+        assembler.movdqa(xmm1, xmm0)?;
+        if lane == Lane::I16 {
+            assembler.psllw(xmm1, 8)?;
+            if signed {
+                assembler.psraw(xmm1, 8)?;
+                assembler.psraw(xmm0, 8)?;
+            } else {
+                assembler.psrlw(xmm1, 8)?;
+                assembler.psrlw(xmm0, 8)?;
+            }
+            assembler.paddw(xmm0, xmm1)
+        } else {
+            assembler.pslld(xmm1, 16)?;
+            if signed {
+                assembler.psrad(xmm1, 16)?;
+                assembler.psrad(xmm0, 16)?;
+            } else {
+                assembler.psrld(xmm1, 16)?;
+                assembler.psrld(xmm0, 16)?;
+            }
+            assembler.paddd(xmm0, xmm1)
+        }
+    })
+}
+
+/// `i16x8.q15mulr_sat_s`, a rounding fixed-point multiplication
+pub(crate) fn q15_mulr(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+) -> Result<(), Error> {
+    if features.sse4_1 {
+        binary(assembler, control, |assembler| {
+            assembler.pmulhrsw(xmm0, xmm1)?;
+            // The only overflow, of -1 times -1, wraps around to -1
+            splat_constant(assembler, xmm1, Lane::I16, 0x8000)?;
+            assembler.pcmpeqw(xmm1, xmm0)?;
+            assembler.pxor(xmm0, xmm1)
+        })
+    } else {
+        lanes(assembler, control, 2, Lane::I16, |assembler, lanes| {
+            assembler.movsx(eax, word_ptr(rsp + lanes.left))?;
+            assembler.movsx(ecx, word_ptr(rsp + lanes.right))?;
+            assembler.imul_2(eax, ecx)?;
+            assembler.add(eax, 0x4000)?;
+            assembler.sar(eax, 15)?;
+            assembler.mov(ecx, 0x7FFF)?;
+            assembler.cmp(eax, ecx)?;
+            assembler.cmovg(eax, ecx)?;
+            assembler.mov(word_ptr(rsp + lanes.result), iced_x86::code_asm::ax)
+        })
+    }
+}
+
+/// `extend_low` and `extend_high`, `lane` being the resulting width
+pub(crate) fn extend(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    lane: Lane,
+    signed: bool,
+    high: bool,
+) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        if high {
+            assembler.psrldq(xmm0, 8)?;
+        }
+        extend_low(assembler, features, lane, signed, xmm0, xmm1)
+    })
+}
+
+/// `extmul_low` and `extmul_high`, multiplying lanes into lanes of twice
+/// their width, `lane` being the resulting width
+pub(crate) fn extend_mul(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    control: &mut ControlStack,
+    lane: Lane,
+    signed: bool,
+    high: bool,
+) -> Result<(), Error> {
+    match lane {
+        Lane::I64 if signed && !features.sse4_1 => {
+            let half = if high { 8 } else { 0 };
+            lanes(assembler, control, 2, Lane::I64, |assembler, lanes| {
+                let source = half + lanes.result / 2;
+                assembler.movsxd(rax, dword_ptr(rsp + LEFT + source))?;
+                assembler.movsxd(rcx, dword_ptr(rsp + VECTOR + source))?;
+                assembler.imul_2(rax, rcx)?;
+                assembler.mov(qword_ptr(rsp + lanes.result), rax)
+            })
+        }
+        Lane::I64 => binary(assembler, control, |assembler| {
+            // Lanes go to the even positions `pmuldq` multiplies
+            let order = if high { 0xFA } else { 0x50 };
+            assembler.pshufd(xmm0, xmm0, order)?;
+            assembler.pshufd(xmm1, xmm1, order)?;
+            if signed {
+                assembler.pmuldq(xmm0, xmm1)
+            } else {
+                assembler.pmuludq(xmm0, xmm1)
+            }
+        }),
+        Lane::I32 => binary(assembler, control, |assembler| {
+            // Lower and upper halves of the products are interleaved
+            assembler.movdqa(xmm2, xmm0)?;
+            assembler.pmullw(xmm0, xmm1)?;
+            if signed {
+                assembler.pmulhw(xmm2, xmm1)?;
+            } else {
+                assembler.pmulhuw(xmm2, xmm1)?;
+            }
+            if high {
+                assembler.punpckhwd(xmm0, xmm2)
+            } else {
+                assembler.punpcklwd(xmm0, xmm2)
+            }
+        }),
+        _ => binary(assembler, control, |assembler| {
+            if high {
+                assembler.psrldq(xmm0, 8)?;
+                assembler.psrldq(xmm1, 8)?;
+            }
+            extend_low(assembler, features, lane, signed, xmm0, xmm2)?;
+            extend_low(assembler, features, lane, signed, xmm1, xmm2)?;
+            assembler.pmullw(xmm0, xmm1)
+        }),
+    }
+}
+
+/// Loads `xmm` with the sign bits of float lanes of type `ty`
+fn sign_mask(
+    assembler: &mut CodeAssembler,
+    ty: Type,
+    xmm: AsmRegisterXmm,
+) -> Result<(), IcedError> {
+    all_ones(assembler, xmm)?;
+    if ty == Type::F32 {
+        assembler.pslld(xmm, 31)
+    } else {
+        assembler.psllq(xmm, 63)
+    }
+}
+
+/// `abs` of float lanes, clearing their sign bits
+pub(crate) fn abs_float(assembler: &mut CodeAssembler, ty: Type) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        sign_mask(assembler, ty, xmm1)?;
+        assembler.andnps(xmm1, xmm0)?;
+        assembler.movaps(xmm0, xmm1)
+    })
+}
+
+/// `neg` of float lanes, flipping their sign bits
+pub(crate) fn neg_float(assembler: &mut CodeAssembler, ty: Type) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        sign_mask(assembler, ty, xmm1)?;
+        assembler.xorps(xmm0, xmm1)
+    })
+}
+
+/// `min` (or `max`, if `max` is set) of float lanes
+///
+/// `minps` and friends return the right operand if either is a NaN, and
+/// don't order zeroes of different signs. Results of both orders of the
+/// operands are merged, which keeps NaNs and the sign of zero, and NaNs
+/// are made canonical.
+pub(crate) fn min_max_float(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    max: bool,
+) -> Result<(), Error> {
+    let f32 = ty == Type::F32;
+    binary(assembler, control, |assembler| {
+        assembler.movaps(xmm2, xmm1)?;
+        match (f32, max) {
+            (true, false) => {
+                assembler.minps(xmm2, xmm0)?;
+                assembler.minps(xmm0, xmm1)?;
+            }
+            (true, true) => {
+                assembler.maxps(xmm2, xmm0)?;
+                assembler.maxps(xmm0, xmm1)?;
+            }
+            (false, false) => {
+                assembler.minpd(xmm2, xmm0)?;
+                assembler.minpd(xmm0, xmm1)?;
+            }
+            (false, true) => {
+                assembler.maxpd(xmm2, xmm0)?;
+                assembler.maxpd(xmm0, xmm1)?;
+            }
+        }
+        if max {
+            // Lanes differing between the orders are a NaN, or zeroes of
+            // which the positive one wins; subtracting their difference
+            // gives either
+            assembler.xorps(xmm0, xmm2)?;
+            assembler.orps(xmm2, xmm0)?;
+            if f32 {
+                assembler.subps(xmm2, xmm0)?;
+            } else {
+                assembler.subpd(xmm2, xmm0)?;
+            }
+        } else {
+            // A negative zero wins, and a NaN keeps its bits
+            assembler.orps(xmm2, xmm0)?;
+        }
+        // NaN lanes keep the quiet bit and lose their payload
+        if f32 {
+            assembler.movaps(xmm0, xmm2)?;
+            assembler.cmpps(xmm0, xmm2, 3)?;
+        } else {
+            assembler.movapd(xmm0, xmm2)?;
+            assembler.cmppd(xmm0, xmm2, 3)?;
+        }
+        if !max {
+            assembler.orps(xmm2, xmm0)?;
+        }
+        if f32 {
+            assembler.psrld(xmm0, 10)?;
+        } else {
+            assembler.psrlq(xmm0, 13)?;
+        }
+        assembler.andnps(xmm0, xmm2)
+    })
+}
+
+/// `pmin` (or `pmax`, if `max` is set), picking the left operand unless the
+/// right one is less (or greater), as `minps` and friends do with the
+/// operands swapped
+pub(crate) fn pseudo_min_max(
+    assembler: &mut CodeAssembler,
+    control: &mut ControlStack,
+    ty: Type,
+    max: bool,
+) -> Result<(), Error> {
+    binary(assembler, control, |assembler| {
+        match (ty == Type::F32, max) {
+            (true, false) => assembler.minps(xmm1, xmm0)?,
+            (true, true) => assembler.maxps(xmm1, xmm0)?,
+            (false, false) => assembler.minpd(xmm1, xmm0)?,
+            (false, true) => assembler.maxpd(xmm1, xmm0)?,
+        }
+        assembler.movaps(xmm0, xmm1)
+    })
+}
+
+/// `ceil`, `floor`, `trunc` and `nearest` of float lanes
+///
+/// Without SSE4.1, lanes are rounded to nearest by adding and subtracting
+/// 2^52 (2^23 for `f32`) to their magnitude, relying on the default
+/// round-to-nearest-even mode, and adjusted by one where they rounded the
+/// wrong way. Lanes of a greater magnitude are already integral, or NaNs,
+/// which get quieted by adding zero.
+pub(crate) fn round(
+    assembler: &mut CodeAssembler,
+    features: Features,
+    ty: Type,
+    rounding: Rounding,
+) -> Result<(), Error> {
+    let f32 = ty == Type::F32;
+    if features.sse4_1 {
+        let mode = match rounding {
+            Rounding::Nearest => 0,
+            Rounding::Floor => 1,
+            Rounding::Ceil => 2,
+            Rounding::Trunc => 3,
+        };
+        return unary(assembler, |assembler| {
+            // Precision exceptions are suppressed
+            if f32 {
+                assembler.roundps(xmm0, xmm0, mode | 8)
+            } else {
+                assembler.roundpd(xmm0, xmm0, mode | 8)
+            }
+        });
+    }
+    let (lane, integral, one) = if f32 {
+        (Lane::I32, 0x4b00_0000, 1f32.to_bits() as u64)
+    } else {
+        (Lane::I64, 0x4330_0000_0000_0000, 1f64.to_bits())
+    };
+    unary(assembler, |assembler| {
+        let add = |assembler: &mut CodeAssembler, left, right| {
+            if f32 {
+                assembler.addps(left, right)
+            } else {
+                assembler.addpd(left, right)
+            }
+        };
+        let sub = |assembler: &mut CodeAssembler, left, right| {
+            if f32 {
+                assembler.subps(left, right)
+            } else {
+                assembler.subpd(left, right)
+            }
+        };
+        // Less than, as `cmpps` predicate 1
+        let less = |assembler: &mut CodeAssembler, left, right| {
+            if f32 {
+                assembler.cmpps(left, right, 1)
+            } else {
+                assembler.cmppd(left, right, 1)
+            }
+        };
+        // xmm1: sign, xmm2: magnitude, xmm3: 2^52, xmm4: small lanes,
+        // xmm5: rounded lanes
+        sign_mask(assembler, ty, xmm1)?;
+        assembler.movaps(xmm2, xmm1)?;
+        assembler.andnps(xmm2, xmm0)?;
+        assembler.andps(xmm1, xmm0)?;
+        splat_constant(assembler, xmm3, lane, integral)?;
+        assembler.movaps(xmm4, xmm2)?;
+        less(assembler, xmm4, xmm3)?;
+        assembler.movaps(xmm5, xmm2)?;
+        add(assembler, xmm5, xmm3)?;
+        sub(assembler, xmm5, xmm3)?;
+        splat_constant(assembler, xmm3, lane, one)?;
+        match rounding {
+            Rounding::Nearest => (),
+            Rounding::Trunc => {
+                // Magnitudes rounded up go one down
+                less(assembler, xmm2, xmm5)?;
+                assembler.andps(xmm2, xmm3)?;
+                sub(assembler, xmm5, xmm2)?;
+            }
+            Rounding::Floor | Rounding::Ceil => {
+                // Rounded with the sign, lanes beyond the operand go one
+                // back towards it
+                assembler.orps(xmm5, xmm1)?;
+                if rounding == Rounding::Floor {
+                    assembler.movaps(xmm2, xmm0)?;
+                    less(assembler, xmm2, xmm5)?;
+                    assembler.andps(xmm2, xmm3)?;
+                    sub(assembler, xmm5, xmm2)?;
+                } else {
+                    assembler.movaps(xmm2, xmm5)?;
+                    less(assembler, xmm2, xmm0)?;
+                    assembler.andps(xmm2, xmm3)?;
+                    add(assembler, xmm5, xmm2)?;
+                }
+            }
+        }
+        // Zeroes keep the sign of the operand
+        assembler.orps(xmm5, xmm1)?;
+        assembler.andps(xmm5, xmm4)?;
+        assembler.xorps(xmm1, xmm1)?;
+        add(assembler, xmm0, xmm1)?;
+        assembler.andnps(xmm4, xmm0)?;
+        assembler.orps(xmm4, xmm5)?;
+        assembler.movaps(xmm0, xmm4)
+    })
+}
+
+/// `f32x4.convert_i32x4_u`
+///
+/// Lanes are split into their lower 16 bits, which convert exactly, and the
+/// rest, converted halved to stay in the signed range.
+pub(crate) fn convert_unsigned(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        assembler.movdqa(xmm1, xmm0)?;
+        assembler.pslld(xmm1, 16)?;
+        assembler.psrld(xmm1, 16)?;
+        assembler.psubd(xmm0, xmm1)?;
+        assembler.cvtdq2ps(xmm1, xmm1)?;
+        assembler.psrld(xmm0, 1)?;
+        assembler.cvtdq2ps(xmm0, xmm0)?;
+        assembler.addps(xmm0, xmm0)?;
+        assembler.addps(xmm0, xmm1)
+    })
+}
+
+/// `f64x2.convert_low_i32x4_u`
+///
+/// Lanes become the lower halves of doubles with the exponent of 2^52, which
+/// then gets subtracted.
+pub(crate) fn convert_low_unsigned(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        splat_constant(assembler, xmm1, Lane::I32, 0x4330_0000)?;
+        assembler.unpcklps(xmm0, xmm1)?;
+        splat_constant(assembler, xmm1, Lane::I64, 0x4330_0000_0000_0000)?;
+        assembler.subpd(xmm0, xmm1)
+    })
+}
+
+/// `i32x4.trunc_sat_f32x4_s`
+///
+/// `cvttps2dq` gives 0x80000000 for NaNs and out of range lanes. NaNs are
+/// zeroed beforehand, and positive lanes that came out negative are
+/// flipped to the maximum.
+pub(crate) fn truncate_signed(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        assembler.movaps(xmm1, xmm0)?;
+        assembler.cmpps(xmm1, xmm1, 0)?;
+        assembler.andps(xmm0, xmm1)?;
+        // Top bit set for lanes that aren't negative
+        assembler.pxor(xmm1, xmm0)?;
+        assembler.cvttps2dq(xmm0, xmm0)?;
+        assembler.pand(xmm1, xmm0)?;
+        assembler.psrad(xmm1, 31)?;
+        assembler.pxor(xmm0, xmm1)
+    })
+}
+
+/// `i32x4.trunc_sat_f32x4_u`
+///
+/// NaNs and negative lanes are clamped to zero. Lanes up to 2^31 convert as
+/// signed, and the amount above it gets converted and added separately.
+pub(crate) fn truncate_unsigned(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        assembler.xorps(xmm1, xmm1)?;
+        assembler.maxps(xmm0, xmm1)?;
+        // 2^31
+        splat_constant(assembler, xmm1, Lane::I32, 0x4f00_0000)?;
+        assembler.movaps(xmm2, xmm0)?;
+        assembler.subps(xmm2, xmm1)?;
+        // Lanes of at least 2^32 saturate through the excess, which
+        // overflows to 0x80000000 and gets flipped
+        assembler.cmpps(xmm1, xmm2, 2)?;
+        assembler.cvttps2dq(xmm2, xmm2)?;
+        assembler.pxor(xmm2, xmm1)?;
+        // Negative excess is dropped
+        assembler.movdqa(xmm1, xmm2)?;
+        assembler.psrad(xmm1, 31)?;
+        assembler.pandn(xmm1, xmm2)?;
+        assembler.cvttps2dq(xmm0, xmm0)?;
+        assembler.paddd(xmm0, xmm1)
+    })
+}
+
+/// `i32x4.trunc_sat_f64x2_s_zero`
+pub(crate) fn truncate_signed_zero(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        // NaNs are zeroed and the rest clamped to the maximum, while
+        // `cvttpd2dq` saturates negative lanes to the minimum
+        assembler.movapd(xmm1, xmm0)?;
+        assembler.cmppd(xmm1, xmm0, 0)?;
+        splat_constant(assembler, xmm2, Lane::I64, 2147483647f64.to_bits())?;
+        assembler.andpd(xmm1, xmm2)?;
+        assembler.minpd(xmm0, xmm1)?;
+        assembler.cvttpd2dq(xmm0, xmm0)
+    })
+}
+
+/// `i32x4.trunc_sat_f64x2_u_zero`
+pub(crate) fn truncate_unsigned_zero(assembler: &mut CodeAssembler) -> Result<(), Error> {
+    unary(assembler, |assembler| {
+        // Lanes clamped to the range convert exactly as 64-bit integers
+        assembler.xorpd(xmm1, xmm1)?;
+        assembler.maxpd(xmm0, xmm1)?;
+        splat_constant(assembler, xmm1, Lane::I64, 4294967295f64.to_bits())?;
+        assembler.minpd(xmm0, xmm1)?;
+        assembler.cvttsd2si(rax, xmm0)?;
+        assembler.unpckhpd(xmm0, xmm0)?;
+        assembler.cvttsd2si(rcx, xmm0)?;
+        assembler.movd(xmm0, eax)?;
+        assembler.movd(xmm1, ecx)?;
+        assembler.punpckldq(xmm0, xmm1)
+    })
+}
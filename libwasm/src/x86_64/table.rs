@@ -0,0 +1,332 @@
+use crate::trap::Trap;
+use crate::x86_64::control::ControlStack;
+use crate::x86_64::memory::copy_items;
+use crate::x86_64::segments::pop_copy_operands;
+use crate::x86_64::{Context, Error};
+use alloc::vec;
+use iced_x86::code_asm::{
+    eax, ptr, qword_ptr, r10, r11, rax, rcx, rdi, rdx, rsi, AsmMemoryOperand, AsmRegister64,
+    CodeAssembler, CodeLabel,
+};
+use wasmparser_nostd::{TableType, Type};
+
+/// Number of entries reserved for growth of tables without a (small enough)
+/// declared maximum
+const GROWTH_RESERVE: u32 = 1024;
+
+/// Table of a module, defined or imported
+///
+/// Defined tables live entirely in the module binary: a descriptor of two
+/// 64-bit slots (current size and the size it is allowed to grow up to)
+/// followed by the entries. Imported tables are another module's, which the
+/// module refers to through a slot holding the address of their descriptor.
+///
+/// Every entry is a 64-bit reference; for `funcref` tables it is the
+/// absolute address of the function, or zero for a null reference.
+/// `externref` entries are opaque handles of the host, with zero as null, so
+/// the host must not use zero as a handle. Functions are preceded by a
+/// 64-bit identifier of their type, so entries set by the host must point to
+/// functions of compiled modules (see
+/// [`Module::function_reference`](super::Module::function_reference)).
+pub struct Table {
+    element_type: Type,
+    initial: u32,
+    maximum: Option<u32>,
+    offset: usize,
+    imported: bool,
+}
+
+impl Table {
+    pub(crate) fn new(table_type: &TableType, offset: usize, imported: bool) -> Self {
+        Self {
+            element_type: table_type.element_type,
+            initial: table_type.initial,
+            maximum: table_type.maximum,
+            offset,
+            imported,
+        }
+    }
+
+    /// Type of the table's elements
+    pub fn element_type(&self) -> Type {
+        self.element_type
+    }
+
+    /// Number of entries the table starts with
+    pub fn initial_size(&self) -> u32 {
+        self.initial
+    }
+
+    /// Number of entries the table can grow up to, if declared
+    pub fn maximum_size(&self) -> Option<u32> {
+        self.maximum
+    }
+
+    /// Number of entries reserved for the table in the module binary
+    pub fn limit(&self) -> u32 {
+        limit(self.initial, self.maximum)
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+
+    pub(crate) fn relocate(&mut self, offsets: &[u32]) {
+        self.offset = offsets[self.offset] as usize;
+    }
+
+    /// Offset of the table descriptor (or the address slot, for imported
+    /// tables) in the module binary
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+fn limit(initial: u32, maximum: Option<u32>) -> u32 {
+    maximum
+        .unwrap_or(u32::MAX)
+        .min(initial.saturating_add(GROWTH_RESERVE))
+}
+
+/// Labels of a table's current size and limit slots and its entries, along
+/// with the type of the entries
+///
+/// For an imported table, `size` labels the slot holding the address of the
+/// descriptor, and the other labels are unused.
+pub(crate) struct TableSlots {
+    size: CodeLabel,
+    limit: CodeLabel,
+    entries: CodeLabel,
+    imported: bool,
+    pub(crate) element_type: Type,
+}
+
+impl TableSlots {
+    /// Emits the descriptor along with null entries
+    pub(crate) fn emit(
+        assembler: &mut CodeAssembler,
+        table_type: &TableType,
+    ) -> Result<Self, Error> {
+        let mut size = assembler.create_label();
+        let mut limit_ = assembler.create_label();
+        let mut entries = assembler.create_label();
+        let reserved = limit(table_type.initial, table_type.maximum);
+        assembler.set_label(&mut size)?;
+        assembler.dq(&[table_type.initial as u64])?;
+        assembler.set_label(&mut limit_)?;
+        assembler.dq(&[reserved as u64])?;
+        assembler.set_label(&mut entries)?;
+        // Even an empty table needs something for the label to point at
+        assembler.dq(&vec![0; reserved.max(1) as usize])?;
+        Ok(Self {
+            size,
+            limit: limit_,
+            entries,
+            imported: false,
+            element_type: table_type.element_type,
+        })
+    }
+
+    /// Emits the address slot of an imported table
+    pub(crate) fn emit_import(
+        assembler: &mut CodeAssembler,
+        table_type: &TableType,
+    ) -> Result<Self, Error> {
+        let mut size = assembler.create_label();
+        assembler.set_label(&mut size)?;
+        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+        Ok(Self {
+            size,
+            limit: assembler.create_label(),
+            entries: assembler.create_label(),
+            imported: true,
+            element_type: table_type.element_type,
+        })
+    }
+
+    /// Operands for the size and limit slots of the descriptor, and for the
+    /// first entry
+    ///
+    /// The address of an imported table's descriptor is loaded into
+    /// `scratch`, which the operands refer to.
+    pub(crate) fn descriptor(
+        &self,
+        assembler: &mut CodeAssembler,
+        scratch: AsmRegister64,
+    ) -> Result<[AsmMemoryOperand; 3], Error> {
+        if self.imported {
+            assembler.mov(scratch, ptr(self.size))?;
+            Ok([
+                qword_ptr(scratch),
+                qword_ptr(scratch + 8),
+                qword_ptr(scratch + 16),
+            ])
+        } else {
+            Ok([
+                qword_ptr(self.size),
+                qword_ptr(self.limit),
+                qword_ptr(self.entries),
+            ])
+        }
+    }
+}
+
+/// Loads the function at the index in `rax` of a table into `r10`, for
+/// `call_indirect` to a function with the `signature`
+///
+/// Traps if the index is out of bounds, the entry is null, or the function's
+/// type is different. Clobbers `rax`.
+pub(crate) fn indirect_callee(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    table: u32,
+    signature: u64,
+) -> Result<(), Error> {
+    let [size, _, entries] = context.tables[&table].descriptor(assembler, r10)?;
+    assembler.cmp(rax, size)?;
+    assembler.jae(context.traps.label(Trap::UndefinedElement))?;
+    assembler.lea(r10, entries)?;
+    assembler.mov(r10, qword_ptr(r10 + rax * 8))?;
+    assembler.test(r10, r10)?;
+    assembler.jz(context.traps.label(Trap::UninitializedElement))?;
+    assembler.mov(rax, signature)?;
+    assembler.cmp(qword_ptr(r10 - 8), rax)?;
+    assembler.jne(context.traps.label(Trap::IndirectCallSignatureMismatch))?;
+    Ok(())
+}
+
+/// Pops an entry index into `rax` and bounds-checks it, leaving the entry's
+/// address in `rdx`
+fn pop_entry(assembler: &mut CodeAssembler, context: &Context, table: u32) -> Result<(), Error> {
+    let [size, _, entries] = context.tables[&table].descriptor(assembler, rdx)?;
+    assembler.pop(rax)?;
+    assembler.cmp(rax, size)?;
+    assembler.jae(context.traps.label(Trap::OutOfBoundsTableAccess))?;
+    assembler.lea(rdx, entries)?;
+    assembler.lea(rdx, ptr(rdx + rax * 8))?;
+    Ok(())
+}
+
+/// `table.get`: replaces the index on top of the operand stack with the entry
+pub(crate) fn get(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    table: u32,
+) -> Result<(), Error> {
+    pop_entry(assembler, context, table)?;
+    control.pop(Type::I32);
+    assembler.push(qword_ptr(rdx))?;
+    control.push(context.tables[&table].element_type);
+    Ok(())
+}
+
+/// `table.set`: pops a reference and an index, and stores the reference
+pub(crate) fn set(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    table: u32,
+) -> Result<(), Error> {
+    assembler.pop(rcx)?;
+    control.pop(context.tables[&table].element_type);
+    pop_entry(assembler, context, table)?;
+    control.pop(Type::I32);
+    assembler.mov(qword_ptr(rdx), rcx)?;
+    Ok(())
+}
+
+/// `table.size`
+pub(crate) fn size(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    table: u32,
+) -> Result<(), Error> {
+    let [size, _, _] = context.tables[&table].descriptor(assembler, rax)?;
+    assembler.push(size)?;
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// `table.grow`: grows the table within its limit, filling new entries with
+/// the given reference, and pushes the previous size, or -1 if the table
+/// can't grow that much
+pub(crate) fn grow(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    table: u32,
+) -> Result<(), Error> {
+    let [size, limit, entries] = context.tables[&table].descriptor(assembler, r11)?;
+    let mut failed = assembler.create_label();
+    let mut done = assembler.create_label();
+    assembler.pop(rcx)?;
+    control.pop(Type::I32);
+    assembler.pop(rax)?;
+    control.pop(context.tables[&table].element_type);
+    assembler.mov(rdx, size)?;
+    assembler.lea(rsi, ptr(rdx + rcx))?;
+    assembler.cmp(rsi, limit)?;
+    assembler.ja(failed)?;
+    assembler.mov(size, rsi)?;
+    assembler.lea(rdi, entries)?;
+    assembler.lea(rdi, ptr(rdi + rdx * 8))?;
+    assembler.rep().stosq()?;
+    assembler.push(rdx)?;
+    assembler.jmp(done)?;
+    control.bind_label(assembler, &mut failed)?;
+    assembler.mov(eax, u32::MAX)?;
+    assembler.push(rax)?;
+    control.bind_label(assembler, &mut done)?;
+    control.push(Type::I32);
+    Ok(())
+}
+
+/// `table.fill`: pops a count, a reference and an index, and sets that many
+/// entries from the index to the reference
+pub(crate) fn fill(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    table: u32,
+) -> Result<(), Error> {
+    let [size, _, entries] = context.tables[&table].descriptor(assembler, r11)?;
+    assembler.pop(rcx)?;
+    assembler.pop(rax)?;
+    assembler.pop(rdi)?;
+    control.pop(Type::I32);
+    control.pop(context.tables[&table].element_type);
+    control.pop(Type::I32);
+    assembler.lea(rdx, ptr(rdi + rcx))?;
+    assembler.cmp(rdx, size)?;
+    assembler.ja(context.traps.label(Trap::OutOfBoundsTableAccess))?;
+    assembler.lea(rdx, entries)?;
+    assembler.lea(rdi, ptr(rdx + rdi * 8))?;
+    assembler.rep().stosq()?;
+    Ok(())
+}
+
+/// `table.copy`: pops the count, source and destination indices, and copies
+/// the entries, as if through a temporary buffer
+pub(crate) fn copy(
+    assembler: &mut CodeAssembler,
+    context: &Context,
+    control: &mut ControlStack,
+    dst_table: u32,
+    src_table: u32,
+) -> Result<(), Error> {
+    pop_copy_operands(assembler, control, [Type::I32; 3])?;
+    let [src_size, _, src_entries] = context.tables[&src_table].descriptor(assembler, r11)?;
+    let [dst_size, _, dst_entries] = context.tables[&dst_table].descriptor(assembler, rdx)?;
+    for (size, index) in [(src_size, rsi), (dst_size, rdi)] {
+        assembler.lea(rax, ptr(index + rcx))?;
+        assembler.cmp(rax, size)?;
+        assembler.ja(context.traps.label(Trap::OutOfBoundsTableAccess))?;
+    }
+    assembler.lea(rax, src_entries)?;
+    assembler.lea(rsi, ptr(rax + rsi * 8))?;
+    assembler.lea(rax, dst_entries)?;
+    assembler.lea(rdi, ptr(rax + rdi * 8))?;
+    copy_items(assembler, control, 8)
+}
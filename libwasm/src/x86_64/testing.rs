@@ -1,4 +1,5 @@
-use super::AssembledModule;
+use super::{AssembledModule, EXCEPTION_STATE_SIZE, PAGE_SIZE};
+use crate::trap::Trap;
 use crate::x86_64::testing::Error::{EmulationError, InternalAssemblyError};
 use crate::x86_64::FunctionIdentifier;
 use alloc::collections::btree_map::Entry;
@@ -10,7 +11,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use core::cell::RefCell;
 use core::mem::size_of;
 use core::ops::{Deref, DerefMut};
-use iced_x86::code_asm::{r10, CodeAssembler};
+use iced_x86::code_asm::{ptr, r10, rdi, CodeAssembler};
 use iced_x86::{IcedError, Mnemonic};
 use unicorn_engine::unicorn_const::{uc_error, Permission};
 use unicorn_engine::RegisterX86::{R10, RSP};
@@ -23,6 +24,7 @@ pub enum Error {
     EmulationError(uc_error),
     InternalAssemblyError(IcedError),
     FunctionNotFound,
+    Trap(Trap),
 }
 
 impl From<uc_error> for Error {
@@ -40,8 +42,12 @@ impl From<IcedError> for Error {
 pub struct Emulator<'a> {
     emulator: Unicorn<'a, ()>,
     module_offset: u64,
-    trampoline_len: u64,
     trampoline_offset: u64,
+    trampoline_end: u64,
+    trap_handler: u64,
+    trap_code: u64,
+    /// Exception state shared by all modules
+    exception_state: u64,
     modules: Vec<Rc<RefCell<Module>>>,
 }
 
@@ -56,27 +62,60 @@ impl<'a> Emulator<'a> {
         // Map memory
         emulator.mem_map(initial_offset, 128 * 1024 * 1024, Permission::ALL)?;
 
-        // Trampoline
+        // Trampoline, followed by the trap handler, which records the trap
+        // code and stops emulation by jumping to the trampoline's end
         let mut assembler = CodeAssembler::new(64)?;
+        let mut end = assembler.create_label();
+        let mut trap_code = assembler.create_label();
         assembler.call(r10)?;
         assembler.nop()?;
-        let trampoline = assembler.assemble(0)?;
+        let end_offset = assembler.assemble(initial_offset)?.len() as u64;
+        assembler.set_label(&mut end)?;
+        assembler.int3()?;
+        let trap_handler_offset = assembler.assemble(initial_offset)?.len() as u64;
+        assembler.mov(ptr(trap_code), rdi)?;
+        assembler.jmp(end)?;
+        let trap_code_offset = assembler.assemble(initial_offset)?.len() as u64;
+        assembler.set_label(&mut trap_code)?;
+        assembler.dq(&[0])?;
+        let trampoline = assembler.assemble(initial_offset)?;
 
         emulator.mem_write(initial_offset, &trampoline)?;
 
-        // Set up stack at the top
-        emulator.reg_write(RSP as i32, 128 * 1024 * 1024 - 1)?;
+        // Set up stack at the top, aligned as the ABI requires before the
+        // trampoline's call
+        emulator.reg_write(RSP as i32, 128 * 1024 * 1024)?;
 
-        Ok(Self {
+        let mut emulator = Self {
             emulator,
             module_offset: initial_offset + trampoline.len() as u64,
-            trampoline_len: trampoline.len() as u64,
             trampoline_offset: initial_offset,
+            trampoline_end: initial_offset + end_offset,
+            trap_handler: initial_offset + trap_handler_offset,
+            trap_code: initial_offset + trap_code_offset,
+            exception_state: 0,
             modules: vec![],
-        })
+        };
+        emulator.exception_state = emulator.allocate(EXCEPTION_STATE_SIZE as u64)?;
+        Ok(emulator)
     }
 
-    pub fn add_module(&mut self, module: AssembledModule) -> Result<Rc<RefCell<Module>>, Error> {
+    pub fn add_module(
+        &mut self,
+        mut module: AssembledModule,
+    ) -> Result<Rc<RefCell<Module>>, Error> {
+        for index in 0..module.memory_count() {
+            let memory = module.memory(index).unwrap();
+            if memory.is_imported() {
+                continue;
+            }
+            // Reserve as much as the memory can grow to
+            let capacity = memory.maximum_pages().unwrap_or(memory.initial_pages());
+            let base = self.allocate(capacity * PAGE_SIZE)?;
+            module.link_memory(index, base, capacity);
+        }
+        module.link_trap_handler(self.trap_handler);
+        module.link_exception_state(self.exception_state);
         self.emulator
             .mem_write(self.module_offset as u64, module.binary())?;
         let module_len = module.binary().len();
@@ -97,6 +136,32 @@ impl<'a> Emulator<'a> {
         Ok(())
     }
 
+    /// Reads module's binary back, as it holds state modified by the code
+    fn sync_module(&mut self, module: Rc<RefCell<Module>>) -> Result<(), Error> {
+        let offset = module.borrow().offset;
+        let mut module = module.borrow_mut();
+        self.emulator
+            .mem_read(offset, &mut module.module.assembled)?;
+        Ok(())
+    }
+
+    /// Allocates page-aligned zeroed memory
+    pub fn allocate(&mut self, size: u64) -> Result<u64, Error> {
+        let page_size = 4096;
+        let offset = self.module_offset.next_multiple_of(page_size);
+        self.emulator.mem_write(offset, &vec![0; size as usize])?;
+        self.module_offset = offset + size;
+        Ok(offset)
+    }
+
+    pub fn read_memory(&self, address: u64, size: usize) -> Result<Vec<u8>, Error> {
+        Ok(self.emulator.mem_read_as_vec(address, size)?)
+    }
+
+    pub fn write_memory(&mut self, address: u64, mem: &[u8]) -> Result<(), Error> {
+        Ok(self.emulator.mem_write(address, mem)?)
+    }
+
     pub fn add_memory(&mut self, mem: &[u8]) -> Result<u64, Error> {
         let offset = self.module_offset as u64;
         self.emulator.mem_write(offset, mem)?;
@@ -117,6 +182,16 @@ impl<'a> Emulator<'a> {
             .module
             .function_entry_point(identifier)
             .ok_or(Error::FunctionNotFound)? as u64;
+        self.call(module, function_offset)
+    }
+
+    /// Runs the module's instantiation routine
+    pub fn instantiate(&mut self, module: Rc<RefCell<Module>>) -> Result<(), Error> {
+        let offset = module.borrow().module.instantiation_entry_point() as u64;
+        self.call(module, offset)
+    }
+
+    fn call(&mut self, module: Rc<RefCell<Module>>, function_offset: u64) -> Result<(), Error> {
         let module_offset = module.borrow().offset;
         for module in self.modules.clone() {
             self.update_module(module)?;
@@ -153,15 +228,29 @@ impl<'a> Emulator<'a> {
 
         self.emulator
             .reg_write(R10 as i32, module_offset + function_offset)?;
+        self.emulator
+            .mem_write(self.trap_code, &[0; size_of::<u64>()])?;
+        let stack = self.emulator.reg_read(RSP as i32)?;
 
-        self.emulator.emu_start(
-            self.trampoline_offset,
-            self.trampoline_offset + self.trampoline_len,
-            0,
-            0,
-        )?;
+        self.emulator
+            .emu_start(self.trampoline_offset, self.trampoline_end, 0, 0)?;
         self.emulator.remove_hook(hook)?;
-        Ok(())
+        for module in self.modules.clone() {
+            self.sync_module(module)?;
+        }
+
+        let mut code = [0; size_of::<u64>()];
+        self.emulator.mem_read(self.trap_code, &mut code)?;
+        match LittleEndian::read_u64(&code) {
+            0 => Ok(()),
+            code => {
+                // Trapped code never returns, leaving the stack behind
+                self.emulator.reg_write(RSP as i32, stack)?;
+                Err(Error::Trap(
+                    Trap::from_code(code as u32).expect("valid trap code"),
+                ))
+            }
+        }
     }
 
     pub fn pop(&mut self) -> Result<u64, Error> {
@@ -190,6 +279,35 @@ impl<'a> Emulator<'a> {
     pub fn write_register(&mut self, register: RegisterX86, value: u64) -> Result<(), Error> {
         Ok(self.emulator.reg_write(register as i32, value)?)
     }
+
+    /// Reads the lower 64 bits of an XMM register
+    pub fn read_xmm_register(&self, register: RegisterX86) -> Result<u64, Error> {
+        let value = self.emulator.reg_read_long(register as i32)?;
+        Ok(LittleEndian::read_u64(&value))
+    }
+
+    /// Writes the lower 64 bits of an XMM register, zeroing the rest
+    pub fn write_xmm_register(&mut self, register: RegisterX86, value: u64) -> Result<(), Error> {
+        let mut buf = [0; 2 * size_of::<u64>()];
+        LittleEndian::write_u64(&mut buf, value);
+        Ok(self.emulator.reg_write_long(register as i32, &buf)?)
+    }
+
+    /// Reads all 128 bits of an XMM register
+    pub fn read_vector_register(&self, register: RegisterX86) -> Result<u128, Error> {
+        let value = self.emulator.reg_read_long(register as i32)?;
+        Ok(LittleEndian::read_u128(&value))
+    }
+
+    pub fn write_vector_register(
+        &mut self,
+        register: RegisterX86,
+        value: u128,
+    ) -> Result<(), Error> {
+        let mut buf = [0; size_of::<u128>()];
+        LittleEndian::write_u128(&mut buf, value);
+        Ok(self.emulator.reg_write_long(register as i32, &buf)?)
+    }
 }
 
 pub struct Module {
@@ -220,6 +338,11 @@ impl Module {
             .unwrap_or(0)
     }
 
+    /// Number of instructions executed in the module so far
+    pub fn total_instruction_execution_count(&self) -> usize {
+        self.executed_instructions.values().sum()
+    }
+
     pub fn offset(&self) -> u64 {
         self.offset
     }
@@ -252,7 +375,10 @@ impl AssembledModule {
 
             print!("{}", output);
             if instr.mnemonic() == Mnemonic::Call && instr.memory_displacement64() > 0 {
-                print!(" // -> {:016X}", offset + instr.memory_displacement64());
+                print!(
+                    " // -> {:016X}",
+                    offset.wrapping_add(instr.memory_displacement64())
+                );
             }
 
             print!(" ( ");
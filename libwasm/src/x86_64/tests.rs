@@ -32,6 +32,8 @@ fn passing_args_and_return_value() {
 (module
 
     (func (export "foo") (param i64) (param i64) (result i64)
+     local.get 0
+     local.get 1
      i64.sub
     )
 )
@@ -247,3 +249,6647 @@ fn locals_basic() {
 
     assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
 }
+
+#[test]
+fn if_else() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (result i64)
+      local.get 0
+      if (result i64)
+        i64.const 1
+      else
+        i64.const 2
+      end
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 1).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 1);
+
+    emulator.write_register(testing::RDI, 0).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 2);
+}
+
+#[test]
+fn if_without_else() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (result i64) (local i64)
+      i64.const 40
+      local.set 1
+      local.get 0
+      if
+        i64.const 2
+        local.set 1
+      end
+      local.get 1
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 1).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 2);
+
+    emulator.write_register(testing::RDI, 0).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 40);
+}
+
+#[test]
+fn br_unwinds_operand_stack() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (result i64)
+      block (result i64)
+        i64.const 1
+        i64.const 2
+        i64.const 42
+        br 0
+        i64.const 3
+        i64.add
+      end
+      i64.const 0
+      i64.add
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn nested_br_if() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (param i32) (result i64)
+      block (result i64)
+        block (result i64)
+          i64.const 10
+          i64.const 1
+          local.get 0
+          br_if 1
+          local.get 1
+          br_if 0
+          i64.add
+        end
+        i64.const 100
+        i64.add
+      end
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    for (first, second, result) in [(1, 0, 1), (0, 1, 101), (0, 0, 111)] {
+        emulator
+            .write_register(testing::RDI, first)
+            .expect("1st arg");
+        emulator
+            .write_register(testing::RSI, second)
+            .expect("2nd arg");
+        emulator
+            .call_function(emu_mod.clone(), "foo")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn loop_br_if() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (param i32) (result i64) (local i64)
+      loop
+        local.get 2
+        i64.const 10
+        i64.add
+        local.set 2
+        local.get 0
+        local.get 1
+        local.set 0
+        br_if 0
+      end
+      local.get 2
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 1).expect("1st arg");
+    emulator.write_register(testing::RSI, 0).expect("2nd arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 20);
+}
+
+#[test]
+fn early_return() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (result i64)
+      i64.const 1
+      local.get 0
+      if
+        i64.const 7
+        return
+      end
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 1).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 7);
+
+    emulator.write_register(testing::RDI, 0).expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 1);
+}
+
+#[test]
+fn br_table_dense() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (result i64)
+      block
+        block
+          block
+            block
+              local.get 0
+              br_table 0 1 2 3
+            end
+            i64.const 100
+            return
+          end
+          i64.const 101
+          return
+        end
+        i64.const 102
+        return
+      end
+      i64.const 103
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    for (index, result) in [
+        (0, 100),
+        (1, 101),
+        (2, 102),
+        (3, 103),
+        (4, 103),
+        (u32::MAX, 103),
+    ] {
+        emulator
+            .write_register(testing::RDI, index as u64)
+            .expect("1st arg");
+        emulator
+            .call_function(emu_mod.clone(), "foo")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn br_table_sparse() {
+    use testing::Emulator;
+    let src = r#"
+(module
+
+    (func (export "foo") (param i32) (result i64)
+      block (result i64)
+        block (result i64)
+          i64.const 7
+          i64.const 1000
+          i64.const 42
+          local.get 0
+          br_table 0 1 1 0 1 0
+        end
+        i64.const 1
+        i64.add
+      end
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    for (index, result) in [
+        (0, 43),
+        (1, 42),
+        (2, 42),
+        (3, 43),
+        (4, 42),
+        (5, 43),
+        (100, 43),
+    ] {
+        emulator
+            .write_register(testing::RDI, index as u64)
+            .expect("1st arg");
+        emulator
+            .call_function(emu_mod.clone(), "foo")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn memory_load_store() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+
+    (func (export "store") (param i32) (param i64)
+      local.get 0
+      local.get 1
+      i64.store offset=8
+    )
+
+    (func (export "store8") (param i32) (param i32)
+      local.get 0
+      local.get 1
+      i32.store8
+    )
+
+    (func (export "i64.load") (param i32) (result i64)
+      local.get 0
+      i64.load
+    )
+
+    (func (export "i64.load8_s") (param i32) (result i64)
+      local.get 0
+      i64.load8_s
+    )
+
+    (func (export "i64.load8_u") (param i32) (result i64)
+      local.get 0
+      i64.load8_u
+    )
+
+    (func (export "i64.load16_s") (param i32) (result i64)
+      local.get 0
+      i64.load16_s
+    )
+
+    (func (export "i64.load32_s") (param i32) (result i64)
+      local.get 0
+      i64.load32_s
+    )
+
+    (func (export "i64.load32_u") (param i32) (result i64)
+      local.get 0
+      i64.load32_u
+    )
+
+    (func (export "i32.load") (param i32) (result i32)
+      local.get 0
+      i32.load
+    )
+
+    (func (export "i32.load8_s") (param i32) (result i32)
+      local.get 0
+      i32.load8_s
+    )
+
+    (func (export "i32.load16_u") (param i32) (result i32)
+      local.get 0
+      i32.load16_u offset=2
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(module.memory(0).unwrap().initial_pages(), 1);
+    assert_eq!(module.memory(0).unwrap().maximum_pages(), None);
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 0).expect("1st arg");
+    emulator
+        .write_register(testing::RSI, 0x8899AABBCCDDEEFF)
+        .expect("2nd arg");
+    emulator
+        .call_function(emu_mod.clone(), "store")
+        .expect("call");
+
+    emulator.write_register(testing::RDI, 4).expect("1st arg");
+    emulator
+        .write_register(testing::RSI, 0x1FF)
+        .expect("2nd arg");
+    emulator
+        .call_function(emu_mod.clone(), "store8")
+        .expect("call");
+
+    for (function, address, result) in [
+        ("i64.load", 8, 0x8899AABBCCDDEEFF),
+        ("i64.load8_s", 8, 0xFFFFFFFFFFFFFFFF),
+        ("i64.load8_u", 8, 0xFF),
+        ("i64.load16_s", 8, 0xFFFFFFFFFFFFEEFF),
+        ("i64.load32_s", 8, 0xFFFFFFFFCCDDEEFF),
+        ("i64.load32_u", 8, 0xCCDDEEFF),
+        ("i32.load", 12, 0x8899AABB),
+        ("i32.load", 4, 0xFF),
+        ("i32.load8_s", 4, 0xFFFFFFFF),
+        ("i32.load16_u", 8, 0xCCDD),
+    ] {
+        emulator
+            .write_register(testing::RDI, address)
+            .expect("1st arg");
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_register(testing::RAX).unwrap(),
+            result,
+            "{}",
+            function
+        );
+    }
+}
+
+#[test]
+fn memory_size_and_grow() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1 3)
+
+    (func (export "size") (result i32)
+      memory.size
+    )
+
+    (func (export "grow") (param i32) (result i32)
+      local.get 0
+      memory.grow
+    )
+
+    (func (export "store") (param i32) (param i64)
+      local.get 0
+      local.get 1
+      i64.store
+    )
+
+    (func (export "load") (param i32) (result i64)
+      local.get 0
+      i64.load
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(module.memory(0).unwrap().maximum_pages(), Some(3));
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator
+        .call_function(emu_mod.clone(), "size")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 1);
+
+    for (delta, result) in [(1, 1), (2, 0xFFFFFFFF), (1, 2), (0, 3)] {
+        emulator
+            .write_register(testing::RDI, delta)
+            .expect("1st arg");
+        emulator
+            .call_function(emu_mod.clone(), "grow")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+
+    emulator
+        .call_function(emu_mod.clone(), "size")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 3);
+
+    // Grown memory is usable
+    let address = 3 * PAGE_SIZE - 8;
+    emulator
+        .write_register(testing::RDI, address)
+        .expect("1st arg");
+    emulator.write_register(testing::RSI, 42).expect("2nd arg");
+    emulator
+        .call_function(emu_mod.clone(), "store")
+        .expect("call");
+    emulator
+        .call_function(emu_mod.clone(), "load")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn memory64() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory i64 1 3)
+    (data (i64.const 16) "\01\02\03\04\05\06\07\08")
+    (data $passive "\aa\bb\cc")
+    (func (export "size") (result i64)
+        memory.size)
+    (func (export "grow") (param i64) (result i64)
+        local.get 0
+        memory.grow)
+    (func (export "load") (param i64) (result i64)
+        local.get 0
+        i64.load)
+    (func (export "load_offset") (param i64) (result i64)
+        local.get 0
+        i64.load offset=0x100000000)
+    (func (export "load8") (param i64) (result i32)
+        local.get 0
+        i32.load8_u offset=8)
+    (func (export "store") (param i64 i64)
+        local.get 0
+        local.get 1
+        i64.store)
+    (func (export "fill") (param i64 i32 i64)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.fill)
+    (func (export "copy") (param i64 i64 i64)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.copy)
+    (func (export "init") (param i64 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.init $passive)
+    (func (export "add") (param i64 i64) (result i64)
+        local.get 0
+        local.get 1
+        i64.atomic.rmw.add)
+    (func (export "vector") (param i64) (result i64)
+        local.get 0
+        v128.load
+        i64x2.extract_lane 1)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(module.memory(0).unwrap().index_type(), Type::I64);
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let mut call = |function: &str, args: &[u64]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+    };
+
+    assert_eq!(call("load", &[16]).unwrap(), 0x0807_0605_0403_0201);
+    assert_eq!(call("size", &[]).unwrap(), 1);
+    // Deltas aren't truncated to 32 bits, and failures are 64-bit -1
+    for (delta, result) in [(0x1_0000_0001, u64::MAX), (u64::MAX, u64::MAX), (1, 1)] {
+        assert_eq!(call("grow", &[delta]).unwrap(), result, "{:#x}", delta);
+    }
+    assert_eq!(call("size", &[]).unwrap(), 2);
+
+    call("store", &[2 * PAGE_SIZE - 8, 42]).expect("store");
+    assert_eq!(call("load", &[2 * PAGE_SIZE - 8]).unwrap(), 42);
+    assert_eq!(call("load8", &[2 * PAGE_SIZE - 9]).unwrap(), 0);
+    assert_eq!(call("add", &[24, 5]).unwrap(), 0);
+    assert_eq!(call("add", &[24, 5]).unwrap(), 5);
+    assert_eq!(call("vector", &[16]).unwrap(), 10);
+
+    // Addresses aren't truncated to 32 bits, and neither are sums with
+    // offsets allowed to wrap around
+    let traps: &[(&str, &[u64])] = &[
+        ("load", &[0x1_0000_0000]),
+        ("load", &[2 * PAGE_SIZE - 7]),
+        ("load", &[u64::MAX - 3]),
+        ("load8", &[u64::MAX - 7]),
+        ("load_offset", &[0]),
+        ("load_offset", &[0xFFFF_FFFF_0000_0010]),
+        ("store", &[0x1_0000_0000 + 16, 0]),
+        ("add", &[0x1_0000_0000 + 24, 1]),
+        ("vector", &[u64::MAX - 15]),
+        ("fill", &[0x1_0000_0000, 0, 0]),
+        ("fill", &[16, 0, 0x1_0000_0000]),
+        ("fill", &[u64::MAX, 0, 2]),
+        ("copy", &[0, 16, 0x1_0000_0000]),
+        ("copy", &[16, u64::MAX, 2]),
+        ("init", &[u64::MAX, 0, 2]),
+        ("init", &[0x1_0000_0000, 0, 1]),
+    ];
+    for (function, args) in traps {
+        let result = call(function, args);
+        assert!(
+            matches!(
+                result,
+                Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+            ),
+            "{} {:x?}",
+            function,
+            args
+        );
+    }
+    assert_eq!(call("load", &[16]).unwrap(), 0x0807_0605_0403_0201);
+
+    call("fill", &[17, 0xAB, 2]).expect("fill");
+    call("copy", &[2 * PAGE_SIZE - 4, 16, 4]).expect("copy");
+    call("init", &[22, 1, 2]).expect("init");
+    assert_eq!(call("load", &[16]).unwrap(), 0xCCBB_0605_04AB_AB01);
+    assert_eq!(
+        call("load", &[2 * PAGE_SIZE - 8]).unwrap(),
+        0x04AB_AB01_0000_002A
+    );
+}
+
+#[test]
+fn multiple_memories() {
+    use crate::trap::Trap;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use testing::Emulator;
+    let io_src = r#"
+(module
+    (memory (export "buffer") 1 2)
+    (func (export "load") (param i32) (result i64)
+        local.get 0
+        i64.load)
+    (func (export "size") (result i32)
+        memory.size)
+)
+"#;
+    let src = r#"
+(module
+    (memory $buffer (import "io" "buffer") 1 2)
+    (memory $dma (import "kernel" "dma") 1)
+    (memory $heap (export "heap") 1 3)
+    (data (memory $heap) (i32.const 8) "\01\02\03\04")
+    (data $passive "\aa\bb")
+    (func (export "load") (param i32 i32) (result i64)
+        (if (result i64) (i32.eqz (local.get 0))
+            (then (i64.load $buffer (local.get 1)))
+            (else
+                (if (result i64) (i32.eq (local.get 0) (i32.const 1))
+                    (then (i64.load $dma (local.get 1)))
+                    (else (i64.load $heap (local.get 1)))))))
+    (func (export "store_dma") (param i32 i64)
+        local.get 0
+        local.get 1
+        i64.store $dma offset=8)
+    (func (export "store_heap") (param i32 i64)
+        local.get 0
+        local.get 1
+        i64.store $heap)
+    (func (export "sizes") (result i32)
+        memory.size $buffer
+        memory.size $dma
+        i32.const 8
+        i32.shl
+        i32.or
+        memory.size $heap
+        i32.const 16
+        i32.shl
+        i32.or)
+    (func (export "grow_buffer") (param i32) (result i32)
+        local.get 0
+        memory.grow $buffer)
+    (func (export "grow_heap") (param i32) (result i32)
+        local.get 0
+        memory.grow $heap)
+    (func (export "copy") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.copy $buffer $heap)
+    (func (export "fill") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.fill $dma)
+    (func (export "init") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.init $buffer $passive)
+)
+"#;
+    let io_binary = wat::parse_str(io_src).expect("binary module");
+    let io_module = X86_64Compiler::default()
+        .compile(&io_binary)
+        .expect("compiled module");
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(module.memory_count(), 3);
+    assert!(module.memory(0).unwrap().is_imported());
+    assert!(module.memory(1).unwrap().is_imported());
+    assert!(!module.memory(2).unwrap().is_imported());
+    assert_eq!(module.memory_export("heap"), Some(2));
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let io_mod = emulator.add_module(io_module).expect("module addition");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    // The buffer is defined by the other module, and the DMA region is
+    // mapped by the host with a descriptor of its own
+    let buffer = io_mod
+        .borrow()
+        .memory_descriptor("buffer", io_mod.borrow().offset())
+        .unwrap();
+    let region = emulator.allocate(PAGE_SIZE).expect("allocation");
+    let mut descriptor = vec![];
+    for value in [region, 1, 1] {
+        descriptor.extend_from_slice(&value.to_le_bytes());
+    }
+    let dma = emulator.add_memory(&descriptor).expect("descriptor");
+    {
+        let mut module = emu_mod.try_borrow_mut().unwrap();
+        module.link_import("io", Some("buffer"), buffer);
+        module.link_import("kernel", Some("dma"), dma);
+    }
+    let heap = emu_mod
+        .borrow()
+        .memory_descriptor("heap", emu_mod.borrow().offset());
+    assert_eq!(
+        heap,
+        Some(emu_mod.borrow().offset() + emu_mod.borrow().memory(2).unwrap().offset() as u64)
+    );
+
+    emulator.instantiate(io_mod.clone()).expect("instantiation");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let mut call = |module: &Rc<RefCell<testing::Module>>, function: &str, args: &[u64]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        emulator
+            .call_function(module.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+    };
+
+    assert_eq!(call(&emu_mod, "load", &[2, 8]).unwrap(), 0x0403_0201);
+    assert_eq!(call(&emu_mod, "load", &[0, 8]).unwrap(), 0);
+    assert_eq!(call(&emu_mod, "sizes", &[]).unwrap(), 0x01_01_01);
+
+    // Growing the imported memory is seen by the module defining it
+    assert_eq!(call(&emu_mod, "grow_buffer", &[1]).unwrap(), 1);
+    assert_eq!(
+        call(&emu_mod, "grow_buffer", &[1]).unwrap(),
+        u32::MAX as u64
+    );
+    assert_eq!(call(&emu_mod, "grow_heap", &[2]).unwrap(), 1);
+    assert_eq!(call(&io_mod, "size", &[]).unwrap(), 2);
+    assert_eq!(call(&emu_mod, "sizes", &[]).unwrap(), 0x03_01_02);
+
+    call(&emu_mod, "copy", &[2 * PAGE_SIZE - 8, 6, 8]).expect("copy");
+    assert_eq!(
+        call(&io_mod, "load", &[2 * PAGE_SIZE - 8]).unwrap(),
+        0x0403_0201_0000
+    );
+    call(&emu_mod, "init", &[3, 0, 2]).expect("init");
+    assert_eq!(call(&io_mod, "load", &[0]).unwrap(), 0xBB_AA00_0000);
+
+    call(&emu_mod, "store_dma", &[0, 0x1122_3344]).expect("store");
+    call(&emu_mod, "fill", &[12, 0xFF, 2]).expect("fill");
+    assert_eq!(call(&emu_mod, "load", &[1, 8]).unwrap(), 0xFFFF_1122_3344);
+    call(&emu_mod, "store_heap", &[3 * PAGE_SIZE - 8, 7]).expect("store");
+    assert_eq!(call(&emu_mod, "load", &[2, 3 * PAGE_SIZE - 8]).unwrap(), 7);
+
+    // Each memory is checked against its own size
+    let traps: &[(&str, &[u64])] = &[
+        ("load", &[1, PAGE_SIZE - 7]),
+        ("load", &[0, 2 * PAGE_SIZE - 7]),
+        ("store_dma", &[PAGE_SIZE - 15, 0]),
+        ("store_heap", &[3 * PAGE_SIZE - 7, 0]),
+        ("copy", &[2 * PAGE_SIZE - 8, 3 * PAGE_SIZE - 7, 8]),
+        ("copy", &[2 * PAGE_SIZE - 7, 0, 8]),
+        ("fill", &[PAGE_SIZE - 1, 0, 2]),
+        ("init", &[2 * PAGE_SIZE - 1, 0, 2]),
+    ];
+    for (function, args) in traps {
+        let result = call(&emu_mod, function, args);
+        assert!(
+            matches!(
+                result,
+                Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+            ),
+            "{} {:x?}",
+            function,
+            args
+        );
+    }
+    // The DMA region is written in place
+    assert_eq!(
+        LittleEndian::read_u64(&emulator.read_memory(region + 8, 8).unwrap()),
+        0xFFFF_1122_3344
+    );
+}
+
+#[test]
+fn imported_tables() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let lib_src = r#"
+(module
+    (type $result (func (result i32)))
+    (table (export "functions") 3 funcref)
+    (elem (i32.const 0) $one $two)
+    (func $one (result i32) i32.const 1)
+    (func $two (result i32) i32.const 2)
+)
+"#;
+    let src = r#"
+(module
+    (type $result (func (result i32)))
+    (import "lib" "functions" (table $shared 3 funcref))
+    (table $own 2 funcref)
+    (elem (table $own) (i32.const 0) func $three)
+    (elem (table $shared) (i32.const 2) func $three)
+    (func $three (result i32) i32.const 3)
+    (func (export "sizes") (result i32)
+        table.size $shared
+        i32.const 100
+        i32.mul
+        table.size $own
+        i32.add)
+    (func (export "call_shared") (param i32) (result i32)
+        local.get 0
+        call_indirect $shared (type $result))
+    (func (export "call_own") (param i32) (result i32)
+        local.get 0
+        call_indirect $own (type $result))
+    (func (export "grow_shared") (param i32) (result i32)
+        ref.null func
+        local.get 0
+        table.grow $shared)
+    (func (export "copy_in") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        table.copy $own $shared)
+)
+"#;
+    let lib_binary = wat::parse_str(lib_src).expect("binary module");
+    let lib_module = X86_64Compiler::default()
+        .compile(&lib_binary)
+        .expect("compiled module");
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    // Imported tables are numbered first
+    assert!(module.table(0).unwrap().is_imported());
+    assert!(!module.table(1).unwrap().is_imported());
+    assert_eq!(lib_module.table_export("functions"), Some(0));
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let lib_mod = emulator.add_module(lib_module).expect("module addition");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let functions = lib_mod
+        .borrow()
+        .table_descriptor("functions", lib_mod.borrow().offset())
+        .unwrap();
+    emu_mod
+        .borrow_mut()
+        .link_import("lib", Some("functions"), functions);
+    emulator
+        .instantiate(lib_mod.clone())
+        .expect("instantiation");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let mut call = |function: &str, args: &[u64]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+    };
+
+    assert_eq!(call("sizes", &[]).unwrap(), 302);
+    // The element segment wrote into the other module's table
+    for (index, result) in [(0, 1), (1, 2), (2, 3)] {
+        assert_eq!(call("call_shared", &[index]).unwrap(), result);
+    }
+    assert_eq!(call("call_own", &[0]).unwrap(), 3);
+    let cases: &[(&str, u64, Trap)] = &[
+        ("call_shared", 3, Trap::UndefinedElement),
+        ("call_own", 1, Trap::UninitializedElement),
+        ("call_own", 2, Trap::UndefinedElement),
+    ];
+    for (function, index, trap) in cases {
+        match call(function, &[*index]) {
+            Err(testing::Error::Trap(t)) => assert_eq!(t, *trap, "{} {}", function, index),
+            other => panic!("{} {} didn't trap: {:?}", function, index, other),
+        }
+    }
+
+    // Growing the imported table is seen by the module defining it
+    assert_eq!(call("grow_shared", &[2]).unwrap(), 3);
+    assert_eq!(call("sizes", &[]).unwrap(), 502);
+    assert_eq!(lib_mod.borrow().table_size(0), Some(5));
+    assert_eq!(emu_mod.borrow().table_size(0), None);
+    call("copy_in", &[0, 1, 2]).expect("copy");
+    assert_eq!(call("call_own", &[0]).unwrap(), 2);
+    assert_eq!(call("call_own", &[1]).unwrap(), 3);
+    assert!(matches!(
+        call("copy_in", &[0, 4, 2]),
+        Err(testing::Error::Trap(Trap::OutOfBoundsTableAccess))
+    ));
+}
+
+#[test]
+fn active_data_segment() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (data (i32.const 16) "\2a\00\00\00\00\00\00\00")
+    (func (export "foo") (result i64)
+     i32.const 16
+     i64.load
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(
+        module.data_segments()[0].mode(),
+        SegmentMode::Active { index: 0 }
+    );
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn memory_init_and_data_drop() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (data "hello")
+    (func (export "init")
+     i32.const 100
+     i32.const 1
+     i32.const 3
+     memory.init 0
+     data.drop 0
+    )
+    (func (export "foo") (result i64)
+     i32.const 100
+     i64.load
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert_eq!(module.data_segments()[0].mode(), SegmentMode::Passive);
+    assert_eq!(module.data_segments()[0].len(), 5);
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(emu_mod.clone(), "init")
+        .expect("call");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+
+    assert_eq!(
+        emulator.read_register(testing::RAX).unwrap(),
+        u64::from_le_bytes(*b"ell\0\0\0\0\0")
+    );
+}
+
+#[test]
+fn bulk_memory() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (data $bytes "\01\08\0f\16\1d\24\2b\32\39\40\47\4e\55\5c\63\6a\71\78\7f\86\8d\94\9b\a2\a9\b0\b7\be\c5\cc\d3\da\e1\e8\ef\f6\fd\04\0b\12\19\20\27\2e\35\3c\43\4a\51\58\5f\66\6d\74\7b\82\89\90\97\9e\a5\ac\b3\ba\c1\c8\cf\d6\dd\e4\eb\f2\f9\00\07\0e\15\1c\23\2a\31\38\3f\46\4d\54\5b\62\69\70\77\7e\85\8c\93\9a\a1\a8\af")
+    (func (export "reset")
+        i32.const 0
+        i32.const 0
+        i32.const 96
+        memory.init $bytes)
+    (func (export "copy") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.copy)
+    (func (export "fill") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.fill)
+    ;; Hash of the first 100 bytes of the memory
+    (func (export "hash") (result i64)
+        (local i32 i64)
+        (loop
+            local.get 1
+            i64.const 31
+            i64.mul
+            local.get 0
+            i64.load8_u
+            i64.add
+            local.set 1
+            local.get 0
+            i32.const 1
+            i32.add
+            local.tee 0
+            i32.const 100
+            i32.lt_u
+            br_if 0)
+        local.get 1)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let initial: Vec<u8> = (0..100)
+        .map(|i| if i < 96 { (i * 7 + 1) as u8 } else { 0 })
+        .collect();
+    let hash = |memory: &[u8]| {
+        memory
+            .iter()
+            .fold(0u64, |h, b| h.wrapping_mul(31).wrapping_add(*b as u64))
+    };
+    let mut run = |function: &str, args: [u64; 3]| {
+        emulator
+            .call_function(emu_mod.clone(), "reset")
+            .expect("call");
+        for (register, arg) in [testing::RDI, testing::RSI, testing::RDX]
+            .into_iter()
+            .zip(args)
+        {
+            emulator.write_register(register, arg).unwrap();
+        }
+        let result = emulator.call_function(emu_mod.clone(), function);
+        emulator
+            .call_function(emu_mod.clone(), "hash")
+            .expect("call");
+        (result, emulator.read_register(testing::RAX).unwrap())
+    };
+
+    let copies: &[[usize; 3]] = &[
+        [40, 0, 20],
+        [0, 40, 20],
+        // Overlapping, with the destination before and after the source
+        [3, 5, 30],
+        [5, 3, 30],
+        [17, 9, 23],
+        [9, 17, 23],
+        [1, 0, 95],
+        [0, 1, 95],
+        [8, 0, 64],
+        [10, 10, 20],
+        [20, 21, 0],
+        [1, 0, 1],
+    ];
+    for [dst, src, len] in copies {
+        let mut memory = initial.clone();
+        memory.copy_within(*src..src + len, *dst);
+        let (result, actual) = run("copy", [*dst as u64, *src as u64, *len as u64]);
+        result.expect("copy");
+        assert_eq!(actual, hash(&memory), "copy {} {} {}", dst, src, len);
+    }
+    let fills: &[[usize; 3]] = &[[0, 0x1234, 96], [3, 0xAB, 13], [5, 0xFF, 0], [1, 7, 8]];
+    for [dst, value, len] in fills {
+        let mut memory = initial.clone();
+        memory[*dst..dst + len].fill(*value as u8);
+        let (result, actual) = run("fill", [*dst as u64, *value as u64, *len as u64]);
+        result.expect("fill");
+        assert_eq!(actual, hash(&memory), "fill {} {} {}", dst, value, len);
+    }
+
+    // Zero-length operations at the end of the memory are fine, anything
+    // going past it traps without touching the memory
+    let cases: &[(&str, [u64; 3], bool)] = &[
+        ("copy", [65536, 0, 0], true),
+        ("copy", [0, 65536, 0], true),
+        ("fill", [65536, 0, 0], true),
+        ("copy", [65537, 0, 0], false),
+        ("copy", [0, 65537, 0], false),
+        ("copy", [65530, 0, 7], false),
+        ("copy", [0, 65530, 7], false),
+        ("copy", [0, 1, 0xFFFF_FFFF], false),
+        ("copy", [0xFFFF_FFFF, 0, 1], false),
+        ("fill", [65535, 1, 2], false),
+        ("fill", [0, 1, 0xFFFF_FFFF], false),
+    ];
+    for (function, args, valid) in cases {
+        let (result, actual) = run(function, *args);
+        if *valid {
+            result.expect("valid operation");
+        } else {
+            assert!(
+                matches!(
+                    result,
+                    Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+                ),
+                "{} {:?}",
+                function,
+                args
+            );
+        }
+        assert_eq!(actual, hash(&initial), "{} {:?}", function, args);
+    }
+}
+
+#[test]
+fn table_copy() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (type $result (func (result i32)))
+    (table $t 8 funcref)
+    (table $u 4 funcref)
+    (elem $funcs func $f0 $f1 $f2 $f3 $f4 $f5 $f6 $f7)
+    (func $f0 (result i32) i32.const 0)
+    (func $f1 (result i32) i32.const 1)
+    (func $f2 (result i32) i32.const 2)
+    (func $f3 (result i32) i32.const 3)
+    (func $f4 (result i32) i32.const 4)
+    (func $f5 (result i32) i32.const 5)
+    (func $f6 (result i32) i32.const 6)
+    (func $f7 (result i32) i32.const 7)
+    (func (export "reset")
+        i32.const 0
+        i32.const 0
+        i32.const 8
+        table.init $t $funcs
+        i32.const 0
+        ref.null func
+        i32.const 4
+        table.fill $u)
+    (func (export "copy") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        table.copy $t $t)
+    (func (export "copy_out") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        table.copy $u $t)
+    (func (export "copy_in") (param i32 i32 i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        table.copy $t $u)
+    ;; Results of the functions in both tables as digits, 9 for null
+    (func (export "entries") (result i64)
+        (local i32 i64)
+        (loop
+            local.get 1
+            i64.const 10
+            i64.mul
+            local.get 0
+            table.get $t
+            ref.is_null
+            if (result i32)
+                i32.const 9
+            else
+                local.get 0
+                call_indirect $t (type $result)
+            end
+            i64.extend_i32_u
+            i64.add
+            local.set 1
+            local.get 0
+            i32.const 1
+            i32.add
+            local.tee 0
+            i32.const 8
+            i32.lt_u
+            br_if 0)
+        i32.const 0
+        local.set 0
+        (loop
+            local.get 1
+            i64.const 10
+            i64.mul
+            local.get 0
+            table.get $u
+            ref.is_null
+            if (result i32)
+                i32.const 9
+            else
+                local.get 0
+                call_indirect $u (type $result)
+            end
+            i64.extend_i32_u
+            i64.add
+            local.set 1
+            local.get 0
+            i32.const 1
+            i32.add
+            local.tee 0
+            i32.const 4
+            i32.lt_u
+            br_if 0)
+        local.get 1)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let digits = |t: &[u64], u: &[u64]| t.iter().chain(u).fold(0, |n, d| n * 10 + d);
+    let mut run = |function: &str, args: [u64; 3]| {
+        emulator
+            .call_function(emu_mod.clone(), "reset")
+            .expect("call");
+        for (register, arg) in [testing::RDI, testing::RSI, testing::RDX]
+            .into_iter()
+            .zip(args)
+        {
+            emulator.write_register(register, arg).unwrap();
+        }
+        let result = emulator.call_function(emu_mod.clone(), function);
+        emulator
+            .call_function(emu_mod.clone(), "entries")
+            .expect("call");
+        (result, emulator.read_register(testing::RAX).unwrap())
+    };
+
+    let cases: &[(&str, [usize; 3])] = &[
+        ("copy", [0, 4, 4]),
+        ("copy", [1, 0, 7]),
+        ("copy", [0, 1, 7]),
+        ("copy", [2, 2, 3]),
+        ("copy", [8, 0, 0]),
+        ("copy_out", [1, 5, 3]),
+        ("copy_in", [6, 0, 2]),
+    ];
+    for (function, [dst, src, len]) in cases {
+        let mut t: Vec<u64> = (0..8).collect();
+        let mut u = vec![9; 4];
+        match *function {
+            "copy" => t.copy_within(*src..src + len, *dst),
+            "copy_out" => u[*dst..dst + len].copy_from_slice(&t[*src..src + len]),
+            _ => t[*dst..dst + len].copy_from_slice(&u[*src..src + len]),
+        }
+        let (result, actual) = run(function, [*dst as u64, *src as u64, *len as u64]);
+        result.expect("copy");
+        assert_eq!(
+            actual,
+            digits(&t, &u),
+            "{} {} {} {}",
+            function,
+            dst,
+            src,
+            len
+        );
+    }
+
+    let initial = digits(&[0, 1, 2, 3, 4, 5, 6, 7], &[9; 4]);
+    let cases: &[(&str, [u64; 3])] = &[
+        ("copy", [9, 0, 0]),
+        ("copy", [0, 9, 0]),
+        ("copy", [1, 0, 8]),
+        ("copy", [0, 0xFFFF_FFFF, 2]),
+        ("copy_out", [0, 0, 5]),
+        ("copy_in", [4, 0, 5]),
+    ];
+    for (function, args) in cases {
+        let (result, actual) = run(function, *args);
+        assert!(
+            matches!(
+                result,
+                Err(testing::Error::Trap(Trap::OutOfBoundsTableAccess))
+            ),
+            "{} {:?}",
+            function,
+            args
+        );
+        assert_eq!(actual, initial, "{} {:?}", function, args);
+    }
+}
+
+#[test]
+fn element_segments() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (table 4 funcref)
+    (elem (i32.const 1) $a $b)
+    (elem func $b)
+    (func $a)
+    (func $b)
+    (func (export "init")
+     i32.const 3
+     i32.const 0
+     i32.const 1
+     table.init 1
+     elem.drop 1
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(emu_mod.clone(), "init")
+        .expect("call");
+
+    let module = emu_mod.borrow();
+    let a = module.offset() + module.function_entry_point(0).unwrap() as u64;
+    let b = module.offset() + module.function_entry_point(1).unwrap() as u64;
+    // Entries follow the size and limit slots
+    let entries = module.table(0).unwrap().offset() + 16;
+    let entry = |i: usize| LittleEndian::read_u64(&module.binary()[entries + i * 8..]);
+    assert_eq!(entry(0), 0);
+    assert_eq!(entry(1), a);
+    assert_eq!(entry(2), b);
+    assert_eq!(entry(3), b);
+}
+
+#[test]
+fn start_function() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (func $start
+     i32.const 0
+     i64.const 42
+     i64.store
+    )
+    (start $start)
+    (func (export "foo") (result i64)
+     i32.const 0
+     i64.load
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(emu_mod.clone(), "foo")
+        .expect("call");
+
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn globals() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (global $counter (mut i64) (i64.const 40))
+    (global $__stack_pointer (export "__stack_pointer") (mut i32) (i32.const 1048576))
+    (func (export "increment") (result i64)
+     global.get $counter
+     i64.const 2
+     i64.add
+     global.set $counter
+     global.get $counter
+    )
+    (func (export "set_sp") (param i32) (result i32)
+     local.get 0
+     global.set $__stack_pointer
+     global.get $__stack_pointer
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    let sp = module.global("__stack_pointer").expect("exported global");
+    assert_eq!(sp.content_type(), Type::I32);
+    assert!(sp.is_mutable());
+    let sp_offset = sp.offset();
+    assert_eq!(
+        LittleEndian::read_u64(&module.binary()[sp_offset..]),
+        1048576
+    );
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(emu_mod.clone(), "increment")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+
+    emulator
+        .write_register(testing::RDI, 65536)
+        .expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "set_sp")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 65536);
+    assert_eq!(
+        LittleEndian::read_u64(&emu_mod.borrow().binary()[sp_offset..]),
+        65536
+    );
+}
+
+#[test]
+fn imported_globals() {
+    use testing::Emulator;
+    let foo_src = r#"
+(module
+    (global $g (import "b" "g") (mut i64))
+    (global $c (import "b" "c") i64)
+    (global $h i64 (global.get $c))
+    (func (export "foo") (result i64)
+     global.get $h
+     global.get $g
+     i64.add
+     global.set $g
+     global.get $g
+    )
+)
+"#;
+    let foo_binary = wat::parse_str(foo_src).expect("binary module");
+    let foo_module = X86_64Compiler::default()
+        .compile(&foo_binary)
+        .expect("compiled module");
+
+    let bar_src = r#"
+(module
+    (global (export "g") (mut i64) (i64.const 21))
+    (global (export "c") i64 (i64.const 21))
+    (func (export "bar") (result i64)
+     global.get 0
+    )
+)
+"#;
+    let bar_binary = wat::parse_str(bar_src).expect("binary module");
+    let bar_module = X86_64Compiler::default()
+        .compile(&bar_binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let mod_foo = emulator.add_module(foo_module).expect("module addition");
+    let mod_bar = emulator.add_module(bar_module).expect("module addition");
+
+    let g = mod_bar.borrow().offset() + mod_bar.borrow().global("g").unwrap().offset() as u64;
+    mod_foo
+        .try_borrow_mut()
+        .unwrap()
+        .link_import("b", Some("g"), g);
+    let c = mod_bar.borrow().offset() + mod_bar.borrow().global("c").unwrap().offset() as u64;
+    mod_foo
+        .try_borrow_mut()
+        .unwrap()
+        .link_import("b", Some("c"), c);
+
+    emulator
+        .instantiate(mod_bar.clone())
+        .expect("instantiation");
+    emulator
+        .instantiate(mod_foo.clone())
+        .expect("instantiation");
+    emulator
+        .call_function(mod_foo.clone(), "foo")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+
+    emulator
+        .call_function(mod_bar.clone(), "bar")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn unreachable_traps() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (func (export "foo") (result i64)
+     i64.const 1
+     unreachable
+    )
+    (func (export "bar") (result i64)
+     i64.const 42
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    assert!(matches!(
+        emulator.call_function(emu_mod.clone(), "foo"),
+        Err(testing::Error::Trap(Trap::Unreachable))
+    ));
+
+    // Emulator is still usable after a trap
+    emulator
+        .call_function(emu_mod.clone(), "bar")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn out_of_bounds_memory_access_traps() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (data "abc")
+    (func (export "load") (param i32) (result i32)
+     local.get 0
+     i32.load offset=4
+    )
+    (func (export "init") (param i32)
+     local.get 0
+     i32.const 0
+     i32.const 3
+     memory.init 0
+    )
+    (func (export "init_dropped")
+     data.drop 0
+     i32.const 0
+     i32.const 0
+     i32.const 1
+     memory.init 0
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+
+    emulator
+        .write_register(testing::RDI, 65528)
+        .expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "load")
+        .expect("call");
+    for address in [65529, 0xFFFF_FFFF] {
+        emulator
+            .write_register(testing::RDI, address)
+            .expect("1st arg");
+        assert!(matches!(
+            emulator.call_function(emu_mod.clone(), "load"),
+            Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+        ));
+    }
+
+    emulator
+        .write_register(testing::RDI, 65533)
+        .expect("1st arg");
+    emulator
+        .call_function(emu_mod.clone(), "init")
+        .expect("call");
+    emulator
+        .write_register(testing::RDI, 65534)
+        .expect("1st arg");
+    assert!(matches!(
+        emulator.call_function(emu_mod.clone(), "init"),
+        Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+    ));
+    assert!(matches!(
+        emulator.call_function(emu_mod.clone(), "init_dropped"),
+        Err(testing::Error::Trap(Trap::OutOfBoundsMemoryAccess))
+    ));
+}
+
+/// Compiles a module exporting every listed operator under its own name,
+/// taking its operands as parameters
+fn operators_module(binary: &[(&str, &str, &str)], unary: &[(&str, &str, &str)]) -> Vec<u8> {
+    use alloc::format;
+    let mut src = String::from("(module\n");
+    for (op, param, result) in binary {
+        src += &format!(
+            "(func (export \"{op}\") (param {param} {param}) (result {result}) local.get 0 local.get 1 {op})\n"
+        );
+    }
+    for (op, param, result) in unary {
+        src += &format!(
+            "(func (export \"{op}\") (param {param}) (result {result}) local.get 0 {op})\n"
+        );
+    }
+    src += ")";
+    wat::parse_str(src).expect("binary module")
+}
+
+#[test]
+fn integer_operators() {
+    use testing::Emulator;
+    let binary_ops = [
+        "mul", "div_s", "div_u", "rem_s", "rem_u", "and", "or", "xor", "shl", "shr_s", "shr_u",
+        "rotl", "rotr",
+    ];
+    let comparisons = [
+        "eq", "ne", "lt_s", "lt_u", "gt_s", "gt_u", "le_s", "le_u", "ge_s", "ge_u",
+    ];
+    let unary_ops = ["clz", "ctz", "popcnt", "extend8_s", "extend16_s"];
+    let mut binary = vec![];
+    let mut unary = vec![];
+    for ty in ["i32", "i64"] {
+        for op in binary_ops {
+            binary.push((alloc::format!("{ty}.{op}"), ty, ty));
+        }
+        for op in comparisons {
+            binary.push((alloc::format!("{ty}.{op}"), ty, "i32"));
+        }
+        for op in unary_ops {
+            unary.push((alloc::format!("{ty}.{op}"), ty, ty));
+        }
+        unary.push((alloc::format!("{ty}.eqz"), ty, "i32"));
+    }
+    unary.push((String::from("i64.extend32_s"), "i64", "i64"));
+    unary.push((String::from("i32.wrap_i64"), "i64", "i32"));
+    unary.push((String::from("i64.extend_i32_s"), "i32", "i64"));
+    unary.push((String::from("i64.extend_i32_u"), "i32", "i64"));
+    let binary_refs = binary
+        .iter()
+        .map(|(op, param, result)| (op.as_str(), *param, *result))
+        .collect::<Vec<_>>();
+    let unary_refs = unary
+        .iter()
+        .map(|(op, param, result)| (op.as_str(), *param, *result))
+        .collect::<Vec<_>>();
+    let binary = operators_module(&binary_refs, &unary_refs);
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let min32 = 0x8000_0000;
+    let min64 = 0x8000_0000_0000_0000;
+    let cases: &[(&str, u64, u64, u64)] = &[
+        ("i32.mul", 0x10000, 0x10001, 0x10000),
+        ("i32.mul", 0xFFFF_FFFF, 3, 0xFFFF_FFFD),
+        ("i64.mul", u64::MAX, 3, u64::MAX - 2),
+        ("i32.div_s", 0xFFFF_FFF9, 2, 0xFFFF_FFFD),
+        ("i32.div_u", 0xFFFF_FFF9, 2, 0x7FFF_FFFC),
+        ("i64.div_s", -7i64 as u64, 2, -3i64 as u64),
+        ("i64.div_u", 7, 2, 3),
+        ("i32.rem_s", 0xFFFF_FFF9, 2, 0xFFFF_FFFF),
+        ("i32.rem_s", min32, 0xFFFF_FFFF, 0),
+        ("i32.rem_u", 0xFFFF_FFF9, 2, 1),
+        ("i64.rem_s", min64, u64::MAX, 0),
+        ("i64.rem_s", -7i64 as u64, 2, -1i64 as u64),
+        ("i64.rem_u", 7, 4, 3),
+        ("i32.and", 0xFF00, 0x0FF0, 0x0F00),
+        ("i64.or", 0xFF00, 0x0FF0, 0xFFF0),
+        ("i64.xor", 0xFF00, 0x0FF0, 0xF0F0),
+        ("i32.shl", 1, 33, 2),
+        ("i64.shl", 1, 65, 2),
+        ("i32.shr_s", min32, 4, 0xF800_0000),
+        ("i32.shr_u", min32, 4, 0x0800_0000),
+        ("i64.shr_s", min64, 4, 0xF800_0000_0000_0000),
+        ("i64.shr_u", min64, 68, 0x0800_0000_0000_0000),
+        ("i32.rotl", min32 | 1, 1, 3),
+        ("i32.rotr", 3, 1, min32 | 1),
+        ("i64.rotl", min64 | 1, 1, 3),
+        ("i64.rotr", 3, 1, min64 | 1),
+        ("i32.eq", 5, 5, 1),
+        ("i32.ne", 5, 5, 0),
+        ("i32.lt_s", 0xFFFF_FFFF, 0, 1),
+        ("i32.lt_u", 0xFFFF_FFFF, 0, 0),
+        ("i32.gt_s", 0xFFFF_FFFF, 0, 0),
+        ("i32.gt_u", 0xFFFF_FFFF, 0, 1),
+        ("i32.le_s", 3, 3, 1),
+        ("i32.le_u", 4, 3, 0),
+        ("i32.ge_s", 0, 0xFFFF_FFFF, 1),
+        ("i32.ge_u", 0, 0xFFFF_FFFF, 0),
+        ("i64.eq", u64::MAX, u64::MAX, 1),
+        ("i64.ne", 1, 2, 1),
+        ("i64.lt_s", u64::MAX, 0, 1),
+        ("i64.lt_u", u64::MAX, 0, 0),
+        ("i64.gt_s", u64::MAX, 0, 0),
+        ("i64.gt_u", u64::MAX, 0, 1),
+        ("i64.le_s", min64, 0, 1),
+        ("i64.le_u", min64, 0, 0),
+        ("i64.ge_s", 0, 0, 1),
+        ("i64.ge_u", 0, 1, 0),
+    ];
+    for (function, a, b, result) in cases {
+        emulator.write_register(testing::RDI, *a).expect("1st arg");
+        emulator.write_register(testing::RSI, *b).expect("2nd arg");
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_register(testing::RAX).unwrap(),
+            *result,
+            "{} {:#x} {:#x}",
+            function,
+            a,
+            b
+        );
+    }
+
+    let cases: &[(&str, u64, u64)] = &[
+        ("i32.clz", 0, 32),
+        ("i32.clz", 0x0080_0000, 8),
+        ("i64.clz", 0, 64),
+        ("i64.clz", 1, 63),
+        ("i32.ctz", 0, 32),
+        ("i32.ctz", 0x0080_0000, 23),
+        ("i64.ctz", 0, 64),
+        ("i64.ctz", min64, 63),
+        ("i32.popcnt", 0xFFFF_FFFF, 32),
+        ("i64.popcnt", 0xF0F0, 8),
+        ("i32.eqz", 0, 1),
+        ("i32.eqz", 0x1_0000_0000, 1),
+        ("i64.eqz", 0x1_0000_0000, 0),
+        ("i32.extend8_s", 0x80, 0xFFFF_FF80),
+        ("i32.extend16_s", 0x7FFF, 0x7FFF),
+        ("i64.extend8_s", 0x80, 0xFFFF_FFFF_FFFF_FF80),
+        ("i64.extend16_s", 0x8000, 0xFFFF_FFFF_FFFF_8000),
+        ("i64.extend32_s", 0x8000_0000, 0xFFFF_FFFF_8000_0000),
+        ("i32.wrap_i64", 0x1_2345_6789, 0x2345_6789),
+        ("i64.extend_i32_s", 0xFFFF_FFFF, u64::MAX),
+        ("i64.extend_i32_u", 0xFFFF_FFFF, 0xFFFF_FFFF),
+    ];
+    for (function, a, result) in cases {
+        emulator.write_register(testing::RDI, *a).expect("1st arg");
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_register(testing::RAX).unwrap(),
+            *result,
+            "{} {:#x}",
+            function,
+            a
+        );
+    }
+}
+
+#[test]
+fn population_count() {
+    use testing::Emulator;
+    let binary = operators_module(
+        &[],
+        &[("i32.popcnt", "i32", "i32"), ("i64.popcnt", "i64", "i64")],
+    );
+    let cases: &[(&str, u64, u64)] = &[
+        ("i32.popcnt", 0, 0),
+        ("i32.popcnt", 0xFFFF_FFFF, 32),
+        ("i32.popcnt", 0x8000_0001, 2),
+        ("i32.popcnt", 0xDEAD_BEEF, 24),
+        ("i64.popcnt", 0, 0),
+        ("i64.popcnt", u64::MAX, 64),
+        ("i64.popcnt", 0x8000_0000_0000_0001, 2),
+        ("i64.popcnt", 0xDEAD_BEEF_0F0F_F0F0, 40),
+    ];
+    // Without the instruction, bits are counted in parallel, whether
+    // functions are optimized or not
+    for (popcnt, optimize) in [(false, false), (true, false), (false, true), (true, true)] {
+        let features = Features {
+            popcnt,
+            ..Features::default()
+        };
+        let module = X86_64Compiler::new(features)
+            .optimize(optimize)
+            .compile(&binary)
+            .expect("compiled module");
+        let mut emulator = Emulator::new().expect("emulator");
+        let emu_mod = emulator.add_module(module).expect("module addition");
+        for (function, a, result) in cases {
+            emulator.write_register(testing::RDI, *a).expect("1st arg");
+            emulator
+                .call_function(emu_mod.clone(), *function)
+                .expect("call");
+            assert_eq!(
+                emulator.read_register(testing::RAX).unwrap(),
+                *result,
+                "{} {:#x} popcnt {} optimize {}",
+                function,
+                a,
+                popcnt,
+                optimize
+            );
+        }
+    }
+}
+
+#[test]
+fn integer_division_traps() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let binary = operators_module(
+        &[
+            ("i32.div_s", "i32", "i32"),
+            ("i32.div_u", "i32", "i32"),
+            ("i32.rem_s", "i32", "i32"),
+            ("i64.div_s", "i64", "i64"),
+            ("i64.rem_u", "i64", "i64"),
+        ],
+        &[],
+    );
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let cases: &[(&str, u64, u64, Trap)] = &[
+        ("i32.div_s", 1, 0, Trap::IntegerDivideByZero),
+        ("i32.div_u", 1, 0, Trap::IntegerDivideByZero),
+        ("i32.rem_s", 1, 0, Trap::IntegerDivideByZero),
+        ("i64.rem_u", 1, 0, Trap::IntegerDivideByZero),
+        // Upper half of an i32 is ignored
+        ("i32.div_s", 1, 0x1_0000_0000, Trap::IntegerDivideByZero),
+        ("i32.div_s", 0x8000_0000, 0xFFFF_FFFF, Trap::IntegerOverflow),
+        (
+            "i64.div_s",
+            0x8000_0000_0000_0000,
+            u64::MAX,
+            Trap::IntegerOverflow,
+        ),
+    ];
+    for (function, a, b, trap) in cases {
+        emulator.write_register(testing::RDI, *a).expect("1st arg");
+        emulator.write_register(testing::RSI, *b).expect("2nd arg");
+        match emulator.call_function(emu_mod.clone(), *function) {
+            Err(testing::Error::Trap(t)) => assert_eq!(t, *trap, "{}", function),
+            other => panic!("{} didn't trap: {:?}", function, other),
+        }
+    }
+}
+
+#[test]
+fn drop_and_select() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (func (export "foo") (param i64) (param i64) (param i32) (result i64)
+     local.get 0
+     local.get 1
+     local.get 2
+     select
+     i64.const 7
+     drop
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    for (condition, result) in [(1, 10), (0, 20), (0x1_0000_0000, 20)] {
+        emulator.write_register(testing::RDI, 10).expect("1st arg");
+        emulator.write_register(testing::RSI, 20).expect("2nd arg");
+        emulator
+            .write_register(testing::RDX, condition)
+            .expect("3rd arg");
+        emulator
+            .call_function(emu_mod.clone(), "foo")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn float_operators() {
+    use testing::Emulator;
+    let binary_ops = ["add", "sub", "mul", "div", "min", "max", "copysign"];
+    let comparisons = ["eq", "ne", "lt", "gt", "le", "ge"];
+    let unary_ops = ["abs", "neg", "sqrt", "ceil", "floor", "trunc", "nearest"];
+    let mut binary = vec![];
+    let mut unary = vec![];
+    for ty in ["f32", "f64"] {
+        for op in binary_ops {
+            binary.push((alloc::format!("{ty}.{op}"), ty, ty));
+        }
+        for op in comparisons {
+            binary.push((alloc::format!("{ty}.{op}"), ty, "i32"));
+        }
+        for op in unary_ops {
+            unary.push((alloc::format!("{ty}.{op}"), ty, ty));
+        }
+    }
+    let binary_refs = binary
+        .iter()
+        .map(|(op, param, result)| (op.as_str(), *param, *result))
+        .collect::<Vec<_>>();
+    let unary_refs = unary
+        .iter()
+        .map(|(op, param, result)| (op.as_str(), *param, *result))
+        .collect::<Vec<_>>();
+    let binary = operators_module(&binary_refs, &unary_refs);
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert!(module.uses_floating_point());
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let f32 = |v: f32| v.to_bits() as u64;
+    let f64 = |v: f64| v.to_bits();
+    let nan32 = 0x7FC0_0000;
+    let nan64 = 0x7FF8_0000_0000_0000;
+    // Non-canonical NaNs with a payload, one of them signaling
+    let snan32 = 0x7FA0_0001;
+    let nan64_payload = 0xFFF8_0000_0000_0001;
+    let cases: &[(&str, u64, u64, u64)] = &[
+        ("f32.add", f32(1.5), f32(2.25), f32(3.75)),
+        ("f32.sub", f32(1.5), f32(2.25), f32(-0.75)),
+        ("f32.mul", f32(1.5), f32(-2.0), f32(-3.0)),
+        ("f32.div", f32(1.0), f32(0.0), f32(f32::INFINITY)),
+        ("f32.min", f32(1.0), f32(-2.0), f32(-2.0)),
+        ("f32.min", f32(0.0), f32(-0.0), f32(-0.0)),
+        ("f32.min", f32(-0.0), f32(0.0), f32(-0.0)),
+        ("f32.min", snan32, f32(1.0), nan32),
+        ("f32.max", f32(1.0), snan32, nan32),
+        ("f32.max", f32(-0.0), f32(0.0), f32(0.0)),
+        ("f32.copysign", f32(2.0), f32(-0.0), f32(-2.0)),
+        ("f64.add", f64(0.1), f64(0.2), f64(0.1 + 0.2)),
+        ("f64.sub", f64(1.0), f64(1.0), f64(0.0)),
+        ("f64.mul", f64(1e300), f64(1e10), f64(f64::INFINITY)),
+        ("f64.div", f64(1.0), f64(3.0), f64(1.0 / 3.0)),
+        (
+            "f64.min",
+            f64(1.0),
+            f64(f64::NEG_INFINITY),
+            f64(f64::NEG_INFINITY),
+        ),
+        ("f64.min", f64(0.0), f64(-0.0), f64(-0.0)),
+        ("f64.min", nan64_payload, f64(1.0), nan64),
+        ("f64.max", f64(0.0), f64(-0.0), f64(0.0)),
+        ("f64.max", f64(1.0), nan64_payload, nan64),
+        ("f64.max", f64(-1.0), f64(2.0), f64(2.0)),
+        ("f64.copysign", f64(-2.0), f64(1.0), f64(2.0)),
+        ("f64.copysign", f64(2.0), nan64_payload, f64(-2.0)),
+    ];
+    for (function, a, b, result) in cases {
+        emulator.write_xmm_register(testing::XMM0, *a).unwrap();
+        emulator.write_xmm_register(testing::XMM1, *b).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_xmm_register(testing::XMM0).unwrap(),
+            *result,
+            "{} {:#x} {:#x}",
+            function,
+            a,
+            b
+        );
+    }
+
+    let cases: &[(&str, u64, u64, u64)] = &[
+        ("f32.eq", f32(1.0), f32(1.0), 1),
+        ("f32.eq", nan32, nan32, 0),
+        ("f32.eq", f32(0.0), f32(-0.0), 1),
+        ("f32.ne", nan32, nan32, 1),
+        ("f32.ne", f32(1.0), f32(1.0), 0),
+        ("f32.lt", f32(1.0), f32(2.0), 1),
+        ("f32.lt", f32(1.0), nan32, 0),
+        ("f32.gt", f32(1.0), f32(2.0), 0),
+        ("f32.le", f32(2.0), f32(2.0), 1),
+        ("f32.ge", nan32, f32(2.0), 0),
+        ("f64.eq", nan64, f64(1.0), 0),
+        ("f64.ne", f64(1.0), f64(2.0), 1),
+        ("f64.lt", f64(-1.0), f64(-2.0), 0),
+        ("f64.lt", nan64, f64(2.0), 0),
+        ("f64.gt", f64(-1.0), f64(-2.0), 1),
+        ("f64.gt", f64(1.0), nan64, 0),
+        ("f64.le", f64(1.0), nan64, 0),
+        ("f64.le", f64(-0.0), f64(0.0), 1),
+        ("f64.ge", f64(3.0), f64(2.0), 1),
+    ];
+    for (function, a, b, result) in cases {
+        emulator.write_xmm_register(testing::XMM0, *a).unwrap();
+        emulator.write_xmm_register(testing::XMM1, *b).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_register(testing::RAX).unwrap(),
+            *result,
+            "{} {:#x} {:#x}",
+            function,
+            a,
+            b
+        );
+    }
+
+    let cases: &[(&str, u64, u64)] = &[
+        ("f32.abs", f32(-1.5), f32(1.5)),
+        ("f32.neg", f32(0.0), f32(-0.0)),
+        ("f32.sqrt", f32(2.25), f32(1.5)),
+        ("f32.ceil", f32(1.25), f32(2.0)),
+        ("f32.ceil", f32(-0.5), f32(-0.0)),
+        ("f32.floor", f32(-1.25), f32(-2.0)),
+        ("f32.floor", f32(0.5), f32(0.0)),
+        ("f32.trunc", f32(-1.75), f32(-1.0)),
+        ("f32.trunc", f32(1e10), f32(1e10)),
+        ("f32.nearest", f32(2.5), f32(2.0)),
+        ("f32.nearest", f32(3.5), f32(4.0)),
+        ("f32.nearest", f32(-0.25), f32(-0.0)),
+        ("f32.nearest", f32(8388607.5), f32(8388608.0)),
+        ("f32.floor", f32(f32::NEG_INFINITY), f32(f32::NEG_INFINITY)),
+        ("f32.ceil", snan32, 0x7FE0_0001),
+        ("f64.abs", nan64_payload, 0x7FF8_0000_0000_0001),
+        ("f64.neg", f64(1.0), f64(-1.0)),
+        ("f64.sqrt", f64(-1.0), nan64 | 1 << 63),
+        ("f64.ceil", f64(-1.5), f64(-1.0)),
+        ("f64.ceil", f64(4503599627370495.5), f64(4503599627370496.0)),
+        ("f64.floor", f64(-0.0), f64(-0.0)),
+        (
+            "f64.floor",
+            f64(-4503599627370495.5),
+            f64(-4503599627370496.0),
+        ),
+        ("f64.trunc", f64(-0.75), f64(-0.0)),
+        ("f64.trunc", f64(1e300), f64(1e300)),
+        ("f64.nearest", f64(-2.5), f64(-2.0)),
+        ("f64.nearest", f64(0.5), f64(0.0)),
+        (
+            "f64.nearest",
+            f64(4503599627370495.5),
+            f64(4503599627370496.0),
+        ),
+        ("f64.nearest", f64(f64::INFINITY), f64(f64::INFINITY)),
+    ];
+    for (function, a, result) in cases {
+        emulator.write_xmm_register(testing::XMM0, *a).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_xmm_register(testing::XMM0).unwrap(),
+            *result,
+            "{} {:#x}",
+            function,
+            a
+        );
+    }
+}
+
+#[test]
+fn float_conversions() {
+    use testing::Emulator;
+    let mut unary = vec![];
+    for int in ["i32", "i64"] {
+        for float in ["f32", "f64"] {
+            for sign in ["s", "u"] {
+                unary.push((alloc::format!("{float}.convert_{int}_{sign}"), int, float));
+                unary.push((alloc::format!("{int}.trunc_{float}_{sign}"), float, int));
+                unary.push((alloc::format!("{int}.trunc_sat_{float}_{sign}"), float, int));
+            }
+        }
+    }
+    unary.push((String::from("f32.demote_f64"), "f64", "f32"));
+    unary.push((String::from("f64.promote_f32"), "f32", "f64"));
+    unary.push((String::from("i32.reinterpret_f32"), "f32", "i32"));
+    unary.push((String::from("i64.reinterpret_f64"), "f64", "i64"));
+    unary.push((String::from("f32.reinterpret_i32"), "i32", "f32"));
+    unary.push((String::from("f64.reinterpret_i64"), "i64", "f64"));
+    let unary_refs = unary
+        .iter()
+        .map(|(op, param, result)| (op.as_str(), *param, *result))
+        .collect::<Vec<_>>();
+    let binary = operators_module(&[], &unary_refs);
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let f32 = |v: f32| v.to_bits() as u64;
+    let f64 = |v: f64| v.to_bits();
+    let nan32 = 0x7FC0_0000;
+    let nan64 = 0x7FF8_0000_0000_0000;
+    let cases: &[(&str, u64, u64)] = &[
+        ("f32.convert_i32_s", 0xFFFF_FFFF, f32(-1.0)),
+        ("f32.convert_i32_u", 0xFFFF_FFFF, f32(4294967296.0)),
+        ("f32.convert_i64_s", -3i64 as u64, f32(-3.0)),
+        ("f32.convert_i64_u", u64::MAX, f32(18446744073709551616.0)),
+        // Rounds to nearest even, only apparent with the lowest bit kept
+        (
+            "f32.convert_i64_u",
+            0x8000_0080_0000_0001,
+            f32(9223373136366403584.0),
+        ),
+        ("f64.convert_i32_s", 0x8000_0000, f64(-2147483648.0)),
+        ("f64.convert_i32_u", 0x8000_0000, f64(2147483648.0)),
+        ("f64.convert_i64_s", 1 << 53, f64(9007199254740992.0)),
+        ("f64.convert_i64_u", 1 << 63, f64(9223372036854775808.0)),
+        (
+            "f64.convert_i64_u",
+            0x8000_0000_0000_0401,
+            f64(9223372036854777856.0),
+        ),
+        ("i32.trunc_f32_s", f32(-2.5), 0xFFFF_FFFE),
+        ("i32.trunc_f32_u", f32(4294967040.0), 0xFFFF_FF00),
+        ("i32.trunc_f64_s", f64(-2147483648.9), 0x8000_0000),
+        ("i32.trunc_f64_u", f64(-0.9), 0),
+        ("i64.trunc_f32_s", f32(-9223372036854775808.0), 1 << 63),
+        (
+            "i64.trunc_f32_u",
+            f32(18446742974197923840.0),
+            0xFFFF_FF00_0000_0000,
+        ),
+        ("i64.trunc_f64_s", f64(1e18), 1_000_000_000_000_000_000),
+        ("i64.trunc_f64_u", f64(9223372036854775808.0), 1 << 63),
+        ("i64.trunc_f64_u", f64(1.5), 1),
+        ("i32.trunc_sat_f32_s", f32(1e10), 0x7FFF_FFFF),
+        ("i32.trunc_sat_f32_u", f32(-1e10), 0),
+        ("i32.trunc_sat_f64_s", f64(-1e10), 0x8000_0000),
+        ("i32.trunc_sat_f64_u", nan64, 0),
+        ("i32.trunc_sat_f64_u", f64(1e10), 0xFFFF_FFFF),
+        ("i64.trunc_sat_f32_s", nan32, 0),
+        ("i64.trunc_sat_f32_u", f32(f32::INFINITY), u64::MAX),
+        ("i64.trunc_sat_f64_s", f64(f64::NEG_INFINITY), 1 << 63),
+        ("i64.trunc_sat_f64_s", f64(-3.5), -3i64 as u64),
+        ("i64.trunc_sat_f64_u", f64(12.9), 12),
+        ("f32.demote_f64", f64(0.1), f32(0.1)),
+        ("f32.demote_f64", f64(1e300), f32(f32::INFINITY)),
+        ("f64.promote_f32", f32(0.5), f64(0.5)),
+        ("i32.reinterpret_f32", f32(-0.0), 0x8000_0000),
+        ("i64.reinterpret_f64", f64(1.0), 0x3FF0_0000_0000_0000),
+        ("f32.reinterpret_i32", 0x3F80_0000, f32(1.0)),
+        ("f64.reinterpret_i64", 0xBFF0_0000_0000_0000, f64(-1.0)),
+    ];
+    for (function, a, result) in cases {
+        emulator.write_register(testing::RDI, *a).unwrap();
+        emulator.write_xmm_register(testing::XMM0, *a).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        let value = if function.starts_with('f') {
+            emulator.read_xmm_register(testing::XMM0).unwrap()
+        } else {
+            emulator.read_register(testing::RAX).unwrap()
+        };
+        assert_eq!(value, *result, "{} {:#x}", function, a);
+    }
+}
+
+#[test]
+fn float_conversion_traps() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let binary = operators_module(
+        &[],
+        &[
+            ("i32.trunc_f32_s", "f32", "i32"),
+            ("i32.trunc_f64_s", "f64", "i32"),
+            ("i32.trunc_f64_u", "f64", "i32"),
+            ("i64.trunc_f32_u", "f32", "i64"),
+            ("i64.trunc_f64_s", "f64", "i64"),
+            ("i64.trunc_f64_u", "f64", "i64"),
+        ],
+    );
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let f32 = |v: f32| v.to_bits() as u64;
+    let f64 = |v: f64| v.to_bits();
+    let cases: &[(&str, u64, Trap)] = &[
+        (
+            "i32.trunc_f32_s",
+            0x7FC0_0000,
+            Trap::InvalidConversionToInteger,
+        ),
+        ("i32.trunc_f32_s", f32(2147483648.0), Trap::IntegerOverflow),
+        ("i32.trunc_f64_s", f64(-2147483649.0), Trap::IntegerOverflow),
+        ("i32.trunc_f64_u", f64(-1.0), Trap::IntegerOverflow),
+        ("i32.trunc_f64_u", f64(4294967296.0), Trap::IntegerOverflow),
+        ("i64.trunc_f32_u", f32(f32::INFINITY), Trap::IntegerOverflow),
+        (
+            "i64.trunc_f64_s",
+            f64(9223372036854775808.0),
+            Trap::IntegerOverflow,
+        ),
+        (
+            "i64.trunc_f64_s",
+            f64(f64::NAN),
+            Trap::InvalidConversionToInteger,
+        ),
+        (
+            "i64.trunc_f64_u",
+            f64(18446744073709551616.0),
+            Trap::IntegerOverflow,
+        ),
+    ];
+    for (function, a, trap) in cases {
+        emulator.write_xmm_register(testing::XMM0, *a).unwrap();
+        match emulator.call_function(emu_mod.clone(), *function) {
+            Err(testing::Error::Trap(t)) => assert_eq!(t, *trap, "{} {:#x}", function, a),
+            other => panic!("{} {:#x} didn't trap: {:?}", function, a, other),
+        }
+    }
+}
+
+#[test]
+fn float_params_and_results() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (func $mix (param i32 f64 i64 f32 f64) (result f64)
+        local.get 1
+        local.get 0
+        f64.convert_i32_s
+        f64.mul
+        local.get 2
+        f64.convert_i64_s
+        f64.add
+        local.get 3
+        f64.promote_f32
+        f64.sub
+        local.get 4
+        f64.div)
+    (func (export "mix") (param i32 f64 i64 f32 f64) (result f64)
+        local.get 0
+        local.get 1
+        local.get 2
+        local.get 3
+        local.get 4
+        call $mix)
+    (func (export "store") (param f32) (result f32)
+        (local f64)
+        i32.const 8
+        local.get 0
+        f32.store
+        i32.const 8
+        f32.load
+        f64.promote_f32
+        local.set 1
+        i32.const 16
+        local.get 1
+        f64.store
+        i32.const 16
+        f64.load
+        f32.demote_f64
+        f32.const 0.5
+        f32.add)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert!(module.uses_floating_point());
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    emulator.write_register(testing::RDI, 3).unwrap();
+    emulator
+        .write_xmm_register(testing::XMM0, 1.5f64.to_bits())
+        .unwrap();
+    emulator.write_register(testing::RSI, 10).unwrap();
+    emulator
+        .write_xmm_register(testing::XMM1, 0.5f32.to_bits() as u64)
+        .unwrap();
+    emulator
+        .write_xmm_register(testing::XMM2, 2f64.to_bits())
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "mix")
+        .expect("call");
+    assert_eq!(
+        f64::from_bits(emulator.read_xmm_register(testing::XMM0).unwrap()),
+        (1.5 * 3.0 + 10.0 - 0.5) / 2.0
+    );
+
+    emulator
+        .write_xmm_register(testing::XMM0, 1.25f32.to_bits() as u64)
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "store")
+        .expect("call");
+    assert_eq!(
+        emulator.read_xmm_register(testing::XMM0).unwrap(),
+        1.75f32.to_bits() as u64
+    );
+
+    let module = X86_64Compiler::default()
+        .compile(
+            &wat::parse_str(r#"(module (func (export "ints") (result i32) i32.const 7))"#).unwrap(),
+        )
+        .expect("compiled module");
+    assert!(!module.uses_floating_point());
+}
+
+#[test]
+fn mixed_integer_signatures() {
+    use iced_x86::code_asm::*;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (func $bar (import "b" "bar") (param i32 i64) (result i32))
+    (func $mix (param i64 i32 i64 i32) (result i32)
+        local.get 1
+        local.get 3
+        i32.add
+        local.get 0
+        i32.wrap_i64
+        i32.sub
+        local.get 2
+        i32.wrap_i64
+        i32.add)
+    (func (export "mix") (param i64 i32 i64 i32) (result i64)
+        local.get 0
+        local.get 1
+        local.get 2
+        local.get 3
+        call $mix
+        i64.extend_i32_u)
+    (func (export "id") (param i32) (result i32)
+        local.get 0)
+    (func (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+    (func (export "sub") (param i64 i32) (result i32)
+        local.get 1
+        i32.const 1
+        i32.sub)
+    (func (export "call_bar") (result i32)
+        i32.const 7
+        i64.const 10
+        call $bar)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    // Host function leaving garbage in the upper half of its i32 result
+    let mut assembler = CodeAssembler::new(64).expect("new assembler");
+    assembler.mov(rax, 0xDEAD_BEEF_0000_0000u64).expect("asm");
+    assembler.add(rax, rdi).expect("asm");
+    assembler.add(rax, rsi).expect("asm");
+    assembler.ret().expect("asm");
+    let assembled = assembler.assemble(0).expect("asm");
+    let bar_fun = emulator.add_memory(&assembled).expect("bar function");
+    emu_mod
+        .try_borrow_mut()
+        .unwrap()
+        .link_import("b", Some("bar"), bar_fun);
+
+    // Upper halves of i32 arguments are unspecified
+    let garbage = 0xDEAD_BEEF_0000_0000;
+    emulator.write_register(testing::RDI, 100).unwrap();
+    emulator.write_register(testing::RSI, garbage | 5).unwrap();
+    emulator.write_register(testing::RDX, 1000).unwrap();
+    emulator.write_register(testing::RCX, garbage | 6).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "mix")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 911);
+
+    emulator.write_register(testing::RDI, garbage | 5).unwrap();
+    emulator.call_function(emu_mod.clone(), "id").expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 5);
+
+    emulator.write_register(testing::RDI, 0xFFFF_FFFF).unwrap();
+    emulator.write_register(testing::RSI, 2).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "add")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 1);
+
+    emulator.write_register(testing::RDI, u64::MAX).unwrap();
+    emulator.write_register(testing::RSI, garbage).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "sub")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 0xFFFF_FFFF);
+
+    emulator
+        .call_function(emu_mod.clone(), "call_bar")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 17);
+}
+
+#[test]
+fn stack_arguments() {
+    use alloc::format;
+    use iced_x86::code_asm::*;
+    use testing::Emulator;
+    // Weighted sums of the parameters, so that any mixup shows
+    let weighted_sum = |params: &[&str], result: &str| {
+        let mut body = format!("{result}.const 0\n");
+        for (i, param) in params.iter().enumerate() {
+            body += &format!("local.get {i}\n");
+            match (*param, result) {
+                ("i32", "i64") => body += "i64.extend_i32_u\n",
+                ("f32", "f64") => body += "f64.promote_f32\n",
+                ("i32", "f64") => body += "f64.convert_i32_s\n",
+                ("i64", "f64") => body += "f64.convert_i64_s\n",
+                _ => (),
+            }
+            body += &format!("{result}.const {}\n{result}.mul\n{result}.add\n", i + 1);
+        }
+        body
+    };
+    let ints = [
+        "i64", "i64", "i64", "i64", "i64", "i64", "i32", "i64", "i32",
+    ];
+    let floats = [
+        "f64", "f32", "f64", "f64", "f64", "f64", "f64", "f64", "f32", "f64",
+    ];
+    let mixed = [
+        "i64", "f64", "i32", "i64", "i64", "f32", "i64", "i64", "i64", "f64", "i32", "f64", "f64",
+        "f64", "f64", "f64", "f32", "f64",
+    ];
+    let src = format!(
+        r#"
+(module
+    (func $host (import "b" "host") (param i64 i64 i64 i64 i64 i64 i64 i32) (result i64))
+    (func $ints (export "ints") (param {ints}) (result i64)
+        {ints_body})
+    (func $floats (param {floats}) (result f64)
+        {floats_body})
+    (func $mixed (param {mixed}) (result f64)
+        {mixed_body})
+    (func (export "call_ints") (result i64)
+        i64.const 1
+        i64.const 2
+        i64.const 3
+        i64.const 4
+        i64.const 5
+        i64.const 6
+        i32.const -1
+        i64.const 8
+        i32.const 9
+        call $ints)
+    (func (export "call_floats") (result f64)
+        f64.const 1
+        f32.const 2
+        f64.const 3
+        f64.const 4
+        f64.const 5
+        f64.const 6
+        f64.const 7
+        f64.const 8
+        f32.const 9
+        f64.const 10
+        call $floats)
+    (func (export "call_mixed") (result f64)
+        {mixed_args}
+        call $mixed)
+    (func (export "call_host") (param i64) (result i64)
+        local.get 0
+        i64.const 1
+        i64.const 2
+        i64.const 3
+        i64.const 4
+        i64.const 5
+        i64.const 6
+        i64.const 7
+        i32.const 8
+        call $host
+        ;; Same with the operand stack 8 bytes deeper
+        i64.const 1
+        i64.const 2
+        i64.const 3
+        i64.const 4
+        i64.const 5
+        i64.const 6
+        i64.const 7
+        i32.const 8
+        call $host
+        i64.add
+        i64.add)
+)
+"#,
+        ints = ints.join(" "),
+        ints_body = weighted_sum(&ints, "i64"),
+        floats = floats.join(" "),
+        floats_body = weighted_sum(&floats, "f64"),
+        mixed = mixed.join(" "),
+        mixed_body = weighted_sum(&mixed, "f64"),
+        mixed_args = mixed
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| format!("{ty}.const {}\n", i + 1))
+            .collect::<String>(),
+    );
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    // Host function using its 1st, 6th and both stack arguments, adding
+    // rsp misalignment (as of before the call) shifted out of the way
+    let mut assembler = CodeAssembler::new(64).expect("new assembler");
+    assembler.mov(rax, rdi).expect("asm");
+    assembler.add(rax, r9).expect("asm");
+    assembler.mov(r11, qword_ptr(rsp + 8)).expect("asm");
+    assembler.imul_3(r11, r11, 10).expect("asm");
+    assembler.add(rax, r11).expect("asm");
+    assembler.mov(r11d, dword_ptr(rsp + 16)).expect("asm");
+    assembler.imul_3(r11, r11, 100).expect("asm");
+    assembler.add(rax, r11).expect("asm");
+    assembler.lea(rcx, ptr(rsp + 8)).expect("asm");
+    assembler.and(ecx, 15).expect("asm");
+    assembler.shl(rcx, 40).expect("asm");
+    assembler.add(rax, rcx).expect("asm");
+    assembler.ret().expect("asm");
+    let assembled = assembler.assemble(0).expect("asm");
+    let host_fun = emulator.add_memory(&assembled).expect("host function");
+    emu_mod
+        .try_borrow_mut()
+        .unwrap()
+        .link_import("b", Some("host"), host_fun);
+
+    let weights = |values: &[f64]| -> f64 {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v * (i + 1) as f64)
+            .sum()
+    };
+
+    emulator
+        .call_function(emu_mod.clone(), "call_ints")
+        .expect("call");
+    let expected = weights(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 4294967295.0, 8.0, 9.0]);
+    assert_eq!(
+        emulator.read_register(testing::RAX).unwrap(),
+        expected as u64
+    );
+
+    emulator
+        .call_function(emu_mod.clone(), "call_floats")
+        .expect("call");
+    let values: Vec<f64> = (1..=10).map(|v| v as f64).collect();
+    assert_eq!(
+        f64::from_bits(emulator.read_xmm_register(testing::XMM0).unwrap()),
+        weights(&values)
+    );
+
+    emulator
+        .call_function(emu_mod.clone(), "call_mixed")
+        .expect("call");
+    let values: Vec<f64> = (1..=mixed.len()).map(|v| v as f64).collect();
+    assert_eq!(
+        f64::from_bits(emulator.read_xmm_register(testing::XMM0).unwrap()),
+        weights(&values)
+    );
+
+    emulator.write_register(testing::RDI, 1000).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "call_host")
+        .expect("call");
+    assert_eq!(
+        emulator.read_register(testing::RAX).unwrap(),
+        1000 + 2 * (1 + 6 + 70 + 800)
+    );
+
+    // Called by the host, with stack arguments pushed in reverse order and
+    // the stack kept aligned
+    for (register, value) in [
+        (testing::RDI, 1),
+        (testing::RSI, 2),
+        (testing::RDX, 3),
+        (testing::RCX, 4),
+        (testing::R8, 5),
+        (testing::R9, 6),
+    ] {
+        emulator.write_register(register, value).unwrap();
+    }
+    emulator.push(0).unwrap();
+    emulator.push(0xDEAD_BEEF_0000_0009).unwrap();
+    emulator.push(8).unwrap();
+    emulator.push(0xDEAD_BEEF_0000_0007).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "ints")
+        .expect("call");
+    for _ in 0..4 {
+        emulator.pop().unwrap();
+    }
+    let expected = weights(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+    assert_eq!(
+        emulator.read_register(testing::RAX).unwrap(),
+        expected as u64
+    );
+}
+
+#[test]
+fn multiple_results() {
+    use iced_x86::code_asm::*;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (func $host (import "b" "host") (param i64) (result i64 i64 i64))
+    (func $pair (export "pair") (param i64) (result i64 i32)
+        local.get 0
+        i32.const 2)
+    (func (export "mixed_pair") (result f64 i32 f32 i64)
+        f64.const 1.5
+        i32.const 2
+        f32.const 3.5
+        i64.const 4)
+    (func $many (export "many") (param i32) (result i32 i64 f64 f32 i64 f64 f64)
+        local.get 0
+        i64.const 2
+        f64.const 3
+        f32.const 4
+        i64.const 5
+        f64.const 6
+        f64.const 7)
+    ;; Results in a return area along with stack arguments
+    (func $wide (param i64 i64 i64 i64 i64 i64 i64 i64) (result i64 i64 i64)
+        local.get 7
+        local.get 6
+        local.get 0)
+    (func (export "call_pair") (result i64)
+        i64.const 1
+        call $pair
+        i64.extend_i32_u
+        i64.const 10
+        i64.mul
+        i64.add)
+    (func (export "call_many") (result f64)
+        (local i32 i64 f64 f32 i64 f64 f64)
+        ;; A deeper operand stack, shouldn't be touched
+        f64.const 1000
+        i32.const 1
+        call $many
+        local.set 6
+        local.set 5
+        local.set 4
+        local.set 3
+        local.set 2
+        local.set 1
+        local.set 0
+        local.get 0
+        f64.convert_i32_u
+        local.get 1
+        f64.convert_i64_u
+        f64.const 10
+        f64.mul
+        f64.add
+        local.get 2
+        f64.const 100
+        f64.mul
+        f64.add
+        local.get 3
+        f64.promote_f32
+        f64.const 1000
+        f64.mul
+        f64.add
+        local.get 4
+        f64.convert_i64_u
+        f64.const 10000
+        f64.mul
+        f64.add
+        local.get 5
+        f64.const 100000
+        f64.mul
+        f64.add
+        local.get 6
+        f64.const 1000000
+        f64.mul
+        f64.add
+        f64.add)
+    (func (export "call_wide") (result i64)
+        i64.const 1
+        i64.const 2
+        i64.const 3
+        i64.const 4
+        i64.const 5
+        i64.const 6
+        i64.const 7
+        i64.const 8
+        call $wide
+        i64.const 100
+        i64.mul
+        i64.add
+        i64.const 10
+        i64.mul
+        i64.add)
+    (func (export "call_host") (result i64)
+        i64.const 5
+        call $host
+        i64.sub
+        i64.sub)
+    (func (export "block") (param i32) (result i64)
+        (local i64)
+        (block (result i32 i64 i32)
+            i32.const 1
+            i64.const 2
+            i32.const 3
+            local.get 0
+            br_if 0
+            drop
+            drop
+            drop
+            i32.const 4
+            i64.const 5
+            i32.const 6)
+        i64.extend_i32_u
+        i64.const 100
+        i64.mul
+        i64.add
+        local.set 1
+        i64.extend_i32_u
+        i64.const 10000
+        i64.mul
+        local.get 1
+        i64.add)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    // Host function returning its argument, twice and thrice it
+    let mut assembler = CodeAssembler::new(64).expect("new assembler");
+    assembler.mov(qword_ptr(rdi), rsi).expect("asm");
+    assembler.lea(rax, ptr(rsi + rsi)).expect("asm");
+    assembler.mov(qword_ptr(rdi + 8), rax).expect("asm");
+    assembler.add(rax, rsi).expect("asm");
+    assembler.mov(qword_ptr(rdi + 16), rax).expect("asm");
+    assembler.mov(rax, rdi).expect("asm");
+    assembler.ret().expect("asm");
+    let assembled = assembler.assemble(0).expect("asm");
+    let host_fun = emulator.add_memory(&assembled).expect("host function");
+    emu_mod
+        .try_borrow_mut()
+        .unwrap()
+        .link_import("b", Some("host"), host_fun);
+
+    emulator.write_register(testing::RDI, 7).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "pair")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 7);
+    assert_eq!(emulator.read_register(testing::RDX).unwrap(), 2);
+
+    emulator
+        .call_function(emu_mod.clone(), "mixed_pair")
+        .expect("call");
+    assert_eq!(
+        emulator.read_xmm_register(testing::XMM0).unwrap(),
+        1.5f64.to_bits()
+    );
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 2);
+    assert_eq!(
+        emulator.read_xmm_register(testing::XMM1).unwrap(),
+        3.5f32.to_bits() as u64
+    );
+    assert_eq!(emulator.read_register(testing::RDX).unwrap(), 4);
+
+    // Called by the host with a return area
+    let area = emulator.allocate(7 * 8).expect("return area");
+    emulator.write_register(testing::RDI, area).unwrap();
+    emulator
+        .write_register(testing::RSI, 0xDEAD_BEEF_0000_0001)
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "many")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), area);
+    let results = emulator.read_memory(area, 7 * 8).expect("results");
+    let results: Vec<u64> = results
+        .chunks(8)
+        .map(|slot| u64::from_le_bytes(slot.try_into().unwrap()))
+        .collect();
+    assert_eq!(
+        results,
+        [
+            1,
+            2,
+            3f64.to_bits(),
+            4f32.to_bits() as u64,
+            5,
+            6f64.to_bits(),
+            7f64.to_bits()
+        ]
+    );
+
+    let cases: &[(&str, u64)] = &[
+        ("call_pair", 21),
+        ("call_wide", 8 + (7 + 100) * 10),
+        ("call_host", 10),
+    ];
+    for (function, result) in cases {
+        emulator
+            .call_function(emu_mod.clone(), *function)
+            .expect("call");
+        assert_eq!(
+            emulator.read_register(testing::RAX).unwrap(),
+            *result,
+            "{}",
+            function
+        );
+    }
+
+    emulator
+        .call_function(emu_mod.clone(), "call_many")
+        .expect("call");
+    assert_eq!(
+        f64::from_bits(emulator.read_xmm_register(testing::XMM0).unwrap()),
+        7654321.0 + 1000.0
+    );
+
+    for (branch, result) in [(1, 10302), (0, 40605)] {
+        emulator.write_register(testing::RDI, branch).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), "block")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn call_indirect() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (type $unary (func (param i32) (result i32)))
+    (func $triple (import "b" "triple") (type $unary))
+    (table 5 funcref)
+    (elem (i32.const 0) $double $triple $wide)
+    (func $double (type $unary)
+     local.get 0
+     i32.const 2
+     i32.mul
+    )
+    (func $wide (param i64) (result i64)
+     local.get 0
+    )
+    (func (export "dispatch") (param i32 i32) (result i32)
+     local.get 1
+     local.get 0
+     call_indirect (type $unary)
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let triple_src = r#"
+(module
+    (func (export "triple") (param i32) (result i32)
+     local.get 0
+     i32.const 3
+     i32.mul
+    )
+)
+"#;
+    let triple_binary = wat::parse_str(triple_src).expect("binary module");
+    let triple_module = X86_64Compiler::default()
+        .compile(&triple_binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let triple_mod = emulator.add_module(triple_module).expect("module addition");
+    let triple = triple_mod.borrow().offset()
+        + triple_mod.borrow().function_entry_point("triple").unwrap() as u64;
+    emu_mod
+        .borrow_mut()
+        .link_import("b", Some("triple"), triple);
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+
+    for (index, result) in [(0, 14), (1, 21)] {
+        emulator.write_register(testing::RDI, index).unwrap();
+        emulator.write_register(testing::RSI, 7).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), "dispatch")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+
+    let cases = [
+        (2, Trap::IndirectCallSignatureMismatch),
+        (3, Trap::UninitializedElement),
+        (5, Trap::UndefinedElement),
+        (0xFFFF_FFFF, Trap::UndefinedElement),
+    ];
+    for (index, trap) in cases {
+        emulator.write_register(testing::RDI, index).unwrap();
+        emulator.write_register(testing::RSI, 7).unwrap();
+        match emulator.call_function(emu_mod.clone(), "dispatch") {
+            Err(testing::Error::Trap(t)) => assert_eq!(t, trap, "index {}", index),
+            other => panic!("index {} didn't trap: {:?}", index, other),
+        }
+    }
+}
+
+#[test]
+fn host_table_entries() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (table 2 funcref)
+    (elem (i32.const 0) $answer)
+    (func $answer (result i32)
+     i32.const 42
+    )
+    (func (export "call") (param i32) (result i32)
+     local.get 0
+     call_indirect (result i32)
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let other_src = r#"
+(module
+    (func (export "seven") (result i32)
+     i32.const 7
+    )
+)
+"#;
+    let other_binary = wat::parse_str(other_src).expect("binary module");
+    let other_module = X86_64Compiler::default()
+        .compile(&other_binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let other_mod = emulator.add_module(other_module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+
+    let answer = emu_mod.borrow().offset() + emu_mod.borrow().function_reference(0).unwrap() as u64;
+    let seven =
+        other_mod.borrow().offset() + other_mod.borrow().function_reference(0).unwrap() as u64;
+    {
+        let mut module = emu_mod.borrow_mut();
+        assert_eq!(module.table_size(0), Some(2));
+        assert_eq!(module.table_entry(0, 0), Ok(answer));
+        assert_eq!(module.table_entry(0, 1), Ok(0));
+        assert_eq!(module.table_entry(0, 2), Err(Trap::OutOfBoundsTableAccess));
+        assert_eq!(module.table_entry(1, 0), Err(Trap::OutOfBoundsTableAccess));
+        assert_eq!(
+            module.set_table_entry(0, 2, seven),
+            Err(Trap::OutOfBoundsTableAccess)
+        );
+        module.set_table_entry(0, 1, seven).unwrap();
+    }
+
+    for (index, result) in [(0, 42), (1, 7)] {
+        emulator.write_register(testing::RDI, index).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), "call")
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), result);
+    }
+}
+
+#[test]
+fn reference_types() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (table $functions 2 funcref)
+    (table $handles 1 4 externref)
+    (global $handle (mut externref) (ref.null extern))
+    (elem declare func $seven)
+    (func $seven (result i32)
+     i32.const 7
+    )
+    (func (export "is_null") (param externref) (result i32)
+     local.get 0
+     ref.is_null
+    )
+    (func (export "null_function") (result i32)
+     ref.null func
+     ref.is_null
+    )
+    (func (export "swap") (param externref) (result externref)
+     global.get $handle
+     local.get 0
+     global.set $handle
+    )
+    (func (export "store") (param i32 externref)
+     local.get 0
+     local.get 1
+     table.set $handles
+    )
+    (func (export "load") (param i32) (result externref)
+     local.get 0
+     table.get $handles
+    )
+    (func (export "grow") (param externref i32) (result i32)
+     local.get 0
+     local.get 1
+     table.grow $handles
+    )
+    (func (export "size") (result i32)
+     table.size $handles
+    )
+    (func (export "fill") (param i32 externref i32)
+     local.get 0
+     local.get 1
+     local.get 2
+     table.fill $handles
+    )
+    (func (export "install") (param i32)
+     local.get 0
+     ref.func $seven
+     table.set $functions
+    )
+    (func (export "call") (param i32) (result i32)
+     local.get 0
+     call_indirect $functions (result i32)
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+
+    let mut call = |function: &str, args: &[u64]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+            .map_err(|error| match error {
+                testing::Error::Trap(trap) => trap,
+                other => panic!("{} failed: {:?}", function, other),
+            })
+    };
+
+    assert_eq!(call("is_null", &[0]), Ok(1));
+    assert_eq!(call("is_null", &[0xCAFE]), Ok(0));
+    assert_eq!(call("null_function", &[]), Ok(1));
+    assert_eq!(call("swap", &[0xCAFE]), Ok(0));
+    assert_eq!(call("swap", &[0xF00D]), Ok(0xCAFE));
+
+    call("store", &[0, 0xBEEF]).unwrap();
+    assert_eq!(call("load", &[0]), Ok(0xBEEF));
+    assert_eq!(call("load", &[1]), Err(Trap::OutOfBoundsTableAccess));
+    assert_eq!(call("grow", &[0xAAAA, 2]), Ok(1));
+    assert_eq!(call("size", &[]), Ok(3));
+    assert_eq!(call("load", &[2]), Ok(0xAAAA));
+    // Beyond the declared maximum
+    assert_eq!(call("grow", &[0, 2]), Ok(u32::MAX as u64));
+    assert_eq!(call("size", &[]), Ok(3));
+    call("fill", &[1, 0xBBBB, 2]).unwrap();
+    assert_eq!(call("load", &[1]), Ok(0xBBBB));
+    assert_eq!(call("load", &[2]), Ok(0xBBBB));
+    assert_eq!(call("fill", &[2, 0, 2]), Err(Trap::OutOfBoundsTableAccess));
+    assert_eq!(call("load", &[0]), Ok(0xBEEF));
+
+    assert_eq!(call("call", &[1]), Err(Trap::UninitializedElement));
+    call("install", &[1]).unwrap();
+    assert_eq!(call("call", &[1]), Ok(7));
+    assert_eq!(emu_mod.borrow().table_entry(1, 0), Ok(0xBEEF));
+}
+
+#[test]
+fn tail_calls() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (type $countdown (func (param i32) (result i32)))
+    (import "b" "weighted"
+     (func $weighted (param i64 i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+    (table 2 funcref)
+    (elem (i32.const 0) $countdown $countdown)
+    (func $sum (export "sum") (param i64 i64) (result i64)
+     local.get 0
+     i64.eqz
+     if
+      local.get 1
+      return
+     end
+     local.get 0
+     i64.const 1
+     i64.sub
+     local.get 1
+     local.get 0
+     i64.add
+     return_call $sum
+    )
+    ;; Alternates between one and three stack arguments
+    (func $few (export "few") (param i64 i64) (result i64)
+     local.get 0
+     i64.eqz
+     if
+      local.get 1
+      return
+     end
+     local.get 0
+     i64.const 1
+     i64.sub
+     local.get 1
+     i64.const 1
+     i64.const 2
+     i64.const 3
+     i64.const 4
+     i64.const 5
+     i64.const 6
+     i64.const 7
+     return_call $wide
+    )
+    (func $wide (param $n i64) (param $acc i64)
+     (param $a i64) (param $b i64) (param $c i64) (param $d i64)
+     (param $e i64) (param $f i64) (param $g i64) (result i64)
+     local.get $n
+     local.get $acc
+     local.get $a
+     local.get $b
+     i64.const 2
+     i64.mul
+     i64.add
+     local.get $c
+     i64.const 3
+     i64.mul
+     i64.add
+     local.get $d
+     i64.const 4
+     i64.mul
+     i64.add
+     local.get $e
+     i64.const 5
+     i64.mul
+     i64.add
+     local.get $f
+     i64.const 6
+     i64.mul
+     i64.add
+     local.get $g
+     i64.const 7
+     i64.mul
+     i64.add
+     i64.add
+     return_call $few
+    )
+    (func $countdown (type $countdown)
+     local.get 0
+     i32.eqz
+     if
+      i32.const 42
+      return
+     end
+     local.get 0
+     i32.const 1
+     i32.sub
+     local.get 0
+     i32.const 1
+     i32.and
+     return_call_indirect (type $countdown)
+    )
+    (func (export "countdown") (param i32) (result i32)
+     local.get 0
+     i32.const 0
+     return_call_indirect (type $countdown)
+    )
+    (func (export "mismatch") (result i64)
+     i64.const 0
+     i64.const 0
+     i32.const 0
+     return_call_indirect (param i64 i64) (result i64)
+    )
+    (func (export "tail_import") (param i64) (result i64)
+     local.get 0
+     i64.const 1
+     i64.const 2
+     i64.const 3
+     i64.const 4
+     i64.const 5
+     i64.const 6
+     i64.const 7
+     i64.const 8
+     return_call $weighted
+    )
+    (func $spread (param i64 i64 i64 i64 i64 i64 i64 i64) (result i64 i64 i64)
+     local.get 7
+     local.get 6
+     local.get 0
+    )
+    (func (export "three") (param i64) (result i64 i64 i64)
+     local.get 0
+     i64.const 1
+     i64.const 2
+     i64.const 3
+     i64.const 4
+     i64.const 5
+     i64.const 6
+     i64.const 7
+     return_call $spread
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let weighted_src = r#"
+(module
+    (func (export "weighted") (param i64 i64 i64 i64 i64 i64 i64 i64 i64) (result i64)
+     local.get 0
+     local.get 6
+     i64.const 10
+     i64.mul
+     i64.add
+     local.get 7
+     i64.const 100
+     i64.mul
+     i64.add
+     local.get 8
+     i64.const 1000
+     i64.mul
+     i64.add
+    )
+)
+"#;
+    let weighted_binary = wat::parse_str(weighted_src).expect("binary module");
+    let weighted_module = X86_64Compiler::default()
+        .compile(&weighted_binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let weighted_mod = emulator
+        .add_module(weighted_module)
+        .expect("module addition");
+    let weighted = weighted_mod.borrow().offset()
+        + weighted_mod
+            .borrow()
+            .function_entry_point("weighted")
+            .unwrap() as u64;
+    emu_mod
+        .borrow_mut()
+        .link_import("b", Some("weighted"), weighted);
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+
+    let stack = emulator
+        .read_register(unicorn_engine::RegisterX86::RSP)
+        .unwrap();
+    // Frames get replaced, so deep recursion leaves the stack far below
+    // untouched, where thousands of frames would go
+    let untouched = |emulator: &Emulator| {
+        let below = emulator.read_memory(stack - 0x40000, 0x20000).unwrap();
+        below.iter().all(|byte| *byte == 0)
+    };
+
+    emulator.write_register(testing::RDI, 10000).unwrap();
+    emulator.write_register(testing::RSI, 0).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "sum")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 50005000);
+    assert!(untouched(&emulator));
+
+    emulator.write_register(testing::RDI, 3000).unwrap();
+    emulator.write_register(testing::RSI, 5).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "few")
+        .expect("call");
+    assert_eq!(
+        emulator.read_register(testing::RAX).unwrap(),
+        5 + 140 * 3000
+    );
+    assert!(untouched(&emulator));
+
+    emulator.write_register(testing::RDI, 3000).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "countdown")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+    assert!(untouched(&emulator));
+    assert_eq!(
+        emulator
+            .read_register(unicorn_engine::RegisterX86::RSP)
+            .unwrap(),
+        stack
+    );
+
+    emulator.write_register(testing::RDI, 3).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "tail_import")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 8763);
+
+    // Results in a return area
+    let area = emulator.allocate(4096).unwrap();
+    emulator.write_register(testing::RDI, area).unwrap();
+    emulator.write_register(testing::RSI, 9).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "three")
+        .expect("call");
+    let results = emulator.read_memory(area, 24).unwrap();
+    assert_eq!(LittleEndian::read_u64(&results[0..]), 7);
+    assert_eq!(LittleEndian::read_u64(&results[8..]), 6);
+    assert_eq!(LittleEndian::read_u64(&results[16..]), 9);
+    assert_eq!(
+        emulator
+            .read_register(unicorn_engine::RegisterX86::RSP)
+            .unwrap(),
+        stack
+    );
+
+    match emulator.call_function(emu_mod.clone(), "mismatch") {
+        Err(testing::Error::Trap(trap)) => assert_eq!(trap, Trap::IndirectCallSignatureMismatch),
+        other => panic!("didn't trap: {:?}", other),
+    }
+}
+
+#[test]
+fn exceptions() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (tag $pair (param i32 i64))
+    (tag $empty)
+    (func $thrower (param i32)
+     local.get 0
+     i32.eqz
+     if
+      i32.const 7
+      i64.const 8
+      throw $pair
+     end
+     local.get 0
+     i32.const 1
+     i32.eq
+     if
+      throw $empty
+     end
+    )
+    ;; Values below the try block survive unwinding
+    (func (export "catch") (param i32) (result i64)
+     (local i64)
+     i64.const 1000
+     try (result i64)
+      local.get 0
+      call $thrower
+      i64.const 1
+     catch $pair
+      local.set 1
+      i64.extend_i32_u
+      local.get 1
+      i64.const 10
+      i64.mul
+      i64.add
+     catch $empty
+      i64.const 2
+     end
+     i64.add
+    )
+    (func (export "catch_all") (param i32) (result i32)
+     try (result i32)
+      local.get 0
+      call $thrower
+      i32.const 1
+     catch_all
+      i32.const 5
+     end
+    )
+    (func (export "uncaught") (param i32)
+     try
+      local.get 0
+      call $thrower
+     catch $empty
+     end
+    )
+    (func (export "rethrow") (param i32) (result i64)
+     (local i64)
+     try (result i64)
+      try
+       local.get 0
+       call $thrower
+      catch $pair
+       drop
+       drop
+       try
+        i32.const 1
+        call $thrower
+       catch_all
+       end
+       rethrow 0
+      catch_all
+       rethrow 0
+      end
+      i64.const 0
+     catch $pair
+      local.set 1
+      i64.extend_i32_u
+      local.get 1
+      i64.add
+     catch $empty
+      i64.const 2
+     end
+    )
+    (func (export "delegate") (result i32)
+     try (result i32)
+      block
+       try (result i32)
+        try
+         i32.const 0
+         call $thrower
+        delegate 2
+        i32.const 0
+       catch $pair
+        drop
+        drop
+        i32.const 99
+       end
+       drop
+      end
+      i32.const 0
+     catch $pair
+      drop
+      drop
+      i32.const 42
+     end
+    )
+    (func (export "delegate_to_caller") (result i32)
+     try (result i32)
+      try
+       i32.const 0
+       call $thrower
+      delegate 1
+      i32.const 0
+     catch_all
+      i32.const 99
+     end
+    )
+    ;; Handlers of blocks left by branches don't catch anything after
+    (func (export "branch_out")
+     block
+      try
+       br 1
+      catch_all
+      end
+     end
+     i32.const 1
+     call $thrower
+    )
+    (func (export "tail_call") (param i32)
+     try
+      local.get 0
+      return_call $thrower
+     catch_all
+     end
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+
+    let mut call = |function: &str, arg: u64| {
+        emulator.write_register(testing::RDI, arg).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+            .map_err(|error| match error {
+                testing::Error::Trap(trap) => trap,
+                other => panic!("{} failed: {:?}", function, other),
+            })
+    };
+
+    assert_eq!(call("catch", 0), Ok(1087));
+    assert_eq!(call("catch", 1), Ok(1002));
+    assert_eq!(call("catch", 2), Ok(1001));
+    assert_eq!(call("catch_all", 0), Ok(5));
+    assert_eq!(call("catch_all", 2), Ok(1));
+    assert_eq!(call("uncaught", 0), Err(Trap::UncaughtException));
+    assert!(call("uncaught", 1).is_ok());
+    assert_eq!(call("rethrow", 0), Ok(15));
+    assert_eq!(call("rethrow", 1), Ok(2));
+    assert_eq!(call("delegate", 0), Ok(42));
+    assert_eq!(call("delegate_to_caller", 0), Err(Trap::UncaughtException));
+    assert_eq!(call("branch_out", 0), Err(Trap::UncaughtException));
+    assert_eq!(call("tail_call", 0), Err(Trap::UncaughtException));
+    assert!(call("tail_call", 2).is_ok());
+    // Handlers are all gone after traps and returns
+    assert_eq!(call("catch", 0), Ok(1087));
+}
+
+#[test]
+fn exceptions_across_modules() {
+    use testing::Emulator;
+    let thrower_src = r#"
+(module
+    (tag $error (export "error") (param i64))
+    (func (export "fail") (param i64)
+     local.get 0
+     throw $error
+    )
+)
+"#;
+    let thrower_binary = wat::parse_str(thrower_src).expect("binary module");
+    let thrower_module = X86_64Compiler::default()
+        .compile(&thrower_binary)
+        .expect("compiled module");
+
+    let catcher_src = r#"
+(module
+    (tag $error (import "a" "error") (param i64))
+    (tag $other)
+    (func $fail (import "a" "fail") (param i64))
+    (func (export "catch") (param i64) (result i64)
+     try (result i64)
+      local.get 0
+      call $fail
+      i64.const 0
+     catch $other
+      i64.const 1
+     catch $error
+      i64.const 1
+      i64.add
+     end
+    )
+)
+"#;
+    let catcher_binary = wat::parse_str(catcher_src).expect("binary module");
+    let catcher_module = X86_64Compiler::default()
+        .compile(&catcher_binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let thrower = emulator
+        .add_module(thrower_module)
+        .expect("module addition");
+    let catcher = emulator
+        .add_module(catcher_module)
+        .expect("module addition");
+
+    let error = thrower
+        .borrow()
+        .tag_identity("error", thrower.borrow().offset())
+        .unwrap();
+    let fail =
+        thrower.borrow().offset() + thrower.borrow().function_entry_point("fail").unwrap() as u64;
+    {
+        let mut catcher = catcher.borrow_mut();
+        catcher.link_import("a", Some("error"), error);
+        catcher.link_import("a", Some("fail"), fail);
+    }
+
+    emulator.write_register(testing::RDI, 41).unwrap();
+    emulator
+        .call_function(catcher.clone(), "catch")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 42);
+}
+
+#[test]
+fn atomics() {
+    use crate::trap::Trap;
+    use alloc::format;
+    use iced_x86::code_asm::*;
+    use testing::Emulator;
+    // Operator, type, address, operands, result, and the word at 8 after the
+    // operation, starting from `INITIAL`
+    type Case<'a> = (&'a str, &'a str, u64, &'a [u64], u64, u64);
+    const INITIAL: u64 = 0x8877_6655_4433_2211;
+    let cases: &[Case] = &[
+        ("i32.atomic.load", "i32", 8, &[], 0x4433_2211, INITIAL),
+        ("i64.atomic.load", "i64", 8, &[], INITIAL, INITIAL),
+        ("i32.atomic.load8_u", "i32", 9, &[], 0x22, INITIAL),
+        ("i32.atomic.load16_u", "i32", 10, &[], 0x4433, INITIAL),
+        ("i64.atomic.load8_u", "i64", 15, &[], 0x88, INITIAL),
+        ("i64.atomic.load16_u", "i64", 14, &[], 0x8877, INITIAL),
+        ("i64.atomic.load32_u", "i64", 12, &[], 0x8877_6655, INITIAL),
+        (
+            "i32.atomic.store",
+            "i32",
+            8,
+            &[0xDEAD_BEEF],
+            0,
+            0x8877_6655_DEAD_BEEF,
+        ),
+        ("i64.atomic.store", "i64", 8, &[5], 0, 5),
+        (
+            "i32.atomic.store8",
+            "i32",
+            9,
+            &[0xABCD],
+            0,
+            0x8877_6655_4433_CD11,
+        ),
+        (
+            "i32.atomic.store16",
+            "i32",
+            14,
+            &[0xFFFF],
+            0,
+            0xFFFF_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.store8",
+            "i64",
+            8,
+            &[0x1FF],
+            0,
+            0x8877_6655_4433_22FF,
+        ),
+        (
+            "i64.atomic.store16",
+            "i64",
+            10,
+            &[u64::MAX],
+            0,
+            0x8877_6655_FFFF_2211,
+        ),
+        (
+            "i64.atomic.store32",
+            "i64",
+            12,
+            &[0x1_2345_6789],
+            0,
+            0x2345_6789_4433_2211,
+        ),
+        // Carries and borrows stay within the value
+        (
+            "i32.atomic.rmw.add",
+            "i32",
+            8,
+            &[0xF000_0000],
+            0x4433_2211,
+            0x8877_6655_3433_2211,
+        ),
+        (
+            "i64.atomic.rmw.add",
+            "i64",
+            8,
+            &[0x1111],
+            INITIAL,
+            0x8877_6655_4433_3322,
+        ),
+        (
+            "i32.atomic.rmw8.add_u",
+            "i32",
+            8,
+            &[0xFF],
+            0x11,
+            0x8877_6655_4433_2210,
+        ),
+        (
+            "i32.atomic.rmw16.add_u",
+            "i32",
+            10,
+            &[0x1_0001],
+            0x4433,
+            0x8877_6655_4434_2211,
+        ),
+        (
+            "i64.atomic.rmw8.add_u",
+            "i64",
+            9,
+            &[1],
+            0x22,
+            0x8877_6655_4433_2311,
+        ),
+        (
+            "i64.atomic.rmw16.add_u",
+            "i64",
+            14,
+            &[1],
+            0x8877,
+            0x8878_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw32.add_u",
+            "i64",
+            12,
+            &[0x7788_99AB],
+            0x8877_6655,
+            0x0000_0000_4433_2211,
+        ),
+        (
+            "i32.atomic.rmw.sub",
+            "i32",
+            8,
+            &[0x4433_2212],
+            0x4433_2211,
+            0x8877_6655_FFFF_FFFF,
+        ),
+        (
+            "i64.atomic.rmw.sub",
+            "i64",
+            8,
+            &[INITIAL + 1],
+            INITIAL,
+            u64::MAX,
+        ),
+        (
+            "i32.atomic.rmw8.sub_u",
+            "i32",
+            11,
+            &[0x45],
+            0x44,
+            0x8877_6655_FF33_2211,
+        ),
+        (
+            "i32.atomic.rmw16.sub_u",
+            "i32",
+            8,
+            &[0x2212],
+            0x2211,
+            0x8877_6655_4433_FFFF,
+        ),
+        (
+            "i64.atomic.rmw8.sub_u",
+            "i64",
+            15,
+            &[0x88],
+            0x88,
+            0x0077_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.sub_u",
+            "i64",
+            12,
+            &[1],
+            0x6655,
+            0x8877_6654_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw32.sub_u",
+            "i64",
+            8,
+            &[0x4433_2212],
+            0x4433_2211,
+            0x8877_6655_FFFF_FFFF,
+        ),
+        (
+            "i32.atomic.rmw.and",
+            "i32",
+            8,
+            &[0x0F0F_0F0F],
+            0x4433_2211,
+            0x8877_6655_0403_0201,
+        ),
+        (
+            "i64.atomic.rmw.and",
+            "i64",
+            8,
+            &[0xFF00_0000_0000_00FF],
+            INITIAL,
+            0x8800_0000_0000_0011,
+        ),
+        (
+            "i32.atomic.rmw8.and_u",
+            "i32",
+            10,
+            &[0xF0],
+            0x33,
+            0x8877_6655_4430_2211,
+        ),
+        (
+            "i32.atomic.rmw16.and_u",
+            "i32",
+            8,
+            &[0xFF00],
+            0x2211,
+            0x8877_6655_4433_2200,
+        ),
+        (
+            "i64.atomic.rmw8.and_u",
+            "i64",
+            12,
+            &[0],
+            0x55,
+            0x8877_6600_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.and_u",
+            "i64",
+            14,
+            &[0x0FF0],
+            0x8877,
+            0x0870_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw32.and_u",
+            "i64",
+            12,
+            &[0xFFFF],
+            0x8877_6655,
+            0x0000_6655_4433_2211,
+        ),
+        (
+            "i32.atomic.rmw.or",
+            "i32",
+            8,
+            &[0x8000_0000],
+            0x4433_2211,
+            0x8877_6655_C433_2211,
+        ),
+        (
+            "i64.atomic.rmw.or",
+            "i64",
+            8,
+            &[0xF0],
+            INITIAL,
+            0x8877_6655_4433_22F1,
+        ),
+        (
+            "i32.atomic.rmw8.or_u",
+            "i32",
+            9,
+            &[0x0F],
+            0x22,
+            0x8877_6655_4433_2F11,
+        ),
+        (
+            "i32.atomic.rmw16.or_u",
+            "i32",
+            14,
+            &[0x0100],
+            0x8877,
+            0x8977_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw8.or_u",
+            "i64",
+            15,
+            &[0x07],
+            0x88,
+            0x8F77_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.or_u",
+            "i64",
+            8,
+            &[0xFFFF_0000],
+            0x2211,
+            INITIAL,
+        ),
+        (
+            "i64.atomic.rmw32.or_u",
+            "i64",
+            8,
+            &[0x1_0000_0000],
+            0x4433_2211,
+            INITIAL,
+        ),
+        (
+            "i32.atomic.rmw.xor",
+            "i32",
+            8,
+            &[u64::MAX],
+            0x4433_2211,
+            0x8877_6655_BBCC_DDEE,
+        ),
+        ("i64.atomic.rmw.xor", "i64", 8, &[INITIAL], INITIAL, 0),
+        (
+            "i32.atomic.rmw8.xor_u",
+            "i32",
+            8,
+            &[0x11],
+            0x11,
+            0x8877_6655_4433_2200,
+        ),
+        (
+            "i32.atomic.rmw16.xor_u",
+            "i32",
+            12,
+            &[0xFFFF],
+            0x6655,
+            0x8877_99AA_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw8.xor_u",
+            "i64",
+            13,
+            &[0xFF],
+            0x66,
+            0x8877_9955_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.xor_u",
+            "i64",
+            10,
+            &[0x4433],
+            0x4433,
+            0x8877_6655_0000_2211,
+        ),
+        (
+            "i64.atomic.rmw32.xor_u",
+            "i64",
+            12,
+            &[0xFFFF_FFFF_0000_FFFF],
+            0x8877_6655,
+            0x8877_99AA_4433_2211,
+        ),
+        (
+            "i32.atomic.rmw.xchg",
+            "i32",
+            8,
+            &[7],
+            0x4433_2211,
+            0x8877_6655_0000_0007,
+        ),
+        ("i64.atomic.rmw.xchg", "i64", 8, &[5], INITIAL, 5),
+        (
+            "i32.atomic.rmw8.xchg_u",
+            "i32",
+            9,
+            &[0x1FF],
+            0x22,
+            0x8877_6655_4433_FF11,
+        ),
+        (
+            "i32.atomic.rmw16.xchg_u",
+            "i32",
+            12,
+            &[0],
+            0x6655,
+            0x8877_0000_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw8.xchg_u",
+            "i64",
+            14,
+            &[0xAB],
+            0x77,
+            0x88AB_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.xchg_u",
+            "i64",
+            8,
+            &[0x1234],
+            0x2211,
+            0x8877_6655_4433_1234,
+        ),
+        (
+            "i64.atomic.rmw32.xchg_u",
+            "i64",
+            12,
+            &[u64::MAX],
+            0x8877_6655,
+            0xFFFF_FFFF_4433_2211,
+        ),
+        // Only the lowest bytes of the expected value are compared
+        (
+            "i32.atomic.rmw.cmpxchg",
+            "i32",
+            8,
+            &[0x4433_2211, 7],
+            0x4433_2211,
+            0x8877_6655_0000_0007,
+        ),
+        (
+            "i32.atomic.rmw.cmpxchg",
+            "i32",
+            8,
+            &[0x4433_2212, 7],
+            0x4433_2211,
+            INITIAL,
+        ),
+        (
+            "i64.atomic.rmw.cmpxchg",
+            "i64",
+            8,
+            &[INITIAL, 1],
+            INITIAL,
+            1,
+        ),
+        (
+            "i64.atomic.rmw.cmpxchg",
+            "i64",
+            8,
+            &[0x4433_2211, 1],
+            INITIAL,
+            INITIAL,
+        ),
+        (
+            "i32.atomic.rmw8.cmpxchg_u",
+            "i32",
+            8,
+            &[0x111, 0x2FF],
+            0x11,
+            0x8877_6655_4433_22FF,
+        ),
+        (
+            "i32.atomic.rmw16.cmpxchg_u",
+            "i32",
+            10,
+            &[0x4432, 0],
+            0x4433,
+            INITIAL,
+        ),
+        (
+            "i64.atomic.rmw8.cmpxchg_u",
+            "i64",
+            15,
+            &[0x88, 0x99],
+            0x88,
+            0x9977_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw16.cmpxchg_u",
+            "i64",
+            14,
+            &[0x1_8877, 0xAAAA],
+            0x8877,
+            0xAAAA_6655_4433_2211,
+        ),
+        (
+            "i64.atomic.rmw32.cmpxchg_u",
+            "i64",
+            12,
+            &[0xFFFF_FFFF_8877_6655, 0xAAAA_BBBB_CCCC_DDDD],
+            0x8877_6655,
+            0xCCCC_DDDD_4433_2211,
+        ),
+    ];
+    let mut functions = String::new();
+    for (i, (op, ty, _, operands, _, _)) in cases.iter().enumerate() {
+        let params = vec![*ty; operands.len()].join(" ");
+        let gets: String = (0..operands.len())
+            .map(|j| format!("local.get {}\n", j + 1))
+            .collect();
+        let result = if op.contains("store") { "" } else { ty };
+        functions += &format!(
+            "(func (export \"case{i}\") (param i32 {params}) (result {result})
+                local.get 0
+                {gets}
+                {op})\n"
+        );
+    }
+    let src = format!(
+        r#"
+(module
+    (memory 1 2 shared)
+    {functions}
+    (func (export "set") (param i64)
+        i32.const 8
+        local.get 0
+        i64.store)
+    (func (export "get") (result i64)
+        i32.const 8
+        i64.load)
+    (func (export "unaligned32") (param i32) (result i32)
+        local.get 0
+        i32.atomic.load)
+    (func (export "unaligned64") (param i32) (result i64)
+        local.get 0
+        i64.const 1
+        i64.atomic.rmw.add)
+    (func (export "unaligned16") (param i32)
+        local.get 0
+        i32.const 1
+        i32.atomic.store16)
+    (func (export "wait32") (param i32 i32 i64) (result i32)
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.atomic.wait32)
+    ;; With the operand stack 8 bytes deeper
+    (func (export "wait64") (param i32 i64 i64) (result i32)
+        i64.const 0
+        local.get 0
+        local.get 1
+        local.get 2
+        memory.atomic.wait64
+        i64.extend_i32_u
+        i64.add
+        i32.wrap_i64)
+    (func (export "notify") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        memory.atomic.notify offset=4)
+    (func (export "fence")
+        atomic.fence)
+    (func (export "grow") (result i32)
+        i32.const 1
+        memory.grow)
+)
+"#
+    );
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert!(module.memory(0).unwrap().is_shared());
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let call = |emulator: &mut Emulator, function: &str, args: &[u64]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+    };
+
+    for (i, (op, _, address, operands, result, memory)) in cases.iter().enumerate() {
+        call(&mut emulator, "set", &[INITIAL]).unwrap();
+        let args: Vec<u64> = [*address].iter().chain(*operands).copied().collect();
+        let actual = call(&mut emulator, &format!("case{i}"), &args).expect("call");
+        if !op.contains("store") {
+            assert_eq!(actual, *result, "{} {:x?}", op, operands);
+        }
+        let actual = call(&mut emulator, "get", &[]).unwrap();
+        assert_eq!(actual, *memory, "{} {:x?}", op, operands);
+    }
+
+    // Bounds are checked before alignment
+    let traps: &[(&str, u64, Trap)] = &[
+        ("unaligned32", 9, Trap::UnalignedAtomic),
+        ("unaligned32", 10, Trap::UnalignedAtomic),
+        ("unaligned32", 65534, Trap::OutOfBoundsMemoryAccess),
+        ("unaligned64", 12, Trap::UnalignedAtomic),
+        ("unaligned64", 65532, Trap::OutOfBoundsMemoryAccess),
+        ("unaligned16", 9, Trap::UnalignedAtomic),
+        ("wait32", 6, Trap::UnalignedAtomic),
+        ("notify", 1, Trap::UnalignedAtomic),
+    ];
+    for (function, address, trap) in traps {
+        let result = call(&mut emulator, function, &[*address]);
+        assert!(
+            matches!(result, Err(testing::Error::Trap(t)) if t == *trap),
+            "{} {}",
+            function,
+            address
+        );
+    }
+    for address in [0, 2, 65534] {
+        call(&mut emulator, "unaligned16", &[address]).expect("aligned store");
+    }
+
+    // Futex hook recording its arguments, along with `rsp` misalignment
+    // (as of before the call) shifted out of the way, and returning 2
+    let record = emulator.allocate(40).expect("record");
+    let mut assembler = CodeAssembler::new(64).expect("new assembler");
+    assembler.mov(rax, record).expect("asm");
+    assembler.mov(qword_ptr(rax), rdi).expect("asm");
+    assembler.mov(qword_ptr(rax + 8), rsi).expect("asm");
+    assembler.mov(qword_ptr(rax + 16), rdx).expect("asm");
+    assembler.mov(qword_ptr(rax + 24), rcx).expect("asm");
+    assembler.lea(rcx, ptr(rsp + 8)).expect("asm");
+    assembler.and(ecx, 15).expect("asm");
+    assembler.mov(qword_ptr(rax + 32), rcx).expect("asm");
+    assembler.mov(rax, 0xFFFF_FFFF_0000_0002u64).expect("asm");
+    assembler.ret().expect("asm");
+    let assembled = assembler.assemble(0).expect("asm");
+    let hook = emulator.add_memory(&assembled).expect("futex hook");
+    emu_mod.try_borrow_mut().unwrap().link_futex(hook);
+    let base = {
+        let module = emu_mod.borrow();
+        let descriptor = module.memory(0).unwrap().offset();
+        LittleEndian::read_u64(&module.binary()[descriptor..])
+    };
+    let cases: &[(&str, [u64; 3], [u64; 4])] = &[
+        (
+            "wait32",
+            [8, 0xFFFF_FFFF_FFFF_FFFE, u64::MAX],
+            [FutexOp::Wait32 as u64, base + 8, 0xFFFF_FFFE, u64::MAX],
+        ),
+        (
+            "wait64",
+            [16, u64::MAX, 1000],
+            [FutexOp::Wait64 as u64, base + 16, u64::MAX, 1000],
+        ),
+        (
+            "notify",
+            [12, 0xFFFF_FFFF_0000_0003, 0],
+            [FutexOp::Notify as u64, base + 16, 3, 0],
+        ),
+    ];
+    for (function, args, expected) in cases {
+        let result = call(&mut emulator, function, args).expect("call");
+        assert_eq!(result, 2, "{}", function);
+        let recorded = emulator.read_memory(record, 40).expect("record");
+        let recorded: Vec<u64> = recorded
+            .chunks(8)
+            .map(|slot| u64::from_le_bytes(slot.try_into().unwrap()))
+            .collect();
+        assert_eq!(&recorded[..4], expected, "{}", function);
+        assert_eq!(recorded[4], 0, "{}", function);
+    }
+    call(&mut emulator, "fence", &[]).expect("call");
+
+    // Shared memories grow up to their maximum
+    assert_eq!(call(&mut emulator, "grow", &[]).unwrap(), 1);
+    assert_eq!(call(&mut emulator, "grow", &[]).unwrap(), 0xFFFF_FFFF);
+
+    // Nothing waits on memories that aren't shared
+    let src = r#"
+(module
+    (memory 1)
+    (func (export "wait") (result i32)
+        i32.const 0
+        i32.const 0
+        i64.const -1
+        memory.atomic.wait32)
+    (func (export "notify") (result i32)
+        i32.const 0
+        i32.const 1
+        memory.atomic.notify)
+    (func (export "add") (result i32)
+        i32.const 0
+        i32.const 2
+        i32.atomic.rmw.add
+        i32.const 0
+        i32.atomic.load
+        i32.add)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+    assert!(!module.memory(0).unwrap().is_shared());
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let result = emulator.call_function(emu_mod.clone(), "wait");
+    assert!(matches!(
+        result,
+        Err(testing::Error::Trap(Trap::ExpectedSharedMemory))
+    ));
+    for (function, expected) in [("notify", 0), ("add", 2)] {
+        emulator
+            .call_function(emu_mod.clone(), function)
+            .expect("call");
+        assert_eq!(emulator.read_register(testing::RAX).unwrap(), expected);
+    }
+}
+
+/// Lane `i` of a vector with lanes of `bits` bits, zero-extended
+fn vector_lane(v: u128, bits: u32, i: u32) -> u64 {
+    (v >> (i * bits)) as u64 & (u64::MAX >> (64 - bits))
+}
+
+/// Lane `i` of a vector with lanes of `bits` bits, sign-extended
+fn signed_lane(v: u128, bits: u32, i: u32) -> i64 {
+    ((vector_lane(v, bits, i) << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// Vector with lanes of `bits` bits given by `f`, truncated to their width
+fn vector(bits: u32, f: impl Fn(u32) -> u64) -> u128 {
+    (0..128 / bits).fold(0, |v, i| {
+        v | ((f(i) & (u64::MAX >> (64 - bits))) as u128) << (i * bits)
+    })
+}
+
+fn f32x4(lanes: [f32; 4]) -> u128 {
+    vector(32, |i| lanes[i as usize].to_bits() as u64)
+}
+
+fn f64x2(lanes: [f64; 2]) -> u128 {
+    vector(64, |i| lanes[i as usize].to_bits())
+}
+
+fn float_lane(v: u128, bits: u32, i: u32) -> f64 {
+    if bits == 32 {
+        f32::from_bits(vector_lane(v, 32, i) as u32) as f64
+    } else {
+        f64::from_bits(vector_lane(v, 64, i))
+    }
+}
+
+/// Vector of float lanes given by `f`, computed as `f64` and rounded to
+/// `f32` lanes if `bits` is 32
+fn float_vector(bits: u32, f: impl Fn(u32) -> f64) -> u128 {
+    if bits == 32 {
+        vector(32, |i| (f(i) as f32).to_bits() as u64)
+    } else {
+        vector(64, |i| f(i).to_bits())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VectorShape {
+    /// `v128 -> v128`
+    Unary,
+    /// `v128 v128 -> v128`
+    Binary,
+    /// `v128 i32 -> v128`
+    Shift,
+    /// `v128 -> i32`
+    Reduce,
+}
+
+type VectorReference = Box<dyn Fn(u128, u128) -> u128>;
+
+/// Operator checked against a reference implementation, which gets the
+/// shift count as its second operand for shifts
+///
+/// Results of float operators may be any NaN where the reference gives a
+/// NaN, lane by lane.
+struct VectorCase {
+    op: String,
+    shape: VectorShape,
+    float_bits: Option<u32>,
+    reference: VectorReference,
+}
+
+/// Comparison of unsigned and signed lanes
+type IntegerComparison = fn(u64, u64, i64, i64) -> bool;
+type FloatComparison = fn(f64, f64) -> bool;
+
+fn vector_cases() -> Vec<VectorCase> {
+    use alloc::format;
+    let mut cases = vec![];
+    let mut case = |op: String, shape, float_bits, reference: VectorReference| {
+        cases.push(VectorCase {
+            op,
+            shape,
+            float_bits,
+            reference,
+        })
+    };
+    let mask = |condition: bool| if condition { u64::MAX } else { 0 };
+
+    case(
+        "v128.not".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| !a),
+    );
+    case(
+        "v128.and".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| a & b),
+    );
+    case(
+        "v128.andnot".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| a & !b),
+    );
+    case(
+        "v128.or".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| a | b),
+    );
+    case(
+        "v128.xor".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| a ^ b),
+    );
+    case(
+        "v128.any_true".into(),
+        VectorShape::Reduce,
+        None,
+        Box::new(|a, _| (a != 0) as u128),
+    );
+
+    for (shape, bits) in [("i8x16", 8), ("i16x8", 16), ("i32x4", 32), ("i64x2", 64)] {
+        let lanes = 128 / bits;
+        let (min, max) = (i64::MIN >> (64 - bits), i64::MAX >> (64 - bits));
+        let umax = u64::MAX >> (64 - bits);
+        let binary = |f: fn(u64, u64, i64, i64) -> u64| -> VectorReference {
+            Box::new(move |a, b| {
+                vector(bits, |i| {
+                    f(
+                        vector_lane(a, bits, i),
+                        vector_lane(b, bits, i),
+                        signed_lane(a, bits, i),
+                        signed_lane(b, bits, i),
+                    )
+                })
+            })
+        };
+        let unary = |f: fn(u64, i64) -> u64| -> VectorReference {
+            Box::new(move |a, _| {
+                vector(bits, |i| {
+                    f(vector_lane(a, bits, i), signed_lane(a, bits, i))
+                })
+            })
+        };
+        case(
+            format!("{shape}.add"),
+            VectorShape::Binary,
+            None,
+            binary(|a, b, _, _| a.wrapping_add(b)),
+        );
+        case(
+            format!("{shape}.sub"),
+            VectorShape::Binary,
+            None,
+            binary(|a, b, _, _| a.wrapping_sub(b)),
+        );
+        if bits > 8 {
+            case(
+                format!("{shape}.mul"),
+                VectorShape::Binary,
+                None,
+                binary(|a, b, _, _| a.wrapping_mul(b)),
+            );
+        }
+        case(
+            format!("{shape}.neg"),
+            VectorShape::Unary,
+            None,
+            unary(|a, _| a.wrapping_neg()),
+        );
+        case(
+            format!("{shape}.abs"),
+            VectorShape::Unary,
+            None,
+            unary(|_, a| a.wrapping_abs() as u64),
+        );
+        let comparisons: [(&str, IntegerComparison); 10] = [
+            ("eq", |a, b, _, _| a == b),
+            ("ne", |a, b, _, _| a != b),
+            ("lt_s", |_, _, a, b| a < b),
+            ("gt_s", |_, _, a, b| a > b),
+            ("le_s", |_, _, a, b| a <= b),
+            ("ge_s", |_, _, a, b| a >= b),
+            ("lt_u", |a, b, _, _| a < b),
+            ("gt_u", |a, b, _, _| a > b),
+            ("le_u", |a, b, _, _| a <= b),
+            ("ge_u", |a, b, _, _| a >= b),
+        ];
+        for (name, comparison) in comparisons {
+            if bits == 64 && name.ends_with("_u") {
+                continue;
+            }
+            case(
+                format!("{shape}.{name}"),
+                VectorShape::Binary,
+                None,
+                Box::new(move |a, b| {
+                    vector(bits, |i| {
+                        mask(comparison(
+                            vector_lane(a, bits, i),
+                            vector_lane(b, bits, i),
+                            signed_lane(a, bits, i),
+                            signed_lane(b, bits, i),
+                        ))
+                    })
+                }),
+            );
+        }
+        if bits < 64 {
+            case(
+                format!("{shape}.min_s"),
+                VectorShape::Binary,
+                None,
+                binary(|_, _, a, b| a.min(b) as u64),
+            );
+            case(
+                format!("{shape}.max_s"),
+                VectorShape::Binary,
+                None,
+                binary(|_, _, a, b| a.max(b) as u64),
+            );
+            case(
+                format!("{shape}.min_u"),
+                VectorShape::Binary,
+                None,
+                binary(|a, b, _, _| a.min(b)),
+            );
+            case(
+                format!("{shape}.max_u"),
+                VectorShape::Binary,
+                None,
+                binary(|a, b, _, _| a.max(b)),
+            );
+        }
+        if bits < 32 {
+            case(
+                format!("{shape}.add_sat_s"),
+                VectorShape::Binary,
+                None,
+                Box::new(move |a, b| {
+                    vector(bits, |i| {
+                        (signed_lane(a, bits, i) + signed_lane(b, bits, i)).clamp(min, max) as u64
+                    })
+                }),
+            );
+            case(
+                format!("{shape}.sub_sat_s"),
+                VectorShape::Binary,
+                None,
+                Box::new(move |a, b| {
+                    vector(bits, |i| {
+                        (signed_lane(a, bits, i) - signed_lane(b, bits, i)).clamp(min, max) as u64
+                    })
+                }),
+            );
+            case(
+                format!("{shape}.add_sat_u"),
+                VectorShape::Binary,
+                None,
+                Box::new(move |a, b| {
+                    vector(bits, |i| {
+                        (vector_lane(a, bits, i) + vector_lane(b, bits, i)).min(umax)
+                    })
+                }),
+            );
+            case(
+                format!("{shape}.sub_sat_u"),
+                VectorShape::Binary,
+                None,
+                binary(|a, b, _, _| a.saturating_sub(b)),
+            );
+            case(
+                format!("{shape}.avgr_u"),
+                VectorShape::Binary,
+                None,
+                binary(|a, b, _, _| (a + b).div_ceil(2)),
+            );
+        }
+        case(
+            format!("{shape}.shl"),
+            VectorShape::Shift,
+            None,
+            Box::new(move |a, n| vector(bits, |i| vector_lane(a, bits, i) << (n as u32 % bits))),
+        );
+        case(
+            format!("{shape}.shr_s"),
+            VectorShape::Shift,
+            None,
+            Box::new(move |a, n| {
+                vector(bits, |i| {
+                    (signed_lane(a, bits, i) >> (n as u32 % bits)) as u64
+                })
+            }),
+        );
+        case(
+            format!("{shape}.shr_u"),
+            VectorShape::Shift,
+            None,
+            Box::new(move |a, n| vector(bits, |i| vector_lane(a, bits, i) >> (n as u32 % bits))),
+        );
+        case(
+            format!("{shape}.all_true"),
+            VectorShape::Reduce,
+            None,
+            Box::new(move |a, _| (0..lanes).all(|i| vector_lane(a, bits, i) != 0) as u128),
+        );
+        case(
+            format!("{shape}.bitmask"),
+            VectorShape::Reduce,
+            None,
+            Box::new(move |a, _| {
+                (0..lanes)
+                    .map(|i| ((signed_lane(a, bits, i) < 0) as u128) << i)
+                    .sum()
+            }),
+        );
+        if bits > 8 {
+            // Operators taking lanes of half the width
+            let (half, narrow) = (
+                bits / 2,
+                ["", "i8x16", "i16x8", "", "i32x4"][(bits / 16) as usize],
+            );
+            let (hmin, hmax) = (-(1i64 << (half - 1)), (1i64 << (half - 1)) - 1);
+            for (part, offset) in [("low", 0), ("high", lanes)] {
+                for (sign, signed) in [("s", true), ("u", false)] {
+                    let extend = move |v: u128, i: u32| {
+                        if signed {
+                            signed_lane(v, half, i) as u64
+                        } else {
+                            vector_lane(v, half, i)
+                        }
+                    };
+                    case(
+                        format!("{shape}.extend_{part}_{narrow}_{sign}"),
+                        VectorShape::Unary,
+                        None,
+                        Box::new(move |a, _| vector(bits, |i| extend(a, i + offset))),
+                    );
+                    case(
+                        format!("{shape}.extmul_{part}_{narrow}_{sign}"),
+                        VectorShape::Binary,
+                        None,
+                        Box::new(move |a, b| {
+                            vector(bits, |i| {
+                                extend(a, i + offset).wrapping_mul(extend(b, i + offset))
+                            })
+                        }),
+                    );
+                }
+            }
+            if bits < 64 {
+                for (sign, signed) in [("s", true), ("u", false)] {
+                    case(
+                        format!("{shape}.extadd_pairwise_{narrow}_{sign}"),
+                        VectorShape::Unary,
+                        None,
+                        Box::new(move |a, _| {
+                            vector(bits, |i| {
+                                if signed {
+                                    (signed_lane(a, half, 2 * i) + signed_lane(a, half, 2 * i + 1))
+                                        as u64
+                                } else {
+                                    vector_lane(a, half, 2 * i) + vector_lane(a, half, 2 * i + 1)
+                                }
+                            })
+                        }),
+                    );
+                }
+                // Narrowing the other way, into lanes of half the width
+                case(
+                    format!("{narrow}.narrow_{shape}_s"),
+                    VectorShape::Binary,
+                    None,
+                    Box::new(move |a, b| {
+                        vector(half, |i| {
+                            let v = if i < lanes { a } else { b };
+                            signed_lane(v, bits, i % lanes).clamp(hmin, hmax) as u64
+                        })
+                    }),
+                );
+                case(
+                    format!("{narrow}.narrow_{shape}_u"),
+                    VectorShape::Binary,
+                    None,
+                    Box::new(move |a, b| {
+                        vector(half, |i| {
+                            let v = if i < lanes { a } else { b };
+                            signed_lane(v, bits, i % lanes).clamp(0, hmax * 2 + 1) as u64
+                        })
+                    }),
+                );
+            }
+        }
+    }
+    case(
+        "i8x16.popcnt".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| vector(8, |i| vector_lane(a, 8, i).count_ones() as u64)),
+    );
+    case(
+        "i8x16.swizzle".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| {
+            vector(8, |i| {
+                let index = vector_lane(b, 8, i) as u32;
+                if index < 16 {
+                    vector_lane(a, 8, index)
+                } else {
+                    0
+                }
+            })
+        }),
+    );
+    case(
+        "i16x8.q15mulr_sat_s".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| {
+            vector(16, |i| {
+                let product = signed_lane(a, 16, i) * signed_lane(b, 16, i);
+                ((product + 0x4000) >> 15).clamp(-0x8000, 0x7FFF) as u64
+            })
+        }),
+    );
+    case(
+        "i32x4.dot_i16x8_s".into(),
+        VectorShape::Binary,
+        None,
+        Box::new(|a, b| {
+            vector(32, |i| {
+                let product = |j| signed_lane(a, 16, j) * signed_lane(b, 16, j);
+                (product(2 * i) + product(2 * i + 1)) as u64
+            })
+        }),
+    );
+
+    for (shape, bits) in [("f32x4", 32), ("f64x2", 64)] {
+        let float = Some(bits);
+        let binary = |f: fn(f64, f64) -> f64| -> VectorReference {
+            Box::new(move |a, b| {
+                float_vector(bits, |i| f(float_lane(a, bits, i), float_lane(b, bits, i)))
+            })
+        };
+        let unary = |f: fn(f64) -> f64| -> VectorReference {
+            Box::new(move |a, _| float_vector(bits, |i| f(float_lane(a, bits, i))))
+        };
+        if bits == 32 {
+            // Computed in single precision, to round once
+            let binary32 = |f: fn(f32, f32) -> f32| -> VectorReference {
+                Box::new(move |a, b| {
+                    vector(32, |i| {
+                        let lane = |v| f32::from_bits(vector_lane(v, 32, i) as u32);
+                        f(lane(a), lane(b)).to_bits() as u64
+                    })
+                })
+            };
+            case(
+                format!("{shape}.add"),
+                VectorShape::Binary,
+                float,
+                binary32(|a, b| a + b),
+            );
+            case(
+                format!("{shape}.sub"),
+                VectorShape::Binary,
+                float,
+                binary32(|a, b| a - b),
+            );
+            case(
+                format!("{shape}.mul"),
+                VectorShape::Binary,
+                float,
+                binary32(|a, b| a * b),
+            );
+            case(
+                format!("{shape}.div"),
+                VectorShape::Binary,
+                float,
+                binary32(|a, b| a / b),
+            );
+            case(
+                format!("{shape}.sqrt"),
+                VectorShape::Unary,
+                float,
+                Box::new(|a, _| {
+                    vector(32, |i| {
+                        f32::from_bits(vector_lane(a, 32, i) as u32)
+                            .sqrt()
+                            .to_bits() as u64
+                    })
+                }),
+            );
+        } else {
+            case(
+                format!("{shape}.add"),
+                VectorShape::Binary,
+                float,
+                binary(|a, b| a + b),
+            );
+            case(
+                format!("{shape}.sub"),
+                VectorShape::Binary,
+                float,
+                binary(|a, b| a - b),
+            );
+            case(
+                format!("{shape}.mul"),
+                VectorShape::Binary,
+                float,
+                binary(|a, b| a * b),
+            );
+            case(
+                format!("{shape}.div"),
+                VectorShape::Binary,
+                float,
+                binary(|a, b| a / b),
+            );
+            case(
+                format!("{shape}.sqrt"),
+                VectorShape::Unary,
+                float,
+                unary(f64::sqrt),
+            );
+        }
+        case(
+            format!("{shape}.min"),
+            VectorShape::Binary,
+            float,
+            binary(|a, b| {
+                if a == b {
+                    if a.is_sign_negative() {
+                        a
+                    } else {
+                        b
+                    }
+                } else if a.is_nan() || b.is_nan() {
+                    f64::NAN
+                } else {
+                    a.min(b)
+                }
+            }),
+        );
+        case(
+            format!("{shape}.max"),
+            VectorShape::Binary,
+            float,
+            binary(|a, b| {
+                if a == b {
+                    if a.is_sign_negative() {
+                        b
+                    } else {
+                        a
+                    }
+                } else if a.is_nan() || b.is_nan() {
+                    f64::NAN
+                } else {
+                    a.max(b)
+                }
+            }),
+        );
+        case(
+            format!("{shape}.pmin"),
+            VectorShape::Binary,
+            float,
+            binary(|a, b| if b < a { b } else { a }),
+        );
+        case(
+            format!("{shape}.pmax"),
+            VectorShape::Binary,
+            float,
+            binary(|a, b| if a < b { b } else { a }),
+        );
+        case(
+            format!("{shape}.abs"),
+            VectorShape::Unary,
+            None,
+            Box::new(move |a, _| {
+                vector(bits, |i| {
+                    vector_lane(a, bits, i) & (u64::MAX >> (65 - bits))
+                })
+            }),
+        );
+        case(
+            format!("{shape}.neg"),
+            VectorShape::Unary,
+            None,
+            Box::new(move |a, _| vector(bits, |i| vector_lane(a, bits, i) ^ (1 << (bits - 1)))),
+        );
+        case(
+            format!("{shape}.ceil"),
+            VectorShape::Unary,
+            float,
+            unary(f64::ceil),
+        );
+        case(
+            format!("{shape}.floor"),
+            VectorShape::Unary,
+            float,
+            unary(f64::floor),
+        );
+        case(
+            format!("{shape}.trunc"),
+            VectorShape::Unary,
+            float,
+            unary(f64::trunc),
+        );
+        case(
+            format!("{shape}.nearest"),
+            VectorShape::Unary,
+            float,
+            unary(f64::round_ties_even),
+        );
+        let comparisons: [(&str, FloatComparison); 6] = [
+            ("eq", |a, b| a == b),
+            ("ne", |a, b| a != b),
+            ("lt", |a, b| a < b),
+            ("gt", |a, b| a > b),
+            ("le", |a, b| a <= b),
+            ("ge", |a, b| a >= b),
+        ];
+        for (name, comparison) in comparisons {
+            case(
+                format!("{shape}.{name}"),
+                VectorShape::Binary,
+                None,
+                Box::new(move |a, b| {
+                    vector(bits, |i| {
+                        mask(comparison(float_lane(a, bits, i), float_lane(b, bits, i)))
+                    })
+                }),
+            );
+        }
+    }
+    case(
+        "i32x4.trunc_sat_f32x4_s".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| vector(32, |i| float_lane(a, 32, i) as i32 as u64)),
+    );
+    case(
+        "i32x4.trunc_sat_f32x4_u".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| vector(32, |i| float_lane(a, 32, i) as u32 as u64)),
+    );
+    case(
+        "i32x4.trunc_sat_f64x2_s_zero".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| {
+            vector(32, |i| {
+                if i < 2 {
+                    float_lane(a, 64, i) as i32 as u64
+                } else {
+                    0
+                }
+            })
+        }),
+    );
+    case(
+        "i32x4.trunc_sat_f64x2_u_zero".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| {
+            vector(32, |i| {
+                if i < 2 {
+                    float_lane(a, 64, i) as u32 as u64
+                } else {
+                    0
+                }
+            })
+        }),
+    );
+    case(
+        "f32x4.convert_i32x4_s".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| {
+            vector(32, |i| {
+                (signed_lane(a, 32, i) as i32 as f32).to_bits() as u64
+            })
+        }),
+    );
+    case(
+        "f32x4.convert_i32x4_u".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| {
+            vector(32, |i| {
+                (vector_lane(a, 32, i) as u32 as f32).to_bits() as u64
+            })
+        }),
+    );
+    case(
+        "f64x2.convert_low_i32x4_s".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| vector(64, |i| (signed_lane(a, 32, i) as f64).to_bits())),
+    );
+    case(
+        "f64x2.convert_low_i32x4_u".into(),
+        VectorShape::Unary,
+        None,
+        Box::new(|a, _| vector(64, |i| (vector_lane(a, 32, i) as f64).to_bits())),
+    );
+    case(
+        "f32x4.demote_f64x2_zero".into(),
+        VectorShape::Unary,
+        Some(32),
+        Box::new(|a, _| {
+            vector(32, |i| {
+                if i < 2 {
+                    (float_lane(a, 64, i) as f32).to_bits() as u64
+                } else {
+                    0
+                }
+            })
+        }),
+    );
+    case(
+        "f64x2.promote_low_f32x4".into(),
+        VectorShape::Unary,
+        Some(64),
+        Box::new(|a, _| vector(64, |i| float_lane(a, 32, i).to_bits())),
+    );
+    cases
+}
+
+/// Vectors operators get checked with
+fn vector_inputs() -> Vec<u128> {
+    let mut inputs = vec![
+        0,
+        u128::MAX,
+        u128::from_le_bytes([0x80; 16]),
+        u128::from_le_bytes([0x7F; 16]),
+        vector(16, |i| {
+            [0x8000, 0x7FFF, 0xFFFF, 1, 0x8001, 0, 0xFF80, 0x0080][i as usize]
+        }),
+        vector(32, |i| {
+            [0x8000_0000, 0x7FFF_FFFF, 0xFFFF, 0xFFFF_8000][i as usize]
+        }),
+        vector(64, |i| {
+            [0x8000_0000_0000_0000, 0x7FFF_FFFF_0000_0001][i as usize]
+        }),
+        vector(8, |i| {
+            [
+                0, 1, 15, 16, 17, 31, 0x70, 0x8F, 0xFF, 5, 3, 14, 2, 0x80, 9, 12,
+            ][i as usize]
+        }),
+        f32x4([0.0, -0.0, 1.5, -2.5]),
+        f32x4([f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1e30]),
+        f32x4([0.5, -0.5, 2.5, -3.5]),
+        f32x4([4294967296.0, 2147483648.0, -2147483904.0, 3e9]),
+        f32x4([8388607.5, -8388607.5, 0.49999997, -1.0e-40]),
+        f32x4([-0.0, 0.0, f32::from_bits(0x7FA0_0001), -7.0]),
+        f64x2([0.5, -2.5]),
+        f64x2([f64::from_bits(0xFFF4_0000_0000_0001), -0.0]),
+        f64x2([4503599627370495.5, -1e300]),
+        f64x2([4294967295.9, -2147483648.9]),
+        f64x2([2147483647.5, 1e-310]),
+        f64x2([0.0, 4294967296.5]),
+    ];
+    // Pseudo-random ones
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    for _ in 0..4 {
+        let mut next = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            state
+        };
+        inputs.push((next() as u128) << 64 | next() as u128);
+    }
+    inputs
+}
+
+fn check_vector_operators(features: Features) {
+    use alloc::format;
+    use testing::Emulator;
+    let inputs = vector_inputs();
+    let shifts = [0, 1, 7, 8, 9, 15, 16, 31, 32, 33, 63, 64, 0xFFFF_FFFF];
+    for case in vector_cases() {
+        let (params, result) = match case.shape {
+            VectorShape::Unary => ("v128", "v128"),
+            VectorShape::Binary => ("v128 v128", "v128"),
+            VectorShape::Shift => ("v128 i32", "v128"),
+            VectorShape::Reduce => ("v128", "i32"),
+        };
+        let gets = if params.contains(' ') {
+            "local.get 0 local.get 1"
+        } else {
+            "local.get 0"
+        };
+        let src = format!(
+            "(module (func (export \"op\") (param {params}) (result {result}) {gets} {}))",
+            case.op
+        );
+        let binary = wat::parse_str(src).expect("binary module");
+        let module = X86_64Compiler::new(features)
+            .compile(&binary)
+            .expect("compiled module");
+        assert!(module.uses_floating_point());
+
+        let mut emulator = Emulator::new().expect("emulator");
+        let emu_mod = emulator.add_module(module).expect("module addition");
+        let pairs: Vec<(u128, u128)> = match case.shape {
+            // Every input against a few others, to keep the emulator busy
+            // for a reasonable time
+            VectorShape::Binary => [0, 1, 2, 5, 11, 17]
+                .iter()
+                .flat_map(|rotation| {
+                    let others = inputs.iter().cycle().skip(*rotation);
+                    inputs.iter().copied().zip(others.copied())
+                })
+                .collect(),
+            VectorShape::Shift => inputs
+                .iter()
+                .flat_map(|a| shifts.iter().map(move |n| (*a, *n)))
+                .collect(),
+            _ => inputs.iter().map(|a| (*a, 0)).collect(),
+        };
+        for (a, b) in pairs {
+            emulator.write_vector_register(testing::XMM0, a).unwrap();
+            match case.shape {
+                VectorShape::Binary => emulator.write_vector_register(testing::XMM1, b).unwrap(),
+                VectorShape::Shift => emulator.write_register(testing::RDI, b as u64).unwrap(),
+                _ => (),
+            }
+            emulator.call_function(emu_mod.clone(), "op").expect("call");
+            let expected = (case.reference)(a, b);
+            let result = if case.shape == VectorShape::Reduce {
+                emulator.read_register(testing::RAX).unwrap() as u32 as u128
+            } else {
+                emulator.read_vector_register(testing::XMM0).unwrap()
+            };
+            let matches = match case.float_bits {
+                Some(bits) => (0..128 / bits).all(|i| {
+                    vector_lane(result, bits, i) == vector_lane(expected, bits, i)
+                        || (float_lane(result, bits, i).is_nan()
+                            && float_lane(expected, bits, i).is_nan())
+                }),
+                None => result == expected,
+            };
+            assert!(
+                matches,
+                "{} {:#034x} {:#034x}: {:#034x}, expected {:#034x} ({:?})",
+                case.op, a, b, result, expected, features
+            );
+        }
+    }
+}
+
+#[test]
+fn vector_operators() {
+    check_vector_operators(Features::default());
+}
+
+#[test]
+fn vector_operators_sse4_1() {
+    check_vector_operators(Features {
+        sse4_1: true,
+        ..Features::default()
+    });
+}
+
+fn check_vector_memory_and_lanes(features: Features) {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (global $g (mut v128) (v128.const i32x4 1 2 3 4))
+    (global $k v128 (v128.const i64x2 -1 7))
+
+    (func (export "store") (param i32 v128)
+      local.get 0
+      local.get 1
+      v128.store offset=16
+    )
+    (func (export "v128.load") (param i32) (result v128)
+      local.get 0
+      v128.load offset=16
+    )
+    (func (export "v128.load8x8_s") (param i32) (result v128)
+      local.get 0
+      v128.load8x8_s offset=16
+    )
+    (func (export "v128.load8x8_u") (param i32) (result v128)
+      local.get 0
+      v128.load8x8_u offset=16
+    )
+    (func (export "v128.load16x4_s") (param i32) (result v128)
+      local.get 0
+      v128.load16x4_s offset=16
+    )
+    (func (export "v128.load32x2_u") (param i32) (result v128)
+      local.get 0
+      v128.load32x2_u offset=16
+    )
+    (func (export "v128.load32x2_s") (param i32) (result v128)
+      local.get 0
+      v128.load32x2_s offset=16
+    )
+    (func (export "v128.load8_splat") (param i32) (result v128)
+      local.get 0
+      v128.load8_splat offset=16
+    )
+    (func (export "v128.load16_splat") (param i32) (result v128)
+      local.get 0
+      v128.load16_splat offset=16
+    )
+    (func (export "v128.load64_splat") (param i32) (result v128)
+      local.get 0
+      v128.load64_splat offset=16
+    )
+    (func (export "v128.load32_zero") (param i32) (result v128)
+      local.get 0
+      v128.load32_zero offset=16
+    )
+    (func (export "v128.load64_zero") (param i32) (result v128)
+      local.get 0
+      v128.load64_zero offset=16
+    )
+    (func (export "v128.load16_lane") (param i32 v128) (result v128)
+      local.get 0
+      local.get 1
+      v128.load16_lane offset=16 5
+    )
+    (func (export "v128.store32_lane") (param i32 v128)
+      local.get 0
+      local.get 1
+      v128.store32_lane offset=16 3
+    )
+    (func (export "v128.store8_lane") (param i32 v128)
+      local.get 0
+      local.get 1
+      v128.store8_lane offset=16 15
+    )
+
+    (func (export "lanes") (param v128) (result i64)
+      local.get 0
+      i8x16.extract_lane_s 1
+      i64.extend_i32_s
+      local.get 0
+      i16x8.extract_lane_u 7
+      i64.extend_i32_u
+      i64.add
+      local.get 0
+      i64x2.extract_lane 1
+      i64.add
+    )
+    (func (export "replace") (param v128 i32 i64 f32 f64) (result v128)
+      local.get 0
+      local.get 1
+      i8x16.replace_lane 0
+      local.get 1
+      i16x8.replace_lane 3
+      local.get 2
+      i64x2.replace_lane 1
+      local.get 3
+      f32x4.replace_lane 2
+    )
+    (func (export "f64_lane") (param v128 f64) (result f64)
+      local.get 0
+      local.get 1
+      f64x2.replace_lane 0
+      f64x2.extract_lane 0
+      local.get 0
+      f64x2.extract_lane 1
+      f64.add
+    )
+    (func (export "splats") (param i32 i64 f32 f64) (result v128)
+      local.get 0
+      i8x16.splat
+      local.get 0
+      i16x8.splat
+      i8x16.add
+      local.get 0
+      i32x4.splat
+      i8x16.add
+      local.get 1
+      i64x2.splat
+      i8x16.add
+      local.get 2
+      f32x4.splat
+      v128.xor
+      local.get 3
+      f64x2.splat
+      v128.xor
+    )
+    (func (export "shuffle") (param v128 v128) (result v128)
+      local.get 0
+      local.get 1
+      i8x16.shuffle 31 0 16 1 17 2 18 3 4 4 30 5 15 14 13 12
+    )
+    (func (export "bitselect") (param v128 v128 v128) (result v128)
+      local.get 0
+      local.get 1
+      local.get 2
+      v128.bitselect
+    )
+    (func (export "locals") (param v128) (result v128) (local v128 i64 v128)
+      ;; Locals start zeroed
+      local.get 1
+      local.get 3
+      v128.or
+      i64.const 5
+      local.set 2
+      local.get 0
+      local.tee 3
+      i64x2.add
+      local.set 1
+      local.get 1
+      local.get 3
+      i32x4.add
+      local.get 2
+      i64x2.splat
+      i64x2.add
+    )
+    (func (export "globals") (param v128) (result v128)
+      global.get $g
+      local.get 0
+      global.set $g
+      global.get $g
+      i32x4.add
+      global.get $k
+      i64x2.add
+    )
+    (func (export "select") (param v128 v128 i32) (result v128)
+      v128.const i64x2 1 1
+      local.get 0
+      local.get 1
+      local.get 2
+      select
+      i64x2.add
+    )
+    (func (export "branch") (param v128 i32) (result v128)
+      block (result v128)
+        i64.const 9
+        local.get 0
+        local.get 1
+        br_if 0
+        drop
+        drop
+        v128.const i64x2 0 0
+      end
+    )
+    (func (export "const") (result v128)
+      v128.const i8x16 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+    )
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::new(features)
+        .compile(&binary)
+        .expect("compiled module");
+    assert!(module.uses_floating_point());
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let mut call = |function: &str, args: &[u64], vectors: &[u128]| {
+        let registers = [testing::RDI, testing::RSI, testing::RDX, testing::RCX];
+        for (register, arg) in registers.iter().zip(args) {
+            emulator.write_register(*register, *arg).unwrap();
+        }
+        let xmm = [testing::XMM0, testing::XMM1, testing::XMM2, testing::XMM3];
+        for (register, vector) in xmm.iter().zip(vectors) {
+            emulator.write_vector_register(*register, *vector).unwrap();
+        }
+        let result = emulator.call_function(emu_mod.clone(), function);
+        (
+            result.map(|_| emulator.read_register(testing::RAX).unwrap()),
+            emulator.read_vector_register(testing::XMM0).unwrap(),
+        )
+    };
+    let add = |bits, a, b| {
+        vector(bits, |i| {
+            vector_lane(a, bits, i).wrapping_add(vector_lane(b, bits, i))
+        })
+    };
+
+    let bytes = 0xF0E1_D2C3_B4A5_9687_7869_5A4B_3C2D_1E0F_u128;
+    call("store", &[1], &[bytes]).0.unwrap();
+    call("store", &[100], &[u128::MAX]).0.unwrap();
+    let cases = [
+        ("v128.load", bytes),
+        ("v128.load8x8_s", 0x0078_0069_005A_004B_003C_002D_001E_000F),
+        ("v128.load8x8_u", 0x0078_0069_005A_004B_003C_002D_001E_000F),
+        ("v128.load16x4_s", 0x0000_7869_0000_5A4B_0000_3C2D_0000_1E0F),
+        ("v128.load32x2_s", 0x0000_0000_7869_5A4B_0000_0000_3C2D_1E0F),
+        ("v128.load32x2_u", 0x0000_0000_7869_5A4B_0000_0000_3C2D_1E0F),
+        ("v128.load8_splat", u128::from_le_bytes([0x0F; 16])),
+        ("v128.load16_splat", vector(16, |_| 0x1E0F)),
+        ("v128.load64_splat", vector(64, |_| 0x7869_5A4B_3C2D_1E0F)),
+        ("v128.load32_zero", 0x3C2D_1E0F),
+        ("v128.load64_zero", 0x7869_5A4B_3C2D_1E0F),
+    ];
+    for (function, expected) in cases {
+        assert_eq!(call(function, &[1], &[]).1, expected, "{}", function);
+    }
+    // The upper half of the stored vector shows sign extension
+    let cases = [
+        (
+            "v128.load8x8_s",
+            vector(16, |i| signed_lane(bytes, 8, i + 8) as u64),
+        ),
+        (
+            "v128.load8x8_u",
+            vector(16, |i| vector_lane(bytes, 8, i + 8)),
+        ),
+        (
+            "v128.load16x4_s",
+            vector(32, |i| signed_lane(bytes, 16, i + 4) as u64),
+        ),
+        (
+            "v128.load32x2_s",
+            vector(64, |i| signed_lane(bytes, 32, i + 2) as u64),
+        ),
+        (
+            "v128.load32x2_u",
+            vector(64, |i| vector_lane(bytes, 32, i + 2)),
+        ),
+    ];
+    for (function, expected) in cases {
+        assert_eq!(call(function, &[9], &[]).1, expected, "{}", function);
+    }
+    assert_eq!(call("v128.load16_lane", &[1], &[0]).1, 0x1E0F << 80);
+    call("v128.store32_lane", &[100], &[bytes]).0.unwrap();
+    call("v128.store8_lane", &[104], &[bytes]).0.unwrap();
+    assert_eq!(
+        call("v128.load", &[100], &[]).1,
+        u128::MAX << 40 | 0xF0 << 32 | 0xF0E1_D2C3
+    );
+    assert_eq!(call("v128.load", &[65504], &[]).1, 0);
+    assert!(matches!(
+        call("v128.load", &[65505], &[]).0,
+        Err(testing::Error::Trap(
+            crate::trap::Trap::OutOfBoundsMemoryAccess
+        ))
+    ));
+
+    let lanes = vector(8, |i| (i * 0x11 + 0x80) as u64);
+    let expected = (signed_lane(lanes, 8, 1) as u64)
+        .wrapping_add(vector_lane(lanes, 16, 7))
+        .wrapping_add(vector_lane(lanes, 64, 1));
+    assert_eq!(call("lanes", &[], &[lanes]).0.unwrap(), expected);
+    let value = 0xAAAA_BBBB_CCCC_DDDD;
+    let float = 1.5f32.to_bits() as u128;
+    let (_, result) = call("replace", &[0x1234_5678, value], &[lanes, float]);
+    let kept = lanes & 0x0000_FFFF_FFFF_FF00;
+    let expected = kept | 0x78 | 0x5678 << 48 | float << 64 | (value as u128 >> 32) << 96;
+    assert_eq!(result, expected);
+    let (_, result) = call(
+        "f64_lane",
+        &[],
+        &[f64x2([0.0, 1.25]), 2.5f64.to_bits() as u128],
+    );
+    assert_eq!(f64::from_bits(result as u64), 3.75);
+
+    let (x, y, z, w) = (0x1234_5678, 0x0102_0304_0506_0708, 2.0f32, -0.5f64);
+    let (_, result) = call(
+        "splats",
+        &[x, y],
+        &[z.to_bits() as u128, w.to_bits() as u128],
+    );
+    let sum = add(
+        8,
+        add(
+            8,
+            add(8, vector(8, |_| x), vector(16, |_| x)),
+            vector(32, |_| x),
+        ),
+        vector(64, |_| y),
+    );
+    let expected = sum ^ vector(32, |_| z.to_bits() as u64) ^ vector(64, |_| w.to_bits());
+    assert_eq!(result, expected);
+
+    let (_, result) = call("const", &[], &[]);
+    assert_eq!(result, vector(8, |i| i as u64));
+
+    let a = vector(8, |i| i as u64);
+    let b = vector(8, |i| 0x10 + i as u64);
+    let (_, result) = call("shuffle", &[], &[a, b]);
+    let indices = [31, 0, 16, 1, 17, 2, 18, 3, 4, 4, 30, 5, 15, 14, 13, 12];
+    assert_eq!(result, vector(8, |i| indices[i as usize]));
+
+    let mask = vector(16, |i| [0, 0xFFFF][i as usize % 2]);
+    let (_, result) = call("bitselect", &[], &[a, b, mask]);
+    assert_eq!(result, (a & mask) | (b & !mask));
+
+    let (_, result) = call("locals", &[], &[vector(64, |i| i as u64 + 1)]);
+    assert_eq!(result, vector(64, |i| [7, 9][i as usize]));
+
+    let (_, result) = call("globals", &[], &[vector(32, |i| 10 * i as u64)]);
+    let initial = vector(32, |i| i as u64 + 1);
+    let constant = vector(64, |i| [u64::MAX, 7][i as usize]);
+    let expected = add(
+        64,
+        add(32, initial, vector(32, |i| 10 * i as u64)),
+        constant,
+    );
+    assert_eq!(result, expected);
+    let (_, result) = call("globals", &[], &[0]);
+    assert_eq!(result, add(64, vector(32, |i| 10 * i as u64), constant));
+
+    for (condition, chosen) in [(1, a), (0, b), (0x1_0000_0000, b)] {
+        let (_, result) = call("select", &[condition], &[a, b]);
+        assert_eq!(result, add(64, chosen, vector(64, |_| 1)));
+    }
+    for (condition, expected) in [(1, a), (0, 0)] {
+        assert_eq!(call("branch", &[condition], &[a]).1, expected);
+    }
+}
+
+#[test]
+fn vector_memory_and_lanes() {
+    check_vector_memory_and_lanes(Features::default());
+}
+
+#[test]
+fn vector_memory_and_lanes_sse4_1() {
+    check_vector_memory_and_lanes(Features {
+        sse4_1: true,
+        ..Features::default()
+    });
+}
+
+#[test]
+fn vector_calls() {
+    use alloc::format;
+    use testing::Emulator;
+    // Seven vectors and a double take the vector registers, the rest is on
+    // the stack: a vector at 0, a float at 16 and a vector at 32
+    let params = [
+        "v128", "v128", "v128", "v128", "v128", "v128", "v128", "f64", "v128", "f32", "v128",
+    ];
+    let vectors: Vec<u128> = (0..9)
+        .map(|j| vector(32, |i| (j * 4 + i + 1) as u64 * 0x0101))
+        .collect();
+    let (double, float) = (3.75f64, -2.5f32);
+    // Weighted sum of the parameters as i32x4
+    let wide = |vectors: &[u128]| {
+        let positions = [0, 1, 2, 3, 4, 5, 6, 8, 10];
+        let scalar = (8 * double as i32 + 10 * float as i32) as u32;
+        vector(32, |i| {
+            let sum = positions
+                .iter()
+                .zip(vectors)
+                .map(|(k, v)| (vector_lane(*v, 32, i) as u32).wrapping_mul(k + 1))
+                .fold(scalar, u32::wrapping_add);
+            sum as u64
+        })
+    };
+    let mut body = String::from("v128.const i32x4 0 0 0 0\n");
+    for (k, param) in params.iter().enumerate() {
+        body += &format!("local.get {k}\n");
+        body += match *param {
+            "f64" => "i32.trunc_f64_s\n",
+            "f32" => "i32.trunc_f32_s\n",
+            _ => "",
+        };
+        if *param == "v128" {
+            body += &format!("i32.const {}\ni32x4.splat\ni32x4.mul\n", k + 1);
+        } else {
+            body += &format!("i32.const {}\ni32.mul\ni32x4.splat\n", k + 1);
+        }
+        body += "i32x4.add\n";
+    }
+    let constant = |v: u128| {
+        format!(
+            "v128.const i32x4 {} {} {} {}\n",
+            vector_lane(v, 32, 0),
+            vector_lane(v, 32, 1),
+            vector_lane(v, 32, 2),
+            vector_lane(v, 32, 3)
+        )
+    };
+    let args = |first: &str| {
+        let mut args = String::from(first);
+        for v in &vectors[1..7] {
+            args += &constant(*v);
+        }
+        args += &format!("f64.const {double}\n");
+        args += &constant(vectors[7]);
+        args += &format!("f32.const {float}\n");
+        args += &constant(vectors[8]);
+        args
+    };
+    let src = format!(
+        r#"
+(module
+    (func $wide (export "wide") (param {params}) (result v128)
+        {body})
+    (func (export "call_wide") (result v128)
+        {call_args}
+        call $wide)
+    (func (export "tail") (param v128) (result v128)
+        {tail_args}
+        return_call $wide)
+    (func $pair (export "pair") (param v128 i64) (result v128 i64 v128)
+        local.get 0
+        local.get 1
+        local.get 0
+        i64x2.neg)
+    (func (export "call_pair") (param v128 i64) (result v128)
+        (local v128)
+        local.get 0
+        local.get 1
+        call $pair
+        local.set 2
+        i64x2.splat
+        i64x2.add
+        local.get 2
+        i64x2.sub)
+    (func $many (export "many") (param v128) (result v128 v128 v128)
+        local.get 0
+        local.get 0
+        i64.const 1
+        i64x2.splat
+        i64x2.add
+        local.get 0
+        v128.not)
+    (func (export "call_many") (param v128) (result f64 v128)
+        ;; A deeper operand stack, shouldn't be touched
+        f64.const 1000
+        local.get 0
+        call $many
+        v128.xor
+        v128.xor)
+)
+"#,
+        params = params.join(" "),
+        call_args = args(&constant(vectors[0])),
+        tail_args = args("local.get 0\n"),
+    );
+    let binary = wat::parse_str(src).expect("binary module");
+    let module = X86_64Compiler::default()
+        .compile(&binary)
+        .expect("compiled module");
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    let add = |a: u128, b: u128| {
+        vector(64, |i| {
+            vector_lane(a, 64, i).wrapping_add(vector_lane(b, 64, i))
+        })
+    };
+
+    emulator
+        .call_function(emu_mod.clone(), "call_wide")
+        .expect("call");
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM0).unwrap(),
+        wide(&vectors)
+    );
+
+    let param = vector(32, |i| 0xFFFF_0000 + i as u64);
+    emulator
+        .write_vector_register(testing::XMM0, param)
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "tail")
+        .expect("call");
+    let mut expected = vectors.clone();
+    expected[0] = param;
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM0).unwrap(),
+        wide(&expected)
+    );
+
+    // Called by the host, with stack arguments pushed in reverse order
+    let registers = [
+        testing::XMM0,
+        testing::XMM1,
+        testing::XMM2,
+        testing::XMM3,
+        testing::XMM4,
+        testing::XMM5,
+        testing::XMM6,
+    ];
+    for (register, v) in registers.iter().zip(&vectors) {
+        emulator.write_vector_register(*register, *v).unwrap();
+    }
+    emulator
+        .write_xmm_register(testing::XMM7, double.to_bits())
+        .unwrap();
+    for slot in [
+        (vectors[8] >> 64) as u64,
+        vectors[8] as u64,
+        0xDEAD_BEEF,
+        0xDEAD_BEEF_0000_0000 | float.to_bits() as u64,
+        (vectors[7] >> 64) as u64,
+        vectors[7] as u64,
+    ] {
+        emulator.push(slot).unwrap();
+    }
+    emulator
+        .call_function(emu_mod.clone(), "wide")
+        .expect("call");
+    for _ in 0..6 {
+        emulator.pop().unwrap();
+    }
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM0).unwrap(),
+        wide(&vectors)
+    );
+
+    let negated = vector(64, |i| vector_lane(param, 64, i).wrapping_neg());
+    emulator
+        .write_vector_register(testing::XMM0, param)
+        .unwrap();
+    emulator.write_register(testing::RDI, 5).unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "pair")
+        .expect("call");
+    assert_eq!(emulator.read_vector_register(testing::XMM0).unwrap(), param);
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), 5);
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM1).unwrap(),
+        negated
+    );
+    emulator
+        .call_function(emu_mod.clone(), "call_pair")
+        .expect("call");
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM0).unwrap(),
+        add(add(param, param), vector(64, |_| 5))
+    );
+
+    // Called by the host with a return area
+    let area = emulator.allocate(3 * 16).expect("return area");
+    emulator.write_register(testing::RDI, area).unwrap();
+    emulator
+        .write_vector_register(testing::XMM0, param)
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "many")
+        .expect("call");
+    assert_eq!(emulator.read_register(testing::RAX).unwrap(), area);
+    let results = emulator.read_memory(area, 3 * 16).expect("results");
+    let results: Vec<u128> = results
+        .chunks(16)
+        .map(|slot| u128::from_le_bytes(slot.try_into().unwrap()))
+        .collect();
+    let incremented = add(param, vector(64, |_| 1));
+    assert_eq!(results, [param, incremented, !param]);
+
+    emulator
+        .write_vector_register(testing::XMM0, param)
+        .unwrap();
+    emulator
+        .call_function(emu_mod.clone(), "call_many")
+        .expect("call");
+    assert_eq!(
+        f64::from_bits(emulator.read_xmm_register(testing::XMM0).unwrap()),
+        1000.0
+    );
+    assert_eq!(
+        emulator.read_vector_register(testing::XMM1).unwrap(),
+        !incremented
+    );
+}
+
+#[test]
+fn validation() {
+    let invalid_function = |src: &str| {
+        let binary = wat::parse_str(src).expect("binary module");
+        match X86_64Compiler::default().compile(&binary) {
+            Err(Error::InvalidFunction {
+                function, offset, ..
+            }) => (binary, function, offset),
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => panic!("invalid module compiled"),
+        }
+    };
+
+    // Functions are numbered after imported ones
+    let (_, function, _) = invalid_function(
+        r#"
+(module
+    (import "env" "f" (func))
+    (func)
+    (func (result i32)
+        i64.const 0)
+)
+"#,
+    );
+    assert_eq!(function, 2);
+
+    let (binary, function, offset) = invalid_function(
+        r#"
+(module
+    (func (param i32)
+        local.get 5
+        drop)
+)
+"#,
+    );
+    assert_eq!(function, 0);
+    assert_eq!(&binary[offset..offset + 2], &[0x20, 5]);
+
+    let (_, function, _) = invalid_function(
+        r#"
+(module
+    (func)
+    (func (result i32)
+        block (result i32)
+            i32.const 0
+        end
+        f32.add)
+)
+"#,
+    );
+    assert_eq!(function, 1);
+
+    // A block left open by the function's end
+    let unbalanced = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+        0x03, 0x02, 0x01, 0x00, // function section
+        0x0a, 0x06, 0x01, 0x04, 0x00, 0x02, 0x40, 0x0b, // code section
+    ];
+    assert!(matches!(
+        X86_64Compiler::default().compile(&unbalanced),
+        Err(Error::InvalidFunction { function: 0, .. })
+    ));
+
+    let modules = [
+        r#"(module (func (type 3)))"#,
+        r#"(module (func call 7))"#,
+        r#"(module (memory 1) (data (i32.const 0) "") (func (result i64) i32.const 0 i64.load 1))"#,
+        r#"(module (global (mut i32) (i32.const 0)) (global i32 (global.get 0)))"#,
+    ];
+    for src in modules {
+        let binary = wat::parse_str(src).expect("binary module");
+        let result = X86_64Compiler::default().compile(&binary);
+        assert!(
+            matches!(
+                result,
+                Err(Error::InvalidModule { .. }) | Err(Error::InvalidFunction { .. })
+            ),
+            "{}",
+            src
+        );
+    }
+    assert!(matches!(
+        X86_64Compiler::default().compile(b"\0asm\x02\0\0\0"),
+        Err(Error::InvalidModule { offset: 4, .. })
+    ));
+}
+
+#[test]
+fn register_allocation() {
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (func $id (param i64) (result i64)
+        local.get 0)
+    (func $id32 (param i32) (result i32)
+        local.get 0)
+    (func (export "sum") (param i32) (result i64)
+        (local i64 i32)
+        block
+            loop
+                local.get 2
+                local.get 0
+                i32.ge_u
+                br_if 1
+                local.get 1
+                local.get 2
+                i64.extend_i32_u
+                local.get 2
+                i64.extend_i32_u
+                i64.mul
+                i64.add
+                local.set 1
+                local.get 2
+                i32.const 3
+                i32.shl
+                local.get 1
+                i64.store
+                local.get 2
+                i32.const 1
+                i32.add
+                local.set 2
+                br 0
+            end
+        end
+        local.get 0
+        i32.const 1
+        i32.sub
+        i32.const 3
+        i32.shl
+        i64.load)
+    ;; More live values than registers
+    (func (export "pressure") (param i64 i64) (result i64)
+        local.get 0
+        i64.const 1
+        i64.add
+        local.get 0
+        i64.const 2
+        i64.mul
+        local.get 1
+        i64.const 3
+        i64.sub
+        local.get 1
+        i64.const 4
+        i64.xor
+        local.get 0
+        local.get 1
+        i64.and
+        local.get 0
+        local.get 1
+        i64.or
+        local.get 0
+        i64.const 5
+        i64.shl
+        local.get 1
+        i64.const 0x123456789
+        i64.add
+        i64.add
+        i64.sub
+        i64.mul
+        i64.xor
+        i64.add
+        i64.sub
+        i64.add)
+    ;; Locals read before they are set keep the old value
+    (func (export "locals") (param i64 i64) (result i64)
+        local.get 0
+        local.get 1
+        local.set 0
+        local.get 0
+        i64.sub
+        local.get 1
+        local.tee 0
+        i64.add)
+    (func (export "locals_spilled") (param i64 i64) (result i64)
+        local.get 0
+        local.get 1
+        i64.const 1
+        i64.add
+        local.get 1
+        i64.const 2
+        i64.add
+        local.get 1
+        i64.const 3
+        i64.add
+        local.get 1
+        i64.const 4
+        i64.add
+        local.get 1
+        i64.const 5
+        i64.add
+        local.get 1
+        i64.const 6
+        i64.add
+        local.get 1
+        local.set 0
+        i64.add
+        i64.add
+        i64.add
+        i64.add
+        i64.add
+        i64.add
+        local.get 0
+        i64.sub)
+    (func (export "bits") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.shl
+        local.get 0
+        local.get 1
+        i32.shr_s
+        i32.xor
+        local.get 0
+        i32.const 35
+        i32.rotl
+        i32.add
+        local.get 0
+        local.get 1
+        i32.lt_s
+        i32.add
+        local.get 0
+        local.get 1
+        i32.gt_u
+        i32.add
+        local.get 1
+        i32.eqz
+        i32.add)
+    ;; The upper half of `i32` values stays zeroed when they are flushed
+    (func (export "extend") (param i32) (result i64)
+        i32.const -1
+        i64.extend_i32_u
+        i32.const -2
+        local.get 0
+        i32.add
+        i64.extend_i32_s
+        call $id
+        i64.add
+        i32.const -1
+        call $id32
+        i64.extend_i32_u
+        i64.add)
+    (func (export "memory") (param i32 i64) (result i64)
+        local.get 0
+        local.get 1
+        i64.store offset=8
+        local.get 0
+        i32.const 0xAB
+        i32.store8 offset=9
+        local.get 0
+        i64.load offset=8
+        local.get 0
+        i64.load16_s offset=8
+        i64.add
+        local.get 0
+        i32.load8_u offset=9
+        i64.extend_i32_u
+        i64.add)
+    (func (export "condition") (param i32) (result i32)
+        local.get 0
+        i32.const 10
+        i32.lt_u
+        if (result i32)
+            local.get 0
+            i32.const 100
+            i32.add
+        else
+            local.get 0
+        end)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let run = |register_allocation: bool| {
+        let module = X86_64Compiler::default()
+            .register_allocation(register_allocation)
+            .compile(&binary)
+            .expect("compiled module");
+        let mut emulator = Emulator::new().expect("emulator");
+        let emu_mod = emulator.add_module(module).expect("module addition");
+        emulator
+            .instantiate(emu_mod.clone())
+            .expect("instantiation");
+        let mut call = |function: &str, args: &[u64]| {
+            let registers = [testing::RDI, testing::RSI];
+            for (register, arg) in registers.iter().zip(args) {
+                emulator.write_register(*register, *arg).unwrap();
+            }
+            emulator
+                .call_function(emu_mod.clone(), function)
+                .expect("call");
+            emulator.read_register(testing::RAX).unwrap()
+        };
+        let sum = call("sum", &[100]);
+        let count = emu_mod.borrow().total_instruction_execution_count();
+        let results = [
+            sum,
+            call("pressure", &[7, 11]),
+            call("pressure", &[u64::MAX - 3, 0x8000_0000]),
+            call("locals", &[50, 8]),
+            call("locals_spilled", &[50, 8]),
+            call("bits", &[0x8000_1234, 3]),
+            call("bits", &[5, 0]),
+            call("extend", &[0]),
+            call("memory", &[16, 0x1122_3344_5566_8899]),
+            call("condition", &[3]),
+            call("condition", &[30]),
+        ];
+        (results, count)
+    };
+
+    let pressure = |a: u64, b: u64| {
+        let mut stack = vec![
+            a.wrapping_add(1),
+            a.wrapping_mul(2),
+            b.wrapping_sub(3),
+            b ^ 4,
+            a & b,
+            a | b,
+            a << 5,
+            b.wrapping_add(0x1_2345_6789),
+        ];
+        let ops: [fn(u64, u64) -> u64; 7] = [
+            u64::wrapping_add,
+            u64::wrapping_sub,
+            u64::wrapping_mul,
+            |a, b| a ^ b,
+            u64::wrapping_add,
+            u64::wrapping_sub,
+            u64::wrapping_add,
+        ];
+        for op in ops {
+            let b = stack.pop().unwrap();
+            let a = stack.pop().unwrap();
+            stack.push(op(a, b));
+        }
+        stack[0]
+    };
+    let bits = |a: u32, b: u32| {
+        ((a << b) ^ ((a as i32 >> b) as u32))
+            .wrapping_add(a.rotate_left(3))
+            .wrapping_add(((a as i32) < (b as i32)) as u32)
+            .wrapping_add((a > b) as u32)
+            .wrapping_add((b == 0) as u32) as u64
+    };
+    let expected = [
+        (0..100u64).map(|i| i * i).sum(),
+        pressure(7, 11),
+        pressure(u64::MAX - 3, 0x8000_0000),
+        50,
+        50 + 5 * 8 + 21,
+        bits(0x8000_1234, 3),
+        bits(5, 0),
+        0xFFFF_FFFF - 2 + 0xFFFF_FFFF,
+        0x1122_3344_5566_AB99u64.wrapping_add(0xFFFF_FFFF_FFFF_AB99) + 0xAB,
+        103,
+        30,
+    ];
+
+    let (allocated, allocated_count) = run(true);
+    let (stacked, stacked_count) = run(false);
+    assert_eq!(allocated, expected);
+    assert_eq!(stacked, expected);
+    // Keeping the loop's operands in registers saves about half of the
+    // instructions
+    assert!(allocated_count * 3 < stacked_count * 2);
+}
+
+#[test]
+fn optimization() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    (data (i32.const 0) "\01\00\00\00\02\00\00\00\03\00\00\00\04\00\00\00")
+    (func $square (param i64) (result i64)
+        local.get 0
+        local.get 0
+        i64.mul)
+    ;; Calls a function small enough to be inlined in a loop
+    (func (export "squares") (param i32) (result i64)
+        (local i64 i32)
+        block
+            loop
+                local.get 2
+                local.get 0
+                i32.ge_u
+                br_if 1
+                local.get 1
+                local.get 2
+                i64.extend_i32_u
+                call $square
+                i64.add
+                local.set 1
+                local.get 2
+                i32.const 1
+                i32.add
+                local.set 2
+                br 0
+            end
+        end
+        local.get 1)
+    ;; Accesses at constant addresses within the initial size, and those
+    ;; covered by an earlier access at the same address, aren't checked
+    (func (export "loads") (param i32) (result i32)
+        i32.const 0
+        i32.load
+        i32.const 4
+        i32.load
+        i32.add
+        local.get 0
+        i32.load offset=8
+        i32.add
+        local.get 0
+        i32.load offset=4
+        i32.add)
+    ;; Folds down to a constant
+    (func (export "folded") (result i32)
+        (local i32)
+        i32.const 6
+        i32.const 7
+        i32.mul
+        local.set 0
+        local.get 0
+        i32.eqz
+        if (result i32)
+            unreachable
+        else
+            local.get 0
+            i32.const 2
+            i32.shl
+        end)
+    (func (export "dispatch") (param i32 i32) (result i32)
+        block
+            block
+                block
+                    local.get 0
+                    br_table 0 1 2
+                end
+                local.get 1
+                i32.const 10
+                i32.add
+                return
+            end
+            local.get 1
+            i32.const 3
+            i32.div_s
+            return
+        end
+        i32.const 7
+        local.get 1
+        local.get 1
+        i32.const 5
+        i32.gt_s
+        select)
+    (func (export "divide") (param i64 i64) (result i64)
+        local.get 0
+        local.get 1
+        i64.div_u)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let run = |optimize: bool| {
+        let module = X86_64Compiler::default()
+            .optimize(optimize)
+            .compile(&binary)
+            .expect("compiled module");
+        let mut emulator = Emulator::new().expect("emulator");
+        let emu_mod = emulator.add_module(module).expect("module addition");
+        emulator
+            .instantiate(emu_mod.clone())
+            .expect("instantiation");
+        let mut counts = vec![];
+        let mut call = |function: &str, args: &[u64]| {
+            let registers = [testing::RDI, testing::RSI];
+            for (register, arg) in registers.iter().zip(args) {
+                emulator.write_register(*register, *arg).unwrap();
+            }
+            let before = emu_mod.borrow().total_instruction_execution_count();
+            let result = emulator
+                .call_function(emu_mod.clone(), function)
+                .map(|_| emulator.read_register(testing::RAX).unwrap());
+            counts.push(emu_mod.borrow().total_instruction_execution_count() - before);
+            result
+        };
+        let results = [
+            call("squares", &[100]),
+            call("folded", &[]),
+            call("loads", &[4]),
+            call("loads", &[65532]),
+            call("dispatch", &[0, 5]),
+            call("dispatch", &[1, 0xFFFF_FFF4]),
+            call("dispatch", &[2, 6]),
+            call("dispatch", &[0x8000_0000, 3]),
+            call("divide", &[100, 7]),
+            call("divide", &[100, 0]),
+        ];
+        (
+            results.map(|result| match result {
+                Ok(value) => Ok(value),
+                Err(testing::Error::Trap(trap)) => Err(trap),
+                Err(_) => panic!("call failed"),
+            }),
+            counts,
+        )
+    };
+
+    let expected = [
+        Ok((0..100u64).map(|i| i * i).sum()),
+        Ok(168),
+        Ok(1 + 2 + 4 + 3),
+        Err(Trap::OutOfBoundsMemoryAccess),
+        Ok(15),
+        Ok(0xFFFF_FFFC),
+        Ok(7),
+        Ok(3),
+        Ok(14),
+        Err(Trap::IntegerDivideByZero),
+    ];
+    let (optimized, optimized_counts) = run(true);
+    let (baseline, baseline_counts) = run(false);
+    assert_eq!(optimized, expected);
+    assert_eq!(baseline, expected);
+    // Inlining and keeping values in registers across the loop pay off, as
+    // do unchecked accesses, and folded functions come down to their result
+    assert!(optimized_counts[0] * 2 < baseline_counts[0]);
+    assert!(optimized_counts[1] * 2 < baseline_counts[1]);
+    assert!(optimized_counts[2] < baseline_counts[2]);
+}
+
+#[test]
+fn tiered_compilation() {
+    use crate::trap::Trap;
+    use testing::Emulator;
+    let src = r#"
+(module
+    (memory 1)
+    ;; Adds 45 times its parameter to a running total kept in memory, and
+    ;; divides the total by the parameter
+    (func $work (param i32) (result i32)
+        (local i32 i32)
+        loop
+            local.get 2
+            local.get 1
+            local.get 0
+            i32.mul
+            i32.add
+            local.set 2
+            local.get 1
+            i32.const 1
+            i32.add
+            local.tee 1
+            i32.const 10
+            i32.lt_u
+            br_if 0
+        end
+        i32.const 0
+        i32.const 0
+        i32.load
+        local.get 2
+        i32.add
+        i32.store
+        i32.const 0
+        i32.load
+        local.get 0
+        i32.div_u)
+    (func (export "run") (param i32 i32) (result i32)
+        (local i32)
+        loop
+            local.get 0
+            call $work
+            local.set 2
+            local.get 0
+            i32.const 1
+            i32.add
+            local.tee 0
+            local.get 1
+            i32.lt_u
+            br_if 0
+        end
+        local.get 2)
+)
+"#;
+    let binary = wat::parse_str(src).expect("binary module");
+    let compiler = X86_64Compiler::default().tiered(true);
+    let module = compiler.compile(&binary).expect("compiled module");
+    let mut emulator = Emulator::new().expect("emulator");
+    let emu_mod = emulator.add_module(module).expect("module addition");
+    emulator
+        .instantiate(emu_mod.clone())
+        .expect("instantiation");
+    let mut total = 0u32;
+    let mut expected = |range: core::ops::Range<u32>| {
+        range
+            .map(|p| {
+                total = total.wrapping_add(45 * p);
+                total / p
+            })
+            .last()
+            .unwrap() as u64
+    };
+    let run = |emulator: &mut Emulator, start: u64, end: u64| {
+        emulator.write_register(testing::RDI, start).unwrap();
+        emulator.write_register(testing::RSI, end).unwrap();
+        emulator
+            .call_function(emu_mod.clone(), "run")
+            .map(|_| emulator.read_register(testing::RAX).unwrap())
+    };
+
+    assert_eq!(run(&mut emulator, 1, 51).unwrap(), expected(1..51));
+    // Every call and loop iteration counts
+    assert_eq!(emu_mod.borrow().execution_count(0), Some(50 + 50 * 10));
+    assert_eq!(emu_mod.borrow().execution_count(1), Some(1 + 50));
+    assert_eq!(emu_mod.borrow().hot_functions(500), vec![0]);
+
+    let base = emu_mod.borrow().offset();
+    let address = emulator.allocate(4096).expect("allocation");
+    // Recompiling from other code of the same size, or for other features,
+    // fails and leaves the function hot
+    let other = wat::parse_str(src.replace("i32.const 10", "i32.const 11")).expect("binary module");
+    assert_eq!(other.len(), binary.len());
+    let sse4_1 = X86_64Compiler::new(Features {
+        sse4_1: true,
+        ..Features::default()
+    })
+    .tiered(true);
+    for (compiler, wasm) in [(&compiler, &other), (&sse4_1, &binary)] {
+        assert!(matches!(
+            compiler.recompile(&mut emu_mod.borrow_mut(), wasm, 0, base, address),
+            Err(Error::ModuleMismatch)
+        ));
+    }
+    assert_eq!(emu_mod.borrow().hot_functions(500), vec![0]);
+    let code = compiler
+        .recompile(&mut emu_mod.borrow_mut(), &binary, 0, base, address)
+        .expect("recompiled function")
+        .expect("optimized function");
+    assert!(code.len() <= 4096);
+    emulator.write_memory(address, &code).expect("code");
+    emu_mod.borrow_mut().link_function(0, address);
+    assert_eq!(emu_mod.borrow().hot_functions(500), vec![]);
+
+    // Calls now go to the optimized code, which doesn't count, and still
+    // shares the module's memory and traps
+    let baseline = emu_mod.borrow().function_entry_point(0).unwrap();
+    let entries = emu_mod.borrow().instruction_execution_count(baseline);
+    assert_eq!(run(&mut emulator, 51, 101).unwrap(), expected(51..101));
+    assert_eq!(
+        emu_mod.borrow().instruction_execution_count(baseline),
+        entries
+    );
+    assert_eq!(emu_mod.borrow().execution_count(0), Some(550));
+    assert!(matches!(
+        run(&mut emulator, 0, 1),
+        Err(testing::Error::Trap(Trap::IntegerDivideByZero))
+    ));
+}
+
+#[test]
+fn artifacts() {
+    use testing::Emulator;
+    let lib_src = r#"
+(module
+    (func (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add)
+)
+"#;
+    let app_src = r#"
+(module
+    (type $binary (func (param i32 i32) (result i32)))
+    (import "lib" "add" (func $add (type $binary)))
+    (memory 1)
+    (global $total (export "total") (mut i32) (i32.const 0))
+    (table 2 funcref)
+    (elem (i32.const 0) $double $add)
+    (data (i32.const 16) "\05\00\00\00")
+    (func $double (type $binary)
+        local.get 0
+        local.get 0
+        i32.add)
+    (func (export "run") (param i32) (result i32)
+        i32.const 16
+        i32.load
+        i32.const 7
+        i32.const 1
+        local.get 0
+        call_indirect (type $binary)
+        call $add
+        global.get $total
+        i32.add
+        global.set $total
+        global.get $total)
+)
+"#;
+    let lib_binary = wat::parse_str(lib_src).expect("binary module");
+    let app_binary = wat::parse_str(app_src).expect("binary module");
+    let lib_module = X86_64Compiler::default()
+        .compile(&lib_binary)
+        .expect("compiled module");
+    let artifact = X86_64Compiler::default()
+        .compile(&app_binary)
+        .expect("compiled module")
+        .to_artifact();
+
+    assert_eq!(
+        ArtifactHeader::read(&artifact),
+        Ok(ArtifactHeader {
+            format_version: FORMAT_VERSION,
+            codegen_version: CODEGEN_VERSION,
+            source_hash: source_hash(&app_binary),
+            features: Features::default(),
+        })
+    );
+    let app_module =
+        AssembledModule::from_artifact(&artifact, Features::default()).expect("loaded module");
+    assert_eq!(app_module.source_hash(), source_hash(&app_binary));
+    assert_eq!(app_module.to_artifact(), artifact);
+    // The import, the host hooks and the defined memory get relocated
+    let relocated = relocations(&artifact).expect("relocations");
+    assert_eq!(relocated, app_module.relocations());
+    let mut kinds: Vec<RelocationKind> = relocated.iter().map(|r| r.kind).collect();
+    kinds.sort();
+    assert_eq!(
+        kinds,
+        [
+            RelocationKind::Function,
+            RelocationKind::MemoryDescriptor,
+            RelocationKind::TrapHandler,
+            RelocationKind::ExceptionState,
+            RelocationKind::Futex,
+        ]
+    );
+    let import = relocated
+        .iter()
+        .find(|r| r.kind == RelocationKind::Function)
+        .unwrap();
+    assert_eq!(
+        LittleEndian::read_u64(&app_module.binary()[import.offset..]),
+        0xBADC0FFEE0DDF00D
+    );
+
+    let mut emulator = Emulator::new().expect("emulator");
+    let lib = emulator.add_module(lib_module).expect("module addition");
+    let app = emulator.add_module(app_module).expect("module addition");
+    let add = lib.borrow().offset() + lib.borrow().function_entry_point("add").unwrap() as u64;
+    app.borrow_mut().link_import("lib", Some("add"), add);
+    emulator.instantiate(app.clone()).expect("instantiation");
+    let mut run = |index: u64| {
+        emulator.write_register(testing::RDI, index).unwrap();
+        emulator.call_function(app.clone(), "run").expect("call");
+        emulator.read_register(testing::RAX).unwrap()
+    };
+    // 5 + 7 * 2, then 5 + 7 + 1 on top of that
+    assert_eq!(run(0), 19);
+    assert_eq!(run(1), 32);
+
+    let load = |artifact: &[u8]| AssembledModule::from_artifact(artifact, Features::default());
+    assert_eq!(
+        load(&artifact[..artifact.len() - 1]).err(),
+        Some(ArtifactError::Malformed)
+    );
+    assert_eq!(load(&artifact[1..]).err(), Some(ArtifactError::Malformed));
+    let mut modified = artifact.clone();
+    modified[MAGIC.len()] = 2;
+    assert_eq!(
+        load(&modified).err(),
+        Some(ArtifactError::UnsupportedFormat(2))
+    );
+    let mut modified = artifact.clone();
+    let version = MAGIC.len() + size_of::<u32>();
+    LittleEndian::write_u32(&mut modified[version..], CODEGEN_VERSION + 1);
+    assert_eq!(
+        load(&modified).err(),
+        Some(ArtifactError::CodegenMismatch(CODEGEN_VERSION + 1))
+    );
+    // Relocations that don't match the module's slots are rejected
+    let mut modified = artifact.clone();
+    let header_len = MAGIC.len() + 3 * size_of::<u32>() + size_of::<u64>();
+    let code_len = LittleEndian::read_u32(&artifact[header_len..]) as usize;
+    let first = header_len + size_of::<u32>() + code_len + size_of::<u32>();
+    modified[first] ^= 8;
+    assert_eq!(load(&modified).err(), Some(ArtifactError::Malformed));
+    // So are offsets past the code, however large
+    let relocations = LittleEndian::read_u32(&artifact[first - size_of::<u32>()..]) as usize;
+    // Offsets of the instantiation routine, the trap handler and so on
+    // follow the relocations and the floating-point flag
+    let instantiation = first + relocations * (size_of::<u64>() + 1) + 1;
+    for offset in [code_len as u64, u64::MAX - 3] {
+        let mut modified = artifact.clone();
+        LittleEndian::write_u64(&mut modified[instantiation..], offset);
+        assert_eq!(load(&modified).err(), Some(ArtifactError::Malformed));
+    }
+    let features = Features {
+        sse4_1: true,
+        ..Features::default()
+    };
+    let artifact = X86_64Compiler::new(features)
+        .compile(&app_binary)
+        .expect("compiled module")
+        .to_artifact();
+    assert_eq!(
+        load(&artifact).err(),
+        Some(ArtifactError::MissingFeatures(features))
+    );
+    assert!(AssembledModule::from_artifact(&artifact, features).is_ok());
+}
@@ -0,0 +1,53 @@
+use crate::trap::Trap;
+use crate::x86_64::Error;
+use alloc::collections::BTreeMap;
+use iced_x86::code_asm::{edi, qword_ptr, rsp, CodeAssembler, CodeLabel};
+
+/// Stubs raising traps, shared by all functions of a module
+///
+/// A trap is raised by calling the host's trap handler, whose address is
+/// kept in a slot filled in with
+/// [`AssembledModule::link_trap_handler`](super::AssembledModule::link_trap_handler).
+/// The handler follows the System V calling convention, taking the trap code
+/// as its only argument, and must not return.
+pub(crate) struct TrapStubs {
+    stubs: BTreeMap<Trap, CodeLabel>,
+    handler: CodeLabel,
+}
+
+impl TrapStubs {
+    pub(crate) fn new(assembler: &mut CodeAssembler) -> Self {
+        Self {
+            stubs: Trap::ALL
+                .iter()
+                .map(|trap| (*trap, assembler.create_label()))
+                .collect(),
+            handler: assembler.create_label(),
+        }
+    }
+
+    /// Label to jump to in order to raise `trap`
+    pub(crate) fn label(&self, trap: Trap) -> CodeLabel {
+        self.stubs[&trap]
+    }
+
+    /// Emits the stubs, followed by the handler slot. Returns the slot's
+    /// offset in the module binary.
+    pub(crate) fn emit(&mut self, assembler: &mut CodeAssembler) -> Result<usize, Error> {
+        let mut raise = assembler.create_label();
+        for (trap, label) in self.stubs.iter_mut() {
+            assembler.set_label(label)?;
+            assembler.mov(edi, trap.code())?;
+            assembler.jmp(raise)?;
+        }
+        assembler.set_label(&mut raise)?;
+        // Operand stack may be at any depth
+        assembler.and(rsp, -16)?;
+        assembler.call(qword_ptr(self.handler))?;
+        assembler.ud2()?;
+        let offset = assembler.instructions().len();
+        assembler.set_label(&mut self.handler)?;
+        assembler.dq(&[0xBADC0FFEE0DDF00D])?;
+        Ok(offset)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::x86_64::Error;
+use alloc::string::ToString;
+use wasmparser_nostd::{
+    BinaryReaderError, ImportSectionEntryType, Parser, Payload, ValidPayload, Validator,
+    WasmFeatures,
+};
+
+/// Proposals the compiler implements, on top of the MVP
+const FEATURES: WasmFeatures = WasmFeatures {
+    reference_types: true,
+    multi_value: true,
+    bulk_memory: true,
+    module_linking: false,
+    simd: true,
+    relaxed_simd: false,
+    threads: true,
+    tail_call: true,
+    deterministic_only: false,
+    multi_memory: true,
+    exceptions: true,
+    memory64: true,
+    extended_const: false,
+};
+
+/// Checks that the module is well-formed and valid, so that code generation
+/// can rely on type indices, operand types and control frames being right
+pub(crate) fn validate(module: &[u8]) -> Result<(), Error> {
+    let mut validator = Validator::new();
+    validator.wasm_features(FEATURES);
+    // Function bodies are numbered after imported functions
+    let mut function_index = 0;
+    for payload in Parser::new(0).parse_all(module) {
+        let payload = payload.map_err(invalid_module)?;
+        if let Payload::ImportSection(is) = &payload {
+            for import in is.clone() {
+                if let ImportSectionEntryType::Function(_) = import.map_err(invalid_module)?.ty {
+                    function_index += 1;
+                }
+            }
+        }
+        if let ValidPayload::Func(mut function, body) =
+            validator.payload(&payload).map_err(invalid_module)?
+        {
+            function
+                .validate(&body)
+                .map_err(|e| Error::InvalidFunction {
+                    function: function_index,
+                    offset: e.offset(),
+                    message: e.message().to_string(),
+                })?;
+            function_index += 1;
+        }
+    }
+    Ok(())
+}
+
+fn invalid_module(e: BinaryReaderError) -> Error {
+    Error::InvalidModule {
+        offset: e.offset(),
+        message: e.message().to_string(),
+    }
+}